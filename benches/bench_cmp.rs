@@ -3,19 +3,21 @@ use std::hint::black_box;
 use criterion::{Criterion, criterion_group, criterion_main};
 use evalexpr::{DefaultNumericTypes, EvalexprError, HashMapContext, Value, context_map};
 use fasteval::{Compiler, Evaler};
-use fee::{IndexedResolver, UContext, prelude::*};
+use fee::{IndexedResolver, LRpn, UContext, prelude::*};
 
 static SIMPLE_EXPR: &str = "3 * 3 - 3 / 3";
 static V_EXPR: &str = "x0 * 2";
 static VF_EXPR: &str = "s0(x0) + c0(x0)";
 static COMPLEX_EXPR: &str = "(-x2 + s0(x2 ^ 2 - 4 * x0 * x1) ^ 0.5) / c0(2 * x0)";
 
-fn fee_context() -> UContext<
+type FeeContext = UContext<
     IndexedResolver<Unlocked, f64>,
     IndexedResolver<Unlocked, ExprFn>,
     IndexedResolver<Locked, f64>,
     IndexedResolver<Locked, ExprFn>,
-> {
+>;
+
+fn fee_context() -> FeeContext {
     let mut v = IndexedResolver::new();
     v.add_id('x', 3);
     v.set('x', 0, 1.0);
@@ -143,6 +145,38 @@ fn bench_eval(c: &mut Criterion, name: &str, expr: &str) {
         let compiled = Expr::compile(expr, &ctx).unwrap();
         b.iter(|| black_box(compiled.eval(&ctx, &mut stack).unwrap()));
     });
+
+    // fee, locked RPN
+    c.bench_function(&format!("cmp/eval/fee-lrpn/{}", name), |b| {
+        let ctx = fee_context().lock();
+        let mut stack = Vec::with_capacity(expr.len() / 2);
+        let compiled = Expr::<LRpn>::try_from((expr, &ctx)).unwrap();
+        b.iter(|| black_box(compiled.eval(&ctx, &mut stack).unwrap()));
+    });
+
+    // fee, register code
+    c.bench_function(&format!("cmp/eval/fee-reg/{}", name), |b| {
+        let ctx = fee_context().lock();
+        let mut stack = Vec::with_capacity(expr.len() / 2);
+        let compiled = Expr::compile(expr, &ctx).unwrap();
+        b.iter(|| black_box(compiled.eval(&ctx, &mut stack).unwrap()));
+    });
+
+    // fee, locked RPN on a stack buffer
+    c.bench_function(&format!("cmp/eval/fee-stackless/{}", name), |b| {
+        let ctx = fee_context().lock();
+        let compiled = Expr::<LRpn>::try_from((expr, &ctx)).unwrap();
+        b.iter(|| black_box(compiled.eval_stackless::<32>(&ctx).unwrap()));
+    });
+
+    // fee, native code
+    #[cfg(feature = "jit")]
+    c.bench_function(&format!("cmp/eval/fee-jit/{}", name), |b| {
+        let ctx = fee_context().lock();
+        let compiled = Expr::<LRpn>::try_from((expr, &ctx)).unwrap();
+        let jit = fee::Jit::try_from(&compiled).unwrap();
+        b.iter(|| black_box(jit.eval_stackless::<0>(&ctx).unwrap()));
+    });
 }
 
 fn parse_group(c: &mut Criterion) {
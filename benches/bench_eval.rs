@@ -1,7 +1,7 @@
 use std::hint::black_box;
 
 use criterion::{Criterion, criterion_group, criterion_main};
-use fee::{IndexedResolver, SmallResolver, prelude::*};
+use fee::{F64x4, GradVar, IndexedResolver, SmallResolver, prelude::*};
 
 static EXPR: &str =
     "f0((2 * 21) + 3 - 35 - ((5 * 80) + 5) + p0, f0((2 * 21) + 3 - 35 - ((5 * 80) + 5) + p0))";
@@ -70,10 +70,65 @@ fn lrpn_eval(c: &mut Criterion)
     });
 }
 
+fn irpn_eval_batch(c: &mut Criterion)
+{
+    let p0: Vec<f64> = (0..10_000).map(|row| row as f64 * 0.5).collect();
+    let mut out = vec![0.0; p0.len()];
+
+    c.bench_function("internal/eval/irpn_batch", |b| {
+        let mut var_resolver = IndexedResolver::new();
+        var_resolver.add_id('p', 1);
+
+        let mut fn_resolver = IndexedResolver::new();
+        fn_resolver.add_id('f', 1);
+        fn_resolver.set('f', 0, ExprFn::new(abs));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(EXPR, &context).unwrap();
+
+        b.iter(|| {
+            expr.eval_batch(&context, &[GradVar::Indexed('p', 0)], &[&p0], &mut out)
+                .unwrap();
+            black_box(&out);
+        });
+    });
+}
+
+fn irpn_eval_lanes(c: &mut Criterion)
+{
+    let p0: Vec<f64> = (0..10_000).map(|row| row as f64 * 0.5).collect();
+    let mut out = vec![0.0; p0.len()];
+
+    c.bench_function("internal/eval/irpn_lanes", |b| {
+        let mut var_resolver = IndexedResolver::new();
+        var_resolver.add_id('p', 1);
+
+        let mut fn_resolver = IndexedResolver::new();
+        fn_resolver.add_id('f', 1);
+        fn_resolver.set('f', 0, ExprFn::new(abs));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(EXPR, &context).unwrap();
+
+        b.iter(|| {
+            expr.eval_lanes::<F64x4>(&context, &[GradVar::Indexed('p', 0)], &[&p0], &mut out)
+                .unwrap();
+            black_box(&out);
+        });
+    });
+}
+
 fn abs(x: &[f64]) -> f64
 {
     x[0].abs()
 }
 
-criterion_group!(benches, rpn_eval, irpn_eval, lrpn_eval);
+criterion_group!(
+    benches,
+    rpn_eval,
+    irpn_eval,
+    lrpn_eval,
+    irpn_eval_batch,
+    irpn_eval_lanes
+);
 criterion_main!(benches);
@@ -0,0 +1,134 @@
+/* Generated by cbindgen, do not edit. */
+
+#ifndef FEE_H
+#define FEE_H
+
+#include <stddef.h>
+
+// Result of the fallible functions, mirroring the variants of `Error`,
+// `ParseError` and `EvalError`.
+typedef enum FeeStatus {
+  FEE_STATUS_OK = 0,
+  // A pointer argument is null
+  FEE_STATUS_NULL_POINTER,
+  // A string argument is not valid UTF-8
+  FEE_STATUS_INVALID_UTF8,
+  // Variables and functions can't be added to a locked context
+  FEE_STATUS_CONTEXT_LOCKED,
+  // Expressions are compiled against a locked context
+  FEE_STATUS_CONTEXT_UNLOCKED,
+  FEE_STATUS_UNKNOWN_VAR,
+  FEE_STATUS_UNKNOWN_FN,
+  FEE_STATUS_UNEXPECTED_CHAR,
+  FEE_STATUS_INVALID_NUMBER,
+  FEE_STATUS_INVALID_ARG_COUNT,
+  FEE_STATUS_UNMATCHED_PARENTHESES,
+  FEE_STATUS_UNEXPECTED_END,
+  FEE_STATUS_STACK_UNDERFLOW,
+  FEE_STATUS_MALFORMED_EXPRESSION,
+  FEE_STATUS_INVALID_OPERATOR,
+  FEE_STATUS_UNKNOWN_DERIVATIVE,
+  FEE_STATUS_INTERNAL_INVARIANT,
+  FEE_STATUS_CODEGEN_ERROR,
+} FeeStatus;
+
+// Variables and functions expressions are compiled against.
+typedef struct FeeContext FeeContext;
+
+// Expression compiled against a locked context.
+typedef struct FeeExpr FeeExpr;
+
+// Handle to a variable of a locked context.
+typedef struct FeeVar FeeVar;
+
+// Function called with a pointer to its `argc` arguments.
+typedef double (*FeeFn)(const double *args, size_t argc);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Returns a description of the last error of the calling thread, or null if
+// none happened. The string is valid until the next failing call.
+const char *fee_last_error(void);
+
+// Creates an empty context with the `abs`, `sqrt` and `ln` functions.
+struct FeeContext *fee_context_new(void);
+
+// Frees a context along with its variable handles.
+//
+// # Safety
+// `ctx` is null or was returned by `fee_context_new`, and isn't used
+// anymore by the caller or by an expression compiled against it.
+void fee_context_free(struct FeeContext *ctx);
+
+// Adds a variable to an unlocked context, or sets its value if it already
+// exists.
+//
+// # Safety
+// `ctx` was returned by `fee_context_new` and `name` is a nul-terminated
+// string.
+enum FeeStatus fee_context_add_var(struct FeeContext *ctx, const char *name, double value);
+
+// Adds a function to an unlocked context, replacing the one with the same
+// name if any.
+//
+// # Safety
+// `ctx` was returned by `fee_context_new`, `name` is a nul-terminated
+// string and `f` can be called as long as the context is alive.
+enum FeeStatus fee_context_add_fn(struct FeeContext *ctx, const char *name, FeeFn f);
+
+// Locks the context, after which expressions can be compiled against it
+// and no variable or function can be added.
+//
+// # Safety
+// `ctx` was returned by `fee_context_new`.
+enum FeeStatus fee_context_lock(struct FeeContext *ctx);
+
+// Writes to `out` a handle to the variable `name` of a locked context, which
+// is valid as long as the context.
+//
+// # Safety
+// `ctx` was returned by `fee_context_new`, `name` is a nul-terminated
+// string and `out` is valid for writes.
+enum FeeStatus fee_context_var(struct FeeContext *ctx, const char *name, struct FeeVar **out);
+
+// Sets the value of a variable, read by the next evaluations.
+//
+// # Safety
+// `var` is null or was returned by `fee_context_var`.
+void fee_set_var(struct FeeVar *var, double value);
+
+// Returns the value of a variable, or `NaN` if `var` is null.
+//
+// # Safety
+// `var` is null or was returned by `fee_context_var`.
+double fee_get_var(const struct FeeVar *var);
+
+// Compiles `src` against a locked context, writing the expression to `out`.
+// The expression can be evaluated as long as the context is alive.
+//
+// # Safety
+// `ctx` was returned by `fee_context_new`, `src` is a nul-terminated string
+// and `out` is valid for writes.
+enum FeeStatus fee_compile(const struct FeeContext *ctx, const char *src, struct FeeExpr **out);
+
+// Frees an expression.
+//
+// # Safety
+// `expr` is null or was returned by `fee_compile`, and isn't used anymore.
+void fee_expr_free(struct FeeExpr *expr);
+
+// Evaluates an expression with the current values of the variables, writing
+// the result to `out`.
+//
+// # Safety
+// `expr` was returned by `fee_compile`, its context is still alive and `out`
+// is valid for writes.
+enum FeeStatus fee_eval(const struct FeeExpr *expr, double *out);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FEE_H */
@@ -0,0 +1,56 @@
+// Reference implementation of the `fee` imports of the WebAssembly modules
+// emitted by `Wasm::try_from`, matching the operators of fee bit for bit:
+//
+//   const { instance } = await WebAssembly.instantiate(bytes, { env, fee });
+
+const I64_MIN = -(2 ** 63);
+const I32_MIN = -(2 ** 31);
+const I32_MAX = 2 ** 31 - 1;
+
+// `x ^ y`: integer exponents (`y as i64 as f64 == y` in Rust, which also
+// holds for `2 ^ 63`) are saturated to an `i32` and applied by repeated
+// squaring like `f64::powi`, the others go through `pow`.
+export function pow(x, y) {
+  if (Number.isInteger(y) && y >= I64_MIN && y <= -I64_MIN) {
+    return powi(x, Math.min(Math.max(y, I32_MIN), I32_MAX));
+  }
+  return powf(x, y);
+}
+
+// `x % y`: the remainder truncated towards zero, which `%` already is.
+export function mod(x, y) {
+  return x % y;
+}
+
+// `f64::powi`, multiplying the squares of `x` selected by the bits of `|n|`
+// and taking the reciprocal for negative exponents.
+function powi(x, n) {
+  let bits = Math.abs(n);
+  let res = 1;
+
+  for (;;) {
+    if (bits % 2 === 1) {
+      res *= x;
+    }
+    bits = Math.floor(bits / 2);
+    if (bits === 0) {
+      break;
+    }
+    x *= x;
+  }
+
+  return n < 0 ? 1 / res : res;
+}
+
+// `f64::powf`, the C `pow`: unlike `Math.pow`, a base of 1 gives 1 even for a
+// `NaN` exponent, and -1 raised to an infinity gives 1 instead of `NaN`.
+// Finite results otherwise come from the `pow` of the JavaScript engine, which
+// may round the last bit differently from the C library fee is linked with.
+function powf(x, y) {
+  if (x === 1 || (x === -1 && !Number.isFinite(y) && !Number.isNaN(y))) {
+    return 1;
+  }
+  return Math.pow(x, y);
+}
+
+export default { pow, mod };
@@ -0,0 +1,407 @@
+//! C bindings.
+//!
+//! Contexts, expressions and variable handles are opaque pointers owned by
+//! the caller, freed with `fee_context_free` and `fee_expr_free`. Variables
+//! and functions are added to a context before it is locked with
+//! `fee_context_lock`; expressions are then compiled against the locked
+//! context and its variables are updated through `FeeVar` handles, without
+//! recompiling the expressions that read them.
+//!
+//! Every fallible function returns a [`FeeStatus`] and, when it fails, keeps
+//! a description of the error for the calling thread, which is returned by
+//! `fee_last_error`. The `include/fee.h` header is generated with cbindgen.
+//!
+//! ```c
+//! FeeContext *ctx = fee_context_new();
+//! fee_context_add_var(ctx, "x", 2.0);
+//! fee_context_add_fn(ctx, "twice", twice);
+//! fee_context_lock(ctx);
+//!
+//! FeeExpr *expr;
+//! if (fee_compile(ctx, "twice(x) + 1", &expr) != FEE_STATUS_OK) {
+//!     fprintf(stderr, "%s\n", fee_last_error());
+//! }
+//!
+//! FeeVar *x;
+//! fee_context_var(ctx, "x", &x);
+//! fee_set_var(x, 3.0);
+//!
+//! double result;
+//! fee_eval(expr, &result);
+//!
+//! fee_expr_free(expr);
+//! fee_context_free(ctx);
+//! ```
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char},
+    ptr,
+};
+
+use crate::{
+    DefaultResolver, Error, EvalError, LContext, LRpn, ParseError, Ptr, UContext, prelude::*,
+};
+
+type Vars<S> = DefaultResolver<S, String, f64>;
+type Fns<S> = DefaultResolver<S, String, ExprFn>;
+type UCtx = UContext<Vars<Unlocked>, Fns<Unlocked>, Vars<Locked>, Fns<Locked>>;
+type LCtx = LContext<Vars<Locked>, Fns<Locked>>;
+
+/// Function called with a pointer to its `argc` arguments.
+pub type FeeFn = Option<unsafe extern "C" fn(args: *const f64, argc: usize) -> f64>;
+
+/// Result of the fallible functions, mirroring the variants of `Error`,
+/// `ParseError` and `EvalError`.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FeeStatus
+{
+    Ok = 0,
+    /// A pointer argument is null
+    NullPointer,
+    /// A string argument is not valid UTF-8
+    InvalidUtf8,
+    /// Variables and functions can't be added to a locked context
+    ContextLocked,
+    /// Expressions are compiled against a locked context
+    ContextUnlocked,
+    UnknownVar,
+    UnknownFn,
+    UnexpectedChar,
+    InvalidNumber,
+    InvalidArgCount,
+    UnmatchedParentheses,
+    UnexpectedEnd,
+    StackUnderflow,
+    MalformedExpression,
+    InvalidOperator,
+    UnknownDerivative,
+    InternalInvariant,
+    CodegenError,
+}
+
+impl From<&Error<'_>> for FeeStatus
+{
+    fn from(err: &Error<'_>) -> Self
+    {
+        match err {
+            Error::UnknownVar(..) => FeeStatus::UnknownVar,
+            Error::UnknownFn(..) => FeeStatus::UnknownFn,
+            Error::ParseError(err) => match err {
+                ParseError::UnexpectedChar(..) => FeeStatus::UnexpectedChar,
+                ParseError::InvalidNumber(..) => FeeStatus::InvalidNumber,
+                ParseError::InvalidArgCount(..) => FeeStatus::InvalidArgCount,
+                ParseError::UnmatchedParentheses(..) => FeeStatus::UnmatchedParentheses,
+                ParseError::UnexpectedEnd(..) => FeeStatus::UnexpectedEnd,
+            },
+            Error::EvalError(EvalError::RPNStackUnderflow) => FeeStatus::StackUnderflow,
+            Error::EvalError(EvalError::MalformedExpression) => FeeStatus::MalformedExpression,
+            Error::InvalidOperator(..) => FeeStatus::InvalidOperator,
+            Error::UnknownDerivative(..) => FeeStatus::UnknownDerivative,
+            Error::InternalInvariant(..) => FeeStatus::InternalInvariant,
+            Error::CodegenError(..) => FeeStatus::CodegenError,
+        }
+    }
+}
+
+/// Variables and functions expressions are compiled against.
+pub struct FeeContext
+{
+    unlocked: Option<UCtx>,
+    locked: Option<LCtx>,
+    /// Handles returned by `fee_context_var`, freed along with the context.
+    /// They are boxed so they don't move when the vector grows.
+    #[allow(clippy::vec_box)]
+    handles: Vec<Box<FeeVar>>,
+}
+
+/// Expression compiled against a locked context.
+pub struct FeeExpr
+{
+    expr: Expr<LRpn<'static>>,
+    ctx: *const LCtx,
+}
+
+/// Handle to a variable of a locked context.
+pub struct FeeVar(Ptr<'static, f64>);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Keeps the description of the error for `fee_last_error`
+fn fail(status: FeeStatus, msg: impl ToString) -> FeeStatus
+{
+    let msg = msg.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(msg).ok());
+    status
+}
+
+fn null() -> FeeStatus
+{
+    fail(FeeStatus::NullPointer, "null pointer argument")
+}
+
+/// Reads a string argument
+unsafe fn str_arg<'a>(ptr: *const c_char) -> Result<&'a str, FeeStatus>
+{
+    if ptr.is_null() {
+        return Err(null());
+    }
+
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|err| fail(FeeStatus::InvalidUtf8, err))
+}
+
+/// Returns a description of the last error of the calling thread, or null if
+/// none happened. The string is valid until the next failing call.
+#[unsafe(no_mangle)]
+pub extern "C" fn fee_last_error() -> *const c_char
+{
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |msg| msg.as_ptr())
+    })
+}
+
+/// Creates an empty context with the `abs`, `sqrt` and `ln` functions.
+#[unsafe(no_mangle)]
+pub extern "C" fn fee_context_new() -> *mut FeeContext
+{
+    Box::into_raw(Box::new(FeeContext {
+        unlocked: Some(Context::new(
+            DefaultResolver::empty(),
+            DefaultResolver::new_fns(),
+        )),
+        locked: None,
+        handles: Vec::new(),
+    }))
+}
+
+/// Frees a context along with its variable handles.
+///
+/// # Safety
+/// `ctx` is null or was returned by `fee_context_new`, and isn't used
+/// anymore by the caller or by an expression compiled against it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_context_free(ctx: *mut FeeContext)
+{
+    if !ctx.is_null() {
+        drop(unsafe { Box::from_raw(ctx) });
+    }
+}
+
+/// Adds a variable to an unlocked context, or sets its value if it already
+/// exists.
+///
+/// # Safety
+/// `ctx` was returned by `fee_context_new` and `name` is a nul-terminated
+/// string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_context_add_var(
+    ctx: *mut FeeContext,
+    name: *const c_char,
+    value: f64,
+) -> FeeStatus
+{
+    let Some(ctx) = (unsafe { ctx.as_mut() }) else {
+        return null();
+    };
+    let name = match unsafe { str_arg(name) } {
+        Ok(name) => name,
+        Err(status) => return status,
+    };
+    let Some(unlocked) = &mut ctx.unlocked else {
+        return fail(FeeStatus::ContextLocked, "the context is locked");
+    };
+
+    unlocked.vars_mut().insert(name.to_string(), value);
+    FeeStatus::Ok
+}
+
+/// Adds a function to an unlocked context, replacing the one with the same
+/// name if any.
+///
+/// # Safety
+/// `ctx` was returned by `fee_context_new`, `name` is a nul-terminated
+/// string and `f` can be called as long as the context is alive.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_context_add_fn(
+    ctx: *mut FeeContext,
+    name: *const c_char,
+    f: FeeFn,
+) -> FeeStatus
+{
+    let (Some(ctx), Some(f)) = (unsafe { ctx.as_mut() }, f) else {
+        return null();
+    };
+    let name = match unsafe { str_arg(name) } {
+        Ok(name) => name,
+        Err(status) => return status,
+    };
+    let Some(unlocked) = &mut ctx.unlocked else {
+        return fail(FeeStatus::ContextLocked, "the context is locked");
+    };
+
+    let f = ExprFn::from_closure(move |args| unsafe { f(args.as_ptr(), args.len()) });
+    unlocked.fns_mut().insert(name.to_string(), f);
+    FeeStatus::Ok
+}
+
+/// Locks the context, after which expressions can be compiled against it
+/// and no variable or function can be added.
+///
+/// # Safety
+/// `ctx` was returned by `fee_context_new`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_context_lock(ctx: *mut FeeContext) -> FeeStatus
+{
+    let Some(ctx) = (unsafe { ctx.as_mut() }) else {
+        return null();
+    };
+    let Some(unlocked) = ctx.unlocked.take() else {
+        return fail(FeeStatus::ContextLocked, "the context is already locked");
+    };
+
+    ctx.locked = Some(unlocked.lock());
+    FeeStatus::Ok
+}
+
+/// Writes to `out` a handle to the variable `name` of a locked context, which
+/// is valid as long as the context.
+///
+/// # Safety
+/// `ctx` was returned by `fee_context_new`, `name` is a nul-terminated
+/// string and `out` is valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_context_var(
+    ctx: *mut FeeContext,
+    name: *const c_char,
+    out: *mut *mut FeeVar,
+) -> FeeStatus
+{
+    let Some(ctx) = (unsafe { ctx.as_mut() }) else {
+        return null();
+    };
+    if out.is_null() {
+        return null();
+    }
+    let name = match unsafe { str_arg(name) } {
+        Ok(name) => name,
+        Err(status) => return status,
+    };
+    let Some(locked) = &ctx.locked else {
+        return fail(FeeStatus::ContextUnlocked, "the context isn't locked");
+    };
+    let Some(var) = locked.get_var_ptr(name) else {
+        return fail(FeeStatus::UnknownVar, format!("unknown variable '{name}'"));
+    };
+
+    // The locked resolver is never moved or freed before the handles
+    let var = unsafe { std::mem::transmute::<Ptr<'_, f64>, Ptr<'static, f64>>(var) };
+    let mut handle = Box::new(FeeVar(var));
+    unsafe { *out = &mut *handle };
+    ctx.handles.push(handle);
+    FeeStatus::Ok
+}
+
+/// Sets the value of a variable, read by the next evaluations.
+///
+/// # Safety
+/// `var` is null or was returned by `fee_context_var`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_set_var(var: *mut FeeVar, value: f64)
+{
+    if let Some(var) = unsafe { var.as_ref() } {
+        var.0.set(value);
+    }
+}
+
+/// Returns the value of a variable, or `NaN` if `var` is null.
+///
+/// # Safety
+/// `var` is null or was returned by `fee_context_var`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_get_var(var: *const FeeVar) -> f64
+{
+    unsafe { var.as_ref() }.map_or(f64::NAN, |var| var.0.get())
+}
+
+/// Compiles `src` against a locked context, writing the expression to `out`.
+/// The expression can be evaluated as long as the context is alive.
+///
+/// # Safety
+/// `ctx` was returned by `fee_context_new`, `src` is a nul-terminated string
+/// and `out` is valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_compile(
+    ctx: *const FeeContext,
+    src: *const c_char,
+    out: *mut *mut FeeExpr,
+) -> FeeStatus
+{
+    let Some(ctx) = (unsafe { ctx.as_ref() }) else {
+        return null();
+    };
+    if out.is_null() {
+        return null();
+    }
+    let src = match unsafe { str_arg(src) } {
+        Ok(src) => src,
+        Err(status) => return status,
+    };
+    let Some(locked) = &ctx.locked else {
+        return fail(FeeStatus::ContextUnlocked, "the context isn't locked");
+    };
+
+    match Expr::<LRpn>::compile(src, locked) {
+        Ok(expr) => {
+            // The expression only points into the locked context
+            let expr = unsafe { std::mem::transmute::<Expr<LRpn<'_>>, Expr<LRpn<'static>>>(expr) };
+            let expr = FeeExpr { expr, ctx: locked };
+            unsafe { *out = Box::into_raw(Box::new(expr)) };
+            FeeStatus::Ok
+        }
+        Err(err) => fail((&err).into(), err),
+    }
+}
+
+/// Frees an expression.
+///
+/// # Safety
+/// `expr` is null or was returned by `fee_compile`, and isn't used anymore.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_expr_free(expr: *mut FeeExpr)
+{
+    if !expr.is_null() {
+        drop(unsafe { Box::from_raw(expr) });
+    }
+}
+
+/// Evaluates an expression with the current values of the variables, writing
+/// the result to `out`.
+///
+/// # Safety
+/// `expr` was returned by `fee_compile`, its context is still alive and `out`
+/// is valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fee_eval(expr: *const FeeExpr, out: *mut f64) -> FeeStatus
+{
+    let Some(expr) = (unsafe { expr.as_ref() }) else {
+        return null();
+    };
+    if out.is_null() {
+        return null();
+    }
+
+    match expr.expr.eval_stackless::<16>(unsafe { &*expr.ctx }) {
+        Ok(val) => {
+            unsafe { *out = val };
+            FeeStatus::Ok
+        }
+        Err(err) => fail((&err).into(), err),
+    }
+}
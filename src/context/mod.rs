@@ -27,6 +27,12 @@ use crate::{EmptyResolver, ExprFn, IndexedResolver, prelude::*};
 ///
 /// If the Context is locked, the user can obtain a [`Ptr`] to a value held
 /// by one of the resolvers and modify it directly.
+///
+/// # Thread safety
+/// A context is `Send` and `Sync` as long as its resolvers are, which is the
+/// case of every resolver of this crate: [`ExprFn`] only holds `Send + Sync`
+/// functions. A context can then be shared by the threads evaluating its
+/// expressions, see [`Ptr`] for the values of a locked context.
 pub struct Context<S, V, F, LV, LF>
 where
     S: ResolverState,
@@ -155,7 +161,12 @@ where
         args: &[f64],
     ) -> Option<f64>
     {
-        Some(self.fns.get(identifier, index)?(args))
+        Some(self.fns.get(identifier, index)?.call(args))
+    }
+
+    pub(crate) fn get_fn_by_index(&self, identifier: usize, index: usize) -> Option<&ExprFn>
+    {
+        self.fns.get(identifier, index)
     }
 }
 
@@ -1,15 +1,60 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use thiserror::Error;
 
+use crate::{Arity, Op};
+
+/// Byte range `start..end` of a piece of the source expression.
+///
+/// Names are never empty, so the errors about a name carry the empty default
+/// span when there is no source to point at, e.g. when lowering an
+/// [`Ast`](crate::Ast) built by [`parse`](crate::parse) or by hand.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct Span
+{
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span
+{
+    pub fn new(start: usize, end: usize) -> Self
+    {
+        Span { start, end }
+    }
+
+    /// Returns whether the span covers no byte.
+    pub fn is_empty(&self) -> bool
+    {
+        self.start >= self.end
+    }
+
+    /// Returns the smallest span covering both `self` and `other`.
+    pub fn merge(self, other: Span) -> Self
+    {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl fmt::Display for Span
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
 #[derive(Debug, Error, PartialEq)]
 pub enum Error<'a>
 {
-    #[error("unknown variable '{0}'")]
-    UnknownVar(Cow<'a, str>),
+    #[error("unknown variable '{0}'{at}", at = at(.1))]
+    UnknownVar(Cow<'a, str>, Span),
 
-    #[error("unknown function '{0}'")]
-    UnknownFn(Cow<'a, str>),
+    #[error("unknown function '{0}'{at}", at = at(.1))]
+    UnknownFn(Cow<'a, str>, Span),
 
     #[error("parse error: {0}")]
     ParseError(ParseError<'a>),
@@ -17,24 +62,36 @@ pub enum Error<'a>
     #[error("eval error: {0}")]
     EvalError(EvalError),
 
+    #[error("operator {0:?} can't be applied to {1} operands")]
+    InvalidOperator(Op, usize),
+
+    #[error("no derivative known for function '{0}'")]
+    UnknownDerivative(Cow<'a, str>),
+
     #[error("internal invariant: {0}")]
     InternalInvariant(String),
+
+    #[error("code generation error: {0}")]
+    CodegenError(String),
 }
 
 #[derive(Debug, Error, PartialEq)]
 pub enum ParseError<'a>
 {
     #[error("unexpected character '{0}' at {1}")]
-    UnexpectedChar(Cow<'a, char>, usize),
+    UnexpectedChar(Cow<'a, char>, Span),
 
     #[error("invalid number '{0}' at {1}")]
-    InvalidNumber(Cow<'a, str>, usize),
+    InvalidNumber(Cow<'a, str>, Span),
+
+    #[error("function '{0}' expects {1}, found {2}{at}", at = at(.3))]
+    InvalidArgCount(Cow<'a, str>, Arity, usize, Span),
 
     #[error("unmatched parentheses at {0}")]
-    UnmatchedParentheses(usize),
+    UnmatchedParentheses(Span),
 
-    #[error("unexpected expresison end")]
-    UnexpectedEnd,
+    #[error("unexpected expresison end at {0}")]
+    UnexpectedEnd(Span),
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -46,3 +103,165 @@ pub enum EvalError
     #[error("malformed expression")]
     MalformedExpression,
 }
+
+impl Error<'_>
+{
+    /// Returns the span of the source expression the error points at, if any.
+    pub fn span(&self) -> Option<Span>
+    {
+        match self {
+            Error::UnknownVar(_, span)
+            | Error::UnknownFn(_, span)
+            | Error::ParseError(ParseError::InvalidArgCount(_, _, _, span)) => {
+                Some(*span).filter(|span| !span.is_empty())
+            }
+            Error::ParseError(err) => Some(err.span()),
+            Error::EvalError(_)
+            | Error::InvalidOperator(_, _)
+            | Error::UnknownDerivative(_)
+            | Error::InternalInvariant(_)
+            | Error::CodegenError(_) => None,
+        }
+    }
+
+    /// Moves the span the error points at `offset` bytes forward, for the
+    /// errors of an expression taken from a larger source.
+    pub(crate) fn offset(mut self, offset: usize) -> Self
+    {
+        let span = match &mut self {
+            Error::UnknownVar(_, span) | Error::UnknownFn(_, span) => span,
+            Error::ParseError(
+                ParseError::UnexpectedChar(_, span)
+                | ParseError::InvalidNumber(_, span)
+                | ParseError::InvalidArgCount(_, _, _, span)
+                | ParseError::UnmatchedParentheses(span)
+                | ParseError::UnexpectedEnd(span),
+            ) => span,
+            _ => return self,
+        };
+
+        *span = Span::new(span.start + offset, span.end + offset);
+        self
+    }
+
+    /// Returns a short suggestion on how to fix the error, if any.
+    pub fn hint(&self) -> Option<String>
+    {
+        match self {
+            Error::UnknownVar(name, _) => Some(format!("variable '{name}' is not defined")),
+            Error::UnknownFn(name, _) => Some(format!("function '{name}' is not defined")),
+            Error::ParseError(err) => err.hint(),
+            Error::EvalError(_)
+            | Error::InvalidOperator(_, _)
+            | Error::UnknownDerivative(_)
+            | Error::InternalInvariant(_)
+            | Error::CodegenError(_) => None,
+        }
+    }
+
+    /// Renders the error with the line of `src` it points at, underlined
+    /// with carets and followed by a hint when one is available.
+    ///
+    /// ```text
+    /// error: parse error: unexpected character '=' at 2..3
+    ///  --> line 1, column 3
+    ///   |
+    /// 1 | a = b
+    ///   |   ^ did you mean `==`?
+    /// ```
+    pub fn render(&self, src: &str) -> String
+    {
+        let mut out = format!("error: {self}");
+
+        let Some(span) = self.span() else {
+            return out;
+        };
+
+        let start = floor_char_boundary(src, span.start);
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let line = &src[line_start..line_end];
+
+        let line_no = src[..line_start].matches('\n').count() + 1;
+        let column = src[line_start..start].chars().count() + 1;
+
+        // Keep tabs in the padding so the carets line up with the source
+        let padding: String = src[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let end = floor_char_boundary(src, span.end.clamp(start, line_end));
+        let carets = src[start..end].chars().count().max(1);
+
+        let gutter = " ".repeat(line_no.to_string().len());
+
+        out.push_str(&format!("\n{gutter}--> line {line_no}, column {column}"));
+        out.push_str(&format!("\n{gutter} |"));
+        out.push_str(&format!("\n{line_no} | {line}"));
+        out.push_str(&format!("\n{gutter} | {padding}{}", "^".repeat(carets)));
+
+        if let Some(hint) = self.hint() {
+            out.push(' ');
+            out.push_str(&hint);
+        }
+
+        out
+    }
+}
+
+impl ParseError<'_>
+{
+    /// Returns the span of the source expression the error points at.
+    pub fn span(&self) -> Span
+    {
+        match self {
+            ParseError::UnexpectedChar(_, span)
+            | ParseError::InvalidNumber(_, span)
+            | ParseError::InvalidArgCount(_, _, _, span)
+            | ParseError::UnmatchedParentheses(span)
+            | ParseError::UnexpectedEnd(span) => *span,
+        }
+    }
+
+    /// Returns a short suggestion on how to fix the error, if any.
+    pub fn hint(&self) -> Option<String>
+    {
+        match self {
+            ParseError::UnexpectedChar(c, _) => match **c {
+                '=' => Some("did you mean `==`?".to_string()),
+                '!' => Some("did you mean `!=`?".to_string()),
+                ':' => Some("`:` without a matching `?`".to_string()),
+                ',' => Some("`,` outside of a function call".to_string()),
+                c if c.is_alphanumeric() || matches!(c, '_' | '.' | '(' | '[') => {
+                    Some("missing operator before this".to_string())
+                }
+                _ => None,
+            },
+            ParseError::InvalidNumber(_, _) => Some("not a valid number literal".to_string()),
+            ParseError::InvalidArgCount(_, _, _, _) => None,
+            ParseError::UnmatchedParentheses(_) => None,
+            ParseError::UnexpectedEnd(_) => Some("expected an operand or `:` here".to_string()),
+        }
+    }
+}
+
+#[inline]
+fn floor_char_boundary(src: &str, mut index: usize) -> usize
+{
+    index = index.min(src.len());
+    while !src.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Location suffix of the error messages, empty when there is no span
+fn at(span: &Span) -> String
+{
+    if span.is_empty() {
+        String::new()
+    } else {
+        format!(" at {span}")
+    }
+}
@@ -0,0 +1,597 @@
+use std::{borrow::Cow, fmt};
+
+use crate::{
+    Error, Span,
+    expr::{
+        Expr, Op, ParseableToken, Token, bool_to_f64, f64_to_bool, lexer::check_arity, stack_depth,
+        tree::Node,
+    },
+    prelude::*,
+    resolver::ResolverState,
+};
+
+/// Syntax tree of an expression, independent of any [`Context`].
+///
+/// An `Ast` is built with [`parse`] or by hand, and can be lowered into the
+/// [`Expr`] matching a context with [`ExprCompiler::from_ast`]. It is printed
+/// back as infix text with only the parentheses its structure requires.
+///
+/// # Examples
+/// ```rust
+/// use fee::{Ast, Op, parse, prelude::*};
+///
+/// let ast = parse("2 * x").unwrap();
+/// assert_eq!(
+///     ast,
+///     Ast::Binary(Op::Mul, Box::new(Ast::Num(2.0)), Box::new(Ast::Var("x".into())))
+/// );
+///
+/// let mut vars = fee::DefaultResolver::empty();
+/// vars.insert("x".to_string(), 3.0);
+/// let ctx = Context::new(vars, fee::EmptyResolver::new());
+///
+/// let expr = Expr::from_ast(&ast, &ctx).unwrap();
+/// assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(6.0));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ast<'e>
+{
+    /// Numeric literal, `true` and `false` are parsed as `1.0` and `0.0`
+    Num(f64),
+    Var(Cow<'e, str>),
+    /// Function call and its arguments
+    Call(Cow<'e, str>, Vec<Ast<'e>>),
+    /// Prefix operator ([`Op::Neg`] or [`Op::Not`]) and its operand
+    Unary(Op, Box<Ast<'e>>),
+    /// Binary operator and its left and right operands
+    Binary(Op, Box<Ast<'e>>, Box<Ast<'e>>),
+    /// Ternary conditional, with its condition and then and else branches
+    Cond(Box<Ast<'e>>, Box<Ast<'e>>, Box<Ast<'e>>),
+}
+
+/// Parses an expression into an [`Ast`] without resolving any name.
+///
+/// Literal subexpressions are kept as written, they are only folded when the
+/// tree is lowered into an [`Expr`].
+pub fn parse(expr: &str) -> Result<Ast<'_>, Error<'_>>
+{
+    let ctx = Context::empty();
+    let postfix: Expr<AstToken<&str>> = Expr::try_from((expr, &ctx))?;
+
+    Ast::from_postfix(postfix.tokens)
+}
+
+/// Postfix token produced by the lexer when parsing an [`Ast`], and by the
+/// compiled expressions when they are turned back into a tree
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub(super) enum AstToken<N>
+{
+    Num(f64),
+    Var(N),
+    Fn(N, usize),
+    Op(Op),
+    Jmp(usize),
+    JmpIfFalse(usize),
+    JmpAnd(usize),
+    JmpOr(usize),
+    Store(usize),
+    Load(usize),
+}
+
+impl<N: Copy> Token for AstToken<N>
+{
+    #[inline]
+    fn f64(num: f64) -> Self
+    {
+        AstToken::Num(num)
+    }
+
+    #[inline]
+    fn i64(num: i64) -> Self
+    {
+        AstToken::Num(num as f64)
+    }
+
+    #[inline]
+    fn bool(val: bool) -> Self
+    {
+        AstToken::Num(bool_to_f64(val))
+    }
+
+    #[inline]
+    fn op(op: Op) -> Self
+    {
+        AstToken::Op(op)
+    }
+
+    #[inline]
+    fn jmp(offset: usize) -> Self
+    {
+        AstToken::Jmp(offset)
+    }
+
+    #[inline]
+    fn jmp_if_false(offset: usize) -> Self
+    {
+        AstToken::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        AstToken::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
+    {
+        AstToken::JmpOr(offset)
+    }
+
+    #[inline]
+    fn store(slot: usize) -> Self
+    {
+        AstToken::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
+    {
+        AstToken::Load(slot)
+    }
+
+    #[inline]
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            AstToken::Var(_) => AstToken::Var(self),
+            AstToken::Fn(_, argc) => AstToken::Fn(self, argc),
+            AstToken::Num(num) => AstToken::Num(num),
+            AstToken::Op(op) => AstToken::Op(op),
+            AstToken::Jmp(offset) => AstToken::Jmp(offset),
+            AstToken::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            AstToken::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            AstToken::JmpOr(offset) => AstToken::JmpOr(offset),
+            AstToken::Store(slot) => AstToken::Store(slot),
+            AstToken::Load(slot) => AstToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for AstToken<&'a str>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+{
+    const FOLD: bool = false;
+
+    #[inline]
+    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>
+    {
+        Some(AstToken::Var(name))
+    }
+
+    #[inline]
+    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>
+    {
+        Some(AstToken::Fn(name, argc))
+    }
+}
+
+impl<'e> Ast<'e>
+{
+    /// Rebuilds the tree of a well-formed postfix expression.
+    pub(super) fn from_postfix<N>(
+        tokens: impl IntoIterator<Item = AstToken<N>>,
+    ) -> Result<Ast<'e>, Error<'e>>
+    where
+        N: Into<Cow<'e, str>> + Clone,
+    {
+        let tokens = tokens.into_iter().map(|tok| (tok, Span::default()));
+        Node::from_postfix(tokens).map(Ast::from)
+    }
+
+    /// Appends the tokens of the tree to `out`, folding literal subexpressions
+    /// like the lexer does. Returns the value of the tree if it was folded into
+    /// a single literal.
+    fn lower<'c, T, S, V, F, LV, LF>(
+        &'e self,
+        out: &mut Vec<T>,
+        ctx: &'c Context<S, V, F, LV, LF>,
+    ) -> Result<Option<f64>, Error<'e>>
+    where
+        S: ResolverState,
+        V: Resolver<S, f64>,
+        F: Resolver<S, ExprFn>,
+        T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+    {
+        match self {
+            Ast::Num(num) => {
+                out.push(T::f64(*num));
+                Ok(Some(*num))
+            }
+            Ast::Var(name) => {
+                let var = T::var(name, ctx).ok_or(Error::UnknownVar(
+                    Cow::Borrowed(name.as_ref()),
+                    Span::default(),
+                ))?;
+                out.push(var);
+                Ok(None)
+            }
+            Ast::Call(name, args) => {
+                check_arity(name, args.len(), Span::default(), ctx)?;
+
+                for arg in args {
+                    arg.lower(out, ctx)?;
+                }
+
+                let fun = T::fun(name, args.len(), ctx).ok_or(Error::UnknownFn(
+                    Cow::Borrowed(name.as_ref()),
+                    Span::default(),
+                ))?;
+                out.push(fun);
+                Ok(None)
+            }
+            Ast::Unary(op, operand) => {
+                if op.num_operands() != 1 {
+                    return Err(Error::InvalidOperator(*op, 1));
+                }
+
+                match operand.lower(out, ctx)? {
+                    Some(num) => Ok(Some(fold(out, *op, &[num]))),
+                    None => {
+                        out.push(T::op(*op));
+                        Ok(None)
+                    }
+                }
+            }
+            Ast::Binary(op @ (Op::And | Op::Or), lhs, rhs) => {
+                let decides = *op == Op::Or;
+
+                match lhs.lower(out, ctx)? {
+                    Some(lhs) if f64_to_bool(lhs) == decides => {
+                        // The literal decides the result, the right operand is discarded
+                        // once lowered aside, so that its names are still checked
+                        rhs.lower(&mut Vec::<T>::new(), ctx)?;
+                        let num = bool_to_f64(decides);
+                        out.pop();
+                        out.push(T::f64(num));
+                        Ok(Some(num))
+                    }
+                    Some(lhs) => match rhs.lower(out, ctx)? {
+                        Some(rhs) => {
+                            out.pop();
+                            Ok(Some(fold(out, *op, &[lhs, rhs])))
+                        }
+                        None => {
+                            out.push(T::op(*op));
+                            Ok(None)
+                        }
+                    },
+                    None => {
+                        let jmp_idx = out.len();
+                        out.push(T::jmp(0));
+
+                        rhs.lower(out, ctx)?;
+                        out.push(T::op(*op));
+
+                        let offset = out.len() - jmp_idx - 1;
+                        out[jmp_idx] = match op {
+                            Op::And => T::jmp_and(offset),
+                            _ => T::jmp_or(offset),
+                        };
+                        Ok(None)
+                    }
+                }
+            }
+            Ast::Binary(op, lhs, rhs) => {
+                if op.num_operands() != 2 {
+                    return Err(Error::InvalidOperator(*op, 2));
+                }
+
+                match (lhs.lower(out, ctx)?, rhs.lower(out, ctx)?) {
+                    (Some(lhs), Some(rhs)) => {
+                        out.pop();
+                        Ok(Some(fold(out, *op, &[lhs, rhs])))
+                    }
+                    _ => {
+                        out.push(T::op(*op));
+                        Ok(None)
+                    }
+                }
+            }
+            Ast::Cond(cond, then_branch, else_branch) => match cond.lower(out, ctx)? {
+                Some(cond) => {
+                    out.pop();
+
+                    // The discarded branch is lowered aside, so that its names are still checked
+                    if f64_to_bool(cond) {
+                        else_branch.lower(&mut Vec::<T>::new(), ctx)?;
+                        then_branch.lower(out, ctx)
+                    } else {
+                        then_branch.lower(&mut Vec::<T>::new(), ctx)?;
+                        else_branch.lower(out, ctx)
+                    }
+                }
+                None => {
+                    let cond_jmp_idx = out.len();
+                    out.push(T::jmp_if_false(0));
+                    then_branch.lower(out, ctx)?;
+
+                    let else_jmp_idx = out.len();
+                    out[cond_jmp_idx] = T::jmp_if_false(else_jmp_idx - cond_jmp_idx);
+                    out.push(T::jmp(0));
+                    else_branch.lower(out, ctx)?;
+
+                    out[else_jmp_idx] = T::jmp(out.len() - else_jmp_idx - 1);
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+/// Replaces the literal on top of `out` with the result of applying `op`
+#[inline]
+fn fold<'a, 'c, T, S, V, F, LV, LF>(out: &mut Vec<T>, op: Op, args: &[f64]) -> f64
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    T: ParseableToken<'a, 'c, S, V, F, LV, LF>,
+{
+    let num = op.apply(args);
+    out.pop();
+    out.push(T::f64(num));
+    num
+}
+
+/// Binding power of prefix operators and negative literals
+const PREFIX: u8 = 8;
+/// Binding power of literals, variables and calls
+const ATOM: u8 = u8::MAX;
+
+impl Ast<'_>
+{
+    /// Binding power of the root of the tree when printed, ternaries bind
+    /// the loosest and atoms the tightest.
+    fn binding(&self) -> u8
+    {
+        match self {
+            Ast::Cond(..) => 0,
+            Ast::Binary(op, ..) => op.precedence() + 1,
+            Ast::Unary(..) => PREFIX,
+            Ast::Num(num) if num.is_sign_negative() && num.is_finite() => PREFIX,
+            Ast::Num(_) | Ast::Var(_) | Ast::Call(..) => ATOM,
+        }
+    }
+
+    #[inline]
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, parens: bool) -> fmt::Result
+    {
+        if parens {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+impl fmt::Display for Ast<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            // Infinities and `NaN` have no literal, they print as the division
+            // they are folded from
+            Ast::Num(num) if num.is_nan() => f.write_str("(0 / 0)"),
+            Ast::Num(num) if num.is_infinite() => {
+                f.write_str(if *num > 0.0 { "(1 / 0)" } else { "(-1 / 0)" })
+            }
+            Ast::Num(num) => {
+                let abs = num.abs();
+
+                // Exponent notation for the literals that would print too many digits
+                if abs.is_finite() && abs != 0.0 && !(1e-5..1e16).contains(&abs) {
+                    write!(f, "{num:e}")
+                } else {
+                    write!(f, "{num}")
+                }
+            }
+            Ast::Var(name) => f.write_str(name),
+            Ast::Call(name, args) => {
+                write!(f, "{name}(")?;
+
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{arg}")?;
+                }
+
+                f.write_str(")")
+            }
+            Ast::Unary(op, operand) => {
+                write!(f, "{op}")?;
+                operand.fmt_operand(f, operand.binding() < PREFIX)
+            }
+            Ast::Binary(op, lhs, rhs) => {
+                let binding = self.binding();
+                let right_assoc = op.is_right_associative();
+
+                let lhs_parens =
+                    lhs.binding() < binding || (lhs.binding() == binding && right_assoc);
+                // A prefix operand can't be split by the operators after it
+                let rhs_parens = rhs.binding() != PREFIX
+                    && (rhs.binding() < binding || (rhs.binding() == binding && !right_assoc));
+
+                lhs.fmt_operand(f, lhs_parens)?;
+                write!(f, " {op} ")?;
+                rhs.fmt_operand(f, rhs_parens)
+            }
+            Ast::Cond(cond, then_branch, else_branch) => {
+                cond.fmt_operand(f, cond.binding() == 0)?;
+                write!(f, " ? {then_branch} : {else_branch}")
+            }
+        }
+    }
+}
+
+impl<'e, 'c, T, S, V, F, LV, LF> TryFrom<(&'e Ast<'_>, &'c Context<S, V, F, LV, LF>)> for Expr<T>
+where
+    T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+{
+    type Error = crate::Error<'e>;
+
+    fn try_from(
+        (ast, ctx): (&'e Ast<'_>, &'c Context<S, V, F, LV, LF>),
+    ) -> Result<Self, Self::Error>
+    {
+        let mut tokens = Vec::new();
+        ast.lower(&mut tokens, ctx)?;
+
+        Ok(Expr {
+            spans: vec![Span::default(); tokens.len()],
+            depth: stack_depth(&tokens)?,
+            tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::Rpn;
+
+    fn num(num: f64) -> Box<Ast<'static>>
+    {
+        Box::new(Ast::Num(num))
+    }
+
+    fn var(name: &'static str) -> Box<Ast<'static>>
+    {
+        Box::new(Ast::Var(Cow::Borrowed(name)))
+    }
+
+    #[test]
+    fn test_parse()
+    {
+        assert_eq!(
+            parse("2 * (3 + x) ^ -y").unwrap(),
+            Ast::Binary(
+                Op::Mul,
+                num(2.0),
+                Box::new(Ast::Binary(
+                    Op::Pow,
+                    Box::new(Ast::Binary(Op::Add, num(3.0), var("x"))),
+                    Box::new(Ast::Unary(Op::Neg, var("y"))),
+                )),
+            )
+        );
+
+        assert_eq!(
+            parse("f(1, g(), x && true) || !false").unwrap(),
+            Ast::Binary(
+                Op::Or,
+                Box::new(Ast::Call(
+                    "f".into(),
+                    vec![
+                        Ast::Num(1.0),
+                        Ast::Call("g".into(), vec![]),
+                        Ast::Binary(Op::And, var("x"), num(1.0)),
+                    ]
+                )),
+                Box::new(Ast::Unary(Op::Not, num(0.0))),
+            )
+        );
+
+        assert_eq!(
+            parse("a ? (b ? 1 : 2) : c ? 3 : 4").unwrap(),
+            Ast::Cond(
+                var("a"),
+                Box::new(Ast::Cond(var("b"), num(1.0), num(2.0))),
+                Box::new(Ast::Cond(var("c"), num(3.0), num(4.0))),
+            )
+        );
+
+        assert_eq!(
+            parse("true ? 1 + 2 : x").unwrap(),
+            Ast::Cond(
+                num(1.0),
+                Box::new(Ast::Binary(Op::Add, num(1.0), num(2.0))),
+                var("x")
+            )
+        );
+    }
+
+    #[test]
+    fn test_lower()
+    {
+        let ctx = Context::empty();
+
+        let ast = parse("(1 + 2) * x + (true ? 4 : y)").unwrap();
+        let expr: Expr<Rpn> = Expr::try_from((&ast, &ctx)).unwrap();
+        assert_eq!(
+            expr.tokens,
+            vec![
+                Rpn::Num(3.0),
+                Rpn::Var("x"),
+                Rpn::Op(Op::Mul),
+                Rpn::Num(4.0),
+                Rpn::Op(Op::Add)
+            ]
+        );
+
+        let ast = parse("x > 1 ? a && b : false || c").unwrap();
+        let expr: Expr<Rpn> = Expr::try_from((&ast, &ctx)).unwrap();
+        let compiled: Expr<Rpn> = Expr::try_from(("x > 1 ? a && b : false || c", &ctx)).unwrap();
+        assert_eq!(expr.tokens, compiled.tokens);
+
+        let ast = Ast::Unary(Op::Add, num(1.0));
+        let expr: Result<Expr<Rpn>, _> = Expr::try_from((&ast, &ctx));
+        assert_eq!(expr, Err(Error::InvalidOperator(Op::Add, 1)));
+    }
+
+    #[test]
+    fn test_display()
+    {
+        let cases = [
+            ("2 * (3 + x) ^ -y", "2 * (3 + x) ^ -y"),
+            ("(a - b) - (c - d)", "a - b - (c - d)"),
+            ("(2 ^ 3) ^ 4", "(2 ^ 3) ^ 4"),
+            ("2 ^ (3 ^ 4)", "2 ^ 3 ^ 4"),
+            ("(-x) ^ 2 + -(x ^ 2)", "(-x) ^ 2 + -x ^ 2"),
+            ("-(a + b) * !(c)", "-(a + b) * !c"),
+            ("a ? (b ? 1 : 2) : (c ? 3 : 4)", "a ? b ? 1 : 2 : c ? 3 : 4"),
+            ("(a ? b : c) ? d : (e || f)", "(a ? b : c) ? d : e || f"),
+            ("x + (a ? b : c)", "x + (a ? b : c)"),
+            ("f((x), !(y && z), g())", "f(x, !(y && z), g())"),
+            (
+                "(a < b) == (c >= d) && x >> 1 ^^ 2",
+                "a < b == (c >= d) && x >> 1 ^^ 2",
+            ),
+            ("true || 0.25 % 1_000", "1 || 0.25 % 1000"),
+        ];
+
+        for (src, expected) in cases {
+            assert_eq!(parse(src).unwrap().to_string(), expected, "{src}");
+        }
+
+        assert_eq!(Ast::Num(1e20).to_string(), "1e20");
+        assert_eq!(Ast::Num(1.5e-7).to_string(), "1.5e-7");
+        assert_eq!(Ast::Num(123456.5).to_string(), "123456.5");
+        assert_eq!(
+            Ast::Binary(Op::Pow, num(-2.0), num(2.0)).to_string(),
+            "(-2) ^ 2"
+        );
+        assert_eq!(
+            Ast::Binary(Op::Sub, var("x"), num(-2.0)).to_string(),
+            "x - -2"
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{
+    Error, EvalError, ExprFn,
+    expr::{
+        Op, bool_to_f64,
+        dual::{DualToken, GradVar},
+        f64_to_bool,
+    },
+};
+
+/// Number of rows evaluated together by [`eval_batch`]
+pub(super) const BLOCK: usize = 256;
+
+/// Instruction of a batch program, applied to a whole block of rows
+#[derive(Clone, Copy)]
+pub(super) enum Step<'f>
+{
+    Num(f64),
+    /// Column of the variable bound at the given position
+    Col(usize),
+    Fn(&'f ExprFn, usize),
+    Op(Op),
+    /// Replaces a condition and both branches of a ternary by the taken branch
+    Select,
+    /// Restricts the calls to functions to the rows where the given slot of the
+    /// stack is true, or false, among the rows of the enclosing masks
+    Mask(usize, bool),
+    /// Lifts the innermost mask
+    Unmask,
+    Store(usize),
+    Load(usize),
+}
+
+/// Evaluates `len` tokens over the rows of `columns`, writing the result of
+/// each row in `out`.
+///
+/// `token` resolves the token at the given position, the variables of `vars`
+/// carrying the position of their first match, which is also their column.
+///
+/// The tokens are resolved once into a branch-free program: both operands of
+/// `&&` and `||` and both branches of a ternary are evaluated for every row,
+/// the taken branch being selected afterwards. The program is then run over
+/// blocks of [`BLOCK`] rows, each step applying its operation to the whole
+/// block. Functions are only called on the rows taking the branch they are
+/// in, as they would be by the short circuits, the other rows of their result
+/// being left unspecified until they are discarded.
+pub(super) fn eval_batch<'e, 'f>(
+    len: usize,
+    vars: &[GradVar<'_>],
+    columns: &[&[f64]],
+    out: &mut [f64],
+    token: impl FnMut(usize) -> Result<DualToken<'f>, Error<'e>>,
+) -> Result<(), Error<'e>>
+{
+    check_columns(vars.len(), columns, out);
+    let (steps, depth, slots) = program(len, token)?;
+
+    let mut stack = vec![0.0; depth * BLOCK];
+    let mut temps = vec![0.0; slots * BLOCK];
+    // Scratch buffers for the calls to functions
+    let mut args = Vec::new();
+    let mut res = vec![0.0; BLOCK];
+    let mut masks = Masks::default();
+
+    for start in (0..out.len()).step_by(BLOCK) {
+        let n = BLOCK.min(out.len() - start);
+        let rows = start..start + n;
+        let mut top = 0;
+        masks.clear(n);
+
+        for step in &steps {
+            match *step {
+                Step::Num(num) => {
+                    block_mut(&mut stack, top, n).fill(num);
+                    top += 1;
+                }
+                Step::Col(col) => {
+                    block_mut(&mut stack, top, n).copy_from_slice(&columns[col][rows.clone()]);
+                    top += 1;
+                }
+                Step::Fn(f, argc) => {
+                    let base = top - argc;
+                    let rows = masks.rows();
+
+                    match f.vectorized() {
+                        Some(_) if rows.is_empty() => {}
+                        Some(vectorized) if rows.len() == n => {
+                            let res = &mut res[..n];
+                            let blocks: Vec<&[f64]> =
+                                (base..top).map(|slot| block(&stack, slot, n)).collect();
+                            vectorized(&blocks, res);
+                            block_mut(&mut stack, base, n).copy_from_slice(res);
+                        }
+                        // The arguments of the masked rows are gathered at the
+                        // start of blocks past the stack
+                        Some(vectorized) => {
+                            args.resize(argc * BLOCK, 0.0);
+                            for (k, slot) in (base..top).enumerate() {
+                                let gathered = &mut args[k * BLOCK..][..rows.len()];
+                                for (arg, row) in gathered.iter_mut().zip(rows) {
+                                    *arg = stack[slot * BLOCK + row];
+                                }
+                            }
+
+                            let res = &mut res[..rows.len()];
+                            let blocks: Vec<&[f64]> = (0..argc)
+                                .map(|k| &args[k * BLOCK..][..rows.len()])
+                                .collect();
+                            vectorized(&blocks, res);
+                            for (res, row) in res.iter().zip(rows) {
+                                stack[base * BLOCK + row] = *res;
+                            }
+                        }
+                        None => {
+                            for &row in rows {
+                                args.clear();
+                                args.extend((base..top).map(|slot| stack[slot * BLOCK + row]));
+                                stack[base * BLOCK + row] = f.call(&args);
+                            }
+                        }
+                    }
+
+                    top = base + 1;
+                }
+                Step::Op(op) if op.num_operands() == 1 => {
+                    unary(op, block_mut(&mut stack, top - 1, n))
+                }
+                Step::Op(op) => {
+                    let (lhs, rhs) = stack.split_at_mut((top - 1) * BLOCK);
+                    binary(op, &mut lhs[(top - 2) * BLOCK..][..n], &rhs[..n]);
+                    top -= 1;
+                }
+                Step::Select => {
+                    let (cond, branches) = stack.split_at_mut((top - 2) * BLOCK);
+                    let (then, other) = branches.split_at_mut(BLOCK);
+                    let cond = &mut cond[(top - 3) * BLOCK..][..n];
+
+                    for ((cond, then), other) in cond.iter_mut().zip(&then[..n]).zip(&other[..n]) {
+                        *cond = if f64_to_bool(*cond) { *then } else { *other };
+                    }
+                    top -= 2;
+                }
+                Step::Mask(slot, on) => masks.push(block(&stack, slot, n), on),
+                Step::Unmask => masks.pop(),
+                Step::Store(slot) => {
+                    block_mut(&mut temps, slot, n).copy_from_slice(block(&stack, top - 1, n))
+                }
+                Step::Load(slot) => {
+                    block_mut(&mut stack, top, n).copy_from_slice(block(&temps, slot, n));
+                    top += 1;
+                }
+            }
+        }
+
+        out[rows].copy_from_slice(block(&stack, 0, n));
+    }
+
+    Ok(())
+}
+
+/// Rows of a block on which functions are called, narrowed by each
+/// [`Step::Mask`] to the rows taking the branch it starts
+#[derive(Default)]
+pub(super) struct Masks
+{
+    /// Rows of each mask, all the rows of the block first
+    levels: Vec<Vec<usize>>,
+    depth: usize,
+}
+
+impl Masks
+{
+    /// Lifts every mask, for a block of `n` rows
+    pub(super) fn clear(&mut self, n: usize)
+    {
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].clear();
+        self.levels[0].extend(0..n);
+        self.depth = 1;
+    }
+
+    /// Rows of the innermost mask, in increasing order
+    pub(super) fn rows(&self) -> &[usize]
+    {
+        &self.levels[self.depth - 1]
+    }
+
+    /// Keeps the rows of the innermost mask where `cond` is `on`
+    pub(super) fn push(&mut self, cond: &[f64], on: bool)
+    {
+        if self.levels.len() == self.depth {
+            self.levels.push(Vec::new());
+        }
+
+        let (outer, inner) = self.levels.split_at_mut(self.depth);
+        let rows = &mut inner[0];
+        rows.clear();
+        rows.extend(
+            outer[self.depth - 1]
+                .iter()
+                .filter(|row| f64_to_bool(cond[**row]) == on),
+        );
+        self.depth += 1;
+    }
+
+    pub(super) fn pop(&mut self)
+    {
+        self.depth -= 1;
+    }
+}
+
+/// Checks the shape of the arguments of a batch evaluation
+pub(super) fn check_columns(vars: usize, columns: &[&[f64]], out: &[f64])
+{
+    assert_eq!(vars, columns.len(), "one column is needed per variable");
+    assert!(
+        columns.iter().all(|col| col.len() >= out.len()),
+        "the columns are shorter than the output"
+    );
+}
+
+/// Resolves the tokens into a branch-free program, returning it along with the
+/// number of blocks needed by its stack and its temporaries
+pub(super) fn program<'e, 'f>(
+    len: usize,
+    mut token: impl FnMut(usize) -> Result<DualToken<'f>, Error<'e>>,
+) -> Result<(Vec<Step<'f>>, usize, usize), Error<'e>>
+{
+    let underflow = || Error::EvalError(EvalError::RPNStackUnderflow);
+    let malformed = || Error::EvalError(EvalError::MalformedExpression);
+
+    let mut steps = Vec::with_capacity(len);
+    // End of the ternaries and of the right operands of `&&` and `||` being
+    // translated, the innermost one last
+    let mut ends: Vec<(usize, Step)> = Vec::new();
+    // Number of masks in effect
+    let mut masks = 0usize;
+    let (mut depth, mut max_depth, mut slots) = (0usize, 0, 0);
+
+    for pc in 0..=len {
+        while let Some(&(_, end)) = ends.last().filter(|(end, _)| *end == pc) {
+            ends.pop();
+            masks = masks.checked_sub(1).ok_or_else(malformed)?;
+            steps.push(Step::Unmask);
+
+            if let Step::Select = end {
+                depth = depth
+                    .checked_sub(2)
+                    .filter(|depth| *depth > 0)
+                    .ok_or_else(underflow)?;
+                steps.push(Step::Select);
+            }
+        }
+
+        if pc == len {
+            break;
+        }
+
+        let tok = token(pc)?;
+        let step = match tok {
+            DualToken::Num(num) | DualToken::Var(num, None) => Step::Num(num),
+            DualToken::Var(_, Some(col)) => Step::Col(col),
+            DualToken::Fn(f, argc) => Step::Fn(f, argc),
+            DualToken::Op(op) => Step::Op(op),
+            // The then branch jumps over the else branch, to the end of the
+            // ternary, and the rows of the else branch are those where the
+            // condition below the then branch is false
+            DualToken::Jmp(offset) => {
+                let end = pc + 1 + offset;
+                if ends.last().is_some_and(|(outer, _)| end > *outer) {
+                    return Err(malformed());
+                }
+                let cond = depth.checked_sub(2).ok_or_else(underflow)?;
+                if masks == 0 {
+                    return Err(malformed());
+                }
+
+                ends.push((end, Step::Select));
+                steps.extend([Step::Unmask, Step::Mask(cond, false)]);
+                continue;
+            }
+            // The condition is kept for the select, the then branch being
+            // masked until the jump over the else branch
+            DualToken::JmpIfFalse(_) => {
+                let cond = depth.checked_sub(1).ok_or_else(underflow)?;
+                steps.push(Step::Mask(cond, true));
+                masks += 1;
+                continue;
+            }
+            // The eager `&&` and `||` operators ending the right operand give
+            // the same result as their short circuits, the right operand being
+            // masked to the rows where it is evaluated
+            DualToken::JmpAnd(offset) | DualToken::JmpOr(offset) => {
+                let end = pc + 1 + offset;
+                if ends.last().is_some_and(|(outer, _)| end > *outer) {
+                    return Err(malformed());
+                }
+                let lhs = depth.checked_sub(1).ok_or_else(underflow)?;
+                let on = matches!(tok, DualToken::JmpAnd(_));
+
+                ends.push((end, Step::Unmask));
+                steps.push(Step::Mask(lhs, on));
+                masks += 1;
+                continue;
+            }
+            DualToken::Store(slot) => Step::Store(slot),
+            DualToken::Load(slot) => Step::Load(slot),
+        };
+
+        depth = match step {
+            Step::Num(_) | Step::Col(_) => depth + 1,
+            Step::Fn(_, argc) => depth.checked_sub(argc).ok_or_else(underflow)? + 1,
+            Step::Op(op) => depth.checked_sub(op.num_operands()).ok_or_else(underflow)? + 1,
+            Step::Store(slot) => {
+                slots = slots.max(slot + 1);
+                depth.checked_sub(1).ok_or_else(underflow)? + 1
+            }
+            Step::Load(slot) if slot < slots => depth + 1,
+            Step::Load(_) => return Err(malformed()),
+            Step::Select | Step::Mask(..) | Step::Unmask => depth,
+        };
+        max_depth = max_depth.max(depth);
+        steps.push(step);
+    }
+
+    if depth != 1 || !ends.is_empty() || masks != 0 {
+        return Err(malformed());
+    }
+
+    Ok((steps, max_depth, slots))
+}
+
+#[inline]
+fn block(buf: &[f64], slot: usize, n: usize) -> &[f64]
+{
+    &buf[slot * BLOCK..][..n]
+}
+
+#[inline]
+fn block_mut(buf: &mut [f64], slot: usize, n: usize) -> &mut [f64]
+{
+    &mut buf[slot * BLOCK..][..n]
+}
+
+/// Applies `op` to each value of `x`
+pub(super) fn unary(op: Op, x: &mut [f64])
+{
+    match op {
+        Op::Neg => x.iter_mut().for_each(|x| *x = -*x),
+        _ => x.iter_mut().for_each(|x| *x = op.apply(&[*x])),
+    }
+}
+
+/// Applies `op` to each pair of values, writing the results over `lhs`.
+///
+/// The common operators get their own loops so that they can be vectorized.
+pub(super) fn binary(op: Op, lhs: &mut [f64], rhs: &[f64])
+{
+    #[inline(always)]
+    fn zip(lhs: &mut [f64], rhs: &[f64], f: impl Fn(f64, f64) -> f64)
+    {
+        for (lhs, rhs) in lhs.iter_mut().zip(rhs) {
+            *lhs = f(*lhs, *rhs);
+        }
+    }
+
+    match op {
+        Op::Add => zip(lhs, rhs, |a, b| a + b),
+        Op::Sub => zip(lhs, rhs, |a, b| a - b),
+        Op::Mul => zip(lhs, rhs, |a, b| a * b),
+        Op::Div => zip(lhs, rhs, |a, b| a / b),
+        Op::Low => zip(lhs, rhs, |a, b| bool_to_f64(a < b)),
+        Op::Great => zip(lhs, rhs, |a, b| bool_to_f64(a > b)),
+        Op::LowEq => zip(lhs, rhs, |a, b| bool_to_f64(a <= b)),
+        Op::GreatEq => zip(lhs, rhs, |a, b| bool_to_f64(a >= b)),
+        Op::Eq => zip(lhs, rhs, |a, b| bool_to_f64(a == b)),
+        Op::NotEq => zip(lhs, rhs, |a, b| bool_to_f64(a != b)),
+        _ => zip(lhs, rhs, |a, b| op.apply(&[a, b])),
+    }
+}
@@ -0,0 +1,533 @@
+//! Rust source code generation.
+//!
+//! Expressions prototyped at runtime can be frozen into plain Rust functions
+//! with [`to_rust`], or a whole file of named formulas turned into a module
+//! with [`to_rust_module`], typically from a build script:
+//!
+//! ```rust,ignore
+//! // build.rs, with `fee` in the `[build-dependencies]`
+//! use fee::codegen::{RustOptions, Vars, to_rust_module};
+//!
+//! fn main()
+//! {
+//!     let src = std::fs::read_to_string("formulas.txt").unwrap();
+//!     let options = RustOptions::new(Vars::Slice(&["x", "y", "t"]));
+//!     let code = to_rust_module(&src, &options).unwrap_or_else(|err| panic!("{}", err.render(&src)));
+//!
+//!     let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("formulas.rs");
+//!     std::fs::write(out, code).unwrap();
+//!     println!("cargo::rerun-if-changed=formulas.txt");
+//! }
+//! ```
+//!
+//! The generated functions evaluate exactly like the compiled expressions:
+//! literal subexpressions are folded the same way, operators keep their
+//! semantics on `f64` (truthiness, `NaN` comparisons, bitwise operators on the
+//! truncated operands, integer powers) and the operands are evaluated in the
+//! same order. Functions are called with a slice of their arguments like an
+//! [`ExprFn`](crate::ExprFn), through the path given by
+//! [`RustOptions::with_fns_path`], or as the `f64` methods for the functions
+//! of [`DefaultResolver::new_fns`](crate::DefaultResolver::new_fns) with
+//! [`RustOptions::with_builtin_fns`].
+
+use crate::{
+    Ast, Error, Op, Span,
+    expr::{ast::parse, f64_is_i64, f64_to_bool},
+};
+
+/// How the generated functions receive the variables of the expressions.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Vars<'a>
+{
+    /// `vars: &[f64]`, each variable being read at the position of its name
+    Slice(&'a [&'a str]),
+    /// `vars: &Type`, each variable being read from the field of the same name
+    Struct(&'a str),
+}
+
+/// Options of the generated Rust code.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct RustOptions<'a>
+{
+    vars: Vars<'a>,
+    fns: &'a str,
+    builtin_fns: bool,
+}
+
+impl<'a> RustOptions<'a>
+{
+    pub fn new(vars: Vars<'a>) -> Self
+    {
+        RustOptions {
+            vars,
+            fns: "",
+            builtin_fns: false,
+        }
+    }
+
+    /// Sets the path the called functions are found at, such as `crate::fns::`.
+    pub fn with_fns_path(mut self, path: &'a str) -> Self
+    {
+        self.fns = path;
+        self
+    }
+
+    /// Emits the calls to `abs`, `sqrt` and `ln` as the `f64` methods, which
+    /// the functions of [`DefaultResolver::new_fns`](crate::DefaultResolver::new_fns)
+    /// call. Only for expressions evaluated with these functions, not with
+    /// others bound to the same names.
+    pub fn with_builtin_fns(mut self) -> Self
+    {
+        self.builtin_fns = true;
+        self
+    }
+}
+
+/// Generates a Rust function named `name` evaluating the expression.
+///
+/// The function allows the Clippy lints, which the literal translation of an
+/// expression such as `x != x` would trigger.
+///
+/// Compiled expressions are turned back into a tree with their `to_ast`
+/// method; literal subexpressions are folded in any case.
+///
+/// # Examples
+/// ```rust
+/// use fee::{
+///     codegen::{self, RustOptions, Vars},
+///     parse,
+/// };
+///
+/// let ast = parse("x > 0 ? sqrt(x) * (2 + 3) : -x ^ 2").unwrap();
+/// let options = RustOptions::new(Vars::Slice(&["x"])).with_builtin_fns();
+///
+/// assert_eq!(
+///     codegen::to_rust("root", &ast, &options).unwrap(),
+///     "/// `x > 0 ? sqrt(x) * (2 + 3) : -x ^ 2`\n\
+///      #[allow(clippy::all)]\n\
+///      pub fn root(vars: &[f64]) -> f64 {\n    \
+///          if vars[0] > 0.0 { vars[0].sqrt() * 5.0 } else { -vars[0].powi(2) }\n\
+///      }\n"
+/// );
+/// ```
+pub fn to_rust(
+    name: &str,
+    ast: &Ast<'_>,
+    options: &RustOptions<'_>,
+) -> Result<String, Error<'static>>
+{
+    let mut generator = Generator {
+        options,
+        uses_vars: false,
+    };
+    let body = generator.value(&fold(ast))?.code;
+
+    let param = match generator.uses_vars {
+        true => "vars",
+        false => "_vars",
+    };
+    let ty = match options.vars {
+        Vars::Slice(_) => "[f64]",
+        Vars::Struct(ty) => ty,
+    };
+
+    Ok(format!(
+        "/// `{ast}`\n#[allow(clippy::all)]\npub fn {}({param}: &{ty}) -> f64 {{\n    {body}\n}}\n",
+        ident(name)?
+    ))
+}
+
+/// Generates a Rust module from a list of named formulas, one `name = expr`
+/// per line. Blank lines and lines starting with `#` are skipped.
+///
+/// The spans of the returned errors point into `src`, so they can be shown
+/// with [`Error::render`].
+///
+/// # Examples
+/// ```rust
+/// use fee::codegen::{self, RustOptions, Vars};
+///
+/// let src = "# Kinematics\nspeed = v0 + a * t\ndist = v0 * t + a * t ^ 2 / 2\n";
+/// let options = RustOptions::new(Vars::Struct("State"));
+///
+/// let code = codegen::to_rust_module(src, &options).unwrap();
+/// assert!(code.contains("pub fn speed(vars: &State) -> f64 {\n    vars.v0 + vars.a * vars.t\n}"));
+///
+/// let err = codegen::to_rust_module("a = 1\nb = 2 +\n", &options).unwrap_err();
+/// assert!(err.render("a = 1\nb = 2 +\n").contains("--> line 2, column 8"));
+/// ```
+pub fn to_rust_module<'a>(src: &'a str, options: &RustOptions<'_>) -> Result<String, Error<'a>>
+{
+    let mut out = String::from("// Generated by fee, do not edit.\n");
+    let mut names = Vec::new();
+    let mut start = 0;
+
+    for line in src.split_inclusive('\n') {
+        let offset = start;
+        start += line.len();
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let line_no = src[..offset].matches('\n').count() + 1;
+        let Some((name, expr)) = line.split_once('=') else {
+            return Err(Error::CodegenError(format!(
+                "line {line_no}: expected `name = expression`"
+            )));
+        };
+
+        let name = name.trim();
+        if names.contains(&name) {
+            return Err(Error::CodegenError(format!(
+                "line {line_no}: formula '{name}' is defined twice"
+            )));
+        }
+        names.push(name);
+
+        let expr = expr.trim_end();
+        let ast = parse(expr).map_err(|err| err.offset(offset + name_end(line)))?;
+
+        out.push('\n');
+        out.push_str(&to_rust(name, &ast, options)?);
+    }
+
+    Ok(out)
+}
+
+/// Position of the expression of a formula line, right after its `=`
+fn name_end(line: &str) -> usize
+{
+    line.find('=').map_or(0, |i| i + 1)
+}
+
+/// Binding power of the generated Rust expressions, from `if` expressions,
+/// which bind the loosest, to atoms
+const IF: u8 = 0;
+const OR: u8 = 1;
+const AND: u8 = 2;
+const CMP: u8 = 3;
+const BIT_OR: u8 = 4;
+const BIT_XOR: u8 = 5;
+const BIT_AND: u8 = 6;
+const SHIFT: u8 = 7;
+const ADD: u8 = 8;
+const MUL: u8 = 9;
+const AS: u8 = 10;
+const UNARY: u8 = 11;
+const ATOM: u8 = 12;
+
+/// Piece of generated Rust code and its binding power
+struct Code
+{
+    code: String,
+    binding: u8,
+}
+
+impl Code
+{
+    fn new(code: String, binding: u8) -> Self
+    {
+        Code { code, binding }
+    }
+
+    /// Returns the code, within parentheses if it binds looser than `min`
+    fn operand(self, min: u8) -> String
+    {
+        if self.binding < min {
+            format!("({})", self.code)
+        } else {
+            self.code
+        }
+    }
+}
+
+struct Generator<'o>
+{
+    options: &'o RustOptions<'o>,
+    /// Whether the expression reads any variable
+    uses_vars: bool,
+}
+
+impl Generator<'_>
+{
+    /// Generates the `f64` value of the tree
+    fn value(&mut self, ast: &Ast<'_>) -> Result<Code, Error<'static>>
+    {
+        let code = match ast {
+            Ast::Num(num) => literal(*num),
+            Ast::Var(name) => Code::new(self.var(name)?, ATOM),
+            Ast::Call(name, args) => self.call(name, args)?,
+            Ast::Unary(Op::Neg, operand) => {
+                // Nested negations are kept apart, `--x` reads as a decrement
+                let operand = self.value(operand)?.operand(ATOM);
+                Code::new(format!("-{operand}"), UNARY)
+            }
+            Ast::Unary(Op::Not, _)
+            | Ast::Binary(
+                Op::Or
+                | Op::And
+                | Op::Low
+                | Op::Great
+                | Op::LowEq
+                | Op::GreatEq
+                | Op::Eq
+                | Op::NotEq,
+                ..,
+            ) => {
+                let cond = self.condition(ast)?.code;
+                Code::new(format!("f64::from(u8::from({cond}))"), ATOM)
+            }
+            Ast::Unary(op, _) => return Err(Error::InvalidOperator(*op, 1)),
+            Ast::Binary(Op::Pow, lhs, rhs) => self.pow(lhs, rhs)?,
+            Ast::Binary(op, lhs, rhs) => {
+                let (symbol, binding) = match op {
+                    Op::Add => ("+", ADD),
+                    Op::Sub => ("-", ADD),
+                    Op::Mul => ("*", MUL),
+                    Op::Div => ("/", MUL),
+                    Op::Mod => ("%", MUL),
+                    Op::BitAnd => ("&", BIT_AND),
+                    Op::BitOr => ("|", BIT_OR),
+                    Op::BitXor => ("^", BIT_XOR),
+                    Op::Shl => ("<<", SHIFT),
+                    Op::Shr => (">>", SHIFT),
+                    _ => return Err(Error::InvalidOperator(*op, 2)),
+                };
+
+                if binding >= ADD {
+                    let lhs = self.value(lhs)?.operand(binding);
+                    let rhs = self.value(rhs)?.operand(binding + 1);
+                    Code::new(format!("{lhs} {symbol} {rhs}"), binding)
+                } else {
+                    let lhs = self.int(lhs)?;
+                    let rhs = self.int(rhs)?;
+                    Code::new(format!("({lhs} {symbol} {rhs}) as f64"), AS)
+                }
+            }
+            Ast::Cond(cond, then_branch, else_branch) => {
+                let cond = self.condition(cond)?.code;
+                let then_branch = self.value(then_branch)?.code;
+                let else_branch = match &**else_branch {
+                    Ast::Cond(..) => self.value(else_branch)?.code,
+                    _ => format!("{{ {} }}", self.value(else_branch)?.code),
+                };
+
+                Code::new(
+                    format!("if {cond} {{ {then_branch} }} else {else_branch}"),
+                    IF,
+                )
+            }
+        };
+
+        Ok(code)
+    }
+
+    /// Generates the `bool` truthiness of the tree
+    fn condition(&mut self, ast: &Ast<'_>) -> Result<Code, Error<'static>>
+    {
+        let code = match ast {
+            Ast::Unary(Op::Not, operand) if is_condition(operand) => {
+                let cond = self.condition(operand)?.operand(UNARY);
+                Code::new(format!("!{cond}"), UNARY)
+            }
+            Ast::Unary(Op::Not, operand) => {
+                let val = self.value(operand)?.operand(CMP + 1);
+                Code::new(format!("{val} == 0.0"), CMP)
+            }
+            Ast::Binary(op @ (Op::Or | Op::And), lhs, rhs) => {
+                let (symbol, binding) = match op {
+                    Op::Or => ("||", OR),
+                    _ => ("&&", AND),
+                };
+
+                let lhs = self.condition(lhs)?.operand(binding);
+                let rhs = self.condition(rhs)?.operand(binding + 1);
+                Code::new(format!("{lhs} {symbol} {rhs}"), binding)
+            }
+            Ast::Binary(
+                op @ (Op::Low | Op::Great | Op::LowEq | Op::GreatEq | Op::Eq | Op::NotEq),
+                lhs,
+                rhs,
+            ) => {
+                // `x as f64 < y` would start the generic arguments of `f64`
+                let lhs = match self.value(lhs)? {
+                    lhs if lhs.binding == AS => lhs.operand(UNARY),
+                    lhs => lhs.operand(CMP + 1),
+                };
+                let rhs = self.value(rhs)?.operand(CMP + 1);
+                Code::new(format!("{lhs} {op} {rhs}"), CMP)
+            }
+            _ => {
+                let val = self.value(ast)?.operand(CMP + 1);
+                Code::new(format!("{val} != 0.0"), CMP)
+            }
+        };
+
+        Ok(code)
+    }
+
+    fn pow(&mut self, lhs: &Ast<'_>, rhs: &Ast<'_>) -> Result<Code, Error<'static>>
+    {
+        let code = match *rhs {
+            Ast::Num(exp) if f64_is_i64(exp) => {
+                let base = self.receiver(lhs)?;
+                Code::new(format!("{base}.powi({})", exp as i32), ATOM)
+            }
+            Ast::Num(exp) => {
+                let base = self.receiver(lhs)?;
+                let exp = literal(exp).code;
+                Code::new(format!("{base}.powf({exp})"), ATOM)
+            }
+            // Integer exponents are only known when evaluating
+            _ => {
+                let base = self.value(lhs)?.code;
+                let exp = self.value(rhs)?.code;
+                Code::new(
+                    format!(
+                        "{{ let (a, b): (f64, f64) = ({base}, {exp}); \
+                         if b == b as i64 as f64 {{ a.powi(b as i32) }} else {{ a.powf(b) }} }}"
+                    ),
+                    IF,
+                )
+            }
+        };
+
+        Ok(code)
+    }
+
+    /// Generates the `i64` bitwise operators work on, truncated the same way
+    /// `Op::apply` does
+    fn int(&mut self, ast: &Ast<'_>) -> Result<String, Error<'static>>
+    {
+        match *ast {
+            Ast::Num(num) => Ok((num as i64).to_string()),
+            _ => Ok(format!("({} as i64)", self.value(ast)?.operand(AS))),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Ast<'_>]) -> Result<Code, Error<'static>>
+    {
+        if let (true, "abs" | "sqrt" | "ln", [arg]) = (self.options.builtin_fns, name, args) {
+            let arg = self.receiver(arg)?;
+            return Ok(Code::new(format!("{arg}.{name}()"), ATOM));
+        }
+
+        let mut code = format!("{}{}(&[", self.options.fns, ident(name)?);
+
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                code.push_str(", ");
+            }
+            code.push_str(&self.value(arg)?.code);
+        }
+
+        code.push_str("])");
+        Ok(Code::new(code, ATOM))
+    }
+
+    /// Generates the value of the tree as the receiver of a method call,
+    /// giving its type to the literals
+    fn receiver(&mut self, ast: &Ast<'_>) -> Result<String, Error<'static>>
+    {
+        match *ast {
+            Ast::Num(num) if num.is_finite() => {
+                let code = literal(num);
+                Ok(Code::new(format!("{}_f64", code.code), code.binding).operand(ATOM))
+            }
+            _ => Ok(self.value(ast)?.operand(ATOM)),
+        }
+    }
+
+    fn var(&mut self, name: &str) -> Result<String, Error<'static>>
+    {
+        self.uses_vars = true;
+
+        match self.options.vars {
+            Vars::Slice(names) => names
+                .iter()
+                .position(|var| *var == name)
+                .map(|idx| format!("vars[{idx}]"))
+                .ok_or_else(|| Error::UnknownVar(name.to_string().into(), Span::default())),
+            Vars::Struct(_) => Ok(format!("vars.{}", ident(name)?)),
+        }
+    }
+}
+
+/// Whether the tree is a condition, which the generated code keeps as a `bool`
+fn is_condition(ast: &Ast<'_>) -> bool
+{
+    matches!(
+        ast,
+        Ast::Unary(Op::Not, _)
+            | Ast::Binary(
+                Op::Or
+                    | Op::And
+                    | Op::Low
+                    | Op::Great
+                    | Op::LowEq
+                    | Op::GreatEq
+                    | Op::Eq
+                    | Op::NotEq,
+                ..
+            )
+    )
+}
+
+fn literal(num: f64) -> Code
+{
+    match num {
+        _ if num.is_nan() => Code::new("f64::NAN".to_string(), ATOM),
+        f64::INFINITY => Code::new("f64::INFINITY".to_string(), ATOM),
+        f64::NEG_INFINITY => Code::new("f64::NEG_INFINITY".to_string(), ATOM),
+        // `Debug` keeps the digits needed to read the same value back
+        _ if num.is_sign_negative() => Code::new(format!("{num:?}"), UNARY),
+        _ => Code::new(format!("{num:?}"), ATOM),
+    }
+}
+
+/// Checks that a name can be used as a Rust identifier
+fn ident(name: &str) -> Result<&str, Error<'static>>
+{
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c)
+            if (c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok(name)
+        }
+        _ => Err(Error::CodegenError(format!(
+            "'{name}' is not a valid Rust identifier"
+        ))),
+    }
+}
+
+/// Folds the literal subexpressions of the tree, like the lexer does when
+/// compiling it
+fn fold<'e>(ast: &Ast<'e>) -> Ast<'e>
+{
+    match ast {
+        Ast::Num(_) | Ast::Var(_) => ast.clone(),
+        Ast::Call(name, args) => Ast::Call(name.clone(), args.iter().map(fold).collect()),
+        Ast::Unary(op, operand) => match fold(operand) {
+            Ast::Num(num) => Ast::Num(op.apply(&[num])),
+            operand => Ast::Unary(*op, Box::new(operand)),
+        },
+        Ast::Binary(op, lhs, rhs) => match (fold(lhs), fold(rhs)) {
+            (Ast::Num(lhs), Ast::Num(rhs)) => Ast::Num(op.apply(&[lhs, rhs])),
+            (lhs, rhs) => Ast::Binary(*op, Box::new(lhs), Box::new(rhs)),
+        },
+        Ast::Cond(cond, then_branch, else_branch) => match fold(cond) {
+            Ast::Num(cond) if f64_to_bool(cond) => fold(then_branch),
+            Ast::Num(_) => fold(else_branch),
+            cond => Ast::Cond(
+                Box::new(cond),
+                Box::new(fold(then_branch)),
+                Box::new(fold(else_branch)),
+            ),
+        },
+    }
+}
@@ -0,0 +1,161 @@
+use crate::expr::{
+    Expr, Op, Token, stack_depth,
+    tree::{Node, Temp},
+};
+
+/// Smallest subexpression worth a temporary slot, storing and loading it
+/// takes two tokens
+const MIN_SIZE: usize = 3;
+
+/// Computes the repeated subexpressions of `expr` once, storing them into
+/// temporary slots that their other occurrences load. `is_pure` tells whether
+/// a function token always returns the same result for the same arguments.
+///
+/// A subexpression is only reused where its first occurrence is always
+/// evaluated before, never across the branches of a ternary operator or
+/// from the right operand of `&&` and `||`. The expression is left as is if
+/// it isn't well-formed.
+pub(super) fn eliminate<T: Token + PartialEq>(expr: &mut Expr<T>, is_pure: impl Fn(&T) -> bool)
+{
+    let postfix = expr
+        .tokens
+        .iter()
+        .zip(&expr.spans)
+        .map(|(tok, span)| (tok.postfix(), *span));
+
+    let Ok(node) = Node::from_postfix(postfix) else {
+        return;
+    };
+
+    let mut analysis = Analysis {
+        is_pure,
+        plan: vec![Temp::Keep; node.size()],
+        next_id: 0,
+        slots: 0,
+    };
+    analysis.visit(&node, &mut Vec::new());
+
+    let mut tokens = Vec::with_capacity(expr.tokens.len());
+    let mut spans = Vec::with_capacity(expr.spans.len());
+    let mut id = 0;
+
+    node.emit_with(&mut tokens, &mut spans, &mut |node| {
+        let temp = analysis.plan[id];
+        id += match temp {
+            Temp::Load(_) => node.size(),
+            _ => 1,
+        };
+        temp
+    });
+
+    if let Ok(depth) = stack_depth(&tokens) {
+        expr.tokens = tokens;
+        expr.spans = spans;
+        expr.depth = depth;
+    }
+}
+
+/// Finds the repeated subexpressions of a tree. Nodes are identified by their
+/// position in evaluation order, parents before their children.
+struct Analysis<P>
+{
+    is_pure: P,
+    plan: Vec<Temp>,
+    next_id: usize,
+    slots: usize,
+}
+
+impl<P> Analysis<P>
+{
+    /// Visits `node` knowing the subexpressions already computed whenever it
+    /// is evaluated, with their id.
+    fn visit<'n, T>(&mut self, node: &'n Node<T>, computed: &mut Vec<(usize, &'n Node<T>)>)
+    where
+        T: PartialEq,
+        P: Fn(&T) -> bool,
+    {
+        let id = self.next_id;
+        let size = node.size();
+        let candidate = size >= MIN_SIZE && self.is_pure(node);
+
+        if candidate {
+            let first = computed
+                .iter()
+                .find(|(_, other)| other.size() == size && other.same(node));
+
+            if let Some(&(first, _)) = first {
+                let slot = match self.plan[first] {
+                    Temp::Store(slot) => slot,
+                    _ => {
+                        self.slots += 1;
+                        self.plan[first] = Temp::Store(self.slots - 1);
+                        self.slots - 1
+                    }
+                };
+
+                self.plan[id] = Temp::Load(slot);
+                self.next_id += size;
+                return;
+            }
+        }
+
+        self.next_id += 1;
+
+        match node {
+            Node::Num(..) | Node::Var(..) => {}
+            Node::Call(_, args, _) => {
+                for arg in args {
+                    self.visit(arg, computed);
+                }
+            }
+            Node::Unary(_, operand, _) => self.visit(operand, computed),
+            Node::Binary(Op::And | Op::Or, lhs, rhs, _) => {
+                self.visit(lhs, computed);
+                self.visit_branch(rhs, computed);
+            }
+            Node::Binary(_, lhs, rhs, _) => {
+                self.visit(lhs, computed);
+                self.visit(rhs, computed);
+            }
+            Node::Cond(cond, then_branch, else_branch, _) => {
+                self.visit(cond, computed);
+                self.visit_branch(then_branch, computed);
+                self.visit_branch(else_branch, computed);
+            }
+        }
+
+        if candidate {
+            computed.push((id, node));
+        }
+    }
+
+    /// Visits a subexpression that may not be evaluated, whose own
+    /// subexpressions can't be reused after it
+    fn visit_branch<'n, T>(&mut self, node: &'n Node<T>, computed: &mut Vec<(usize, &'n Node<T>)>)
+    where
+        T: PartialEq,
+        P: Fn(&T) -> bool,
+    {
+        let len = computed.len();
+        self.visit(node, computed);
+        computed.truncate(len);
+    }
+
+    /// Whether the tree only calls pure functions
+    fn is_pure<T>(&self, node: &Node<T>) -> bool
+    where
+        P: Fn(&T) -> bool,
+    {
+        match node {
+            Node::Num(..) | Node::Var(..) => true,
+            Node::Call(fun, args, _) => {
+                (self.is_pure)(fun) && args.iter().all(|arg| self.is_pure(arg))
+            }
+            Node::Unary(_, operand, _) => self.is_pure(operand),
+            Node::Binary(_, lhs, rhs, _) => self.is_pure(lhs) && self.is_pure(rhs),
+            Node::Cond(cond, then_branch, else_branch, _) => {
+                self.is_pure(cond) && self.is_pure(then_branch) && self.is_pure(else_branch)
+            }
+        }
+    }
+}
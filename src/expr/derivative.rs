@@ -0,0 +1,314 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use ahash::RandomState;
+
+use crate::{
+    Ast, Error,
+    expr::{Op, bool_to_f64},
+};
+
+/// Builds the partial derivative of a function from the arguments of the call
+type Rule = for<'a> fn(&[Ast<'a>]) -> Ast<'a>;
+
+/// Partial derivative of a function with respect to one of its arguments
+#[derive(Debug, Clone)]
+enum Partial
+{
+    /// Another function of the context, called with the same arguments
+    Fn(String),
+    Rule(Rule),
+}
+
+/// Registry of the derivatives of the functions an expression can call.
+///
+/// Each function registers its partial derivatives with respect to each of
+/// its arguments. [`Derivatives::new`] knows the functions of
+/// [`DefaultResolver::new_fns`](crate::DefaultResolver::new_fns), custom
+/// [`ExprFn`](crate::ExprFn)s register theirs with [`Derivatives::insert`].
+///
+/// # Examples
+/// ```rust
+/// use fee::{Derivatives, DefaultResolver, parse, prelude::*};
+///
+/// let mut fns = DefaultResolver::new_fns();
+/// fns.insert("sin".to_string(), ExprFn::new(|x| x[0].sin()));
+/// fns.insert("cos".to_string(), ExprFn::new(|x| x[0].cos()));
+///
+/// let mut derivatives = Derivatives::new();
+/// derivatives.insert("sin", ["cos"]);
+///
+/// let ast = parse("sin(2 * x)").unwrap();
+/// let d_ast = derivatives.derivative(&ast, "x").unwrap();
+/// assert_eq!(d_ast.to_string(), "cos(2 * x) * 2");
+///
+/// let mut vars = DefaultResolver::empty();
+/// vars.insert("x".to_string(), 0.0);
+/// let ctx = Context::new(vars, fns);
+///
+/// let expr = Expr::from_ast(&d_ast, &ctx).unwrap();
+/// assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(2.0));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Derivatives
+{
+    partials: HashMap<String, Vec<Partial>, RandomState>,
+}
+
+impl Derivatives
+{
+    /// Creates a registry with the derivatives of `sqrt`, `abs` and `ln`.
+    pub fn new() -> Self
+    {
+        let mut derivatives = Derivatives::empty();
+        derivatives.insert_rules("sqrt", [d_sqrt as Rule]);
+        derivatives.insert_rules("abs", [d_abs as Rule]);
+        derivatives.insert_rules("ln", [d_ln as Rule]);
+        derivatives
+    }
+
+    pub fn empty() -> Self
+    {
+        Derivatives {
+            partials: HashMap::default(),
+        }
+    }
+
+    /// Registers the partial derivatives of the function `name`, one for each
+    /// of its arguments in order. Each partial derivative is the name of
+    /// another function of the context, called with the same arguments.
+    pub fn insert<P>(&mut self, name: impl Into<String>, partials: impl IntoIterator<Item = P>)
+    where
+        P: Into<String>,
+    {
+        let partials = partials
+            .into_iter()
+            .map(|partial| Partial::Fn(partial.into()))
+            .collect();
+        self.partials.insert(name.into(), partials);
+    }
+
+    fn insert_rules(&mut self, name: &str, rules: impl IntoIterator<Item = Rule>)
+    {
+        let partials = rules.into_iter().map(Partial::Rule).collect();
+        self.partials.insert(name.to_string(), partials);
+    }
+
+    /// Differentiates `ast` with respect to the variable `var`.
+    ///
+    /// The result is simplified as it is built: subexpressions that don't
+    /// depend on `var` have a derivative of exactly `0`, so the terms they
+    /// multiply are dropped.
+    ///
+    /// Comparisons, logical and bitwise operators are piecewise constant, so
+    /// their derivative is `0`. A ternary is differentiated branch by branch,
+    /// and a power with a variable exponent is differentiated as
+    /// `a ^ b * (b' * ln(a) + b * a' / a)`, calling the `ln` of
+    /// [`DefaultResolver::new_fns`](crate::DefaultResolver::new_fns).
+    ///
+    /// Returns [`Error::UnknownDerivative`] if a call whose arguments depend on
+    /// `var` has no registered partial derivative for them.
+    pub fn derivative<'e>(&self, ast: &Ast<'e>, var: &str) -> Result<Ast<'e>, Error<'e>>
+    {
+        let d_ast = match ast {
+            Ast::Num(_) => Ast::Num(0.0),
+            Ast::Var(name) => Ast::Num(bool_to_f64(name == var)),
+            Ast::Call(name, args) => {
+                let mut sum = Ast::Num(0.0);
+
+                for (i, arg) in args.iter().enumerate() {
+                    let d_arg = self.derivative(arg, var)?;
+
+                    if is_num(&d_arg, 0.0) {
+                        continue;
+                    }
+
+                    let partial = match self.partials.get(name.as_ref()).and_then(|p| p.get(i)) {
+                        Some(Partial::Fn(partial)) => {
+                            Ast::Call(Cow::Owned(partial.clone()), args.clone())
+                        }
+                        Some(Partial::Rule(rule)) => rule(args),
+                        None => return Err(Error::UnknownDerivative(name.clone())),
+                    };
+
+                    sum = add(sum, mul(partial, d_arg));
+                }
+
+                sum
+            }
+            Ast::Unary(Op::Neg, operand) => neg(self.derivative(operand, var)?),
+            Ast::Unary(_, _) => Ast::Num(0.0),
+            Ast::Binary(op, lhs, rhs) => {
+                let (d_lhs, d_rhs) = (self.derivative(lhs, var)?, self.derivative(rhs, var)?);
+                let (lhs, rhs) = (lhs.as_ref().clone(), rhs.as_ref().clone());
+
+                match op {
+                    Op::Add => add(d_lhs, d_rhs),
+                    Op::Sub => sub(d_lhs, d_rhs),
+                    Op::Mul => add(mul(d_lhs, rhs), mul(lhs, d_rhs)),
+                    Op::Div if is_num(&d_rhs, 0.0) => div(d_lhs, rhs),
+                    Op::Div => div(
+                        sub(mul(d_lhs, rhs.clone()), mul(lhs, d_rhs)),
+                        pow(rhs, Ast::Num(2.0)),
+                    ),
+                    Op::Pow if is_num(&d_rhs, 0.0) => {
+                        let exp = sub(rhs.clone(), Ast::Num(1.0));
+                        mul(mul(rhs, pow(lhs, exp)), d_lhs)
+                    }
+                    Op::Pow => {
+                        let ln = Ast::Call(Cow::Borrowed("ln"), vec![lhs.clone()]);
+                        let d_exp = add(mul(d_rhs, ln), div(mul(rhs.clone(), d_lhs), lhs.clone()));
+                        mul(pow(lhs, rhs), d_exp)
+                    }
+                    // a % b == a - trunc(a / b) * b
+                    Op::Mod => {
+                        let trunc = div(sub(lhs.clone(), binary(Op::Mod, lhs, rhs.clone())), rhs);
+                        sub(d_lhs, mul(d_rhs, trunc))
+                    }
+                    _ => Ast::Num(0.0),
+                }
+            }
+            Ast::Cond(cond, then_branch, else_branch) => {
+                let d_then = self.derivative(then_branch, var)?;
+                let d_else = self.derivative(else_branch, var)?;
+
+                if d_then == d_else {
+                    d_then
+                } else {
+                    Ast::Cond(cond.clone(), Box::new(d_then), Box::new(d_else))
+                }
+            }
+        };
+
+        Ok(d_ast)
+    }
+}
+
+impl Default for Derivatives
+{
+    fn default() -> Self
+    {
+        Derivatives::new()
+    }
+}
+
+/// Differentiates `ast` with respect to the variable `var`, knowing the
+/// derivatives of the functions in [`Derivatives::new`].
+///
+/// See [`Derivatives::derivative`] for the details.
+///
+/// # Examples
+/// ```rust
+/// use fee::{derivative, parse};
+///
+/// let ast = parse("y0 * (p0 - p1 * y1)").unwrap();
+/// assert_eq!(derivative(&ast, "y0").unwrap().to_string(), "p0 - p1 * y1");
+/// assert_eq!(derivative(&ast, "y1").unwrap().to_string(), "y0 * -p1");
+/// ```
+pub fn derivative<'e>(ast: &Ast<'e>, var: &str) -> Result<Ast<'e>, Error<'e>>
+{
+    Derivatives::new().derivative(ast, var)
+}
+
+/// `0.5 / sqrt(x)`
+fn d_sqrt<'a>(args: &[Ast<'a>]) -> Ast<'a>
+{
+    let sqrt = Ast::Call(Cow::Borrowed("sqrt"), args.to_vec());
+    Ast::Binary(Op::Div, Box::new(Ast::Num(0.5)), Box::new(sqrt))
+}
+
+/// `(x > 0) - (x < 0)`, the sign of `x`
+fn d_abs<'a>(args: &[Ast<'a>]) -> Ast<'a>
+{
+    let x = args.first().cloned().unwrap_or(Ast::Num(0.0));
+    let positive = Ast::Binary(Op::Great, Box::new(x.clone()), Box::new(Ast::Num(0.0)));
+    let negative = Ast::Binary(Op::Low, Box::new(x), Box::new(Ast::Num(0.0)));
+    Ast::Binary(Op::Sub, Box::new(positive), Box::new(negative))
+}
+
+/// `1 / x`
+fn d_ln<'a>(args: &[Ast<'a>]) -> Ast<'a>
+{
+    let x = args.first().cloned().unwrap_or(Ast::Num(0.0));
+    Ast::Binary(Op::Div, Box::new(Ast::Num(1.0)), Box::new(x))
+}
+
+#[inline]
+fn is_num(ast: &Ast<'_>, num: f64) -> bool
+{
+    matches!(ast, Ast::Num(x) if *x == num)
+}
+
+/// Applies `op`, folding it if both operands are literals
+fn binary<'e>(op: Op, lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    match (&lhs, &rhs) {
+        (Ast::Num(lhs), Ast::Num(rhs)) => Ast::Num(op.apply(&[*lhs, *rhs])),
+        _ => Ast::Binary(op, Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+fn add<'e>(lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    if is_num(&lhs, 0.0) {
+        rhs
+    } else if is_num(&rhs, 0.0) {
+        lhs
+    } else {
+        binary(Op::Add, lhs, rhs)
+    }
+}
+
+fn sub<'e>(lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    if is_num(&rhs, 0.0) {
+        lhs
+    } else if is_num(&lhs, 0.0) {
+        neg(rhs)
+    } else {
+        binary(Op::Sub, lhs, rhs)
+    }
+}
+
+fn mul<'e>(lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    if is_num(&lhs, 0.0) || is_num(&rhs, 0.0) {
+        Ast::Num(0.0)
+    } else if is_num(&lhs, 1.0) {
+        rhs
+    } else if is_num(&rhs, 1.0) {
+        lhs
+    } else {
+        binary(Op::Mul, lhs, rhs)
+    }
+}
+
+fn div<'e>(lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    if is_num(&lhs, 0.0) {
+        Ast::Num(0.0)
+    } else if is_num(&rhs, 1.0) {
+        lhs
+    } else {
+        binary(Op::Div, lhs, rhs)
+    }
+}
+
+fn pow<'e>(lhs: Ast<'e>, rhs: Ast<'e>) -> Ast<'e>
+{
+    if is_num(&rhs, 0.0) {
+        Ast::Num(1.0)
+    } else if is_num(&rhs, 1.0) {
+        lhs
+    } else {
+        binary(Op::Pow, lhs, rhs)
+    }
+}
+
+fn neg(ast: Ast<'_>) -> Ast<'_>
+{
+    match ast {
+        Ast::Num(num) => Ast::Num(-num),
+        Ast::Unary(Op::Neg, operand) => *operand,
+        _ => Ast::Unary(Op::Neg, Box::new(ast)),
+    }
+}
@@ -0,0 +1,313 @@
+use std::borrow::Cow;
+
+use crate::{
+    Error, EvalError, ExprFn,
+    expr::{Op, f64_to_bool},
+    parsing,
+};
+
+/// Variable a gradient is computed with respect to, see
+/// [`ExprEvaluator::eval_with_gradient`](crate::prelude::ExprEvaluator::eval_with_gradient),
+/// or bound to a column of values in
+/// [`ExprEvaluator::eval_batch`](crate::prelude::ExprEvaluator::eval_batch).
+///
+/// Both forms select the same variable when its name follows the naming
+/// convention of [`IndexedResolver`](crate::IndexedResolver), so
+/// `GradVar::Name("y0")` and `GradVar::Indexed('y', 0)` are interchangeable.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum GradVar<'a>
+{
+    /// Variable with the given name
+    Name(&'a str),
+    /// Variable with the given id and index, e.g. `('y', 0)` for `y0`
+    Indexed(char, usize),
+}
+
+impl<'a> From<&'a str> for GradVar<'a>
+{
+    fn from(name: &'a str) -> Self
+    {
+        GradVar::Name(name)
+    }
+}
+
+impl From<(char, usize)> for GradVar<'_>
+{
+    fn from((id, idx): (char, usize)) -> Self
+    {
+        GradVar::Indexed(id, idx)
+    }
+}
+
+impl GradVar<'_>
+{
+    /// Whether this is the variable named `name`
+    pub(crate) fn is_named(&self, name: &str) -> bool
+    {
+        match *self {
+            GradVar::Name(var) => var == name,
+            GradVar::Indexed(..) => {
+                parsing::parse_indexed_name(name).is_some_and(|(id, idx)| self.is_indexed(id, idx))
+            }
+        }
+    }
+
+    /// Whether this is the variable `idx` of the letter identifier `id`
+    pub(crate) fn is_indexed(&self, id: usize, idx: usize) -> bool
+    {
+        match *self {
+            GradVar::Name(name) => parsing::parse_indexed_name(name) == Some((id, idx)),
+            GradVar::Indexed(letter, index) => {
+                letter.is_ascii_lowercase() && (letter as u8 - b'a') as usize == id && index == idx
+            }
+        }
+    }
+
+    pub(crate) fn name(&self) -> Cow<'_, str>
+    {
+        match *self {
+            GradVar::Name(name) => Cow::Borrowed(name),
+            GradVar::Indexed(id, idx) => Cow::Owned(format!("{id}{idx}")),
+        }
+    }
+}
+
+/// Token of a compiled expression, resolved for [`eval_dual`]
+pub(super) enum DualToken<'f>
+{
+    Num(f64),
+    /// Value of a variable and its position among the [`GradVar`]s, if any
+    Var(f64, Option<usize>),
+    Fn(&'f ExprFn, usize),
+    Op(Op),
+    Jmp(usize),
+    JmpIfFalse(usize),
+    JmpAnd(usize),
+    JmpOr(usize),
+    Store(usize),
+    Load(usize),
+}
+
+/// Evaluates `len` tokens over dual numbers, carrying the partial derivatives
+/// with respect to the variables of `wrt`.
+///
+/// Each dual number takes `wrt.len() + 1` slots of `stack`, its value followed
+/// by its partial derivatives. `token` resolves the token at the given position,
+/// seeding variables in the position of their first match in `wrt`, and
+/// `fn_name` names the function called at the given position when it has no
+/// derivative companion.
+pub(super) fn eval_dual<'e, 'f>(
+    len: usize,
+    wrt: &[GradVar<'_>],
+    stack: &mut Vec<f64>,
+    mut token: impl FnMut(usize) -> Result<DualToken<'f>, Error<'e>>,
+    fn_name: impl Fn(usize) -> Cow<'e, str>,
+) -> Result<(f64, Vec<f64>), Error<'e>>
+{
+    let n = wrt.len();
+    let width = n + 1;
+    let underflow = || Error::EvalError(EvalError::RPNStackUnderflow);
+
+    // Scratch buffers for the arguments of a call, their partial derivatives and
+    // the dual number of a result
+    let mut args = Vec::new();
+    let mut partials = Vec::new();
+    let mut res = vec![0.0; width];
+    let mut temps = Vec::new();
+
+    stack.clear();
+    let mut pc = 0;
+
+    while pc < len {
+        let tok = token(pc)?;
+        pc += 1;
+
+        match tok {
+            DualToken::Num(num) => push(stack, num, n, None),
+            DualToken::Var(val, slot) => push(stack, val, n, slot),
+            DualToken::Fn(f, argc) => {
+                let start = stack
+                    .len()
+                    .checked_sub(argc * width)
+                    .ok_or_else(underflow)?;
+
+                call(
+                    f,
+                    &stack[start..],
+                    &mut args,
+                    &mut partials,
+                    &mut res,
+                    || fn_name(pc - 1),
+                )?;
+                stack.truncate(start);
+                stack.extend_from_slice(&res);
+            }
+            DualToken::Op(op) => {
+                let start = stack
+                    .len()
+                    .checked_sub(op.num_operands() * width)
+                    .ok_or_else(underflow)?;
+
+                let (lhs, rhs) = stack[start..].split_at(width);
+                apply(op, lhs, rhs, &mut res);
+                stack.truncate(start);
+                stack.extend_from_slice(&res);
+            }
+            DualToken::Jmp(offset) => pc += offset,
+            DualToken::JmpIfFalse(offset) => {
+                let start = stack.len().checked_sub(width).ok_or_else(underflow)?;
+                let cond = stack[start];
+                stack.truncate(start);
+
+                if !f64_to_bool(cond) {
+                    pc += offset;
+                }
+            }
+            DualToken::JmpAnd(offset) | DualToken::JmpOr(offset) => {
+                let start = stack.len().checked_sub(width).ok_or_else(underflow)?;
+                let decides = matches!(tok, DualToken::JmpOr(_));
+
+                if f64_to_bool(stack[start]) == decides {
+                    stack.truncate(start);
+                    push(stack, if decides { 1.0 } else { 0.0 }, n, None);
+                    pc += offset;
+                }
+            }
+            DualToken::Store(slot) => {
+                let start = stack.len().checked_sub(width).ok_or_else(underflow)?;
+
+                if temps.len() < (slot + 1) * width {
+                    temps.resize((slot + 1) * width, 0.0);
+                }
+                temps[slot * width..(slot + 1) * width].copy_from_slice(&stack[start..]);
+            }
+            DualToken::Load(slot) => {
+                let dual = temps
+                    .get(slot * width..(slot + 1) * width)
+                    .ok_or(Error::EvalError(EvalError::MalformedExpression))?;
+                stack.extend_from_slice(dual);
+            }
+        }
+    }
+
+    if stack.len() != width {
+        return Err(Error::EvalError(EvalError::MalformedExpression));
+    }
+
+    let (val, mut grad) = (stack[0], stack[1..].to_vec());
+    stack.clear();
+    copy_repeated(wrt, &mut grad);
+
+    Ok((val, grad))
+}
+
+/// Copies the derivatives of the variables selected more than once in `wrt`,
+/// which were only seeded in their first position
+pub(super) fn copy_repeated(wrt: &[GradVar<'_>], grad: &mut [f64])
+{
+    for i in 1..wrt.len() {
+        if let Some(first) = wrt[..i].iter().position(|var| var.name() == wrt[i].name()) {
+            grad[i] = grad[first];
+        }
+    }
+}
+
+/// Pushes a dual number, seeded with a derivative of 1 in `slot`
+#[inline]
+fn push(stack: &mut Vec<f64>, val: f64, n: usize, slot: Option<usize>)
+{
+    let start = stack.len();
+    stack.push(val);
+    stack.resize(start + n + 1, 0.0);
+
+    if let Some(slot) = slot {
+        stack[start + 1 + slot] = 1.0;
+    }
+}
+
+/// Adds `coef * d` to `grad`. Zero derivatives are skipped, so a term that
+/// doesn't depend on a variable adds nothing even if `coef` isn't finite.
+#[inline]
+pub(super) fn accumulate(grad: &mut [f64], coef: f64, d: &[f64])
+{
+    for (g, d) in grad.iter_mut().zip(d) {
+        if *d != 0.0 {
+            *g += coef * d;
+        }
+    }
+}
+
+/// Calls `f` on the values of the dual numbers `duals`, writing the dual number
+/// of the result to `out`. `fn_name` names `f` when its derivative is needed
+/// but it has no derivative companion.
+#[inline]
+pub(super) fn call<'e>(
+    f: &ExprFn,
+    duals: &[f64],
+    args: &mut Vec<f64>,
+    partials: &mut Vec<f64>,
+    out: &mut [f64],
+    fn_name: impl FnOnce() -> Cow<'e, str>,
+) -> Result<(), Error<'e>>
+{
+    let width = out.len();
+
+    args.clear();
+    args.extend(duals.iter().step_by(width));
+    out.fill(0.0);
+    out[0] = f.call(args);
+
+    let depends = duals
+        .chunks(width)
+        .any(|dual| dual[1..].iter().any(|d| *d != 0.0));
+
+    if depends {
+        let derivative = f
+            .derivative()
+            .ok_or_else(|| Error::UnknownDerivative(fn_name()))?;
+
+        partials.clear();
+        partials.resize(args.len(), 0.0);
+        derivative(args, partials);
+
+        for (dual, partial) in duals.chunks(width).zip(partials.iter()) {
+            accumulate(&mut out[1..], *partial, &dual[1..]);
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies `op` to the dual numbers `lhs` and `rhs`, writing the result to
+/// `out`. `rhs` is empty for unary operators.
+#[inline]
+pub(super) fn apply(op: Op, lhs: &[f64], rhs: &[f64], out: &mut [f64])
+{
+    let rhs_val = rhs.first().copied().unwrap_or(0.0);
+    let val = op.apply(&[lhs[0], rhs_val]);
+    out.fill(0.0);
+    out[0] = val;
+
+    if let Some((d_lhs, d_rhs)) = op_partials(op, lhs[0], rhs_val, val) {
+        accumulate(&mut out[1..], d_lhs, &lhs[1..]);
+        accumulate(&mut out[1..], d_rhs, rhs.get(1..).unwrap_or_default());
+    }
+}
+
+/// Partial derivatives of `op` with respect to its operands, `None` if the
+/// operator is piecewise constant.
+#[inline]
+pub(super) fn op_partials(op: Op, lhs: f64, rhs: f64, val: f64) -> Option<(f64, f64)>
+{
+    match op {
+        Op::Add => Some((1.0, 1.0)),
+        Op::Sub => Some((1.0, -1.0)),
+        Op::Mul => Some((rhs, lhs)),
+        Op::Div => Some((1.0 / rhs, -lhs / (rhs * rhs))),
+        Op::Pow => Some((rhs * Op::Pow.apply(&[lhs, rhs - 1.0]), val * lhs.ln())),
+        // a % b == a - trunc(a / b) * b
+        Op::Mod => Some((1.0, -(lhs - val) / rhs)),
+        Op::Neg => Some((-1.0, 0.0)),
+        _ => None,
+    }
+}
@@ -1,8 +1,18 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use crate::{
-    Error, EvalError, IndexedResolver, UContext,
-    expr::{ExprCompiler, NotIndexedResolver, Op, ParseableToken},
+    Ast, Error, EvalError, IndexedResolver, UContext,
+    expr::{
+        Buffer, EvalStack, ExprCompiler, NotIndexedResolver, Op, ParseableToken, Temps, Token,
+        ast::AstToken,
+        batch, cse,
+        dual::{self, DualToken, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        load,
+        simplify::{self, SimplifyMode},
+        store,
+    },
     parsing,
     prelude::*,
     resolver::{LockedResolver, ResolverState, UnlockedResolver},
@@ -15,13 +25,23 @@ pub enum IFRpn<'e>
     Var(&'e str),
     Fn(usize, usize, usize),
     Op(Op),
+    /// Jumps forward the given number of tokens
+    Jmp(usize),
+    /// Pops the condition and jumps forward the given number of tokens if it is false
+    JmpIfFalse(usize),
+    /// Short-circuits `&&`, jumping forward the given number of tokens and
+    /// leaving `false` on the stack if the left operand is false
+    JmpAnd(usize),
+    /// Short-circuits `||`, jumping forward the given number of tokens and
+    /// leaving `true` on the stack if the left operand is true
+    JmpOr(usize),
+    /// Copies the value on top of the stack into the given temporary slot
+    Store(usize),
+    /// Pushes the value of the given temporary slot
+    Load(usize),
 }
 
-impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for IFRpn<'a>
-where
-    S: ResolverState,
-    V: Resolver<S, f64>,
-    F: Resolver<S, ExprFn>,
+impl Token for IFRpn<'_>
 {
     #[inline]
     fn f64(num: f64) -> Self
@@ -48,18 +68,142 @@ where
     }
 
     #[inline]
-    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn jmp(offset: usize) -> Self
+    {
+        IFRpn::Jmp(offset)
+    }
+
+    #[inline]
+    fn jmp_if_false(offset: usize) -> Self
+    {
+        IFRpn::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        IFRpn::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
     {
-        IFRpn::Var(name)
+        IFRpn::JmpOr(offset)
     }
 
     #[inline]
-    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn store(slot: usize) -> Self
     {
-        let name_bytes = name.as_bytes();
-        let letter = name_bytes[0] - b'a';
-        let idx = parsing::parse_usize(&name_bytes[1..]);
-        IFRpn::Fn(letter as usize, idx, argc)
+        IFRpn::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
+    {
+        IFRpn::Load(slot)
+    }
+
+    #[inline]
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            IFRpn::Num(num) => AstToken::Num(num),
+            IFRpn::Var(..) => AstToken::Var(self),
+            IFRpn::Fn(.., argc) => AstToken::Fn(self, argc),
+            IFRpn::Op(op) => AstToken::Op(op),
+            IFRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IFRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IFRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IFRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IFRpn::Store(slot) => AstToken::Store(slot),
+            IFRpn::Load(slot) => AstToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, 'c, S, V, LV, LF> ParseableToken<'a, 'c, S, V, IndexedResolver<S, ExprFn>, LV, LF>
+    for IFRpn<'a>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+{
+    #[inline]
+    fn var(
+        name: &'a str,
+        _ctx: &'c Context<S, V, IndexedResolver<S, ExprFn>, LV, LF>,
+    ) -> Option<Self>
+    {
+        Some(IFRpn::Var(name))
+    }
+
+    #[inline]
+    fn fun(
+        name: &'a str,
+        argc: usize,
+        ctx: &'c Context<S, V, IndexedResolver<S, ExprFn>, LV, LF>,
+    ) -> Option<Self>
+    {
+        let (id, idx) = ctx.fns().index_of(name)?;
+        Some(IFRpn::Fn(id, idx, argc))
+    }
+}
+
+impl<'e> Expr<IFRpn<'e>>
+{
+    /// Rebuilds the syntax tree of the compiled expression, with its literal
+    /// subexpressions already folded.
+    pub fn to_ast(&self) -> Result<Ast<'e>, Error<'e>>
+    {
+        Ast::from_postfix(self.tokens.iter().map(|tok| match *tok {
+            IFRpn::Num(num) => AstToken::Num(num),
+            IFRpn::Var(name) => AstToken::Var(Cow::Borrowed(name)),
+            IFRpn::Fn(id, idx, argc) => {
+                AstToken::Fn(Cow::Owned(parsing::indexed_name(id, idx)), argc)
+            }
+            IFRpn::Op(op) => AstToken::Op(op),
+            IFRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IFRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IFRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IFRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IFRpn::Store(slot) => AstToken::Store(slot),
+            IFRpn::Load(slot) => AstToken::Load(slot),
+        }))
+    }
+}
+
+impl Expr<IFRpn<'_>>
+{
+    /// Rewrites the expression into a cheaper equivalent before it is
+    /// evaluated, see [`SimplifyMode`] for the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
+    {
+        simplify::simplify_expr(self, mode);
+    }
+
+    /// Computes the repeated subexpressions once, storing them into temporary
+    /// slots read by their other occurrences. Calls to impure functions
+    /// ([`ExprFn::impure`]) and to functions the context can't resolve are
+    /// never merged.
+    pub fn eliminate_common_subexprs<S, V, LV>(
+        &mut self,
+        ctx: &Context<S, V, IndexedResolver<S, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+    ) where
+        S: ResolverState,
+        V: Resolver<S, f64>,
+    {
+        cse::eliminate(self, |tok| match *tok {
+            IFRpn::Fn(id, idx, _) => ctx.get_fn_by_index(id, idx).is_some_and(ExprFn::is_pure),
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Expr<IFRpn<'_>>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.to_ast().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
     }
 }
 
@@ -90,69 +234,102 @@ where
     {
         Expr::try_from((expr, ctx))
     }
+
+    fn from_ast(
+        ast: &'e Ast<'_>,
+        ctx: &'c UContext<
+            V,
+            IndexedResolver<Unlocked, ExprFn>,
+            LV,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+    ) -> Result<Expr<IFRpn<'e>>, Error<'e>>
+    {
+        Expr::try_from((ast, ctx))
+    }
 }
 
-impl<'e, V, LV>
-    ExprEvaluator<
-        'e,
-        Unlocked,
-        V,
-        IndexedResolver<Unlocked, ExprFn>,
-        LV,
-        IndexedResolver<Locked, ExprFn>,
-    > for Expr<IFRpn<'e>>
-where
-    V: NotIndexedResolver + UnlockedResolver<f64, LV>,
-    LV: LockedResolver<f64>,
+impl<'e> Expr<IFRpn<'e>>
 {
-    fn eval(
+    /// Evaluates the expression on `stack`, which is left empty if it
+    /// succeeds
+    fn eval_on<V, LV>(
         &self,
         ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
-        stack: &mut Vec<f64>,
+        stack: &mut impl EvalStack,
     ) -> Result<f64, Error<'e>>
+    where
+        V: Resolver<Unlocked, f64>,
     {
-        if self.tokens.len() == 1 {
-            if let IFRpn::Num(num) = &self.tokens[0] {
-                return Ok(*num);
-            }
+        if let [IFRpn::Num(num)] = self.tokens.as_slice() {
+            return Ok(*num);
         }
 
-        for tok in self.tokens.iter() {
+        let mut temps = Temps::new();
+        let mut pc = 0;
+
+        while let Some(tok) = self.tokens.get(pc) {
+            pc += 1;
+
             match tok {
                 IFRpn::Num(num) => stack.push(*num),
-                IFRpn::Var(name) => stack.push(
-                    *ctx.get_var(name)
-                        .ok_or_else(|| Error::UnknownVar(Cow::Borrowed(name)))?,
-                ),
+                IFRpn::Var(name) => {
+                    stack.push(*ctx.get_var(name).ok_or_else(|| {
+                        Error::UnknownVar(Cow::Borrowed(name), self.spans[pc - 1])
+                    })?)
+                }
                 IFRpn::Fn(id, idx, argc) => {
-                    if *argc > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - argc;
                     let args = unsafe { stack.get_unchecked(start..) };
                     let val = ctx.call_fn_by_index(*id, *idx, args).ok_or_else(|| {
-                        Error::UnknownFn(Cow::Owned(format!(
-                            "{}{}",
-                            (*id as u8 + b'a') as char,
-                            idx
-                        )))
+                        Error::UnknownFn(
+                            Cow::Owned(parsing::indexed_name(*id, *idx)),
+                            self.spans[pc - 1],
+                        )
                     })?;
 
                     stack.truncate(start);
                     stack.push(val);
                 }
                 IFRpn::Op(op) => {
-                    if op.num_operands() > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - op.num_operands();
                     let args = unsafe { stack.get_unchecked(start..) };
                     let res = op.apply(args);
                     stack.truncate(start);
                     stack.push(res);
                 }
+                IFRpn::Jmp(offset) => pc += offset,
+                IFRpn::JmpIfFalse(offset) => {
+                    let cond = stack
+                        .pop()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(cond) {
+                        pc += offset;
+                    }
+                }
+                IFRpn::JmpAnd(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(*lhs) {
+                        *lhs = 0.0;
+                        pc += offset;
+                    }
+                }
+                IFRpn::JmpOr(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if f64_to_bool(*lhs) {
+                        *lhs = 1.0;
+                        pc += offset;
+                    }
+                }
+                IFRpn::Store(slot) => store(&mut temps, *slot, stack)?,
+                IFRpn::Load(slot) => load(&temps, *slot, stack)?,
             }
         }
 
@@ -161,4 +338,119 @@ where
             _ => Err(Error::EvalError(EvalError::MalformedExpression)),
         }
     }
+
+    /// Resolves the token at `pc` for the evaluations over dual numbers and by
+    /// batches, numbering the variables by their position in `vars`
+    fn dual_token<'f, V, LV>(
+        &self,
+        ctx: &'f UContext<
+            V,
+            IndexedResolver<Unlocked, ExprFn>,
+            LV,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+        vars: &[GradVar<'_>],
+        pc: usize,
+    ) -> Result<DualToken<'f>, Error<'e>>
+    where
+        V: Resolver<Unlocked, f64>,
+    {
+        Ok(match self.tokens[pc] {
+            IFRpn::Num(num) => DualToken::Num(num),
+            IFRpn::Var(name) => {
+                let val = *ctx
+                    .get_var(name)
+                    .ok_or_else(|| Error::UnknownVar(Cow::Borrowed(name), self.spans[pc]))?;
+                DualToken::Var(val, vars.iter().position(|var| var.is_named(name)))
+            }
+            IFRpn::Fn(id, idx, argc) => {
+                let f = ctx.get_fn_by_index(id, idx).ok_or_else(|| {
+                    Error::UnknownFn(Cow::Owned(parsing::indexed_name(id, idx)), self.spans[pc])
+                })?;
+                DualToken::Fn(f, argc)
+            }
+            IFRpn::Op(op) => DualToken::Op(op),
+            IFRpn::Jmp(offset) => DualToken::Jmp(offset),
+            IFRpn::JmpIfFalse(offset) => DualToken::JmpIfFalse(offset),
+            IFRpn::JmpAnd(offset) => DualToken::JmpAnd(offset),
+            IFRpn::JmpOr(offset) => DualToken::JmpOr(offset),
+            IFRpn::Store(slot) => DualToken::Store(slot),
+            IFRpn::Load(slot) => DualToken::Load(slot),
+        })
+    }
+}
+
+impl<'e, V, LV>
+    ExprEvaluator<
+        'e,
+        Unlocked,
+        V,
+        IndexedResolver<Unlocked, ExprFn>,
+        LV,
+        IndexedResolver<Locked, ExprFn>,
+    > for Expr<IFRpn<'e>>
+where
+    V: NotIndexedResolver + UnlockedResolver<f64, LV>,
+    LV: LockedResolver<f64>,
+{
+    fn eval(
+        &self,
+        ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+        stack: &mut Vec<f64>,
+    ) -> Result<f64, Error<'e>>
+    {
+        self.eval_on(ctx, stack)
+    }
+
+    fn eval_stackless<const N: usize>(
+        &self,
+        ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+    ) -> Result<f64, Error<'e>>
+    {
+        if self.depth <= N {
+            self.eval_on(ctx, &mut Buffer::<N>::new())
+        } else {
+            self.eval_on(ctx, &mut Vec::with_capacity(self.depth))
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, wrt, pc);
+        let fn_name = |pc: usize| match self.tokens[pc] {
+            IFRpn::Fn(id, idx, _) => Cow::Owned(parsing::indexed_name(id, idx)),
+            _ => Cow::Borrowed(""),
+        };
+
+        dual::eval_dual(self.tokens.len(), wrt, stack, token, fn_name)
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        batch::eval_batch(self.tokens.len(), vars, columns, out, token)
+    }
+
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &UContext<V, IndexedResolver<Unlocked, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        lanes::eval_lanes::<L>(self.tokens.len(), vars, columns, out, token)
+    }
 }
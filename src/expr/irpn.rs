@@ -1,13 +1,31 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use crate::{
-    Error, EvalError, IndexedResolver, UContext,
-    expr::{ExprCompiler, Op, ParseableToken},
+    Ast, Error, EvalError, IndexedResolver, UContext,
+    expr::{
+        Buffer, EvalStack, ExprCompiler, Op, ParseableToken, Temps, Token,
+        ast::AstToken,
+        batch, cse,
+        dual::{self, DualToken, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        load,
+        simplify::{self, SimplifyMode},
+        store,
+    },
     parsing,
     prelude::*,
     resolver::ResolverState,
 };
 
+/// Unlocked context with indexed variables and functions
+type IContext = UContext<
+    IndexedResolver<Unlocked, f64>,
+    IndexedResolver<Unlocked, ExprFn>,
+    IndexedResolver<Locked, f64>,
+    IndexedResolver<Locked, ExprFn>,
+>;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum IRpn
 {
@@ -15,13 +33,23 @@ pub enum IRpn
     Var(usize, usize),
     Fn(usize, usize, usize),
     Op(Op),
+    /// Jumps forward the given number of tokens
+    Jmp(usize),
+    /// Pops the condition and jumps forward the given number of tokens if it is false
+    JmpIfFalse(usize),
+    /// Short-circuits `&&`, jumping forward the given number of tokens and
+    /// leaving `false` on the stack if the left operand is false
+    JmpAnd(usize),
+    /// Short-circuits `||`, jumping forward the given number of tokens and
+    /// leaving `true` on the stack if the left operand is true
+    JmpOr(usize),
+    /// Copies the value on top of the stack into the given temporary slot
+    Store(usize),
+    /// Pushes the value of the given temporary slot
+    Load(usize),
 }
 
-impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for IRpn
-where
-    S: ResolverState,
-    V: Resolver<S, f64>,
-    F: Resolver<S, ExprFn>,
+impl Token for IRpn
 {
     #[inline]
     fn f64(num: f64) -> Self
@@ -48,21 +76,142 @@ where
     }
 
     #[inline]
-    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn jmp(offset: usize) -> Self
+    {
+        IRpn::Jmp(offset)
+    }
+
+    #[inline]
+    fn jmp_if_false(offset: usize) -> Self
+    {
+        IRpn::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        IRpn::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
+    {
+        IRpn::JmpOr(offset)
+    }
+
+    #[inline]
+    fn store(slot: usize) -> Self
+    {
+        IRpn::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
     {
-        let name_bytes = name.as_bytes();
-        let letter = name_bytes[0] - b'a';
-        let idx = parsing::parse_usize(&name_bytes[1..]);
-        IRpn::Var(letter as usize, idx)
+        IRpn::Load(slot)
     }
 
     #[inline]
-    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            IRpn::Num(num) => AstToken::Num(num),
+            IRpn::Var(..) => AstToken::Var(self),
+            IRpn::Fn(.., argc) => AstToken::Fn(self, argc),
+            IRpn::Op(op) => AstToken::Op(op),
+            IRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IRpn::Store(slot) => AstToken::Store(slot),
+            IRpn::Load(slot) => AstToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, 'c, S, LV, LF>
+    ParseableToken<'a, 'c, S, IndexedResolver<S, f64>, IndexedResolver<S, ExprFn>, LV, LF> for IRpn
+where
+    S: ResolverState,
+{
+    #[inline]
+    fn var(
+        name: &'a str,
+        ctx: &'c Context<S, IndexedResolver<S, f64>, IndexedResolver<S, ExprFn>, LV, LF>,
+    ) -> Option<Self>
+    {
+        let (id, idx) = ctx.vars().index_of(name)?;
+        Some(IRpn::Var(id, idx))
+    }
+
+    #[inline]
+    fn fun(
+        name: &'a str,
+        argc: usize,
+        ctx: &'c Context<S, IndexedResolver<S, f64>, IndexedResolver<S, ExprFn>, LV, LF>,
+    ) -> Option<Self>
+    {
+        let (id, idx) = ctx.fns().index_of(name)?;
+        Some(IRpn::Fn(id, idx, argc))
+    }
+}
+
+impl Expr<IRpn>
+{
+    /// Rebuilds the syntax tree of the compiled expression, with its literal
+    /// subexpressions already folded.
+    pub fn to_ast(&self) -> Result<Ast<'static>, Error<'static>>
+    {
+        Ast::from_postfix(self.tokens.iter().map(|tok| match *tok {
+            IRpn::Num(num) => AstToken::Num(num),
+            IRpn::Var(id, idx) => AstToken::Var(Cow::Owned(parsing::indexed_name(id, idx))),
+            IRpn::Fn(id, idx, argc) => {
+                AstToken::Fn(Cow::Owned(parsing::indexed_name(id, idx)), argc)
+            }
+            IRpn::Op(op) => AstToken::Op(op),
+            IRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IRpn::Store(slot) => AstToken::Store(slot),
+            IRpn::Load(slot) => AstToken::Load(slot),
+        }))
+    }
+}
+
+impl Expr<IRpn>
+{
+    /// Rewrites the expression into a cheaper equivalent before it is
+    /// evaluated, see [`SimplifyMode`] for the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
     {
-        let name_bytes = name.as_bytes();
-        let letter = name_bytes[0] - b'a';
-        let idx = parsing::parse_usize(&name_bytes[1..]);
-        IRpn::Fn(letter as usize, idx, argc)
+        simplify::simplify_expr(self, mode);
+    }
+
+    /// Computes the repeated subexpressions once, storing them into temporary
+    /// slots read by their other occurrences. Calls to impure functions
+    /// ([`ExprFn::impure`]) and to functions the context can't resolve are
+    /// never merged.
+    pub fn eliminate_common_subexprs<S, V, LV>(
+        &mut self,
+        ctx: &Context<S, V, IndexedResolver<S, ExprFn>, LV, IndexedResolver<Locked, ExprFn>>,
+    ) where
+        S: ResolverState,
+        V: Resolver<S, f64>,
+    {
+        cse::eliminate(self, |tok| match *tok {
+            IRpn::Fn(id, idx, _) => ctx.get_fn_by_index(id, idx).is_some_and(ExprFn::is_pure),
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Expr<IRpn>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.to_ast().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
     }
 }
 
@@ -90,75 +239,98 @@ impl<'e, 'c>
     {
         Expr::try_from((expr, ctx))
     }
-}
 
-impl<'e>
-    ExprEvaluator<
-        'e,
-        Unlocked,
-        IndexedResolver<Unlocked, f64>,
-        IndexedResolver<Unlocked, ExprFn>,
-        IndexedResolver<Locked, f64>,
-        IndexedResolver<Locked, ExprFn>,
-    > for Expr<IRpn>
-{
-    fn eval(
-        &self,
-        ctx: &UContext<
+    fn from_ast(
+        ast: &'e Ast<'_>,
+        ctx: &'c UContext<
             IndexedResolver<Unlocked, f64>,
             IndexedResolver<Unlocked, ExprFn>,
             IndexedResolver<Locked, f64>,
             IndexedResolver<Locked, ExprFn>,
         >,
-        stack: &mut Vec<f64>,
-    ) -> Result<f64, Error<'e>>
+    ) -> Result<Expr<IRpn>, Error<'e>>
     {
-        if self.tokens.len() == 1 {
-            if let IRpn::Num(num) = &self.tokens[0] {
-                return Ok(*num);
-            }
+        Expr::try_from((ast, ctx))
+    }
+}
+
+impl Expr<IRpn>
+{
+    /// Evaluates the expression on `stack`, which is left empty if it
+    /// succeeds
+    fn eval_on<'e>(&self, ctx: &IContext, stack: &mut impl EvalStack) -> Result<f64, Error<'e>>
+    {
+        if let [IRpn::Num(num)] = self.tokens.as_slice() {
+            return Ok(*num);
         }
 
-        for tok in self.tokens.iter() {
+        let mut temps = Temps::new();
+        let mut pc = 0;
+
+        while let Some(tok) = self.tokens.get(pc) {
+            pc += 1;
+
             match tok {
                 IRpn::Num(num) => stack.push(*num),
                 IRpn::Var(id, idx) => {
                     stack.push(*ctx.get_var_by_index(*id, *idx).ok_or_else(|| {
-                        Error::UnknownVar(Cow::Owned(format!(
-                            "{}{}",
-                            (*id as u8 + b'a') as char,
-                            idx
-                        )))
+                        Error::UnknownVar(
+                            Cow::Owned(parsing::indexed_name(*id, *idx)),
+                            self.spans[pc - 1],
+                        )
                     })?)
                 }
                 IRpn::Fn(id, idx, argc) => {
-                    if *argc > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - argc;
                     let args = unsafe { stack.get_unchecked(start..) };
                     let val = ctx.call_fn_by_index(*id, *idx, args).ok_or_else(|| {
-                        Error::UnknownFn(Cow::Owned(format!(
-                            "{}{}",
-                            (*id as u8 + b'a') as char,
-                            idx
-                        )))
+                        Error::UnknownFn(
+                            Cow::Owned(parsing::indexed_name(*id, *idx)),
+                            self.spans[pc - 1],
+                        )
                     })?;
                     stack.truncate(start);
                     stack.push(val);
                 }
                 IRpn::Op(op) => {
-                    if op.num_operands() > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - op.num_operands();
                     let args = unsafe { stack.get_unchecked(start..) };
                     let res = op.apply(args);
                     stack.truncate(start);
                     stack.push(res);
                 }
+                IRpn::Jmp(offset) => pc += offset,
+                IRpn::JmpIfFalse(offset) => {
+                    let cond = stack
+                        .pop()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(cond) {
+                        pc += offset;
+                    }
+                }
+                IRpn::JmpAnd(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(*lhs) {
+                        *lhs = 0.0;
+                        pc += offset;
+                    }
+                }
+                IRpn::JmpOr(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if f64_to_bool(*lhs) {
+                        *lhs = 1.0;
+                        pc += offset;
+                    }
+                }
+                IRpn::Store(slot) => store(&mut temps, *slot, stack)?,
+                IRpn::Load(slot) => load(&temps, *slot, stack)?,
             }
         }
 
@@ -167,6 +339,136 @@ impl<'e>
             _ => Err(Error::EvalError(EvalError::MalformedExpression)),
         }
     }
+
+    /// Resolves the token at `pc` for the evaluations over dual numbers and by
+    /// batches, numbering the variables by their position in `vars`
+    fn dual_token<'e, 'f>(
+        &self,
+        ctx: &'f IContext,
+        vars: &[GradVar<'_>],
+        pc: usize,
+    ) -> Result<DualToken<'f>, Error<'e>>
+    {
+        Ok(match self.tokens[pc] {
+            IRpn::Num(num) => DualToken::Num(num),
+            IRpn::Var(id, idx) => {
+                let val = *ctx.get_var_by_index(id, idx).ok_or_else(|| {
+                    Error::UnknownVar(Cow::Owned(parsing::indexed_name(id, idx)), self.spans[pc])
+                })?;
+                DualToken::Var(val, vars.iter().position(|var| var.is_indexed(id, idx)))
+            }
+            IRpn::Fn(id, idx, argc) => {
+                let f = ctx.get_fn_by_index(id, idx).ok_or_else(|| {
+                    Error::UnknownFn(Cow::Owned(parsing::indexed_name(id, idx)), self.spans[pc])
+                })?;
+                DualToken::Fn(f, argc)
+            }
+            IRpn::Op(op) => DualToken::Op(op),
+            IRpn::Jmp(offset) => DualToken::Jmp(offset),
+            IRpn::JmpIfFalse(offset) => DualToken::JmpIfFalse(offset),
+            IRpn::JmpAnd(offset) => DualToken::JmpAnd(offset),
+            IRpn::JmpOr(offset) => DualToken::JmpOr(offset),
+            IRpn::Store(slot) => DualToken::Store(slot),
+            IRpn::Load(slot) => DualToken::Load(slot),
+        })
+    }
+}
+
+impl<'e>
+    ExprEvaluator<
+        'e,
+        Unlocked,
+        IndexedResolver<Unlocked, f64>,
+        IndexedResolver<Unlocked, ExprFn>,
+        IndexedResolver<Locked, f64>,
+        IndexedResolver<Locked, ExprFn>,
+    > for Expr<IRpn>
+{
+    fn eval(
+        &self,
+        ctx: &UContext<
+            IndexedResolver<Unlocked, f64>,
+            IndexedResolver<Unlocked, ExprFn>,
+            IndexedResolver<Locked, f64>,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+        stack: &mut Vec<f64>,
+    ) -> Result<f64, Error<'e>>
+    {
+        self.eval_on(ctx, stack)
+    }
+
+    fn eval_stackless<const N: usize>(
+        &self,
+        ctx: &UContext<
+            IndexedResolver<Unlocked, f64>,
+            IndexedResolver<Unlocked, ExprFn>,
+            IndexedResolver<Locked, f64>,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+    ) -> Result<f64, Error<'e>>
+    {
+        if self.depth <= N {
+            self.eval_on(ctx, &mut Buffer::<N>::new())
+        } else {
+            self.eval_on(ctx, &mut Vec::with_capacity(self.depth))
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &UContext<
+            IndexedResolver<Unlocked, f64>,
+            IndexedResolver<Unlocked, ExprFn>,
+            IndexedResolver<Locked, f64>,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, wrt, pc);
+        let fn_name = |pc: usize| match self.tokens[pc] {
+            IRpn::Fn(id, idx, _) => Cow::Owned(parsing::indexed_name(id, idx)),
+            _ => Cow::Borrowed(""),
+        };
+
+        dual::eval_dual(self.tokens.len(), wrt, stack, token, fn_name)
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &UContext<
+            IndexedResolver<Unlocked, f64>,
+            IndexedResolver<Unlocked, ExprFn>,
+            IndexedResolver<Locked, f64>,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        batch::eval_batch(self.tokens.len(), vars, columns, out, token)
+    }
+
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &UContext<
+            IndexedResolver<Unlocked, f64>,
+            IndexedResolver<Unlocked, ExprFn>,
+            IndexedResolver<Locked, f64>,
+            IndexedResolver<Locked, ExprFn>,
+        >,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        lanes::eval_lanes::<L>(self.tokens.len(), vars, columns, out, token)
+    }
 }
 
 #[cfg(test)]
@@ -177,7 +479,12 @@ mod tests
     #[test]
     fn test_new()
     {
-        let ctx = Context::empty();
+        let mut vars = IndexedResolver::new();
+        vars.add_id('p', 20);
+        vars.add_id('y', 2);
+        let mut fns = IndexedResolver::new();
+        fns.add_id('f', 2);
+        let ctx = Context::new(vars, fns);
 
         let expr = "2 - (4 + (p19 - 2) * (p19 + 2))";
         let rpn_expr = Expr::<IRpn>::try_from((expr, &ctx)).unwrap();
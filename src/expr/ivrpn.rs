@@ -1,8 +1,18 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use crate::{
-    Error, EvalError, IndexedResolver, UContext,
-    expr::{ExprCompiler, NotIndexedResolver, Op, ParseableToken},
+    Ast, Error, EvalError, IndexedResolver, UContext,
+    expr::{
+        Buffer, EvalStack, ExprCompiler, NotIndexedResolver, Op, ParseableToken, Temps, Token,
+        ast::AstToken,
+        batch, cse,
+        dual::{self, DualToken, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        load,
+        simplify::{self, SimplifyMode},
+        store,
+    },
     parsing,
     prelude::*,
     resolver::{LockedResolver, ResolverState, UnlockedResolver},
@@ -15,13 +25,23 @@ pub enum IVRpn<'e>
     Var(usize, usize),
     Fn(&'e str, usize),
     Op(Op),
+    /// Jumps forward the given number of tokens
+    Jmp(usize),
+    /// Pops the condition and jumps forward the given number of tokens if it is false
+    JmpIfFalse(usize),
+    /// Short-circuits `&&`, jumping forward the given number of tokens and
+    /// leaving `false` on the stack if the left operand is false
+    JmpAnd(usize),
+    /// Short-circuits `||`, jumping forward the given number of tokens and
+    /// leaving `true` on the stack if the left operand is true
+    JmpOr(usize),
+    /// Copies the value on top of the stack into the given temporary slot
+    Store(usize),
+    /// Pushes the value of the given temporary slot
+    Load(usize),
 }
 
-impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for IVRpn<'a>
-where
-    S: ResolverState,
-    V: Resolver<S, f64>,
-    F: Resolver<S, ExprFn>,
+impl Token for IVRpn<'_>
 {
     #[inline]
     fn f64(num: f64) -> Self
@@ -48,18 +68,136 @@ where
     }
 
     #[inline]
-    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn jmp(offset: usize) -> Self
     {
-        let name_bytes = name.as_bytes();
-        let letter = name_bytes[0] - b'a';
-        let idx = parsing::parse_usize(&name_bytes[1..]);
-        IVRpn::Var(letter as usize, idx)
+        IVRpn::Jmp(offset)
     }
 
     #[inline]
-    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn jmp_if_false(offset: usize) -> Self
     {
-        IVRpn::Fn(name, argc)
+        IVRpn::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        IVRpn::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
+    {
+        IVRpn::JmpOr(offset)
+    }
+
+    #[inline]
+    fn store(slot: usize) -> Self
+    {
+        IVRpn::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
+    {
+        IVRpn::Load(slot)
+    }
+
+    #[inline]
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            IVRpn::Num(num) => AstToken::Num(num),
+            IVRpn::Var(..) => AstToken::Var(self),
+            IVRpn::Fn(.., argc) => AstToken::Fn(self, argc),
+            IVRpn::Op(op) => AstToken::Op(op),
+            IVRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IVRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IVRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IVRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IVRpn::Store(slot) => AstToken::Store(slot),
+            IVRpn::Load(slot) => AstToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, 'c, S, F, LV, LF> ParseableToken<'a, 'c, S, IndexedResolver<S, f64>, F, LV, LF>
+    for IVRpn<'a>
+where
+    S: ResolverState,
+    F: Resolver<S, ExprFn>,
+{
+    #[inline]
+    fn var(name: &'a str, ctx: &'c Context<S, IndexedResolver<S, f64>, F, LV, LF>) -> Option<Self>
+    {
+        let (id, idx) = ctx.vars().index_of(name)?;
+        Some(IVRpn::Var(id, idx))
+    }
+
+    #[inline]
+    fn fun(
+        name: &'a str,
+        argc: usize,
+        _ctx: &'c Context<S, IndexedResolver<S, f64>, F, LV, LF>,
+    ) -> Option<Self>
+    {
+        Some(IVRpn::Fn(name, argc))
+    }
+}
+
+impl<'e> Expr<IVRpn<'e>>
+{
+    /// Rebuilds the syntax tree of the compiled expression, with its literal
+    /// subexpressions already folded.
+    pub fn to_ast(&self) -> Result<Ast<'e>, Error<'e>>
+    {
+        Ast::from_postfix(self.tokens.iter().map(|tok| match *tok {
+            IVRpn::Num(num) => AstToken::Num(num),
+            IVRpn::Var(id, idx) => AstToken::Var(Cow::Owned(parsing::indexed_name(id, idx))),
+            IVRpn::Fn(name, argc) => AstToken::Fn(Cow::Borrowed(name), argc),
+            IVRpn::Op(op) => AstToken::Op(op),
+            IVRpn::Jmp(offset) => AstToken::Jmp(offset),
+            IVRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            IVRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            IVRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            IVRpn::Store(slot) => AstToken::Store(slot),
+            IVRpn::Load(slot) => AstToken::Load(slot),
+        }))
+    }
+}
+
+impl Expr<IVRpn<'_>>
+{
+    /// Rewrites the expression into a cheaper equivalent before it is
+    /// evaluated, see [`SimplifyMode`] for the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
+    {
+        simplify::simplify_expr(self, mode);
+    }
+
+    /// Computes the repeated subexpressions once, storing them into temporary
+    /// slots read by their other occurrences. Calls to impure functions
+    /// ([`ExprFn::impure`]) and to functions the context can't resolve are
+    /// never merged.
+    pub fn eliminate_common_subexprs<S, V, F, LV, LF>(&mut self, ctx: &Context<S, V, F, LV, LF>)
+    where
+        S: ResolverState,
+        V: Resolver<S, f64>,
+        F: Resolver<S, ExprFn>,
+    {
+        cse::eliminate(self, |tok| match tok {
+            IVRpn::Fn(name, _) => ctx.get_fn(name).is_some_and(ExprFn::is_pure),
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Expr<IVRpn<'_>>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.to_ast().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
     }
 }
 
@@ -85,66 +223,98 @@ where
     {
         Expr::try_from((expr, ctx))
     }
+
+    fn from_ast(
+        ast: &'e Ast<'_>,
+        ctx: &'c UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+    ) -> Result<Expr<IVRpn<'e>>, Error<'e>>
+    {
+        Expr::try_from((ast, ctx))
+    }
 }
 
-impl<'e, F, LF>
-    ExprEvaluator<'e, Unlocked, IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>
-    for Expr<IVRpn<'e>>
-where
-    F: NotIndexedResolver + UnlockedResolver<ExprFn, LF>,
-    LF: LockedResolver<ExprFn>,
+impl<'e> Expr<IVRpn<'e>>
 {
-    fn eval(
+    /// Evaluates the expression on `stack`, which is left empty if it
+    /// succeeds
+    fn eval_on<F, LF>(
         &self,
         ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
-        stack: &mut Vec<f64>,
+        stack: &mut impl EvalStack,
     ) -> Result<f64, Error<'e>>
+    where
+        F: Resolver<Unlocked, ExprFn>,
     {
-        if self.tokens.len() == 1 {
-            if let IVRpn::Num(num) = &self.tokens[0] {
-                return Ok(*num);
-            }
+        if let [IVRpn::Num(num)] = self.tokens.as_slice() {
+            return Ok(*num);
         }
 
-        for tok in self.tokens.iter() {
+        let mut temps = Temps::new();
+        let mut pc = 0;
+
+        while let Some(tok) = self.tokens.get(pc) {
+            pc += 1;
+
             match tok {
                 IVRpn::Num(num) => stack.push(*num),
                 IVRpn::Var(id, idx) => {
                     stack.push(*ctx.get_var_by_index(*id, *idx).ok_or_else(|| {
-                        Error::UnknownVar(Cow::Owned(format!(
-                            "{}{}",
-                            (*id as u8 + b'a') as char,
-                            idx
-                        )))
+                        Error::UnknownVar(
+                            Cow::Owned(parsing::indexed_name(*id, *idx)),
+                            self.spans[pc - 1],
+                        )
                     })?)
                 }
                 IVRpn::Fn(name, argc) => {
-                    if *argc > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - argc;
                     let args = unsafe { stack.get_unchecked(start..) };
                     let val = ctx
                         .get_fn(name)
-                        .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name)))?(
-                        args
-                    );
+                        .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name), self.spans[pc - 1]))?
+                        .call(args);
 
                     stack.truncate(start);
                     stack.push(val);
                 }
                 IVRpn::Op(op) => {
-                    if op.num_operands() > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - op.num_operands();
                     let args = unsafe { stack.get_unchecked(start..) };
                     let res = op.apply(args);
                     stack.truncate(start);
                     stack.push(res);
                 }
+                IVRpn::Jmp(offset) => pc += offset,
+                IVRpn::JmpIfFalse(offset) => {
+                    let cond = stack
+                        .pop()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(cond) {
+                        pc += offset;
+                    }
+                }
+                IVRpn::JmpAnd(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(*lhs) {
+                        *lhs = 0.0;
+                        pc += offset;
+                    }
+                }
+                IVRpn::JmpOr(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if f64_to_bool(*lhs) {
+                        *lhs = 1.0;
+                        pc += offset;
+                    }
+                }
+                IVRpn::Store(slot) => store(&mut temps, *slot, stack)?,
+                IVRpn::Load(slot) => load(&temps, *slot, stack)?,
             }
         }
 
@@ -153,4 +323,108 @@ where
             _ => Err(Error::EvalError(EvalError::MalformedExpression)),
         }
     }
+
+    /// Resolves the token at `pc` for the evaluations over dual numbers and by
+    /// batches, numbering the variables by their position in `vars`
+    fn dual_token<'f, F, LF>(
+        &self,
+        ctx: &'f UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+        vars: &[GradVar<'_>],
+        pc: usize,
+    ) -> Result<DualToken<'f>, Error<'e>>
+    where
+        F: Resolver<Unlocked, ExprFn>,
+    {
+        Ok(match self.tokens[pc] {
+            IVRpn::Num(num) => DualToken::Num(num),
+            IVRpn::Var(id, idx) => {
+                let val = *ctx.get_var_by_index(id, idx).ok_or_else(|| {
+                    Error::UnknownVar(Cow::Owned(parsing::indexed_name(id, idx)), self.spans[pc])
+                })?;
+                DualToken::Var(val, vars.iter().position(|var| var.is_indexed(id, idx)))
+            }
+            IVRpn::Fn(name, argc) => {
+                let f = ctx
+                    .get_fn(name)
+                    .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name), self.spans[pc]))?;
+                DualToken::Fn(f, argc)
+            }
+            IVRpn::Op(op) => DualToken::Op(op),
+            IVRpn::Jmp(offset) => DualToken::Jmp(offset),
+            IVRpn::JmpIfFalse(offset) => DualToken::JmpIfFalse(offset),
+            IVRpn::JmpAnd(offset) => DualToken::JmpAnd(offset),
+            IVRpn::JmpOr(offset) => DualToken::JmpOr(offset),
+            IVRpn::Store(slot) => DualToken::Store(slot),
+            IVRpn::Load(slot) => DualToken::Load(slot),
+        })
+    }
+}
+
+impl<'e, F, LF>
+    ExprEvaluator<'e, Unlocked, IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>
+    for Expr<IVRpn<'e>>
+where
+    F: NotIndexedResolver + UnlockedResolver<ExprFn, LF>,
+    LF: LockedResolver<ExprFn>,
+{
+    fn eval(
+        &self,
+        ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+        stack: &mut Vec<f64>,
+    ) -> Result<f64, Error<'e>>
+    {
+        self.eval_on(ctx, stack)
+    }
+
+    fn eval_stackless<const N: usize>(
+        &self,
+        ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+    ) -> Result<f64, Error<'e>>
+    {
+        if self.depth <= N {
+            self.eval_on(ctx, &mut Buffer::<N>::new())
+        } else {
+            self.eval_on(ctx, &mut Vec::with_capacity(self.depth))
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, wrt, pc);
+        let fn_name = |pc: usize| match self.tokens[pc] {
+            IVRpn::Fn(name, _) => Cow::Borrowed(name),
+            _ => Cow::Borrowed(""),
+        };
+
+        dual::eval_dual(self.tokens.len(), wrt, stack, token, fn_name)
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        batch::eval_batch(self.tokens.len(), vars, columns, out, token)
+    }
+
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &UContext<IndexedResolver<Unlocked, f64>, F, IndexedResolver<Locked, f64>, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        lanes::eval_lanes::<L>(self.tokens.len(), vars, columns, out, token)
+    }
 }
@@ -0,0 +1,669 @@
+use std::{cell::Cell, fmt, mem::ManuallyDrop, slice};
+
+use cranelift_codegen::{
+    ir::{
+        AbiParam, Block, FuncRef, InstBuilder, MemFlags, StackSlot, StackSlotData, StackSlotKind,
+        Type, Value, condcodes::FloatCC, types,
+    },
+    isa::OwnedTargetIsa,
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Linkage, Module, default_libcall_names};
+
+use crate::{
+    Error, EvalError, LContext, Ptr,
+    expr::{
+        Op, batch,
+        dual::GradVar,
+        lanes::Lane,
+        lrpn::LRpn,
+        reg::{Operand, Reg},
+    },
+    prelude::*,
+    resolver::LockedResolver,
+};
+
+/// Expression compiled to native code with Cranelift.
+///
+/// Register code ([`Reg`]) is translated into a function taking the addresses
+/// of the variables it reads: variables are loaded from their address in the
+/// locked context, or from a buffer holding the values of a row in a batch
+/// evaluation, registers become SSA values and functions are called directly
+/// with the address of their [`ExprFn`]. Evaluating the expression is then a
+/// single call.
+///
+/// It is built from register code or from any locked postfix expression with
+/// `Jit::try_from`, and borrows the locked context the same way they do.
+///
+/// Unlike the other expressions, `Jit` is `Send` but not `Sync`: the module
+/// owning the native code cannot be shared between threads.
+///
+/// # Examples
+/// ```rust
+/// use fee::{IndexedResolver, Jit, prelude::*};
+///
+/// let mut vars = IndexedResolver::new();
+/// vars.add_id('x', 2);
+/// vars.set('x', 0, 3.0);
+/// vars.set('x', 1, 4.0);
+/// let ctx = Context::new(vars, IndexedResolver::new()).lock();
+///
+/// let expr = Expr::compile("x0 * x0 + x1 * x1", &ctx).unwrap();
+/// let jit = Jit::try_from(&expr).unwrap();
+/// assert_eq!(jit.eval(&ctx, &mut Vec::new()), Ok(25.0));
+///
+/// ctx.get_var_ptr("x1").unwrap().set(0.0);
+/// assert_eq!(jit.eval(&ctx, &mut Vec::new()), Ok(9.0));
+/// ```
+pub struct Jit<'a>
+{
+    /// Register code the function was compiled from
+    code: Expr<Reg<'a>>,
+    /// Variables read by the code, in the order of their addresses in the
+    /// table passed to `func`
+    vars: Vec<Ptr<'a, f64>>,
+    func: extern "C" fn(*const *const f64) -> f64,
+    /// Owns the memory of `func`, which is freed when dropped
+    module: ManuallyDrop<JITModule>,
+}
+
+impl<'a> Jit<'a>
+{
+    /// Returns the register code the expression was compiled from.
+    pub fn code(&self) -> &Expr<Reg<'a>>
+    {
+        &self.code
+    }
+}
+
+impl<'a> TryFrom<&Expr<Reg<'a>>> for Jit<'a>
+{
+    type Error = Error<'static>;
+
+    fn try_from(expr: &Expr<Reg<'a>>) -> Result<Self, Self::Error>
+    {
+        let mut builder = JITBuilder::with_isa(isa()?, default_libcall_names());
+        builder
+            .symbol("fee_call", call as *const u8)
+            .symbol("fee_pow", pow as *const u8)
+            .symbol("fee_mod", rem as *const u8);
+
+        let mut module = JITModule::new(builder);
+        let vars = vars(&expr.tokens);
+
+        match define(&mut module, &expr.tokens, expr.depth, &vars) {
+            Ok(func) => Ok(Jit {
+                code: Expr {
+                    tokens: expr.tokens.clone(),
+                    spans: expr.spans.clone(),
+                    depth: expr.depth,
+                },
+                vars,
+                // The function was compiled with the signature of `func`
+                func: unsafe {
+                    std::mem::transmute::<*const u8, extern "C" fn(*const *const f64) -> f64>(func)
+                },
+                module: ManuallyDrop::new(module),
+            }),
+            Err(err) => {
+                unsafe { module.free_memory() };
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<'a> TryFrom<&Expr<LRpn<'a>>> for Jit<'a>
+{
+    type Error = Error<'static>;
+
+    /// Lowers the expression into register code first.
+    fn try_from(expr: &Expr<LRpn<'a>>) -> Result<Self, Self::Error>
+    {
+        Jit::try_from(&Expr::<Reg>::try_from(expr)?)
+    }
+}
+
+impl Drop for Jit<'_>
+{
+    fn drop(&mut self)
+    {
+        // `func` can't be called once the expression is gone
+        unsafe { ManuallyDrop::take(&mut self.module).free_memory() };
+    }
+}
+
+impl fmt::Debug for Jit<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.debug_struct("Jit")
+            .field("code", &self.code)
+            .field("vars", &self.vars)
+            .field("func", &self.func)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, V, F> ExprEvaluator<'a, Locked, V, F, V, F> for Jit<'a>
+where
+    V: LockedResolver<f64>,
+    F: LockedResolver<ExprFn>,
+{
+    #[inline]
+    fn eval(&self, _ctx: &LContext<V, F>, _stack: &mut Vec<f64>) -> Result<f64, Error<'a>>
+    {
+        Ok((self.func)(self.addresses()))
+    }
+
+    #[inline]
+    fn eval_stackless<const N: usize>(&self, _ctx: &LContext<V, F>) -> Result<f64, Error<'a>>
+    {
+        Ok((self.func)(self.addresses()))
+    }
+
+    /// Gradients are evaluated on the register code.
+    fn eval_with_gradient(
+        &self,
+        ctx: &LContext<V, F>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'a>>
+    {
+        self.code.eval_with_gradient(ctx, wrt, stack)
+    }
+
+    /// Rows are evaluated one after another with the native code, which reads
+    /// the variables from a buffer holding the values of the row.
+    fn eval_batch(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        // Locked variables are told apart by their address
+        let ptrs: Vec<_> = vars
+            .iter()
+            .map(|var| ctx.get_var_ptr(&var.name()))
+            .collect();
+
+        batch::check_columns(vars.len(), columns, out);
+
+        // Variables bound to several columns take the value of the first one,
+        // the others keep the value of the context
+        let cols: Vec<_> = self
+            .vars
+            .iter()
+            .map(|var| ptrs.iter().position(|ptr| *ptr == Some(*var)))
+            .collect();
+        let row: Vec<_> = self.vars.iter().map(|var| Cell::new(var.get())).collect();
+        let addresses: Vec<_> = row
+            .iter()
+            .map(|value| value.as_ptr().cast_const())
+            .collect();
+
+        for (i, res) in out.iter_mut().enumerate() {
+            for (value, col) in row.iter().zip(&cols) {
+                if let Some(col) = col {
+                    value.set(columns[*col][i]);
+                }
+            }
+            *res = (self.func)(addresses.as_ptr());
+        }
+
+        Ok(())
+    }
+
+    /// Rows are evaluated one after another, like in
+    /// [`eval_batch`](ExprEvaluator::eval_batch).
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        self.eval_batch(ctx, vars, columns, out)
+    }
+}
+
+impl Jit<'_>
+{
+    /// Table of the addresses of the variables in the locked context
+    #[inline]
+    fn addresses(&self) -> *const *const f64
+    {
+        // `Ptr` is a transparent pointer
+        self.vars.as_ptr().cast()
+    }
+}
+
+/// Variables read by register code, in the order of their first read
+fn vars<'a>(code: &[Reg<'a>]) -> Vec<Ptr<'a, f64>>
+{
+    let mut vars = Vec::new();
+
+    for instr in code {
+        let operands = match *instr {
+            Reg::Mov(_, src)
+            | Reg::Unary(_, _, src)
+            | Reg::JmpIfFalse(src, _)
+            | Reg::JmpAnd(src, ..)
+            | Reg::JmpOr(src, ..)
+            | Reg::Ret(src) => [Some(src), None],
+            Reg::Binary(_, _, lhs, rhs) => [Some(lhs), Some(rhs)],
+            Reg::Call(..) | Reg::Jmp(_) => [None, None],
+        };
+
+        for operand in operands.into_iter().flatten() {
+            if let Operand::Var(ptr) = operand
+                && !vars.contains(&ptr)
+            {
+                vars.push(ptr);
+            }
+        }
+    }
+
+    vars
+}
+
+/// Calls the function at `f` with the `argc` arguments at `args`
+extern "C" fn call(f: *const ExprFn, args: *const f64, argc: usize) -> f64
+{
+    unsafe { (*f).call(slice::from_raw_parts(args, argc)) }
+}
+
+extern "C" fn pow(lhs: f64, rhs: f64) -> f64
+{
+    Op::Pow.apply(&[lhs, rhs])
+}
+
+extern "C" fn rem(lhs: f64, rhs: f64) -> f64
+{
+    Op::Mod.apply(&[lhs, rhs])
+}
+
+fn codegen_error(err: impl fmt::Display) -> Error<'static>
+{
+    Error::CodegenError(err.to_string())
+}
+
+/// Target of the host machine
+fn isa() -> Result<OwnedTargetIsa, Error<'static>>
+{
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(codegen_error)?;
+    // Functions are loaded anywhere in memory, far from the helpers they call
+    flags
+        .set("use_colocated_libcalls", "false")
+        .map_err(codegen_error)?;
+    flags.set("is_pic", "true").map_err(codegen_error)?;
+
+    cranelift_native::builder()
+        .map_err(codegen_error)?
+        .finish(settings::Flags::new(flags))
+        .map_err(codegen_error)
+}
+
+/// Compiles register code using `regs` registers into a function of the
+/// module, returning its address. The function takes the table of the
+/// addresses of `vars`.
+fn define(
+    module: &mut JITModule,
+    code: &[Reg<'_>],
+    regs: usize,
+    vars: &[Ptr<'_, f64>],
+) -> Result<*const u8, Error<'static>>
+{
+    let ptr = module.target_config().pointer_type();
+
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(ptr));
+    sig.returns.push(AbiParam::new(types::F64));
+    let func = module
+        .declare_function("eval", Linkage::Export, &sig)
+        .map_err(codegen_error)?;
+
+    let mut call_sig = module.make_signature();
+    call_sig.params.extend([AbiParam::new(ptr); 3]);
+    call_sig.returns.push(AbiParam::new(types::F64));
+    let call = module
+        .declare_function("fee_call", Linkage::Import, &call_sig)
+        .map_err(codegen_error)?;
+
+    let mut binary_sig = module.make_signature();
+    binary_sig.params.extend([AbiParam::new(types::F64); 2]);
+    binary_sig.returns.push(AbiParam::new(types::F64));
+    let pow = module
+        .declare_function("fee_pow", Linkage::Import, &binary_sig)
+        .map_err(codegen_error)?;
+    let rem = module
+        .declare_function("fee_mod", Linkage::Import, &binary_sig)
+        .map_err(codegen_error)?;
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    let mut fn_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+
+    // Arguments of the calls are passed through a slot of the frame
+    let argc = code
+        .iter()
+        .map(|instr| match instr {
+            Reg::Call(_, _, argc) => *argc,
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let args = builder.create_sized_stack_slot(StackSlotData::new(
+        StackSlotKind::ExplicitSlot,
+        (argc.max(1) * size_of::<f64>()) as u32,
+        3,
+    ));
+
+    // The table is the parameter of the entry block
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    let table = builder.block_params(entry)[0];
+
+    let translation = Translation {
+        call: module.declare_func_in_func(call, builder.func),
+        pow: module.declare_func_in_func(pow, builder.func),
+        rem: module.declare_func_in_func(rem, builder.func),
+        builder,
+        ptr,
+        args,
+        entry,
+        vars,
+        table,
+    };
+    translation.translate(code, regs)?;
+
+    module
+        .define_function(func, &mut ctx)
+        .map_err(codegen_error)?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().map_err(codegen_error)?;
+
+    Ok(module.get_finalized_function(func))
+}
+
+/// Translates register code into Cranelift IR, each register being a
+/// variable of the function
+struct Translation<'f, 'v>
+{
+    builder: FunctionBuilder<'f>,
+    /// Pointer type of the target
+    ptr: Type,
+    /// Slot the arguments of the calls are written to
+    args: StackSlot,
+    entry: Block,
+    /// Variables in the order of the table of their addresses
+    vars: &'v [Ptr<'v, f64>],
+    /// Address of the table
+    table: Value,
+    call: FuncRef,
+    pow: FuncRef,
+    rem: FuncRef,
+}
+
+impl Translation<'_, '_>
+{
+    fn translate(mut self, code: &[Reg<'_>], regs: usize) -> Result<(), Error<'static>>
+    {
+        let malformed = || Error::EvalError(EvalError::MalformedExpression);
+
+        // Instructions starting a block: the targets of the jumps and the
+        // instructions following them
+        let mut blocks: Vec<Option<Block>> = vec![None; code.len()];
+
+        for (pc, instr) in code.iter().enumerate() {
+            let target = match *instr {
+                Reg::Jmp(offset)
+                | Reg::JmpIfFalse(_, offset)
+                | Reg::JmpAnd(_, _, offset)
+                | Reg::JmpOr(_, _, offset) => Some(pc + 1 + offset),
+                Reg::Ret(_) => None,
+                _ => continue,
+            };
+
+            for start in target.into_iter().chain([pc + 1]) {
+                match blocks.get_mut(start) {
+                    Some(block) => _ = block.get_or_insert_with(|| self.builder.create_block()),
+                    None if Some(start) == target => return Err(malformed()),
+                    None => {}
+                }
+            }
+        }
+
+        self.builder.switch_to_block(self.entry);
+
+        for reg in 0..regs {
+            let zero = self.builder.ins().f64const(0.0);
+            self.builder.declare_var(var(reg), types::F64);
+            self.builder.def_var(var(reg), zero);
+        }
+
+        // Whether the current block falls through into the next instruction
+        let mut open = true;
+
+        for (pc, instr) in code.iter().enumerate() {
+            if let Some(block) = blocks[pc] {
+                if open {
+                    self.builder.ins().jump(block, &[]);
+                }
+                self.builder.switch_to_block(block);
+                open = true;
+            }
+
+            match *instr {
+                Reg::Mov(dst, src) => {
+                    let val = self.read(src)?;
+                    self.builder.def_var(var(dst), val);
+                }
+                Reg::Unary(op, dst, src) => {
+                    let src = self.read(src)?;
+                    let res = self.op(op, &[src])?;
+                    self.builder.def_var(var(dst), res);
+                }
+                Reg::Binary(op, dst, lhs, rhs) => {
+                    let lhs = self.read(lhs)?;
+                    let rhs = self.read(rhs)?;
+                    let res = self.op(op, &[lhs, rhs])?;
+                    self.builder.def_var(var(dst), res);
+                }
+                Reg::Call(ptr, dst, argc) => {
+                    for arg in 0..argc {
+                        let val = self.builder.use_var(var(dst + arg));
+                        let offset = (arg * size_of::<f64>()) as i32;
+                        self.builder.ins().stack_store(val, self.args, offset);
+                    }
+
+                    let f = self.builder.ins().iconst(self.ptr, ptr.as_ptr() as i64);
+                    let args = self.builder.ins().stack_addr(self.ptr, self.args, 0);
+                    let argc = self.builder.ins().iconst(self.ptr, argc as i64);
+                    let res = self.call(self.call, &[f, args, argc]);
+                    self.builder.def_var(var(dst), res);
+                }
+                Reg::Jmp(offset) => {
+                    let target = blocks[pc + 1 + offset].ok_or_else(malformed)?;
+                    self.builder.ins().jump(target, &[]);
+                    open = false;
+                }
+                Reg::JmpIfFalse(cond, offset) => {
+                    let target = blocks[pc + 1 + offset].ok_or_else(malformed)?;
+                    let next = blocks
+                        .get(pc + 1)
+                        .copied()
+                        .flatten()
+                        .ok_or_else(malformed)?;
+                    let cond = self.read(cond)?;
+                    let cond = self.truthy(cond);
+                    self.builder.ins().brif(cond, next, &[], target, &[]);
+                    open = false;
+                }
+                Reg::JmpAnd(lhs, dst, offset) | Reg::JmpOr(lhs, dst, offset) => {
+                    let target = blocks[pc + 1 + offset].ok_or_else(malformed)?;
+                    let next = blocks
+                        .get(pc + 1)
+                        .copied()
+                        .flatten()
+                        .ok_or_else(malformed)?;
+                    let lhs = self.read(lhs)?;
+                    let lhs = self.truthy(lhs);
+
+                    // The result is written on the way to the target
+                    let short = self.builder.create_block();
+                    let res = match instr {
+                        Reg::JmpAnd(..) => {
+                            self.builder.ins().brif(lhs, next, &[], short, &[]);
+                            0.0
+                        }
+                        _ => {
+                            self.builder.ins().brif(lhs, short, &[], next, &[]);
+                            1.0
+                        }
+                    };
+
+                    self.builder.switch_to_block(short);
+                    let res = self.builder.ins().f64const(res);
+                    self.builder.def_var(var(dst), res);
+                    self.builder.ins().jump(target, &[]);
+                    open = false;
+                }
+                Reg::Ret(src) => {
+                    let val = self.read(src)?;
+                    self.builder.ins().return_(&[val]);
+                    open = false;
+                }
+            }
+        }
+
+        if open {
+            return Err(malformed());
+        }
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Ok(())
+    }
+
+    fn read(&mut self, operand: Operand<'_>) -> Result<Value, Error<'static>>
+    {
+        let val = match operand {
+            Operand::Num(num) => self.builder.ins().f64const(num),
+            Operand::Var(ptr) => {
+                let slot = self
+                    .vars
+                    .iter()
+                    .position(|var| *var == ptr)
+                    .ok_or(Error::EvalError(EvalError::MalformedExpression))?;
+
+                let offset = (slot * size_of::<*const f64>()) as i32;
+                let addr = self.builder.ins().load(
+                    self.ptr,
+                    MemFlags::trusted().with_readonly(),
+                    self.table,
+                    offset,
+                );
+                self.builder
+                    .ins()
+                    .load(types::F64, MemFlags::trusted(), addr, 0)
+            }
+            Operand::Reg(reg) => self.builder.use_var(var(reg)),
+        };
+
+        Ok(val)
+    }
+
+    fn op(&mut self, op: Op, args: &[Value]) -> Result<Value, Error<'static>>
+    {
+        let res = match (op, args) {
+            (Op::Neg, &[x]) => self.builder.ins().fneg(x),
+            (Op::Not, &[x]) => {
+                let zero = self.builder.ins().f64const(0.0);
+                let cond = self.builder.ins().fcmp(FloatCC::Equal, x, zero);
+                self.boolean(cond)
+            }
+
+            (Op::Add, &[lhs, rhs]) => self.builder.ins().fadd(lhs, rhs),
+            (Op::Sub, &[lhs, rhs]) => self.builder.ins().fsub(lhs, rhs),
+            (Op::Mul, &[lhs, rhs]) => self.builder.ins().fmul(lhs, rhs),
+            (Op::Div, &[lhs, rhs]) => self.builder.ins().fdiv(lhs, rhs),
+            (Op::Pow, &[lhs, rhs]) => self.call(self.pow, &[lhs, rhs]),
+            (Op::Mod, &[lhs, rhs]) => self.call(self.rem, &[lhs, rhs]),
+
+            (Op::Or | Op::And, &[lhs, rhs]) => {
+                let lhs = self.truthy(lhs);
+                let rhs = self.truthy(rhs);
+                let cond = match op {
+                    Op::Or => self.builder.ins().bor(lhs, rhs),
+                    _ => self.builder.ins().band(lhs, rhs),
+                };
+                self.boolean(cond)
+            }
+
+            (Op::Low | Op::Great | Op::LowEq | Op::GreatEq | Op::Eq | Op::NotEq, &[lhs, rhs]) => {
+                let cc = match op {
+                    Op::Low => FloatCC::LessThan,
+                    Op::Great => FloatCC::GreaterThan,
+                    Op::LowEq => FloatCC::LessThanOrEqual,
+                    Op::GreatEq => FloatCC::GreaterThanOrEqual,
+                    Op::Eq => FloatCC::Equal,
+                    _ => FloatCC::NotEqual,
+                };
+                let cond = self.builder.ins().fcmp(cc, lhs, rhs);
+                self.boolean(cond)
+            }
+
+            // Operands are truncated the same way `as i64` does
+            (Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr, &[lhs, rhs]) => {
+                let lhs = self.builder.ins().fcvt_to_sint_sat(types::I64, lhs);
+                let rhs = self.builder.ins().fcvt_to_sint_sat(types::I64, rhs);
+                let ins = self.builder.ins();
+                let res = match op {
+                    Op::BitAnd => ins.band(lhs, rhs),
+                    Op::BitOr => ins.bor(lhs, rhs),
+                    Op::BitXor => ins.bxor(lhs, rhs),
+                    Op::Shl => ins.ishl(lhs, rhs),
+                    _ => ins.sshr(lhs, rhs),
+                };
+                self.builder.ins().fcvt_from_sint(types::F64, res)
+            }
+
+            _ => return Err(Error::InvalidOperator(op, args.len())),
+        };
+
+        Ok(res)
+    }
+
+    fn call(&mut self, func: FuncRef, args: &[Value]) -> Value
+    {
+        let inst = self.builder.ins().call(func, args);
+        self.builder.inst_results(inst)[0]
+    }
+
+    /// Converts a number into a condition, `NaN` being true
+    fn truthy(&mut self, val: Value) -> Value
+    {
+        let zero = self.builder.ins().f64const(0.0);
+        self.builder.ins().fcmp(FloatCC::NotEqual, val, zero)
+    }
+
+    fn boolean(&mut self, cond: Value) -> Value
+    {
+        let one = self.builder.ins().f64const(1.0);
+        let zero = self.builder.ins().f64const(0.0);
+        self.builder.ins().select(cond, one, zero)
+    }
+}
+
+fn var(reg: usize) -> Variable
+{
+    Variable::from_u32(reg as u32)
+}
@@ -0,0 +1,377 @@
+use smallvec::SmallVec;
+
+use crate::{
+    Error, ExprFn,
+    expr::{
+        Op,
+        batch::{self, Step},
+        dual::{DualToken, GradVar},
+    },
+};
+
+/// Group of `f64` values evaluated together by
+/// [`ExprEvaluator::eval_lanes`](crate::prelude::ExprEvaluator::eval_lanes),
+/// one per row.
+///
+/// It is implemented by [`F64x4`] and [`F64x8`], backed by `std::simd`
+/// vectors with the `simd` feature (nightly only) and by arrays otherwise.
+pub trait Lane: sealed::LaneOps {}
+
+mod sealed
+{
+    use crate::expr::Op;
+
+    pub trait LaneOps: Copy
+    {
+        /// Number of rows of the lane
+        const WIDTH: usize;
+
+        fn splat(x: f64) -> Self;
+        /// Loads the first values of the lane, the others are set to zero
+        fn from_slice(x: &[f64]) -> Self;
+        fn as_slice(&self) -> &[f64];
+        fn as_mut_slice(&mut self) -> &mut [f64];
+        fn unary(self, op: Op) -> Self;
+        fn binary(self, op: Op, rhs: Self) -> Self;
+        /// Takes the value of `then` in the rows where `self` is true, and the
+        /// value of `other` in the rest
+        fn select(self, then: Self, other: Self) -> Self;
+    }
+}
+
+use sealed::LaneOps;
+
+macro_rules! lane {
+    ($name:ident, $width:literal) => {
+        #[doc = concat!("Lane of ", stringify!($width), " rows, see [`Lane`]")]
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        pub struct $name(repr::Repr<$width>);
+
+        impl Lane for $name {}
+
+        impl LaneOps for $name
+        {
+            const WIDTH: usize = $width;
+
+            #[inline]
+            fn splat(x: f64) -> Self
+            {
+                $name(repr::Repr::splat(x))
+            }
+
+            #[inline]
+            fn from_slice(x: &[f64]) -> Self
+            {
+                let mut lane = [0.0; $width];
+                lane[..x.len()].copy_from_slice(x);
+                $name(repr::Repr::from_array(lane))
+            }
+
+            #[inline]
+            fn as_slice(&self) -> &[f64]
+            {
+                self.0.as_array()
+            }
+
+            #[inline]
+            fn as_mut_slice(&mut self) -> &mut [f64]
+            {
+                self.0.as_mut_array()
+            }
+
+            #[inline]
+            fn unary(self, op: Op) -> Self
+            {
+                $name(self.0.unary(op))
+            }
+
+            #[inline]
+            fn binary(self, op: Op, rhs: Self) -> Self
+            {
+                $name(self.0.binary(op, rhs.0))
+            }
+
+            #[inline]
+            fn select(self, then: Self, other: Self) -> Self
+            {
+                $name(self.0.select(then.0, other.0))
+            }
+        }
+    };
+}
+
+lane!(F64x4, 4);
+lane!(F64x8, 8);
+
+/// Lanes as `std::simd` vectors, comparisons giving masks that select `1.0` or
+/// `0.0` and bitwise operators working on vectors of `i64`
+#[cfg(feature = "simd")]
+mod repr
+{
+    use std::simd::{Simd, prelude::*};
+
+    use crate::expr::Op;
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub(super) struct Repr<const N: usize>(Simd<f64, N>);
+
+    impl<const N: usize> Repr<N>
+    {
+        #[inline]
+        pub(super) fn splat(x: f64) -> Self
+        {
+            Repr(Simd::splat(x))
+        }
+
+        #[inline]
+        pub(super) fn from_array(x: [f64; N]) -> Self
+        {
+            Repr(Simd::from_array(x))
+        }
+
+        #[inline]
+        pub(super) fn as_array(&self) -> &[f64; N]
+        {
+            self.0.as_array()
+        }
+
+        #[inline]
+        pub(super) fn as_mut_array(&mut self) -> &mut [f64; N]
+        {
+            self.0.as_mut_array()
+        }
+
+        #[inline]
+        pub(super) fn unary(self, op: Op) -> Self
+        {
+            let (one, zero) = (Simd::splat(1.0), Simd::splat(0.0));
+
+            Repr(match op {
+                Op::Neg => -self.0,
+                _ => self.0.simd_eq(zero).select(one, zero),
+            })
+        }
+
+        #[inline]
+        pub(super) fn binary(self, op: Op, rhs: Self) -> Self
+        {
+            let (a, b) = (self.0, rhs.0);
+            let (one, zero) = (Simd::splat(1.0), Simd::splat(0.0));
+            let int = |f: fn(Simd<i64, N>, Simd<i64, N>) -> Simd<i64, N>| {
+                f(a.cast::<i64>(), b.cast::<i64>()).cast::<f64>()
+            };
+
+            Repr(match op {
+                Op::Add => a + b,
+                Op::Sub => a - b,
+                Op::Mul => a * b,
+                Op::Div => a / b,
+                Op::Mod => a % b,
+                Op::Pow => Simd::from_array(std::array::from_fn(|i| Op::Pow.apply(&[a[i], b[i]]))),
+
+                Op::Or => (a.simd_ne(zero) | b.simd_ne(zero)).select(one, zero),
+                Op::And => (a.simd_ne(zero) & b.simd_ne(zero)).select(one, zero),
+
+                Op::Low => a.simd_lt(b).select(one, zero),
+                Op::Great => a.simd_gt(b).select(one, zero),
+                Op::LowEq => a.simd_le(b).select(one, zero),
+                Op::GreatEq => a.simd_ge(b).select(one, zero),
+                Op::Eq => a.simd_eq(b).select(one, zero),
+                Op::NotEq => a.simd_ne(b).select(one, zero),
+
+                Op::BitAnd => int(|a, b| a & b),
+                Op::BitOr => int(|a, b| a | b),
+                Op::BitXor => int(|a, b| a ^ b),
+
+                Op::Shl => int(|a, b| a << b),
+                Op::Shr => int(|a, b| a >> b),
+
+                Op::Neg | Op::Not => return self.unary(op),
+            })
+        }
+
+        #[inline]
+        pub(super) fn select(self, then: Self, other: Self) -> Self
+        {
+            Repr(self.0.simd_ne(Simd::splat(0.0)).select(then.0, other.0))
+        }
+    }
+}
+
+/// Portable lanes as arrays, each operator looping over its rows
+#[cfg(not(feature = "simd"))]
+mod repr
+{
+    use crate::expr::{Op, batch, f64_to_bool};
+
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub(super) struct Repr<const N: usize>([f64; N]);
+
+    impl<const N: usize> Repr<N>
+    {
+        #[inline]
+        pub(super) fn splat(x: f64) -> Self
+        {
+            Repr([x; N])
+        }
+
+        #[inline]
+        pub(super) fn from_array(x: [f64; N]) -> Self
+        {
+            Repr(x)
+        }
+
+        #[inline]
+        pub(super) fn as_array(&self) -> &[f64; N]
+        {
+            &self.0
+        }
+
+        #[inline]
+        pub(super) fn as_mut_array(&mut self) -> &mut [f64; N]
+        {
+            &mut self.0
+        }
+
+        #[inline]
+        pub(super) fn unary(mut self, op: Op) -> Self
+        {
+            batch::unary(op, &mut self.0);
+            self
+        }
+
+        #[inline]
+        pub(super) fn binary(mut self, op: Op, rhs: Self) -> Self
+        {
+            batch::binary(op, &mut self.0, &rhs.0);
+            self
+        }
+
+        #[inline]
+        pub(super) fn select(self, then: Self, other: Self) -> Self
+        {
+            Repr(std::array::from_fn(|i| {
+                if f64_to_bool(self.0[i]) {
+                    then.0[i]
+                } else {
+                    other.0[i]
+                }
+            }))
+        }
+    }
+}
+
+/// Evaluates `len` tokens over the rows of `columns` like [`batch::eval_batch`],
+/// `L::WIDTH` rows at a time, each slot of the stack holding a lane.
+pub(super) fn eval_lanes<'e, 'f, L: Lane>(
+    len: usize,
+    vars: &[GradVar<'_>],
+    columns: &[&[f64]],
+    out: &mut [f64],
+    token: impl FnMut(usize) -> Result<DualToken<'f>, Error<'e>>,
+) -> Result<(), Error<'e>>
+{
+    batch::check_columns(vars.len(), columns, out);
+    let (steps, depth, slots) = batch::program(len, token)?;
+
+    let mut stack = vec![L::splat(0.0); depth];
+    let mut temps = vec![L::splat(0.0); slots];
+    // Scratch buffer for the arguments of the functions without a vectorized version
+    let mut args = Vec::new();
+    let mut masks = batch::Masks::default();
+
+    for start in (0..out.len()).step_by(L::WIDTH) {
+        let n = L::WIDTH.min(out.len() - start);
+        let mut top = 0;
+        masks.clear(n);
+
+        for step in &steps {
+            match *step {
+                Step::Num(num) => {
+                    stack[top] = L::splat(num);
+                    top += 1;
+                }
+                Step::Col(col) => {
+                    stack[top] = L::from_slice(&columns[col][start..start + n]);
+                    top += 1;
+                }
+                Step::Fn(f, argc) => {
+                    let base = top - argc;
+                    stack[base] = call(f, &stack[base..top], masks.rows(), &mut args);
+                    top = base + 1;
+                }
+                Step::Op(op) if op.num_operands() == 1 => stack[top - 1] = stack[top - 1].unary(op),
+                Step::Op(op) => {
+                    stack[top - 2] = stack[top - 2].binary(op, stack[top - 1]);
+                    top -= 1;
+                }
+                Step::Select => {
+                    stack[top - 3] = stack[top - 3].select(stack[top - 2], stack[top - 1]);
+                    top -= 2;
+                }
+                Step::Mask(slot, on) => masks.push(&stack[slot].as_slice()[..n], on),
+                Step::Unmask => masks.pop(),
+                Step::Store(slot) => temps[slot] = stack[top - 1],
+                Step::Load(slot) => {
+                    stack[top] = temps[slot];
+                    top += 1;
+                }
+            }
+        }
+
+        out[start..start + n].copy_from_slice(&stack[0].as_slice()[..n]);
+    }
+
+    Ok(())
+}
+
+/// Calls `f` on the given rows of the lanes of its arguments, the other rows
+/// of the result being zero
+#[inline]
+pub(super) fn call<L: Lane>(f: &ExprFn, lanes: &[L], rows: &[usize], args: &mut Vec<f64>) -> L
+{
+    let mut res = L::splat(0.0);
+
+    match f.vectorized() {
+        Some(_) if rows.is_empty() => {}
+        // The rows, in increasing order, are the first ones of the lanes
+        Some(vectorized) if rows.last() == Some(&(rows.len() - 1)) => {
+            let args: SmallVec<[&[f64]; 4]> = lanes
+                .iter()
+                .map(|lane| &lane.as_slice()[..rows.len()])
+                .collect();
+            vectorized(&args, &mut res.as_mut_slice()[..rows.len()]);
+        }
+        Some(vectorized) => {
+            let gathered: SmallVec<[L; 4]> = lanes
+                .iter()
+                .map(|lane| {
+                    let mut gathered = L::splat(0.0);
+                    for (arg, row) in gathered.as_mut_slice().iter_mut().zip(rows) {
+                        *arg = lane.as_slice()[*row];
+                    }
+                    gathered
+                })
+                .collect();
+            let args: SmallVec<[&[f64]; 4]> = gathered
+                .iter()
+                .map(|lane| &lane.as_slice()[..rows.len()])
+                .collect();
+
+            let mut scattered = L::splat(0.0);
+            vectorized(&args, &mut scattered.as_mut_slice()[..rows.len()]);
+            for (value, row) in scattered.as_slice().iter().zip(rows) {
+                res.as_mut_slice()[*row] = *value;
+            }
+        }
+        None => {
+            for row in rows {
+                args.clear();
+                args.extend(lanes.iter().map(|lane| lane.as_slice()[*row]));
+                res.as_mut_slice()[*row] = f.call(args);
+            }
+        }
+    }
+
+    res
+}
@@ -3,8 +3,8 @@ use std::{borrow::Cow, iter::Peekable, str::CharIndices};
 use smallvec::{SmallVec, smallvec};
 
 use crate::{
-    Error, ParseError,
-    expr::{Op, ParseableToken},
+    Error, ParseError, Span,
+    expr::{Op, ParseableToken, bool_to_f64, f64_to_bool, stack_depth},
     parsing,
     prelude::*,
     resolver::{LockedResolver, ResolverState},
@@ -12,11 +12,39 @@ use crate::{
 
 enum Infix
 {
-    Op(Op),
-    /// Left parenthesis and the number of commas before it
-    LParen(usize),
+    /// Operator and its span
+    Op(Op, Span),
+    /// Left parenthesis, the number of commas before it and its position
+    LParen(usize, usize),
     /// Function and the start and end indices of the function name
     Fn(usize, usize),
+    /// Short-circuit operator and the output index of its jump
+    Short(Op, usize),
+    /// Short-circuit operator whose result is decided by a literal left operand and
+    /// the output length before the discarded right operand
+    Skip(Op, usize),
+    /// Ternary condition and the output index of its conditional jump
+    Cond(usize),
+    /// Ternary condition known at compile time and the output length before the branches
+    ConstCond(bool, usize),
+    /// Ternary else branch and the output index of its unconditional jump
+    Else(usize),
+    /// Ternary else branch that must be discarded when closed, the output length before it
+    /// and the value of the then branch if it is a literal
+    Discard(usize, Option<f64>),
+}
+
+impl Infix
+{
+    /// Returns the operator of the entry, if any
+    #[inline]
+    fn op(&self) -> Option<Op>
+    {
+        match self {
+            Infix::Op(op, _) | Infix::Short(op, _) | Infix::Skip(op, _) => Some(*op),
+            _ => None,
+        }
+    }
 }
 
 struct LexData<'e>
@@ -29,9 +57,44 @@ struct LexBuffers<T>
 {
     f64_cache: SmallVec<[f64; 4]>,
     output: Vec<T>,
+    /// Span of every token in the output
+    spans: Vec<Span>,
     ops: Vec<Infix>,
 }
 
+impl<T> LexBuffers<T>
+{
+    #[inline]
+    fn push(&mut self, token: T, span: Span)
+    {
+        self.output.push(token);
+        self.spans.push(span);
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<Span>
+    {
+        self.output.pop();
+        self.spans.pop()
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize)
+    {
+        self.output.truncate(len);
+        self.spans.truncate(len);
+    }
+
+    /// Returns the span covering all the tokens from `start` to the end of the output
+    #[inline]
+    fn span_from(&self, start: usize) -> Span
+    {
+        self.spans[start..]
+            .iter()
+            .fold(self.spans[start], |acc, span| acc.merge(*span))
+    }
+}
+
 struct Lexer<'e>
 {
     data: LexData<'e>,
@@ -56,12 +119,17 @@ where
         let mut buffers = LexBuffers {
             f64_cache: smallvec![],
             output: Vec::with_capacity(input.len() / 2),
+            spans: Vec::with_capacity(input.len() / 2),
             ops: Vec::new(),
         };
 
-        let tokens = Lexer::new(input).lex(&mut buffers, ctx)?;
+        Lexer::new(input).lex(&mut buffers, ctx)?;
 
-        Ok(Expr { tokens })
+        Ok(Expr {
+            depth: stack_depth(&buffers.output)?,
+            tokens: buffers.output,
+            spans: buffers.spans,
+        })
     }
 }
 
@@ -83,7 +151,7 @@ impl<'e> Lexer<'e>
         mut self,
         buffers: &mut LexBuffers<T>,
         ctx: &'c Context<S, V, F, LV, LF>,
-    ) -> Result<Vec<T>, Error<'e>>
+    ) -> Result<(), Error<'e>>
     where
         S: ResolverState,
         V: Resolver<S, f64>,
@@ -98,21 +166,52 @@ impl<'e> Lexer<'e>
                     // Ignore whitespace
                 }
                 '(' | '[' => {
-                    buffers.ops.push(Infix::LParen(comma_count));
+                    if let State::ExpectingOperator = self.state {
+                        return Err(unexpected(c, i));
+                    }
+
+                    buffers.ops.push(Infix::LParen(comma_count, i));
                 }
                 ')' | ']' => {
-                    while let Some(top) = buffers.ops.pop() {
+                    let span = Span::new(i, i + c.len_utf8());
+
+                    // Only a function call may have nothing before its closing parenthesis
+                    let no_args = match self.state {
+                        State::Default => {
+                            let empty_call =
+                                matches!(
+                                    buffers.ops.as_slice(),
+                                    [.., Infix::Fn(_, _), Infix::LParen(_, _)]
+                                ) && self.data.input[..i].trim_end().ends_with(['(', '[']);
+
+                            if !empty_call {
+                                return Err(Error::ParseError(ParseError::UnexpectedEnd(span)));
+                            }
+
+                            true
+                        }
+                        State::ExpectingOperator => false,
+                    };
+
+                    loop {
+                        let Some(top) = buffers.ops.pop() else {
+                            return Err(Error::ParseError(ParseError::UnmatchedParentheses(span)));
+                        };
+
                         match top {
-                            Infix::LParen(commas) => {
-                                if let Some(Infix::Fn(start, end)) = buffers.ops.last() {
-                                    let fn_token = T::fun(
-                                        &self.data.input[*start..*end],
-                                        comma_count - commas + 1,
-                                        ctx,
-                                    );
+                            Infix::LParen(commas, _) => {
+                                if let Some(&Infix::Fn(start, end)) = buffers.ops.last() {
+                                    let name = &self.data.input[start..end];
+                                    let argc = if no_args { 0 } else { comma_count - commas + 1 };
+
+                                    let span = Span::new(start, span.end);
+
+                                    check_arity(name, argc, span, ctx)?;
+                                    let fn_token = T::fun(name, argc, ctx)
+                                        .ok_or(Error::UnknownFn(Cow::Borrowed(name), span))?;
 
                                     buffers.f64_cache.clear();
-                                    buffers.output.push(fn_token);
+                                    buffers.push(fn_token, span);
 
                                     comma_count = commas;
                                     buffers.ops.pop();
@@ -120,28 +219,66 @@ impl<'e> Lexer<'e>
 
                                 break;
                             }
-                            Infix::Op(op) => pre_evaluate(buffers, op),
+                            Infix::Op(_, _)
+                            | Infix::Short(_, _)
+                            | Infix::Skip(_, _)
+                            | Infix::Else(_)
+                            | Infix::Discard(_, _) => close(buffers, top)?,
+                            Infix::Cond(_) | Infix::ConstCond(_, _) => {
+                                return Err(Error::ParseError(ParseError::UnexpectedEnd(span)));
+                            }
                             Infix::Fn(_, _) => {
-                                panic!("fn token popped while unfolding after rparen")
+                                return Err(Error::InternalInvariant(
+                                    "fn token popped while unfolding after rparen".to_string(),
+                                ));
                             }
                         }
                     }
+
+                    self.state = State::ExpectingOperator;
                 }
                 ',' => {
+                    if let State::Default = self.state {
+                        return Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+                            i,
+                            i + 1,
+                        ))));
+                    }
+
                     comma_count += 1;
                     self.state = State::Default;
 
-                    while let Some(top) = buffers.ops.last() {
-                        match top {
-                            Infix::LParen(_) => break,
-                            Infix::Op(op) => {
-                                let op = *op;
-                                buffers.ops.pop();
-                                pre_evaluate(buffers, op);
+                    loop {
+                        match buffers.ops.last() {
+                            Some(Infix::LParen(_, _)) => {
+                                // Commas are only allowed between the arguments of a function
+                                match buffers.ops.iter().rev().nth(1) {
+                                    Some(Infix::Fn(_, _)) => break,
+                                    _ => return Err(unexpected(c, i)),
+                                }
                             }
-                            Infix::Fn(_, _) => {
-                                panic!("fn token popped while unfolding after comma")
+                            Some(
+                                Infix::Op(_, _)
+                                | Infix::Short(_, _)
+                                | Infix::Skip(_, _)
+                                | Infix::Else(_)
+                                | Infix::Discard(_, _),
+                            ) => {
+                                if let Some(top) = buffers.ops.pop() {
+                                    close(buffers, top)?;
+                                }
+                            }
+                            Some(Infix::Cond(_) | Infix::ConstCond(_, _)) => {
+                                return Err(Error::ParseError(ParseError::UnexpectedEnd(
+                                    Span::new(i, i + 1),
+                                )));
+                            }
+                            Some(Infix::Fn(_, _)) => {
+                                return Err(Error::InternalInvariant(
+                                    "fn token popped while unfolding after comma".to_string(),
+                                ));
                             }
+                            None => return Err(unexpected(c, i)),
                         }
                     }
                 }
@@ -152,13 +289,38 @@ impl<'e> Lexer<'e>
             }
         }
 
-        while let Some(Infix::Op(op)) = buffers.ops.pop() {
-            pre_evaluate(buffers, op);
+        let end = self.data.input.len();
+
+        // The expression can't end while an operand is expected
+        if let State::Default = self.state {
+            return Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+                end, end,
+            ))));
+        }
+
+        while let Some(top) = buffers.ops.pop() {
+            match top {
+                Infix::Op(_, _)
+                | Infix::Short(_, _)
+                | Infix::Skip(_, _)
+                | Infix::Else(_)
+                | Infix::Discard(_, _) => close(buffers, top)?,
+                Infix::Cond(_) | Infix::ConstCond(_, _) => {
+                    return Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+                        end, end,
+                    ))));
+                }
+                Infix::LParen(_, pos) | Infix::Fn(pos, _) => {
+                    return Err(Error::ParseError(ParseError::UnmatchedParentheses(
+                        Span::new(pos, pos + 1),
+                    )));
+                }
+            }
         }
 
         debug_assert!(buffers.ops.is_empty());
 
-        Ok(buffers.output.clone())
+        Ok(())
     }
 }
 
@@ -192,16 +354,18 @@ impl State
     }
 
     #[inline]
-    fn handle_expecting_operator<'e, 'c, T, S: 'c, V: 'c, F: 'c, LV: 'c, LF: 'c>(
+    fn handle_expecting_operator<'e, 'c, T, S, V, F, LV, LF>(
         data: &mut LexData<'e>,
         buffers: &mut LexBuffers<T>,
         i: usize,
         c: char,
     ) -> Result<State, Error<'e>>
     where
-        S: ResolverState,
-        V: Resolver<S, f64>,
-        F: Resolver<S, ExprFn>,
+        S: ResolverState + 'c,
+        V: Resolver<S, f64> + 'c,
+        F: Resolver<S, ExprFn> + 'c,
+        LV: 'c,
+        LF: 'c,
         T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
     {
         let chars = &mut data.chars;
@@ -217,12 +381,7 @@ impl State
             }
         }
 
-        #[inline]
-        fn unexpected(c: char, i: usize) -> Error<'static>
-        {
-            Error::ParseError(ParseError::UnexpectedChar(Cow::Owned(c), i))
-        }
-
+        let start = i;
         let op = match c {
             '+' => Op::Add,
             '-' => Op::Sub,
@@ -237,13 +396,7 @@ impl State
                     Op::Pow
                 }
             }
-            '!' => {
-                if bump_if(chars, '=') {
-                    Op::NotEq
-                } else {
-                    return Err(unexpected(c, i));
-                }
-            }
+            '!' if bump_if(chars, '=') => Op::NotEq,
             '>' => {
                 if bump_if(chars, '=') {
                     Op::GreatEq
@@ -262,12 +415,14 @@ impl State
                     Op::Low
                 }
             }
-            '=' => {
-                if bump_if(chars, '=') {
-                    Op::Eq
-                } else {
-                    return Err(unexpected(c, i));
-                }
+            '=' if bump_if(chars, '=') => Op::Eq,
+            '?' => {
+                process_cond(buffers, Span::new(i, i + 1))?;
+                return Ok(State::Default);
+            }
+            ':' => {
+                process_else(buffers, c, i)?;
+                return Ok(State::Default);
             }
             '&' => {
                 if bump_if(chars, '&') {
@@ -287,7 +442,8 @@ impl State
             _ => return Err(unexpected(c, i)),
         };
 
-        process_operator(buffers, op);
+        let end = chars.peek().map_or(data.input.len(), |&(i, _)| i);
+        process_operator(buffers, op, Span::new(start, end))?;
         Ok(State::Default)
     }
 
@@ -305,21 +461,25 @@ impl State
         F: Resolver<S, ExprFn>,
         T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
     {
-        return match c {
+        match c {
             '-' => {
-                process_operator(buffers, Op::Neg);
+                process_operator(buffers, Op::Neg, Span::new(i, i + 1))?;
                 Ok(State::Default)
             }
             '!' => {
-                process_operator(buffers, Op::Not);
+                process_operator(buffers, Op::Not, Span::new(i, i + 1))?;
                 Ok(State::Default)
             }
             // numbers
             '0'..='9' | '.' => {
-                let num = parsing::parse_uf64(c, &mut data.chars);
+                let num = parsing::parse_number(data.input, i, c, &mut data.chars)
+                    .map_err(Error::ParseError)?;
+                let end = data.chars.peek().map_or(data.input.len(), |&(i, _)| i);
 
-                buffers.output.push(T::f64(num));
-                buffers.f64_cache.push(num);
+                buffers.push(T::f64(num), Span::new(i, end));
+                if T::FOLD {
+                    buffers.f64_cache.push(num);
+                }
 
                 Ok(State::ExpectingOperator)
             }
@@ -348,50 +508,299 @@ impl State
                     }
                 };
 
-                let token = match identifier {
-                    "true" => T::bool(true),
-                    "false" => T::bool(false),
-                    _ => T::var(identifier, ctx),
-                };
+                let span = Span::new(start_index, start_index + identifier.len());
+
+                match identifier {
+                    "true" | "false" => {
+                        let val = identifier == "true";
+
+                        buffers.push(T::bool(val), span);
+                        if T::FOLD {
+                            buffers.f64_cache.push(bool_to_f64(val));
+                        }
+                    }
+                    _ => {
+                        let var_token = T::var(identifier, ctx)
+                            .ok_or(Error::UnknownVar(Cow::Borrowed(identifier), span))?;
+
+                        buffers.push(var_token, span);
+                        buffers.f64_cache.clear();
+                    }
+                }
 
-                buffers.output.push(token);
-                buffers.f64_cache.clear();
                 Ok(State::ExpectingOperator)
             }
 
-            _ => Err(Error::ParseError(ParseError::UnexpectedChar(
-                Cow::Owned(c),
+            // an operand is missing before the else branch
+            ':' => Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
                 i,
-            ))),
-        };
+                i + 1,
+            )))),
+
+            _ => Err(unexpected(c, i)),
+        }
     }
 }
 
 #[inline]
-fn process_operator<'e, 'c, T, S, V, F, LV, LF>(buffers: &mut LexBuffers<T>, op: Op)
+fn unexpected(c: char, i: usize) -> Error<'static>
+{
+    Error::ParseError(ParseError::UnexpectedChar(
+        Cow::Owned(c),
+        Span::new(i, i + c.len_utf8()),
+    ))
+}
+
+#[inline]
+fn process_operator<'e, 'c, T, S, V, F, LV, LF>(
+    buffers: &mut LexBuffers<T>,
+    op: Op,
+    span: Span,
+) -> Result<(), Error<'e>>
 where
     S: ResolverState,
     V: Resolver<S, f64>,
     F: Resolver<S, ExprFn>,
     T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
 {
-    while let Some(Infix::Op(top)) = buffers.ops.last() {
+    // Prefix operators have no left operand, so they can't close pending operators
+    let is_prefix = op.num_operands() == 1;
+
+    while let Some(top) = buffers
+        .ops
+        .last()
+        .and_then(Infix::op)
+        .filter(|_| !is_prefix)
+    {
         let prec = op.precedence();
         let top_prec = top.precedence();
         let should_pop = top_prec > prec || (!op.is_right_associative() && top_prec == prec);
 
-        if should_pop {
-            pre_evaluate(buffers, *top);
-            buffers.ops.pop();
-        } else {
+        if !should_pop {
             break;
         }
+
+        if let Some(top) = buffers.ops.pop() {
+            close(buffers, top)?;
+        }
+    }
+
+    match op {
+        Op::And | Op::Or => process_short_circuit(buffers, op, span),
+        _ => buffers.ops.push(Infix::Op(op, span)),
+    }
+
+    Ok(())
+}
+
+/// Pushes a short-circuit operator after its left operand.
+///
+/// A literal left operand that decides the result discards the right operand,
+/// otherwise a jump over the right operand is emitted and patched once the
+/// operator is closed.
+#[inline]
+fn process_short_circuit<'e, 'c, T, S, V, F, LV, LF>(
+    buffers: &mut LexBuffers<T>,
+    op: Op,
+    span: Span,
+) where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+{
+    let decides = op == Op::Or;
+
+    match buffers.f64_cache.last() {
+        Some(&lhs) if f64_to_bool(lhs) == decides => {
+            let num = bool_to_f64(decides);
+
+            let lhs_span = buffers.pop().unwrap_or(span);
+            buffers.push(T::f64(num), lhs_span.merge(span));
+            buffers.f64_cache.pop();
+            buffers.f64_cache.push(num);
+
+            buffers.ops.push(Infix::Skip(op, buffers.output.len()));
+        }
+        Some(_) => buffers.ops.push(Infix::Op(op, span)),
+        None => {
+            buffers.ops.push(Infix::Short(op, buffers.output.len()));
+            buffers.push(
+                match op {
+                    Op::And => T::jmp_and(0),
+                    _ => T::jmp_or(0),
+                },
+                span,
+            );
+        }
+    }
+}
+
+/// Checks the number of arguments of a function call against the declared
+/// arity of the function, if it can already be resolved.
+#[inline]
+pub(super) fn check_arity<'e, S, V, F, LV, LF>(
+    name: &'e str,
+    argc: usize,
+    span: Span,
+    ctx: &Context<S, V, F, LV, LF>,
+) -> Result<(), Error<'e>>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+{
+    match ctx.get_fn(name) {
+        Some(f) if !f.arity().accepts(argc) => Err(Error::ParseError(ParseError::InvalidArgCount(
+            Cow::Borrowed(name),
+            f.arity(),
+            argc,
+            span,
+        ))),
+        _ => Ok(()),
     }
-    buffers.ops.push(Infix::Op(op));
+}
+
+/// Handles the `?` of a ternary operator.
+///
+/// The condition is folded away when it is a literal, otherwise a conditional
+/// jump is emitted and patched once the `:` is found.
+#[inline]
+fn process_cond<'e, 'c, T, S, V, F, LV, LF>(
+    buffers: &mut LexBuffers<T>,
+    span: Span,
+) -> Result<(), Error<'e>>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+{
+    // The ternary operator has the lowest precedence
+    while buffers.ops.last().and_then(Infix::op).is_some() {
+        if let Some(top) = buffers.ops.pop() {
+            close(buffers, top)?;
+        }
+    }
+
+    if let Some(cond) = buffers.f64_cache.pop() {
+        buffers.pop();
+        buffers
+            .ops
+            .push(Infix::ConstCond(f64_to_bool(cond), buffers.output.len()));
+    } else {
+        buffers.ops.push(Infix::Cond(buffers.output.len()));
+        buffers.push(T::jmp_if_false(0), span);
+    }
+
+    buffers.f64_cache.clear();
+    Ok(())
+}
+
+/// Handles the `:` of a ternary operator at `i`, which is unexpected if there
+/// is no matching `?`.
+#[inline]
+fn process_else<'e, 'c, T, S, V, F, LV, LF>(
+    buffers: &mut LexBuffers<T>,
+    c: char,
+    i: usize,
+) -> Result<(), Error<'e>>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+{
+    let span = Span::new(i, i + c.len_utf8());
+
+    loop {
+        match buffers.ops.pop().ok_or_else(|| unexpected(c, i))? {
+            top @ (Infix::Op(_, _)
+            | Infix::Short(_, _)
+            | Infix::Skip(_, _)
+            | Infix::Else(_)
+            | Infix::Discard(_, _)) => close(buffers, top)?,
+            Infix::Cond(jmp_idx) => {
+                let else_jmp_idx = buffers.output.len();
+                buffers.output[jmp_idx] = T::jmp_if_false(else_jmp_idx - jmp_idx);
+                buffers.push(T::jmp(0), span);
+                buffers.ops.push(Infix::Else(else_jmp_idx));
+                break;
+            }
+            Infix::ConstCond(true, start) => {
+                let literal = match buffers.f64_cache.last() {
+                    Some(&num) if buffers.output.len() == start + 1 => Some(num),
+                    _ => None,
+                };
+                buffers
+                    .ops
+                    .push(Infix::Discard(buffers.output.len(), literal));
+                break;
+            }
+            Infix::ConstCond(false, start) => {
+                buffers.truncate(start);
+                break;
+            }
+            Infix::LParen(_, _) | Infix::Fn(_, _) => return Err(unexpected(c, i)),
+        }
+    }
+
+    buffers.f64_cache.clear();
+    Ok(())
+}
+
+/// Closes an operator or the else branch of a ternary operator popped from
+/// the operators stack.
+#[inline]
+fn close<'e, 'c, T, S, V, F, LV, LF>(
+    buffers: &mut LexBuffers<T>,
+    top: Infix,
+) -> Result<(), Error<'e>>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    T: ParseableToken<'e, 'c, S, V, F, LV, LF>,
+{
+    match top {
+        Infix::Op(op, span) => pre_evaluate(buffers, op, span),
+        Infix::Short(op, jmp_idx) => {
+            buffers.push(T::op(op), buffers.spans[jmp_idx]);
+
+            let offset = buffers.output.len() - jmp_idx - 1;
+            buffers.output[jmp_idx] = match op {
+                Op::And => T::jmp_and(offset),
+                _ => T::jmp_or(offset),
+            };
+            buffers.f64_cache.clear();
+        }
+        Infix::Skip(op, start) => {
+            buffers.spans[start - 1] = buffers.span_from(start - 1);
+            buffers.truncate(start);
+            buffers.f64_cache.clear();
+            buffers.f64_cache.push(bool_to_f64(op == Op::Or));
+        }
+        Infix::Else(jmp_idx) => {
+            buffers.output[jmp_idx] = T::jmp(buffers.output.len() - jmp_idx - 1);
+            buffers.f64_cache.clear();
+        }
+        Infix::Discard(start, literal) => {
+            buffers.truncate(start);
+            buffers.f64_cache.clear();
+            buffers.f64_cache.extend(literal);
+        }
+        Infix::LParen(_, _) | Infix::Fn(_, _) | Infix::Cond(_) | Infix::ConstCond(_, _) => {
+            return Err(Error::InternalInvariant(
+                "only operators and else branches can be closed".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
 }
 
 #[inline]
-fn pre_evaluate<'e, 'c, T, S, V, F, LV, LF>(buffers: &mut LexBuffers<T>, op: Op)
+fn pre_evaluate<'e, 'c, T, S, V, F, LV, LF>(buffers: &mut LexBuffers<T>, op: Op, span: Span)
 where
     S: ResolverState,
     V: Resolver<S, f64>,
@@ -409,15 +818,16 @@ where
         let num = op.apply(args);
 
         let token: T = T::f64(num);
+        let span = buffers.span_from(output_len - n_operands).merge(span);
 
-        buffers.output.truncate(output_len - n_operands);
-        buffers.output.push(token);
+        buffers.truncate(output_len - n_operands);
+        buffers.push(token, span);
 
         buffers.f64_cache.truncate(f64_cache_len - n_operands);
         buffers.f64_cache.push(num);
     } else {
         let token: T = T::op(op);
-        buffers.output.push(token);
+        buffers.push(token, span);
         buffers.f64_cache.clear();
     }
 }
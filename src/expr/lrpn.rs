@@ -1,6 +1,18 @@
+use std::{borrow::Cow, fmt};
+
 use crate::{
-    Error, EvalError, LContext, Ptr,
-    expr::{Op, ParseableToken},
+    Ast, Error, EvalError, LContext, Ptr,
+    expr::{
+        Buffer, EvalStack, LockedRpnResolvers, Op, ParseableToken, Temps, Token,
+        ast::AstToken,
+        batch, cse,
+        dual::{self, DualToken, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        load,
+        simplify::{self, SimplifyMode},
+        store,
+    },
     prelude::*,
     resolver::LockedResolver,
 };
@@ -12,12 +24,23 @@ pub enum LRpn<'a>
     Var(Ptr<'a, f64>),
     Fn(Ptr<'a, ExprFn>, usize),
     Op(Op),
+    /// Jumps forward the given number of tokens
+    Jmp(usize),
+    /// Pops the condition and jumps forward the given number of tokens if it is false
+    JmpIfFalse(usize),
+    /// Short-circuits `&&`, jumping forward the given number of tokens and
+    /// leaving `false` on the stack if the left operand is false
+    JmpAnd(usize),
+    /// Short-circuits `||`, jumping forward the given number of tokens and
+    /// leaving `true` on the stack if the left operand is true
+    JmpOr(usize),
+    /// Copies the value on top of the stack into the given temporary slot
+    Store(usize),
+    /// Pushes the value of the given temporary slot
+    Load(usize),
 }
 
-impl<'a, 'c, V, F> ParseableToken<'a, 'c, Locked, V, F, V, F> for LRpn<'c>
-where
-    V: LockedResolver<f64>,
-    F: LockedResolver<ExprFn>,
+impl Token for LRpn<'_>
 {
     #[inline]
     fn f64(num: f64) -> Self
@@ -43,71 +66,282 @@ where
         LRpn::Op(op)
     }
 
-    // TODO: Return an error manin
     #[inline]
-    fn var(name: &'a str, ctx: &'c LContext<V, F>) -> Self
+    fn jmp(offset: usize) -> Self
+    {
+        LRpn::Jmp(offset)
+    }
+
+    #[inline]
+    fn jmp_if_false(offset: usize) -> Self
+    {
+        LRpn::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        LRpn::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
     {
-        LRpn::Var(ctx.get_var_ptr(name).unwrap())
+        LRpn::JmpOr(offset)
     }
 
     #[inline]
-    fn fun(name: &'a str, argc: usize, ctx: &'c LContext<V, F>) -> Self
+    fn store(slot: usize) -> Self
     {
-        LRpn::Fn(ctx.get_fn_ptr(name).unwrap(), argc)
+        LRpn::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
+    {
+        LRpn::Load(slot)
+    }
+
+    #[inline]
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            LRpn::Num(num) => AstToken::Num(num),
+            LRpn::Var(..) => AstToken::Var(self),
+            LRpn::Fn(.., argc) => AstToken::Fn(self, argc),
+            LRpn::Op(op) => AstToken::Op(op),
+            LRpn::Jmp(offset) => AstToken::Jmp(offset),
+            LRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            LRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            LRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            LRpn::Store(slot) => AstToken::Store(slot),
+            LRpn::Load(slot) => AstToken::Load(slot),
+        }
     }
 }
 
-impl<'e, 'c, V, F> ExprCompiler<'e, 'c, Locked, V, F, V, F, LRpn<'c>> for Expr<LRpn<'c>>
+impl<'a, 'c, V, F> ParseableToken<'a, 'c, Locked, V, F, V, F> for LRpn<'c>
 where
     V: LockedResolver<f64>,
     F: LockedResolver<ExprFn>,
 {
-    fn compile(expr: &'e str, ctx: &'c LContext<V, F>) -> Result<Expr<LRpn<'c>>, Error<'e>>
+    #[inline]
+    fn var(name: &'a str, ctx: &'c LContext<V, F>) -> Option<Self>
     {
-        Expr::try_from((expr, ctx))
+        ctx.get_var_ptr(name).map(LRpn::Var)
+    }
+
+    #[inline]
+    fn fun(name: &'a str, argc: usize, ctx: &'c LContext<V, F>) -> Option<Self>
+    {
+        ctx.get_fn_ptr(name).map(|f| LRpn::Fn(f, argc))
     }
 }
 
-impl<'a, V, F> ExprEvaluator<'a, Locked, V, F, V, F> for Expr<LRpn<'a>>
+impl Expr<LRpn<'_>>
+{
+    /// Rebuilds the syntax tree of the compiled expression, with its literal
+    /// subexpressions already folded.
+    ///
+    /// A locked expression only holds pointers to the resolved items, so its
+    /// variables and functions are named after their address (`@0x...`), see
+    /// [`Expr::display`] for their names.
+    pub fn to_ast(&self) -> Result<Ast<'static>, Error<'static>>
+    {
+        self.ast(address, address)
+    }
+
+    /// Returns the expression printed like its `Display` implementation, with
+    /// the variables and functions named after the names they resolve from in
+    /// `ctx` instead of their address. The items the resolvers can't name,
+    /// such as the value of a [`ConstantResolver`](crate::ConstantResolver),
+    /// keep their address.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, IndexedResolver, LRpn, prelude::*};
+    ///
+    /// let mut vars = IndexedResolver::new();
+    /// vars.add_id('x', 2);
+    /// let ctx = Context::new(vars, DefaultResolver::new_fns()).lock();
+    ///
+    /// let expr: Expr<LRpn> = Expr::compile("x0 + sqrt(x1 * (1 + 1))", &ctx).unwrap();
+    /// assert_eq!(expr.display(&ctx).to_string(), "x0 + sqrt(x1 * 2)");
+    /// ```
+    pub fn display<'s, V, F>(&'s self, ctx: &'s LContext<V, F>) -> impl fmt::Display + 's
+    where
+        V: LockedResolver<f64>,
+        F: LockedResolver<ExprFn>,
+    {
+        Named(self.ast(
+            |ptr| ptr_name(ctx.vars(), ptr),
+            |ptr| ptr_name(ctx.fns(), ptr),
+        ))
+    }
+
+    /// Rebuilds the syntax tree, naming the variables with `var` and the
+    /// functions with `fun`
+    fn ast<'n>(
+        &self,
+        var: impl Fn(Ptr<'_, f64>) -> Cow<'n, str>,
+        fun: impl Fn(Ptr<'_, ExprFn>) -> Cow<'n, str>,
+    ) -> Result<Ast<'n>, Error<'n>>
+    {
+        Ast::from_postfix(self.tokens.iter().map(|tok| match *tok {
+            LRpn::Num(num) => AstToken::Num(num),
+            LRpn::Var(ptr) => AstToken::Var(var(ptr)),
+            LRpn::Fn(ptr, argc) => AstToken::Fn(fun(ptr), argc),
+            LRpn::Op(op) => AstToken::Op(op),
+            LRpn::Jmp(offset) => AstToken::Jmp(offset),
+            LRpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            LRpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            LRpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            LRpn::Store(slot) => AstToken::Store(slot),
+            LRpn::Load(slot) => AstToken::Load(slot),
+        }))
+    }
+}
+
+impl Expr<LRpn<'_>>
+{
+    /// Rewrites the expression into a cheaper equivalent before it is
+    /// evaluated, see [`SimplifyMode`] for the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
+    {
+        simplify::simplify_expr(self, mode);
+    }
+
+    /// Computes the repeated subexpressions once, storing them into temporary
+    /// slots read by their other occurrences. Calls to impure functions
+    /// ([`ExprFn::impure`]) are never merged.
+    pub fn eliminate_common_subexprs(&mut self)
+    {
+        cse::eliminate(self, |tok| match tok {
+            LRpn::Fn(ptr, _) => ptr.as_fn().is_pure(),
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Expr<LRpn<'_>>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.to_ast().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
+    }
+}
+
+/// Syntax tree of a locked expression named with its context, see
+/// [`Expr::display`]
+struct Named<'n>(Result<Ast<'n>, Error<'n>>);
+
+impl fmt::Display for Named<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.0.as_ref().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
+    }
+}
+
+/// Name of an item of a locked context after its address
+pub(super) fn address<T>(ptr: Ptr<'_, T>) -> Cow<'static, str>
+{
+    Cow::Owned(format!("@{ptr:p}"))
+}
+
+/// Name `ptr` was resolved from in `resolver`, or its address if the resolver
+/// can't tell
+pub(super) fn ptr_name<'r, T>(resolver: &'r impl LockedResolver<T>, ptr: Ptr<'_, T>)
+-> Cow<'r, str>
+{
+    resolver.name_of(ptr).unwrap_or_else(|| address(ptr))
+}
+
+impl<'e, 'c, V, F> ExprCompiler<'e, 'c, Locked, V, F, V, F, LRpn<'c>> for Expr<LRpn<'c>>
 where
     V: LockedResolver<f64>,
     F: LockedResolver<ExprFn>,
+    (V, F): LockedRpnResolvers,
 {
-    fn eval(&self, _ctx: &LContext<V, F>, stack: &mut Vec<f64>) -> Result<f64, Error<'a>>
+    fn compile(expr: &'e str, ctx: &'c LContext<V, F>) -> Result<Expr<LRpn<'c>>, Error<'e>>
     {
-        if self.tokens.len() == 1 {
-            if let LRpn::Num(num) = &self.tokens[0] {
-                return Ok(*num);
-            }
+        Expr::try_from((expr, ctx))
+    }
+
+    fn from_ast(ast: &'e Ast<'_>, ctx: &'c LContext<V, F>) -> Result<Expr<LRpn<'c>>, Error<'e>>
+    {
+        Expr::try_from((ast, ctx))
+    }
+}
+
+impl<'a> Expr<LRpn<'a>>
+{
+    /// Evaluates the expression on `stack`, which is left empty if it
+    /// succeeds
+    fn eval_on(&self, stack: &mut impl EvalStack) -> Result<f64, Error<'a>>
+    {
+        if let [LRpn::Num(num)] = self.tokens.as_slice() {
+            return Ok(*num);
         }
 
-        for tok in self.tokens.iter() {
+        let mut temps = Temps::new();
+        let mut pc = 0;
+
+        while let Some(tok) = self.tokens.get(pc) {
+            pc += 1;
+
             match tok {
                 LRpn::Num(num) => stack.push(*num),
                 LRpn::Var(ptr) => stack.push(ptr.get()),
                 LRpn::Fn(ptr, argc) => {
-                    if *argc > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - argc;
                     let args = unsafe { stack.get_unchecked(start..) };
-                    let val = (ptr.get())(args);
+                    let val = ptr.call(args);
 
                     stack.truncate(start);
                     stack.push(val);
                 }
                 LRpn::Op(op) => {
-                    if op.num_operands() > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - op.num_operands();
                     let args = unsafe { stack.get_unchecked(start..) };
                     let res = op.apply(args);
                     stack.truncate(start);
                     stack.push(res);
                 }
+                LRpn::Jmp(offset) => pc += offset,
+                LRpn::JmpIfFalse(offset) => {
+                    let cond = stack
+                        .pop()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(cond) {
+                        pc += offset;
+                    }
+                }
+                LRpn::JmpAnd(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(*lhs) {
+                        *lhs = 0.0;
+                        pc += offset;
+                    }
+                }
+                LRpn::JmpOr(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if f64_to_bool(*lhs) {
+                        *lhs = 1.0;
+                        pc += offset;
+                    }
+                }
+                LRpn::Store(slot) => store(&mut temps, *slot, stack)?,
+                LRpn::Load(slot) => load(&temps, *slot, stack)?,
             }
         }
 
@@ -116,4 +350,98 @@ where
             _ => Err(Error::EvalError(EvalError::MalformedExpression)),
         }
     }
+
+    /// Resolves the token at `pc` for the evaluations over dual numbers and by
+    /// batches, numbering the variables by their position in `ptrs`
+    fn dual_token(&self, ptrs: &[Option<Ptr<'_, f64>>], pc: usize) -> DualToken<'a>
+    {
+        match self.tokens[pc] {
+            LRpn::Num(num) => DualToken::Num(num),
+            LRpn::Var(ptr) => {
+                DualToken::Var(ptr.get(), ptrs.iter().position(|var| *var == Some(ptr)))
+            }
+            LRpn::Fn(ptr, argc) => DualToken::Fn(ptr.as_fn(), argc),
+            LRpn::Op(op) => DualToken::Op(op),
+            LRpn::Jmp(offset) => DualToken::Jmp(offset),
+            LRpn::JmpIfFalse(offset) => DualToken::JmpIfFalse(offset),
+            LRpn::JmpAnd(offset) => DualToken::JmpAnd(offset),
+            LRpn::JmpOr(offset) => DualToken::JmpOr(offset),
+            LRpn::Store(slot) => DualToken::Store(slot),
+            LRpn::Load(slot) => DualToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, V, F> ExprEvaluator<'a, Locked, V, F, V, F> for Expr<LRpn<'a>>
+where
+    V: LockedResolver<f64>,
+    F: LockedResolver<ExprFn>,
+{
+    fn eval(&self, _ctx: &LContext<V, F>, stack: &mut Vec<f64>) -> Result<f64, Error<'a>>
+    {
+        self.eval_on(stack)
+    }
+
+    fn eval_stackless<const N: usize>(&self, _ctx: &LContext<V, F>) -> Result<f64, Error<'a>>
+    {
+        if self.depth <= N {
+            self.eval_on(&mut Buffer::<N>::new())
+        } else {
+            self.eval_on(&mut Vec::with_capacity(self.depth))
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &LContext<V, F>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'a>>
+    {
+        // Locked variables are told apart by their address
+        let ptrs: Vec<_> = wrt.iter().map(|var| ctx.get_var_ptr(&var.name())).collect();
+
+        let token = |pc| Ok(self.dual_token(&ptrs, pc));
+        let fn_name = |pc: usize| match self.tokens[pc] {
+            LRpn::Fn(ptr, _) => Cow::Owned(format!("@{ptr:p}")),
+            _ => Cow::Borrowed(""),
+        };
+
+        dual::eval_dual(self.tokens.len(), wrt, stack, token, fn_name)
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        // Locked variables are told apart by their address
+        let ptrs: Vec<_> = vars
+            .iter()
+            .map(|var| ctx.get_var_ptr(&var.name()))
+            .collect();
+
+        let token = |pc| Ok(self.dual_token(&ptrs, pc));
+        batch::eval_batch(self.tokens.len(), vars, columns, out, token)
+    }
+
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        let ptrs: Vec<_> = vars
+            .iter()
+            .map(|var| ctx.get_var_ptr(&var.name()))
+            .collect();
+
+        let token = |pc| Ok(self.dual_token(&ptrs, pc));
+        lanes::eval_lanes::<L>(self.tokens.len(), vars, columns, out, token)
+    }
 }
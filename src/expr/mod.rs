@@ -1,19 +1,44 @@
+mod cse;
 mod lexer;
+mod tree;
 
+pub mod ast;
+mod batch;
+pub mod codegen;
+pub mod derivative;
+pub mod dual;
 pub mod ifrpn;
 pub mod irpn;
 pub mod ivrpn;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod lanes;
 pub mod lrpn;
+#[cfg(feature = "rayon")]
+pub mod par;
+pub mod reg;
 pub mod rpn;
+pub mod simplify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 use std::borrow::Borrow;
+use std::fmt;
 use std::hash::Hash;
+use std::mem::MaybeUninit;
+use std::ops::RangeFrom;
+
+use smallvec::SmallVec;
 
 use crate::ExprFn;
+use crate::expr::ast::{Ast, AstToken};
+use crate::expr::dual::GradVar;
+use crate::expr::lanes::Lane;
 use crate::prelude::Resolver;
-use crate::resolver::ResolverState;
+use crate::resolver::{Locked, ResolverState};
 use crate::{
-    ConstantResolver, DefaultResolver, EmptyResolver, Error, SmallResolver, context::Context,
+    ConstantResolver, DefaultResolver, EmptyResolver, Error, EvalError, IndexedResolver,
+    SmallResolver, Span, context::Context,
 };
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -120,6 +145,277 @@ impl Op
     }
 }
 
+impl fmt::Display for Op
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let symbol = match self {
+            Op::Add => "+",
+            Op::Sub | Op::Neg => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Pow => "^",
+            Op::Mod => "%",
+
+            Op::Not => "!",
+
+            Op::Or => "||",
+            Op::And => "&&",
+
+            Op::Low => "<",
+            Op::Great => ">",
+            Op::LowEq => "<=",
+            Op::GreatEq => ">=",
+            Op::Eq => "==",
+            Op::NotEq => "!=",
+
+            Op::BitAnd => "&",
+            Op::BitOr => "|",
+            Op::BitXor => "^^",
+
+            Op::Shl => "<<",
+            Op::Shr => ">>",
+        };
+
+        f.write_str(symbol)
+    }
+}
+
+/// Temporary slots written by the `Store` tokens and read by the `Load` tokens
+/// of an expression whose common subexpressions were eliminated
+type Temps = SmallVec<[f64; 4]>;
+
+/// Copies the value on top of `stack` into the temporary `slot`
+#[inline]
+fn store(temps: &mut Temps, slot: usize, stack: &impl EvalStack) -> Result<(), Error<'static>>
+{
+    let val = stack
+        .last()
+        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+    if temps.len() <= slot {
+        temps.resize(slot + 1, 0.0);
+    }
+    temps[slot] = val;
+    Ok(())
+}
+
+/// Pushes the value of the temporary `slot` on `stack`
+#[inline]
+fn load(temps: &Temps, slot: usize, stack: &mut impl EvalStack) -> Result<(), Error<'static>>
+{
+    let val = *temps
+        .get(slot)
+        .ok_or(Error::EvalError(EvalError::MalformedExpression))?;
+
+    stack.push(val);
+    Ok(())
+}
+
+/// Stack a postfix expression is evaluated on.
+///
+/// Compiled expressions are validated along with their maximum stack depth
+/// (see [`stack_depth`]), so evaluators take the operands of operators and
+/// functions without checking the stack holds them.
+trait EvalStack
+{
+    fn len(&self) -> usize;
+    fn push(&mut self, val: f64);
+    fn pop(&mut self) -> Option<f64>;
+    fn last(&self) -> Option<f64>;
+    fn last_mut(&mut self) -> Option<&mut f64>;
+    fn truncate(&mut self, len: usize);
+
+    /// Returns the values from `start` to the top of the stack.
+    ///
+    /// # Safety
+    /// `start` must not be greater than the length of the stack.
+    unsafe fn get_unchecked(&self, start: RangeFrom<usize>) -> &[f64];
+
+    #[inline]
+    fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+}
+
+impl EvalStack for Vec<f64>
+{
+    #[inline]
+    fn len(&self) -> usize
+    {
+        Vec::len(self)
+    }
+
+    #[inline]
+    fn push(&mut self, val: f64)
+    {
+        Vec::push(self, val);
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<f64>
+    {
+        Vec::pop(self)
+    }
+
+    #[inline]
+    fn last(&self) -> Option<f64>
+    {
+        self.as_slice().last().copied()
+    }
+
+    #[inline]
+    fn last_mut(&mut self) -> Option<&mut f64>
+    {
+        self.as_mut_slice().last_mut()
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize)
+    {
+        Vec::truncate(self, len);
+    }
+
+    #[inline]
+    unsafe fn get_unchecked(&self, start: RangeFrom<usize>) -> &[f64]
+    {
+        unsafe { self.as_slice().get_unchecked(start) }
+    }
+}
+
+/// Stack of up to `N` values kept on the call stack, used by
+/// [`ExprEvaluator::eval_stackless`] for the expressions whose maximum stack
+/// depth fits in it
+struct Buffer<const N: usize>
+{
+    vals: [MaybeUninit<f64>; N],
+    len: usize,
+}
+
+impl<const N: usize> Buffer<N>
+{
+    #[inline]
+    fn new() -> Self
+    {
+        Buffer {
+            vals: [const { MaybeUninit::uninit() }; N],
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> EvalStack for Buffer<N>
+{
+    #[inline]
+    fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    #[inline]
+    fn push(&mut self, val: f64)
+    {
+        // The depth of the expression was checked against `N`
+        unsafe { self.vals.get_unchecked_mut(self.len).write(val) };
+        self.len += 1;
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<f64>
+    {
+        self.len = self.len.checked_sub(1)?;
+        Some(unsafe { self.vals[self.len].assume_init() })
+    }
+
+    #[inline]
+    fn last(&self) -> Option<f64>
+    {
+        let top = self.len.checked_sub(1)?;
+        Some(unsafe { self.vals[top].assume_init() })
+    }
+
+    #[inline]
+    fn last_mut(&mut self) -> Option<&mut f64>
+    {
+        let top = self.len.checked_sub(1)?;
+        Some(unsafe { self.vals[top].assume_init_mut() })
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize)
+    {
+        self.len = self.len.min(len);
+    }
+
+    #[inline]
+    unsafe fn get_unchecked(&self, start: RangeFrom<usize>) -> &[f64]
+    {
+        let vals = unsafe { self.vals.get_unchecked(start.start..self.len) };
+        unsafe { &*(vals as *const [MaybeUninit<f64>] as *const [f64]) }
+    }
+}
+
+/// Checks that postfix tokens evaluate to a single value without taking more
+/// operands than the stack holds, whichever branches are taken, and returns
+/// the maximum number of values on the stack.
+fn stack_depth<'e, T: Token>(tokens: &[T]) -> Result<usize, Error<'e>>
+{
+    let malformed = || Error::EvalError(EvalError::MalformedExpression);
+
+    // Depth before each token, `None` right after an unconditional jump
+    let mut depth = Some(0);
+    let mut max = 0;
+    // Token each pending jump lands on, with the depth it lands with
+    let mut targets: Vec<(usize, usize)> = Vec::new();
+
+    for pc in 0..=tokens.len() {
+        for &(_, landing) in targets.iter().filter(|(target, _)| *target == pc) {
+            match depth {
+                Some(depth) if depth != landing => return Err(malformed()),
+                _ => depth = Some(landing),
+            }
+        }
+        targets.retain(|(target, _)| *target != pc);
+
+        let Some(tok) = tokens.get(pc) else {
+            break;
+        };
+        let mut cur = depth.ok_or_else(malformed)?;
+
+        let (pops, pushes) = match tok.postfix() {
+            AstToken::Num(_) | AstToken::Var(_) | AstToken::Load(_) => (0, 1),
+            AstToken::Fn(_, argc) => (argc, 1),
+            AstToken::Op(op) => (op.num_operands(), 1),
+            AstToken::Store(_) => (1, 1),
+            AstToken::Jmp(offset) => {
+                targets.push((pc + 1 + offset, cur));
+                depth = None;
+                continue;
+            }
+            AstToken::JmpIfFalse(offset) => {
+                cur = cur.checked_sub(1).ok_or_else(malformed)?;
+                targets.push((pc + 1 + offset, cur));
+                (0, 0)
+            }
+            AstToken::JmpAnd(offset) | AstToken::JmpOr(offset) => {
+                targets.push((pc + 1 + offset, cur));
+                (1, 1)
+            }
+        };
+
+        cur = cur.checked_sub(pops).ok_or_else(malformed)? + pushes;
+        max = max.max(cur);
+        depth = Some(cur);
+    }
+
+    match depth {
+        _ if tokens.is_empty() => Ok(0),
+        Some(1) if targets.is_empty() => Ok(max),
+        _ => Err(malformed()),
+    }
+}
+
 #[inline]
 fn f64_is_i64(num: f64) -> bool
 {
@@ -145,10 +441,26 @@ fn bool_to_f64(a: bool) -> f64
 /// depending on the context you will be using it with.
 ///
 /// After compilation, the expression can be evaluated using the [`Expr::eval`] method.
+///
+/// Compilation validates the whole expression, so a compiled expression is always
+/// well-formed: unmatched parentheses, missing operands and misplaced commas are
+/// reported as [`ParseError`](crate::ParseError)s instead of failing during evaluation.
+///
+/// # Thread safety
+/// Every `Expr<T>` is `Send` and `Sync`, evaluating it only needs a shared
+/// reference, so an expression can be evaluated by several threads at once
+/// (see `par_eval_batch` with the `rayon` feature).
+/// Locked expressions ([`LRpn`](crate::LRpn), [`Reg`](crate::Reg)) hold
+/// [`Ptr`](crate::Ptr)s to the values of their context, which must not be set
+/// during a parallel evaluation.
 #[derive(Debug, PartialEq)]
 pub struct Expr<Token>
 {
     tokens: Vec<Token>,
+    /// Source span of each token
+    spans: Vec<Span>,
+    /// Maximum number of values on the stack during evaluation
+    depth: usize,
 }
 
 impl<Token> Expr<Token>
@@ -157,6 +469,28 @@ impl<Token> Expr<Token>
     {
         self.tokens.len()
     }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.tokens.is_empty()
+    }
+
+    /// Returns the span of the source expression each token was compiled from.
+    pub fn spans(&self) -> &[Span]
+    {
+        &self.spans
+    }
+
+    /// Returns the maximum number of values the expression keeps on the stack
+    /// while it is evaluated, computed when it is compiled. For register code
+    /// ([`Reg`](crate::Reg)), this is the number of registers.
+    ///
+    /// Expressions whose depth fits in `N` can be evaluated without a stack
+    /// with [`ExprEvaluator::eval_stackless`].
+    pub fn max_stack_depth(&self) -> usize
+    {
+        self.depth
+    }
 }
 
 trait NotIndexedResolver {}
@@ -168,19 +502,44 @@ impl<S: ResolverState, T> NotIndexedResolver for ConstantResolver<S, T> {}
 impl<S: ResolverState, K: AsRef<str> + Eq, T> NotIndexedResolver for SmallResolver<S, K, T> {}
 impl<S: ResolverState> NotIndexedResolver for EmptyResolver<S> {}
 
+/// Locked variable and function resolvers compiled to [`LRpn`](lrpn::LRpn)
+/// tokens, every pair but indexed variables with indexed functions, which
+/// compile to [`Reg`](reg::Reg) instructions
+trait LockedRpnResolvers {}
+impl<V: NotIndexedResolver, F> LockedRpnResolvers for (V, F) {}
+impl<F: NotIndexedResolver> LockedRpnResolvers for (IndexedResolver<Locked, f64>, F) {}
+
+/// Token of a postfix expression, whatever its variables and functions resolve to
 #[allow(unused)]
-trait ParseableToken<'a, 'c, S, V, F, LV, LF>
-where
-    S: ResolverState,
-    V: Resolver<S, f64>,
-    F: Resolver<S, ExprFn>,
+trait Token: Copy
 {
     fn f64(num: f64) -> Self;
     fn i64(num: i64) -> Self;
     fn bool(val: bool) -> Self;
     fn op(op: Op) -> Self;
-    fn var(name: &'a str, ctx: &'c Context<S, V, F, LV, LF>) -> Self;
-    fn fun(name: &'a str, argc: usize, ctx: &'c Context<S, V, F, LV, LF>) -> Self;
+    fn jmp(offset: usize) -> Self;
+    fn jmp_if_false(offset: usize) -> Self;
+    fn jmp_and(offset: usize) -> Self;
+    fn jmp_or(offset: usize) -> Self;
+    fn store(slot: usize) -> Self;
+    fn load(slot: usize) -> Self;
+    /// Returns the structure of the token, variables and functions are kept as is
+    fn postfix(self) -> AstToken<Self>;
+}
+
+trait ParseableToken<'a, 'c, S, V, F, LV, LF>: Token
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+{
+    /// Whether literal subexpressions are folded while lexing
+    const FOLD: bool = true;
+
+    /// Returns `None` if the variable can't be resolved at compile time
+    fn var(name: &'a str, ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>;
+    /// Returns `None` if the function can't be resolved at compile time
+    fn fun(name: &'a str, argc: usize, ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>;
 }
 
 pub trait ExprCompiler<'e, 'c, S, V, F, LV, LF, T>
@@ -190,6 +549,12 @@ where
     F: Resolver<S, ExprFn>,
 {
     fn compile(expr: &'e str, ctx: &'c Context<S, V, F, LV, LF>) -> Result<Expr<T>, Error<'e>>;
+
+    /// Lowers an [`Ast`](crate::Ast) into the expression matching the context,
+    /// folding its literal subexpressions.
+    ///
+    /// Tokens lowered from an `Ast` have no source span, so errors point at `0..0`.
+    fn from_ast(ast: &'e Ast<'_>, ctx: &'c Context<S, V, F, LV, LF>) -> Result<Expr<T>, Error<'e>>;
 }
 
 pub trait ExprEvaluator<'e, S, V, F, LV, LF>
@@ -199,4 +564,148 @@ where
     F: Resolver<S, ExprFn>,
 {
     fn eval(&self, ctx: &Context<S, V, F, LV, LF>, stack: &mut Vec<f64>) -> Result<f64, Error<'e>>;
+
+    /// Evaluates the expression on a buffer of `N` values kept on the call
+    /// stack instead of a caller-provided stack. Expressions deeper than `N`
+    /// (see [`Expr::max_stack_depth`]) are evaluated on a heap-allocated
+    /// stack sized at once.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, prelude::*};
+    ///
+    /// let mut vars = DefaultResolver::empty();
+    /// vars.insert("x".to_string(), 3.0);
+    /// let ctx = Context::new(vars, DefaultResolver::new_fns());
+    ///
+    /// let expr = Expr::compile("(x + 1) * (x - 1)", &ctx).unwrap();
+    /// assert_eq!(expr.max_stack_depth(), 3);
+    /// assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(8.0));
+    /// ```
+    fn eval_stackless<const N: usize>(
+        &self,
+        ctx: &Context<S, V, F, LV, LF>,
+    ) -> Result<f64, Error<'e>>
+    {
+        self.eval(ctx, &mut Vec::new())
+    }
+
+    /// Evaluates the expression over dual numbers, returning its value and its
+    /// partial derivatives with respect to each variable of `wrt`.
+    ///
+    /// The derivatives are computed in a single pass (forward-mode automatic
+    /// differentiation). Functions whose arguments depend on the variables
+    /// need a derivative companion ([`ExprFn::with_derivative`]), otherwise
+    /// [`Error::UnknownDerivative`] is returned.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, GradVar, prelude::*};
+    ///
+    /// let mut vars = DefaultResolver::empty();
+    /// vars.insert("x".to_string(), 3.0);
+    /// vars.insert("y".to_string(), 2.0);
+    /// let ctx = Context::new(vars, DefaultResolver::new_fns());
+    ///
+    /// let expr = Expr::compile("x ^ 2 * y + sqrt(y * 8)", &ctx).unwrap();
+    /// let wrt = [GradVar::Name("x"), GradVar::Name("y")];
+    ///
+    /// let (val, grad) = expr.eval_with_gradient(&ctx, &wrt, &mut Vec::new()).unwrap();
+    /// assert_eq!(val, 22.0);
+    /// assert_eq!(grad, vec![12.0, 10.0]);
+    /// ```
+    fn eval_with_gradient(
+        &self,
+        ctx: &Context<S, V, F, LV, LF>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'e>>;
+
+    /// Evaluates the expression once per row of `columns`, writing the result
+    /// of each row in `out`.
+    ///
+    /// `columns[k]` holds the values of the variable `vars[k]`, one per row,
+    /// the other variables keep their value in the context. Rows are evaluated
+    /// by blocks, walking the tokens once per block and applying each operator
+    /// to the whole block. The operators of both branches of the ternaries and
+    /// of both operands of `&&` and `||` are applied to every row of a block,
+    /// keeping the value of the taken branch, but functions are only called on
+    /// the rows taking the branch they are in, as many times as by
+    /// [`ExprEvaluator::eval`]. Functions with a vectorized companion
+    /// ([`ExprFn::with_vectorized`]) are called once per block and branch, on
+    /// the arguments of these rows.
+    ///
+    /// Register code is evaluated row by row instead, reading the variables of
+    /// `vars` from their column. Native code is also evaluated row by row,
+    /// reading the variables from a buffer holding the values of the row. The
+    /// context is never written to.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `columns` and `vars` have different lengths or if a column is
+    /// shorter than `out`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, GradVar, prelude::*};
+    ///
+    /// let mut vars = DefaultResolver::empty();
+    /// vars.insert("x".to_string(), 0.0);
+    /// vars.insert("y".to_string(), 10.0);
+    /// let ctx = Context::new(vars, DefaultResolver::new_fns());
+    ///
+    /// let expr = Expr::compile("x > 1 ? x * y : -x", &ctx).unwrap();
+    /// let x = [0.5, 1.5, 2.0];
+    /// let mut out = [0.0; 3];
+    ///
+    /// expr.eval_batch(&ctx, &[GradVar::Name("x")], &[&x], &mut out).unwrap();
+    /// assert_eq!(out, [-0.5, 15.0, 20.0]);
+    /// ```
+    fn eval_batch(
+        &self,
+        ctx: &Context<S, V, F, LV, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>;
+
+    /// Evaluates the expression once per row of `columns` like
+    /// [`ExprEvaluator::eval_batch`], a lane of rows at a time: each slot of
+    /// the stack holds the values of 4 ([`F64x4`](crate::F64x4)) or 8
+    /// ([`F64x8`](crate::F64x8)) rows, and every operator, comparisons, bitwise
+    /// operators and `^` included, is applied to all of them at once.
+    ///
+    /// With the `simd` feature, which needs a nightly compiler, lanes are
+    /// `std::simd` vectors, otherwise they are arrays looped over.
+    ///
+    /// Register code runs its instructions on registers holding a lane each,
+    /// the rows that jump over an instruction being masked out of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `columns` and `vars` have different lengths or if a column is
+    /// shorter than `out`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, F64x4, GradVar, prelude::*};
+    ///
+    /// let mut vars = DefaultResolver::empty();
+    /// vars.insert("x".to_string(), 0.0);
+    /// let ctx = Context::new(vars, DefaultResolver::new_fns());
+    ///
+    /// let expr = Expr::compile("(x > 1) + (x * 4 & 6) + x ^ 2", &ctx).unwrap();
+    /// let x = [0.5, 1.5, 2.0, -1.0, 3.0];
+    /// let mut out = [0.0; 5];
+    ///
+    /// expr.eval_lanes::<F64x4>(&ctx, &[GradVar::Name("x")], &[&x], &mut out).unwrap();
+    /// assert_eq!(out, [2.25, 9.25, 5.0, 5.0, 14.0]);
+    /// ```
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &Context<S, V, F, LV, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>;
 }
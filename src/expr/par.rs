@@ -0,0 +1,96 @@
+use rayon::prelude::*;
+use smallvec::SmallVec;
+
+use crate::{
+    Error, ExprFn,
+    context::Context,
+    expr::{ExprEvaluator, batch, dual::GradVar},
+    resolver::{Resolver, ResolverState},
+};
+
+/// Number of rows of the ranges evaluated by each task, a multiple of the
+/// blocks of the batch evaluation
+const ROWS: usize = 16 * batch::BLOCK;
+
+/// Evaluates each expression of `exprs` once per row of `columns` on the
+/// threads of the current rayon pool, writing its results in the output of the
+/// same position in `outs`.
+///
+/// This is a parallel [`ExprEvaluator::eval_batch`]: the rows of every output
+/// are partitioned into ranges, and each range of each expression is evaluated
+/// by its own task on its own stack, so that all the cores are kept busy with a
+/// few expressions over many rows as well as with many expressions over a few
+/// rows. The expressions and the context are only shared.
+///
+/// Variables must not be set while `par_eval_batch` runs: the tasks read them
+/// without synchronization, so a [`Ptr::set`](crate::Ptr::set) from another
+/// thread, or from a function called by an expression, is a data race.
+///
+/// If several evaluations fail, one of their errors is returned and the
+/// content of `outs` is unspecified.
+///
+/// # Panics
+///
+/// Panics if `exprs` and `outs` have different lengths, if `columns` and
+/// `vars` have different lengths or if a column is shorter than an output.
+///
+/// # Examples
+/// ```rust
+/// use fee::{DefaultResolver, GradVar, par_eval_batch, prelude::*};
+///
+/// let mut vars = DefaultResolver::empty();
+/// vars.insert("x".to_string(), 0.0);
+/// vars.insert("y".to_string(), 10.0);
+/// let ctx = Context::new(vars, DefaultResolver::new_fns());
+///
+/// let exprs = ["x + y", "x * x", "x > 1 ? y : -y"].map(|src| Expr::compile(src, &ctx).unwrap());
+/// let x: Vec<f64> = (0..10_000).map(|i| i as f64 / 1000.0).collect();
+/// let mut outs = [vec![0.0; 10_000], vec![0.0; 10_000], vec![0.0; 10_000]];
+///
+/// let mut out_refs: Vec<&mut [f64]> = outs.iter_mut().map(Vec::as_mut_slice).collect();
+/// par_eval_batch(&exprs, &ctx, &[GradVar::Name("x")], &[&x], &mut out_refs).unwrap();
+///
+/// assert_eq!(outs[0][2000], 12.0);
+/// assert_eq!(outs[1][3000], 9.0);
+/// assert_eq!([outs[2][1000], outs[2][1001]], [-10.0, 10.0]);
+/// ```
+pub fn par_eval_batch<'e, E, S, V, F, LV, LF>(
+    exprs: &[E],
+    ctx: &Context<S, V, F, LV, LF>,
+    vars: &[GradVar<'_>],
+    columns: &[&[f64]],
+    outs: &mut [&mut [f64]],
+) -> Result<(), Error<'e>>
+where
+    E: ExprEvaluator<'e, S, V, F, LV, LF> + Sync,
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+    Context<S, V, F, LV, LF>: Sync,
+{
+    assert_eq!(
+        exprs.len(),
+        outs.len(),
+        "one output is needed per expression"
+    );
+    // Checked before spawning any task, so that nothing is evaluated on failure
+    for out in outs.iter() {
+        batch::check_columns(vars.len(), columns, out);
+    }
+
+    exprs
+        .par_iter()
+        .zip(outs.par_iter_mut())
+        .try_for_each(|(expr, out)| {
+            out.par_chunks_mut(ROWS)
+                .enumerate()
+                .try_for_each(|(i, out)| {
+                    let start = i * ROWS;
+                    let columns: SmallVec<[&[f64]; 8]> = columns
+                        .iter()
+                        .map(|col| &col[start..start + out.len()])
+                        .collect();
+                    expr.eval_batch(ctx, vars, &columns, out)
+                })
+        })
+}
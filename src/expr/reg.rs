@@ -0,0 +1,850 @@
+use std::{borrow::Cow, fmt};
+
+use crate::{
+    Ast, Error, EvalError, IndexedResolver, LContext, Ptr, Span,
+    expr::{
+        Op, batch,
+        dual::{self, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        lrpn::{self, LRpn},
+    },
+    prelude::*,
+    resolver::LockedResolver,
+};
+
+/// Operand of a [`Reg`] instruction
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Operand<'a>
+{
+    Num(f64),
+    Var(Ptr<'a, f64>),
+    /// Register of the given index
+    Reg(usize),
+}
+
+/// Instruction of an expression compiled to register code.
+///
+/// Instead of going through a stack, each instruction reads its operands in
+/// place, be they constants, variables or registers, and writes its result to
+/// a register (`dst = lhs op rhs`). A locked context with indexed variables
+/// and functions compiles to register code, any other locked expression can
+/// be lowered into it with `Expr::<Reg>::try_from`.
+///
+/// # Examples
+/// ```rust
+/// use fee::{DefaultResolver, LRpn, Reg, SimplifyMode, prelude::*};
+///
+/// let mut vars = DefaultResolver::empty();
+/// vars.insert("x".to_string(), 3.0);
+/// let ctx = Context::new(vars, DefaultResolver::new_fns()).lock();
+///
+/// let mut rpn = Expr::<LRpn>::try_from(("(x * 1 + 1) * (x + 1)", &ctx)).unwrap();
+/// rpn.simplify(SimplifyMode::IeeeSafe);
+/// rpn.eliminate_common_subexprs();
+///
+/// let expr = Expr::<Reg>::try_from(&rpn).unwrap();
+/// assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(16.0));
+/// ```
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Reg<'a>
+{
+    /// `dst = src`
+    Mov(usize, Operand<'a>),
+    /// `dst = op src`
+    Unary(Op, usize, Operand<'a>),
+    /// `dst = lhs op rhs`
+    Binary(Op, usize, Operand<'a>, Operand<'a>),
+    /// Calls the function with the given number of registers starting at `dst`
+    /// as arguments, writing its result to `dst`
+    Call(Ptr<'a, ExprFn>, usize, usize),
+    /// Jumps forward the given number of instructions
+    Jmp(usize),
+    /// Jumps forward the given number of instructions if the operand is false
+    JmpIfFalse(Operand<'a>, usize),
+    /// Short-circuits `&&`, writing `false` to the register and jumping forward
+    /// the given number of instructions if the operand is false
+    JmpAnd(Operand<'a>, usize, usize),
+    /// Short-circuits `||`, writing `true` to the register and jumping forward
+    /// the given number of instructions if the operand is true
+    JmpOr(Operand<'a>, usize, usize),
+    /// Ends the evaluation with the value of the operand
+    Ret(Operand<'a>),
+}
+
+impl<'a> TryFrom<&Expr<LRpn<'a>>> for Expr<Reg<'a>>
+{
+    type Error = Error<'static>;
+
+    /// Lowers a locked postfix expression into register code, keeping the
+    /// temporary slots of its common subexpressions in the first registers.
+    fn try_from(expr: &Expr<LRpn<'a>>) -> Result<Self, Self::Error>
+    {
+        let temps = expr
+            .tokens
+            .iter()
+            .filter_map(|tok| match tok {
+                LRpn::Store(slot) | LRpn::Load(slot) => Some(slot + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut lowering = Lowering {
+            temps,
+            regs: temps,
+            stack: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+            jumps: Vec::new(),
+        };
+        let mut last = Span::default();
+
+        for (pc, (tok, span)) in expr.tokens.iter().zip(&expr.spans).enumerate() {
+            lowering.land(pc, last)?;
+            lowering.lower(*tok, pc, *span)?;
+            last = *span;
+        }
+        lowering.land(expr.tokens.len(), last)?;
+
+        let result = lowering.pop()?;
+        if !lowering.stack.is_empty() || !lowering.jumps.is_empty() {
+            return Err(Error::EvalError(EvalError::MalformedExpression));
+        }
+
+        lowering.emit(Reg::Ret(result), last);
+
+        Ok(Expr {
+            tokens: lowering.code,
+            spans: lowering.spans,
+            depth: lowering.regs,
+        })
+    }
+}
+
+/// Translates postfix tokens into register code by tracking the operand held
+/// by each position of the stack they would use. A position is only copied
+/// into its register when an instruction needs it there, so constants and
+/// variables are read in place.
+struct Lowering<'a>
+{
+    /// Registers below `temps` hold the temporary slots, the stack position
+    /// `pos` is held by the register `temps + pos`
+    temps: usize,
+    /// Number of registers used so far
+    regs: usize,
+    stack: Vec<Operand<'a>>,
+    code: Vec<Reg<'a>>,
+    spans: Vec<Span>,
+    /// Token targeted by each pending jump, the instruction of the jump and
+    /// whether branches merge their value at the target
+    jumps: Vec<(usize, usize, bool)>,
+}
+
+impl<'a> Lowering<'a>
+{
+    fn lower(&mut self, tok: LRpn<'a>, pc: usize, span: Span) -> Result<(), Error<'static>>
+    {
+        match tok {
+            LRpn::Num(num) => self.stack.push(Operand::Num(num)),
+            LRpn::Var(ptr) => self.stack.push(Operand::Var(ptr)),
+            LRpn::Fn(ptr, argc) => {
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(argc)
+                    .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                for pos in start..self.stack.len() {
+                    self.force(pos, span);
+                }
+                self.stack.truncate(start);
+
+                let dst = self.define();
+                self.emit(Reg::Call(ptr, dst, argc), span);
+            }
+            LRpn::Op(op) if op.num_operands() == 1 => {
+                let src = self.pop()?;
+                let dst = self.define();
+                self.emit(Reg::Unary(op, dst, src), span);
+            }
+            LRpn::Op(op) => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let dst = self.define();
+                self.emit(Reg::Binary(op, dst, lhs, rhs), span);
+            }
+            LRpn::Jmp(offset) => {
+                // The value of the first branch moves to where the other one ends
+                let top = self.top()?;
+                self.force(top, span);
+                self.stack.pop();
+                self.jump(Reg::Jmp(0), pc + 1 + offset, true, span);
+            }
+            LRpn::JmpIfFalse(offset) => {
+                let cond = self.pop()?;
+                self.jump(Reg::JmpIfFalse(cond, 0), pc + 1 + offset, false, span);
+            }
+            LRpn::JmpAnd(offset) | LRpn::JmpOr(offset) => {
+                let top = self.top()?;
+                let (lhs, dst) = (self.stack[top], self.reg(top));
+                let jmp = match tok {
+                    LRpn::JmpAnd(_) => Reg::JmpAnd(lhs, dst, 0),
+                    _ => Reg::JmpOr(lhs, dst, 0),
+                };
+                self.jump(jmp, pc + 1 + offset, true, span);
+            }
+            // Slots are stored once, before any of their loads
+            LRpn::Store(slot) => {
+                let top = self.top()?;
+                self.emit(Reg::Mov(slot, self.stack[top]), span);
+            }
+            LRpn::Load(slot) => self.stack.push(Operand::Reg(slot)),
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the jumps targeting the token `pc`, the value branches merge
+    /// there is moved to its register first.
+    fn land(&mut self, pc: usize, span: Span) -> Result<(), Error<'static>>
+    {
+        if self
+            .jumps
+            .iter()
+            .any(|&(target, _, merges)| target == pc && merges)
+        {
+            let top = self.top()?;
+            self.force(top, span);
+        }
+
+        let here = self.code.len();
+        let code = &mut self.code;
+
+        self.jumps.retain(|&(target, at, _)| {
+            if target != pc {
+                return true;
+            }
+
+            if let Reg::Jmp(offset)
+            | Reg::JmpIfFalse(_, offset)
+            | Reg::JmpAnd(_, _, offset)
+            | Reg::JmpOr(_, _, offset) = &mut code[at]
+            {
+                *offset = here - at - 1;
+            }
+            false
+        });
+
+        Ok(())
+    }
+
+    fn jump(&mut self, jmp: Reg<'a>, target: usize, merges: bool, span: Span)
+    {
+        self.jumps.push((target, self.code.len(), merges));
+        self.emit(jmp, span);
+    }
+
+    /// Moves the operand of the stack position `pos` to its register
+    fn force(&mut self, pos: usize, span: Span)
+    {
+        let reg = self.reg(pos);
+
+        if self.stack[pos] != Operand::Reg(reg) {
+            self.emit(Reg::Mov(reg, self.stack[pos]), span);
+            self.stack[pos] = Operand::Reg(reg);
+        }
+    }
+
+    /// Pushes the result of an instruction, returning its register
+    fn define(&mut self) -> usize
+    {
+        let reg = self.reg(self.stack.len());
+        self.stack.push(Operand::Reg(reg));
+        reg
+    }
+
+    fn reg(&mut self, pos: usize) -> usize
+    {
+        let reg = self.temps + pos;
+        self.regs = self.regs.max(reg + 1);
+        reg
+    }
+
+    fn top(&self) -> Result<usize, Error<'static>>
+    {
+        self.stack
+            .len()
+            .checked_sub(1)
+            .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))
+    }
+
+    fn pop(&mut self) -> Result<Operand<'a>, Error<'static>>
+    {
+        self.stack
+            .pop()
+            .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))
+    }
+
+    fn emit(&mut self, instr: Reg<'a>, span: Span)
+    {
+        self.code.push(instr);
+        self.spans.push(span);
+    }
+}
+
+/// Reads an operand from the register file, or a variable with `var`
+#[inline(always)]
+fn read<'a>(regs: &[f64], operand: Operand<'a>, var: &impl Fn(Ptr<'a, f64>) -> f64) -> f64
+{
+    match operand {
+        Operand::Num(num) => num,
+        Operand::Var(ptr) => var(ptr),
+        // Lowering keeps every register below the depth of the expression
+        Operand::Reg(reg) => unsafe { *regs.get_unchecked(reg) },
+    }
+}
+
+#[inline(always)]
+fn write(regs: &mut [f64], reg: usize, val: f64)
+{
+    unsafe {
+        *regs.get_unchecked_mut(reg) = val;
+    }
+}
+
+/// Names of the variables and functions of an expression when it is printed
+struct Names<V, F>
+{
+    var: V,
+    fun: F,
+}
+
+impl<'n, V, F> Names<V, F>
+where
+    V: Fn(Ptr<'_, f64>) -> Cow<'n, str>,
+    F: Fn(Ptr<'_, ExprFn>) -> Cow<'n, str>,
+{
+    fn new(var: V, fun: F) -> Self
+    {
+        Names { var, fun }
+    }
+
+    fn operand(&self, operand: Operand<'_>) -> Cow<'n, str>
+    {
+        match operand {
+            Operand::Num(num) => Cow::Owned(num.to_string()),
+            Operand::Var(ptr) => (self.var)(ptr),
+            Operand::Reg(reg) => Cow::Owned(format!("r{reg}")),
+        }
+    }
+
+    fn instr(&self, f: &mut fmt::Formatter<'_>, instr: &Reg<'_>) -> fmt::Result
+    {
+        let operand = |operand| self.operand(operand);
+
+        match *instr {
+            Reg::Mov(dst, src) => write!(f, "r{dst} = {}", operand(src)),
+            Reg::Unary(op, dst, src) => write!(f, "r{dst} = {op}{}", operand(src)),
+            Reg::Binary(op, dst, lhs, rhs) => {
+                write!(f, "r{dst} = {} {op} {}", operand(lhs), operand(rhs))
+            }
+            Reg::Call(ptr, dst, argc) => {
+                write!(f, "r{dst} = {}(", (self.fun)(ptr))?;
+                for reg in dst..dst + argc {
+                    if reg > dst {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "r{reg}")?;
+                }
+                f.write_str(")")
+            }
+            Reg::Jmp(offset) => write!(f, "jmp +{offset}"),
+            Reg::JmpIfFalse(cond, offset) => write!(f, "if !{} jmp +{offset}", operand(cond)),
+            Reg::JmpAnd(lhs, dst, offset) => {
+                write!(f, "if !{} r{dst} = 0, jmp +{offset}", operand(lhs))
+            }
+            Reg::JmpOr(lhs, dst, offset) => {
+                write!(f, "if {} r{dst} = 1, jmp +{offset}", operand(lhs))
+            }
+            Reg::Ret(src) => write!(f, "ret {}", operand(src)),
+        }
+    }
+
+    fn instrs(&self, f: &mut fmt::Formatter<'_>, instrs: &[Reg<'_>]) -> fmt::Result
+    {
+        for (i, instr) in instrs.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            self.instr(f, instr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of an item after its address (`@0x...`)
+type Address<T> = for<'p> fn(Ptr<'p, T>) -> Cow<'static, str>;
+
+/// Names the variables and functions after their address
+const ADDRESSES: Names<Address<f64>, Address<ExprFn>> = Names {
+    var: lrpn::address,
+    fun: lrpn::address,
+};
+
+impl fmt::Display for Operand<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str(&ADDRESSES.operand(*self))
+    }
+}
+
+impl fmt::Display for Reg<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        ADDRESSES.instr(f, self)
+    }
+}
+
+/// Lists the instructions of the expression, one per line, see
+/// [`Expr::display`] for the names of its variables and functions.
+impl fmt::Display for Expr<Reg<'_>>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        ADDRESSES.instrs(f, &self.tokens)
+    }
+}
+
+/// Instructions of a register expression named with its context, see
+/// [`Expr::display`]
+struct Named<'s, 'a, V, F>
+where
+    V: LockedResolver<f64>,
+    F: LockedResolver<ExprFn>,
+{
+    expr: &'s Expr<Reg<'a>>,
+    ctx: &'s LContext<V, F>,
+}
+
+impl<V, F> fmt::Display for Named<'_, '_, V, F>
+where
+    V: LockedResolver<f64>,
+    F: LockedResolver<ExprFn>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let names = Names::new(
+            |ptr| lrpn::ptr_name(self.ctx.vars(), ptr),
+            |ptr| lrpn::ptr_name(self.ctx.fns(), ptr),
+        );
+        names.instrs(f, &self.expr.tokens)
+    }
+}
+
+impl<'e, 'c>
+    ExprCompiler<
+        'e,
+        'c,
+        Locked,
+        IndexedResolver<Locked, f64>,
+        IndexedResolver<Locked, ExprFn>,
+        IndexedResolver<Locked, f64>,
+        IndexedResolver<Locked, ExprFn>,
+        Reg<'c>,
+    > for Expr<Reg<'c>>
+{
+    fn compile(
+        expr: &'e str,
+        ctx: &'c LContext<IndexedResolver<Locked, f64>, IndexedResolver<Locked, ExprFn>>,
+    ) -> Result<Expr<Reg<'c>>, Error<'e>>
+    {
+        let rpn = Expr::<LRpn<'c>>::try_from((expr, ctx))?;
+        Expr::try_from(&rpn)
+    }
+
+    fn from_ast(
+        ast: &'e Ast<'_>,
+        ctx: &'c LContext<IndexedResolver<Locked, f64>, IndexedResolver<Locked, ExprFn>>,
+    ) -> Result<Expr<Reg<'c>>, Error<'e>>
+    {
+        let rpn = Expr::<LRpn<'c>>::try_from((ast, ctx))?;
+        Expr::try_from(&rpn)
+    }
+}
+
+impl<'a> Expr<Reg<'a>>
+{
+    /// Returns the instructions listed like the `Display` implementation, with
+    /// the variables and functions named after the names they resolve from in
+    /// `ctx` instead of their address.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{IndexedResolver, prelude::*};
+    ///
+    /// let mut vars = IndexedResolver::new();
+    /// vars.add_id('x', 2);
+    /// let mut fns = IndexedResolver::new();
+    /// fns.add_id('f', 1);
+    /// let ctx = Context::new(vars, fns).lock();
+    ///
+    /// let expr = Expr::compile("x0 + f0(x1)", &ctx).unwrap();
+    /// assert_eq!(expr.display(&ctx).to_string(), "r1 = x1\nr1 = f0(r1)\nr0 = x0 + r1\nret r0");
+    /// ```
+    pub fn display<'s, V, F>(&'s self, ctx: &'s LContext<V, F>) -> impl fmt::Display + 's
+    where
+        V: LockedResolver<f64>,
+        F: LockedResolver<ExprFn>,
+    {
+        Named { expr: self, ctx }
+    }
+
+    /// Runs the instructions on a register file holding at least as many
+    /// registers as the depth of the expression
+    fn run(&self, regs: &mut [f64]) -> Result<f64, Error<'a>>
+    {
+        self.run_with(regs, |ptr| ptr.get())
+    }
+
+    /// Runs the instructions like [`Expr::run`], reading the variables with `var`
+    #[inline(always)]
+    fn run_with(
+        &self,
+        regs: &mut [f64],
+        var: impl Fn(Ptr<'a, f64>) -> f64,
+    ) -> Result<f64, Error<'a>>
+    {
+        if let [Reg::Ret(Operand::Num(num))] = self.tokens.as_slice() {
+            return Ok(*num);
+        }
+
+        let mut pc = 0;
+
+        while let Some(instr) = self.tokens.get(pc) {
+            pc += 1;
+
+            match *instr {
+                Reg::Mov(dst, src) => {
+                    let val = read(regs, src, &var);
+                    write(regs, dst, val);
+                }
+                Reg::Unary(op, dst, src) => {
+                    let res = op.apply(&[read(regs, src, &var)]);
+                    write(regs, dst, res);
+                }
+                Reg::Binary(op, dst, lhs, rhs) => {
+                    let res = op.apply(&[read(regs, lhs, &var), read(regs, rhs, &var)]);
+                    write(regs, dst, res);
+                }
+                Reg::Call(ptr, dst, argc) => {
+                    let args = unsafe { regs.get_unchecked(dst..dst + argc) };
+                    let val = ptr.call(args);
+                    write(regs, dst, val);
+                }
+                Reg::Jmp(offset) => pc += offset,
+                Reg::JmpIfFalse(cond, offset) => {
+                    if !f64_to_bool(read(regs, cond, &var)) {
+                        pc += offset;
+                    }
+                }
+                Reg::JmpAnd(lhs, dst, offset) => {
+                    if !f64_to_bool(read(regs, lhs, &var)) {
+                        write(regs, dst, 0.0);
+                        pc += offset;
+                    }
+                }
+                Reg::JmpOr(lhs, dst, offset) => {
+                    if f64_to_bool(read(regs, lhs, &var)) {
+                        write(regs, dst, 1.0);
+                        pc += offset;
+                    }
+                }
+                Reg::Ret(src) => return Ok(read(regs, src, &var)),
+            }
+        }
+
+        Err(Error::EvalError(EvalError::MalformedExpression))
+    }
+}
+
+impl<'a, V, F> ExprEvaluator<'a, Locked, V, F, V, F> for Expr<Reg<'a>>
+where
+    V: LockedResolver<f64>,
+    F: LockedResolver<ExprFn>,
+{
+    fn eval(&self, _ctx: &LContext<V, F>, stack: &mut Vec<f64>) -> Result<f64, Error<'a>>
+    {
+        // The stack serves as the register file
+        stack.clear();
+        stack.resize(self.depth, 0.0);
+
+        let res = self.run(stack);
+        stack.clear();
+        res
+    }
+
+    fn eval_stackless<const N: usize>(&self, _ctx: &LContext<V, F>) -> Result<f64, Error<'a>>
+    {
+        if self.depth <= N {
+            self.run(&mut [0.0; N])
+        } else {
+            self.run(&mut vec![0.0; self.depth])
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &LContext<V, F>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'a>>
+    {
+        // Locked variables are told apart by their address
+        let ptrs: Vec<_> = wrt.iter().map(|var| ctx.get_var_ptr(&var.name())).collect();
+        let width = wrt.len() + 1;
+
+        // Reads an operand as a dual number, its value followed by its partial
+        // derivatives, seeding variables in the position of their first match
+        let read = |stack: &[f64], operand: Operand<'_>, dual: &mut [f64]| match operand {
+            Operand::Reg(reg) => dual.copy_from_slice(&stack[reg * width..(reg + 1) * width]),
+            Operand::Num(num) => {
+                dual.fill(0.0);
+                dual[0] = num;
+            }
+            Operand::Var(ptr) => {
+                dual.fill(0.0);
+                dual[0] = ptr.get();
+
+                if let Some(slot) = ptrs.iter().position(|var| *var == Some(ptr)) {
+                    dual[1 + slot] = 1.0;
+                }
+            }
+        };
+
+        // Each register of the file holds a dual number
+        stack.clear();
+        stack.resize(self.depth * width, 0.0);
+
+        let mut lhs = vec![0.0; width];
+        let mut rhs = vec![0.0; width];
+        let mut args = Vec::new();
+        let mut partials = Vec::new();
+        let mut pc = 0;
+
+        while let Some(instr) = self.tokens.get(pc) {
+            pc += 1;
+
+            match *instr {
+                Reg::Mov(dst, src) => {
+                    read(stack, src, &mut lhs);
+                    stack[dst * width..(dst + 1) * width].copy_from_slice(&lhs);
+                }
+                Reg::Unary(op, dst, src) => {
+                    read(stack, src, &mut lhs);
+                    dual::apply(op, &lhs, &[], &mut stack[dst * width..(dst + 1) * width]);
+                }
+                Reg::Binary(op, dst, a, b) => {
+                    read(stack, a, &mut lhs);
+                    read(stack, b, &mut rhs);
+                    dual::apply(op, &lhs, &rhs, &mut stack[dst * width..(dst + 1) * width]);
+                }
+                Reg::Call(ptr, dst, argc) => {
+                    let duals = &stack[dst * width..(dst + argc) * width];
+                    dual::call(
+                        ptr.as_fn(),
+                        duals,
+                        &mut args,
+                        &mut partials,
+                        &mut lhs,
+                        || Cow::Owned(format!("@{ptr:p}")),
+                    )?;
+                    stack[dst * width..(dst + 1) * width].copy_from_slice(&lhs);
+                }
+                Reg::Jmp(offset) => pc += offset,
+                Reg::JmpIfFalse(cond, offset) => {
+                    read(stack, cond, &mut lhs);
+
+                    if !f64_to_bool(lhs[0]) {
+                        pc += offset;
+                    }
+                }
+                Reg::JmpAnd(src, dst, offset) | Reg::JmpOr(src, dst, offset) => {
+                    read(stack, src, &mut lhs);
+                    let decides = matches!(instr, Reg::JmpOr(..));
+
+                    if f64_to_bool(lhs[0]) == decides {
+                        let dual = &mut stack[dst * width..(dst + 1) * width];
+                        dual.fill(0.0);
+                        dual[0] = if decides { 1.0 } else { 0.0 };
+                        pc += offset;
+                    }
+                }
+                Reg::Ret(src) => {
+                    read(stack, src, &mut lhs);
+                    stack.clear();
+
+                    let mut grad = lhs[1..].to_vec();
+                    dual::copy_repeated(wrt, &mut grad);
+                    return Ok((lhs[0], grad));
+                }
+            }
+        }
+
+        stack.clear();
+        Err(Error::EvalError(EvalError::MalformedExpression))
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        // Locked variables are told apart by their address
+        let ptrs: Vec<_> = vars
+            .iter()
+            .map(|var| ctx.get_var_ptr(&var.name()))
+            .collect();
+        let mut regs = vec![0.0; self.depth];
+
+        batch::check_columns(vars.len(), columns, out);
+
+        for (row, res) in out.iter_mut().enumerate() {
+            *res = self.run_with(&mut regs, |ptr| {
+                match ptrs.iter().position(|var| *var == Some(ptr)) {
+                    Some(col) => columns[col][row],
+                    None => ptr.get(),
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// The instructions run on registers holding a lane each. The rows that
+    /// jump over instructions are masked out until the target of their jump,
+    /// as all the jumps go forward.
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &LContext<V, F>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'a>>
+    {
+        let ptrs: Vec<_> = vars
+            .iter()
+            .map(|var| ctx.get_var_ptr(&var.name()))
+            .collect();
+
+        batch::check_columns(vars.len(), columns, out);
+
+        let mut regs = vec![L::splat(0.0); self.depth];
+        // Rows that jumped, waiting for the instruction they jumped to
+        let mut jumps: Vec<(usize, L)> = Vec::new();
+        // Scratch buffers for the rows of a call and the arguments of a row
+        let mut rows = Vec::new();
+        let mut args = Vec::new();
+
+        for start in (0..out.len()).step_by(L::WIDTH) {
+            let n = L::WIDTH.min(out.len() - start);
+            let read = |regs: &[L], operand: Operand<'a>| match operand {
+                Operand::Num(num) => L::splat(num),
+                Operand::Reg(reg) => regs[reg],
+                Operand::Var(ptr) => match ptrs.iter().position(|var| *var == Some(ptr)) {
+                    Some(col) => L::from_slice(&columns[col][start..start + n]),
+                    None => L::splat(ptr.get()),
+                },
+            };
+
+            // `1.0` in the rows running the current instruction
+            let mut active = L::splat(1.0);
+            active.as_mut_slice()[n..].fill(0.0);
+            let mut res = L::splat(0.0);
+            jumps.clear();
+
+            for (pc, instr) in self.tokens.iter().enumerate() {
+                jumps.retain(|(target, jumped)| {
+                    if *target == pc {
+                        active = active.binary(Op::Or, *jumped);
+                    }
+                    *target != pc
+                });
+
+                if active.as_slice().iter().all(|row| *row == 0.0) {
+                    continue;
+                }
+
+                // Writes `val` to the register `dst` in the given rows only
+                let write = |regs: &mut [L], rows: L, dst: usize, val: L| {
+                    regs[dst] = rows.select(val, regs[dst]);
+                };
+
+                match *instr {
+                    Reg::Mov(dst, src) => {
+                        let val = read(&regs, src);
+                        write(&mut regs, active, dst, val);
+                    }
+                    Reg::Unary(op, dst, src) => {
+                        let val = read(&regs, src).unary(op);
+                        write(&mut regs, active, dst, val);
+                    }
+                    Reg::Binary(op, dst, lhs, rhs) => {
+                        let val = read(&regs, lhs).binary(op, read(&regs, rhs));
+                        write(&mut regs, active, dst, val);
+                    }
+                    Reg::Call(ptr, dst, argc) => {
+                        rows.clear();
+                        rows.extend((0..n).filter(|row| active.as_slice()[*row] != 0.0));
+
+                        let val =
+                            lanes::call(ptr.as_fn(), &regs[dst..dst + argc], &rows, &mut args);
+                        write(&mut regs, active, dst, val);
+                    }
+                    Reg::Jmp(offset) => {
+                        jumps.push((pc + 1 + offset, active));
+                        active = L::splat(0.0);
+                    }
+                    Reg::JmpIfFalse(cond, offset) => {
+                        let cond = read(&regs, cond);
+                        jumps.push((pc + 1 + offset, active.binary(Op::And, cond.unary(Op::Not))));
+                        active = active.binary(Op::And, cond);
+                    }
+                    Reg::JmpAnd(lhs, dst, offset) | Reg::JmpOr(lhs, dst, offset) => {
+                        let lhs = read(&regs, lhs);
+                        let (decided, val) = match instr {
+                            Reg::JmpOr(..) => (lhs, 1.0),
+                            _ => (lhs.unary(Op::Not), 0.0),
+                        };
+                        let jumped = active.binary(Op::And, decided);
+
+                        write(&mut regs, jumped, dst, L::splat(val));
+                        jumps.push((pc + 1 + offset, jumped));
+                        active = active.binary(Op::And, decided.unary(Op::Not));
+                    }
+                    Reg::Ret(src) => {
+                        res = active.select(read(&regs, src), res);
+                        active = L::splat(0.0);
+                    }
+                }
+            }
+
+            // Rows that never returned
+            let running = jumps.iter().fold(active, |running, (_, jumped)| {
+                running.binary(Op::Or, *jumped)
+            });
+            if running.as_slice().iter().any(|row| *row != 0.0) {
+                return Err(Error::EvalError(EvalError::MalformedExpression));
+            }
+
+            out[start..start + n].copy_from_slice(&res.as_slice()[..n]);
+        }
+
+        Ok(())
+    }
+}
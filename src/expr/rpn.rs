@@ -1,8 +1,18 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt};
 
 use crate::{
-    Error, EvalError, UContext,
-    expr::{ExprCompiler, NotIndexedResolver, Op, ParseableToken},
+    Ast, Error, EvalError, UContext,
+    expr::{
+        Buffer, EvalStack, ExprCompiler, NotIndexedResolver, Op, ParseableToken, Temps, Token,
+        ast::AstToken,
+        batch, cse,
+        dual::{self, DualToken, GradVar},
+        f64_to_bool,
+        lanes::{self, Lane},
+        load,
+        simplify::{self, SimplifyMode},
+        store,
+    },
     prelude::*,
     resolver::{LockedResolver, ResolverState, UnlockedResolver},
 };
@@ -14,13 +24,23 @@ pub enum Rpn<'e>
     Var(&'e str),
     Fn(&'e str, usize),
     Op(Op),
+    /// Jumps forward the given number of tokens
+    Jmp(usize),
+    /// Pops the condition and jumps forward the given number of tokens if it is false
+    JmpIfFalse(usize),
+    /// Short-circuits `&&`, jumping forward the given number of tokens and
+    /// leaving `false` on the stack if the left operand is false
+    JmpAnd(usize),
+    /// Short-circuits `||`, jumping forward the given number of tokens and
+    /// leaving `true` on the stack if the left operand is true
+    JmpOr(usize),
+    /// Copies the value on top of the stack into the given temporary slot
+    Store(usize),
+    /// Pushes the value of the given temporary slot
+    Load(usize),
 }
 
-impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for Rpn<'a>
-where
-    S: ResolverState,
-    V: Resolver<S, f64>,
-    F: Resolver<S, ExprFn>,
+impl Token for Rpn<'_>
 {
     #[inline]
     fn f64(num: f64) -> Self
@@ -47,15 +67,145 @@ where
     }
 
     #[inline]
-    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn jmp(offset: usize) -> Self
+    {
+        Rpn::Jmp(offset)
+    }
+
+    #[inline]
+    fn jmp_if_false(offset: usize) -> Self
+    {
+        Rpn::JmpIfFalse(offset)
+    }
+
+    #[inline]
+    fn jmp_and(offset: usize) -> Self
+    {
+        Rpn::JmpAnd(offset)
+    }
+
+    #[inline]
+    fn jmp_or(offset: usize) -> Self
+    {
+        Rpn::JmpOr(offset)
+    }
+
+    #[inline]
+    fn store(slot: usize) -> Self
+    {
+        Rpn::Store(slot)
+    }
+
+    #[inline]
+    fn load(slot: usize) -> Self
     {
-        Rpn::Var(name)
+        Rpn::Load(slot)
     }
 
     #[inline]
-    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Self
+    fn postfix(self) -> AstToken<Self>
+    {
+        match self {
+            Rpn::Num(num) => AstToken::Num(num),
+            Rpn::Var(..) => AstToken::Var(self),
+            Rpn::Fn(.., argc) => AstToken::Fn(self, argc),
+            Rpn::Op(op) => AstToken::Op(op),
+            Rpn::Jmp(offset) => AstToken::Jmp(offset),
+            Rpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            Rpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            Rpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            Rpn::Store(slot) => AstToken::Store(slot),
+            Rpn::Load(slot) => AstToken::Load(slot),
+        }
+    }
+}
+
+impl<'a, 'c, S, V, F, LV, LF> ParseableToken<'a, 'c, S, V, F, LV, LF> for Rpn<'a>
+where
+    S: ResolverState,
+    V: Resolver<S, f64>,
+    F: Resolver<S, ExprFn>,
+{
+    #[inline]
+    fn var(name: &'a str, _ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>
+    {
+        Some(Rpn::Var(name))
+    }
+
+    #[inline]
+    fn fun(name: &'a str, argc: usize, _ctx: &'c Context<S, V, F, LV, LF>) -> Option<Self>
+    {
+        Some(Rpn::Fn(name, argc))
+    }
+}
+
+impl<'e> Expr<Rpn<'e>>
+{
+    /// Rebuilds the syntax tree of the compiled expression, with its literal
+    /// subexpressions already folded.
+    pub fn to_ast(&self) -> Result<Ast<'e>, Error<'e>>
+    {
+        Ast::from_postfix(self.tokens.iter().map(|tok| match *tok {
+            Rpn::Num(num) => AstToken::Num(num),
+            Rpn::Var(name) => AstToken::Var(Cow::Borrowed(name)),
+            Rpn::Fn(name, argc) => AstToken::Fn(Cow::Borrowed(name), argc),
+            Rpn::Op(op) => AstToken::Op(op),
+            Rpn::Jmp(offset) => AstToken::Jmp(offset),
+            Rpn::JmpIfFalse(offset) => AstToken::JmpIfFalse(offset),
+            Rpn::JmpAnd(offset) => AstToken::JmpAnd(offset),
+            Rpn::JmpOr(offset) => AstToken::JmpOr(offset),
+            Rpn::Store(slot) => AstToken::Store(slot),
+            Rpn::Load(slot) => AstToken::Load(slot),
+        }))
+    }
+}
+
+impl Expr<Rpn<'_>>
+{
+    /// Rewrites the expression into a cheaper equivalent before it is
+    /// evaluated, see [`SimplifyMode`] for the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
+    {
+        simplify::simplify_expr(self, mode);
+    }
+
+    /// Computes the repeated subexpressions once, storing them into temporary
+    /// slots read by their other occurrences. Calls to impure functions
+    /// ([`ExprFn::impure`]) and to functions the context can't resolve are
+    /// never merged.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use fee::{DefaultResolver, EmptyResolver, prelude::*};
+    ///
+    /// let mut vars = DefaultResolver::empty();
+    /// vars.insert("p19".to_string(), 3.0);
+    /// let ctx = Context::new(vars, EmptyResolver::new());
+    ///
+    /// let mut expr = Expr::compile("(p19 - 2) * (p19 - 2) + (p19 - 2)", &ctx).unwrap();
+    /// expr.eliminate_common_subexprs(&ctx);
+    /// assert_eq!(expr.len(), 8);
+    /// assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(2.0));
+    /// ```
+    pub fn eliminate_common_subexprs<S, V, F, LV, LF>(&mut self, ctx: &Context<S, V, F, LV, LF>)
+    where
+        S: ResolverState,
+        V: Resolver<S, f64>,
+        F: Resolver<S, ExprFn>,
     {
-        Rpn::Fn(name, argc)
+        cse::eliminate(self, |tok| match tok {
+            Rpn::Fn(name, _) => ctx.get_fn(name).is_some_and(ExprFn::is_pure),
+            _ => true,
+        });
+    }
+}
+
+impl fmt::Display for Expr<Rpn<'_>>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        let ast = self.to_ast().map_err(|_| fmt::Error)?;
+        write!(f, "{ast}")
     }
 }
 
@@ -70,57 +220,96 @@ where
     {
         Expr::try_from((expr, ctx))
     }
+
+    fn from_ast(
+        ast: &'e Ast<'_>,
+        ctx: &'c UContext<V, F, LV, LF>,
+    ) -> Result<Expr<Rpn<'e>>, Error<'e>>
+    {
+        Expr::try_from((ast, ctx))
+    }
 }
 
-impl<'e, V, F, LV, LF> ExprEvaluator<'e, Unlocked, V, F, LV, LF> for Expr<Rpn<'e>>
-where
-    V: NotIndexedResolver + UnlockedResolver<f64, LV>,
-    F: NotIndexedResolver + UnlockedResolver<ExprFn, LF>,
-    LV: LockedResolver<f64>,
-    LF: LockedResolver<ExprFn>,
+impl<'e> Expr<Rpn<'e>>
 {
-    fn eval(&self, ctx: &UContext<V, F, LV, LF>, stack: &mut Vec<f64>) -> Result<f64, Error<'e>>
+    /// Evaluates the expression on `stack`, which is left empty if it
+    /// succeeds
+    fn eval_on<V, F, LV, LF>(
+        &self,
+        ctx: &UContext<V, F, LV, LF>,
+        stack: &mut impl EvalStack,
+    ) -> Result<f64, Error<'e>>
+    where
+        V: Resolver<Unlocked, f64>,
+        F: Resolver<Unlocked, ExprFn>,
     {
-        if self.tokens.len() == 1 {
-            if let Rpn::Num(num) = &self.tokens[0] {
-                return Ok(*num);
-            }
+        if let [Rpn::Num(num)] = self.tokens.as_slice() {
+            return Ok(*num);
         }
 
-        for tok in self.tokens.iter() {
+        let mut temps = Temps::new();
+        let mut pc = 0;
+
+        while let Some(tok) = self.tokens.get(pc) {
+            pc += 1;
+
             match tok {
                 Rpn::Num(num) => stack.push(*num),
-                Rpn::Var(name) => stack.push(
-                    *ctx.get_var(name)
-                        .ok_or_else(|| Error::UnknownVar(Cow::Borrowed(name)))?,
-                ),
+                Rpn::Var(name) => {
+                    stack.push(*ctx.get_var(name).ok_or_else(|| {
+                        Error::UnknownVar(Cow::Borrowed(name), self.spans[pc - 1])
+                    })?)
+                }
                 Rpn::Fn(name, argc) => {
-                    if *argc > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - argc;
                     let args = unsafe { stack.get_unchecked(start..) };
                     let val = ctx
                         .get_fn(name)
-                        .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name)))?(
-                        args
-                    );
+                        .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name), self.spans[pc - 1]))?
+                        .call(args);
 
                     stack.truncate(start);
                     stack.push(val);
                 }
                 Rpn::Op(op) => {
-                    if op.num_operands() > stack.len() {
-                        return Err(Error::EvalError(EvalError::RPNStackUnderflow));
-                    }
-
                     let start = stack.len() - op.num_operands();
                     let args = unsafe { stack.get_unchecked(start..) };
                     let res = op.apply(args);
                     stack.truncate(start);
                     stack.push(res);
                 }
+                Rpn::Jmp(offset) => pc += offset,
+                Rpn::JmpIfFalse(offset) => {
+                    let cond = stack
+                        .pop()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(cond) {
+                        pc += offset;
+                    }
+                }
+                Rpn::JmpAnd(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if !f64_to_bool(*lhs) {
+                        *lhs = 0.0;
+                        pc += offset;
+                    }
+                }
+                Rpn::JmpOr(offset) => {
+                    let lhs = stack
+                        .last_mut()
+                        .ok_or(Error::EvalError(EvalError::RPNStackUnderflow))?;
+
+                    if f64_to_bool(*lhs) {
+                        *lhs = 1.0;
+                        pc += offset;
+                    }
+                }
+                Rpn::Store(slot) => store(&mut temps, *slot, stack)?,
+                Rpn::Load(slot) => load(&temps, *slot, stack)?,
             }
         }
 
@@ -129,12 +318,112 @@ where
             _ => Err(Error::EvalError(EvalError::MalformedExpression)),
         }
     }
+
+    /// Resolves the token at `pc` for the evaluations over dual numbers and by
+    /// batches, numbering the variables by their position in `vars`
+    fn dual_token<'f, V, F, LV, LF>(
+        &self,
+        ctx: &'f UContext<V, F, LV, LF>,
+        vars: &[GradVar<'_>],
+        pc: usize,
+    ) -> Result<DualToken<'f>, Error<'e>>
+    where
+        V: Resolver<Unlocked, f64>,
+        F: Resolver<Unlocked, ExprFn>,
+    {
+        Ok(match self.tokens[pc] {
+            Rpn::Num(num) => DualToken::Num(num),
+            Rpn::Var(name) => {
+                let val = *ctx
+                    .get_var(name)
+                    .ok_or_else(|| Error::UnknownVar(Cow::Borrowed(name), self.spans[pc]))?;
+                DualToken::Var(val, vars.iter().position(|var| var.is_named(name)))
+            }
+            Rpn::Fn(name, argc) => {
+                let f = ctx
+                    .get_fn(name)
+                    .ok_or_else(|| Error::UnknownFn(Cow::Borrowed(name), self.spans[pc]))?;
+                DualToken::Fn(f, argc)
+            }
+            Rpn::Op(op) => DualToken::Op(op),
+            Rpn::Jmp(offset) => DualToken::Jmp(offset),
+            Rpn::JmpIfFalse(offset) => DualToken::JmpIfFalse(offset),
+            Rpn::JmpAnd(offset) => DualToken::JmpAnd(offset),
+            Rpn::JmpOr(offset) => DualToken::JmpOr(offset),
+            Rpn::Store(slot) => DualToken::Store(slot),
+            Rpn::Load(slot) => DualToken::Load(slot),
+        })
+    }
+}
+
+impl<'e, V, F, LV, LF> ExprEvaluator<'e, Unlocked, V, F, LV, LF> for Expr<Rpn<'e>>
+where
+    V: NotIndexedResolver + UnlockedResolver<f64, LV>,
+    F: NotIndexedResolver + UnlockedResolver<ExprFn, LF>,
+    LV: LockedResolver<f64>,
+    LF: LockedResolver<ExprFn>,
+{
+    fn eval(&self, ctx: &UContext<V, F, LV, LF>, stack: &mut Vec<f64>) -> Result<f64, Error<'e>>
+    {
+        self.eval_on(ctx, stack)
+    }
+
+    fn eval_stackless<const N: usize>(&self, ctx: &UContext<V, F, LV, LF>)
+    -> Result<f64, Error<'e>>
+    {
+        if self.depth <= N {
+            self.eval_on(ctx, &mut Buffer::<N>::new())
+        } else {
+            self.eval_on(ctx, &mut Vec::with_capacity(self.depth))
+        }
+    }
+
+    fn eval_with_gradient(
+        &self,
+        ctx: &UContext<V, F, LV, LF>,
+        wrt: &[GradVar<'_>],
+        stack: &mut Vec<f64>,
+    ) -> Result<(f64, Vec<f64>), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, wrt, pc);
+        let fn_name = |pc: usize| match self.tokens[pc] {
+            Rpn::Fn(name, _) => Cow::Borrowed(name),
+            _ => Cow::Borrowed(""),
+        };
+
+        dual::eval_dual(self.tokens.len(), wrt, stack, token, fn_name)
+    }
+
+    fn eval_batch(
+        &self,
+        ctx: &UContext<V, F, LV, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        batch::eval_batch(self.tokens.len(), vars, columns, out, token)
+    }
+
+    fn eval_lanes<L: Lane>(
+        &self,
+        ctx: &UContext<V, F, LV, LF>,
+        vars: &[GradVar<'_>],
+        columns: &[&[f64]],
+        out: &mut [f64],
+    ) -> Result<(), Error<'e>>
+    {
+        let token = |pc| self.dual_token(ctx, vars, pc);
+        lanes::eval_lanes::<L>(self.tokens.len(), vars, columns, out, token)
+    }
 }
 
 #[cfg(test)]
 mod tests
 {
     use super::*;
+    use crate::Span;
 
     #[test]
     fn test_new()
@@ -176,6 +465,13 @@ mod tests
             ]
         );
 
+        let expr = "f() + g( )";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![Rpn::Fn("f", 0), Rpn::Fn("g", 0), Rpn::Op(Op::Add)]
+        );
+
         let expr = "(2 * 21) + 3 + -35 - ((5 * 80) + 5) + 10 + -p0";
         let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
         assert_eq!(
@@ -204,4 +500,158 @@ mod tests
             ]
         );
     }
+
+    #[test]
+    fn test_ternary()
+    {
+        let ctx = Context::empty();
+
+        let expr = "x > 0 ? 1 / x : -x";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![
+                Rpn::Var("x"),
+                Rpn::Num(0.0),
+                Rpn::Op(Op::Great),
+                Rpn::JmpIfFalse(4),
+                Rpn::Num(1.0),
+                Rpn::Var("x"),
+                Rpn::Op(Op::Div),
+                Rpn::Jmp(2),
+                Rpn::Var("x"),
+                Rpn::Op(Op::Neg),
+            ]
+        );
+
+        let expr = "a ? b : c ? d : e";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![
+                Rpn::Var("a"),
+                Rpn::JmpIfFalse(2),
+                Rpn::Var("b"),
+                Rpn::Jmp(5),
+                Rpn::Var("c"),
+                Rpn::JmpIfFalse(2),
+                Rpn::Var("d"),
+                Rpn::Jmp(1),
+                Rpn::Var("e"),
+            ]
+        );
+
+        let expr = "(1 < 2 ? 3 : x) + 4";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(rpn_expr.tokens, vec![Rpn::Num(7.0)]);
+
+        let expr = "1 > 2 ? x : y * 2";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![Rpn::Var("y"), Rpn::Num(2.0), Rpn::Op(Op::Mul)]
+        );
+    }
+
+    #[test]
+    fn test_short_circuit()
+    {
+        let ctx = Context::empty();
+
+        let expr = "x != 0 && f(1 / x) > 2 || y";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![
+                Rpn::Var("x"),
+                Rpn::Num(0.0),
+                Rpn::Op(Op::NotEq),
+                Rpn::JmpAnd(7),
+                Rpn::Num(1.0),
+                Rpn::Var("x"),
+                Rpn::Op(Op::Div),
+                Rpn::Fn("f", 1),
+                Rpn::Num(2.0),
+                Rpn::Op(Op::Great),
+                Rpn::Op(Op::And),
+                Rpn::JmpOr(2),
+                Rpn::Var("y"),
+                Rpn::Op(Op::Or),
+            ]
+        );
+
+        let expr = "1 > 2 && f(x) || 3";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(rpn_expr.tokens, vec![Rpn::Num(1.0)]);
+
+        let expr = "true && x";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![Rpn::Num(1.0), Rpn::Var("x"), Rpn::Op(Op::And)]
+        );
+
+        let expr = "2 || f(x)";
+        let rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        assert_eq!(rpn_expr.tokens, vec![Rpn::Num(1.0)]);
+    }
+
+    #[test]
+    fn test_eliminate_common_subexprs()
+    {
+        let ctx = Context::empty();
+
+        let expr = "2 - (4 + (p19 - 2) * (p19 - 2))";
+        let mut rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        rpn_expr.eliminate_common_subexprs(&ctx);
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![
+                Rpn::Num(2.0),
+                Rpn::Num(4.0),
+                Rpn::Var("p19"),
+                Rpn::Num(2.0),
+                Rpn::Op(Op::Sub),
+                Rpn::Store(0),
+                Rpn::Load(0),
+                Rpn::Op(Op::Mul),
+                Rpn::Op(Op::Add),
+                Rpn::Op(Op::Sub),
+            ]
+        );
+        assert_eq!(rpn_expr.spans[6], Span::new(26, 27));
+
+        // Only reused where the first occurrence is always evaluated
+        let expr = "(x - 1) + (y ? x - 1 : 0)";
+        let mut rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        rpn_expr.eliminate_common_subexprs(&ctx);
+        assert_eq!(
+            rpn_expr.tokens,
+            vec![
+                Rpn::Var("x"),
+                Rpn::Num(1.0),
+                Rpn::Op(Op::Sub),
+                Rpn::Store(0),
+                Rpn::Var("y"),
+                Rpn::JmpIfFalse(2),
+                Rpn::Load(0),
+                Rpn::Jmp(1),
+                Rpn::Num(0.0),
+                Rpn::Op(Op::Add),
+            ]
+        );
+
+        let expr = "y ? x - 1 : (x - 1) * 2";
+        let mut rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        let tokens = rpn_expr.tokens.clone();
+        rpn_expr.eliminate_common_subexprs(&ctx);
+        assert_eq!(rpn_expr.tokens, tokens);
+
+        // Functions the context can't resolve may be impure
+        let expr = "f(x) + f(x)";
+        let mut rpn_expr = Expr::<Rpn>::try_from((expr, &ctx)).unwrap();
+        let tokens = rpn_expr.tokens.clone();
+        rpn_expr.eliminate_common_subexprs(&ctx);
+        assert_eq!(rpn_expr.tokens, tokens);
+    }
 }
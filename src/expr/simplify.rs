@@ -0,0 +1,307 @@
+use std::mem;
+
+use crate::{
+    Ast, Span,
+    expr::{Expr, Op, Token, bool_to_f64, f64_to_bool, stack_depth, tree::Node},
+};
+
+/// Rewrites applied by the `simplify` methods of [`Ast`] and of every
+/// compiled [`Expr`].
+///
+/// Literal subexpressions are always folded. [`SimplifyMode::IeeeSafe`] only
+/// applies the rewrites that give the same result for every input, while
+/// [`SimplifyMode::FastMath`] also applies those that are only exact for
+/// finite operands: `0 * x` is `NaN` when `x` is infinite, and `x + 0` is
+/// `0` when `x` is `-0`.
+///
+/// # Examples
+/// ```rust
+/// use fee::{SimplifyMode, parse};
+///
+/// let mut ast = parse("--x * 1 + 0 * y + (2 * z) * 3").unwrap();
+/// ast.simplify(SimplifyMode::IeeeSafe);
+/// assert_eq!(ast.to_string(), "x + 0 * y + 2 * z * 3");
+///
+/// ast.simplify(SimplifyMode::FastMath);
+/// assert_eq!(ast.to_string(), "x + 6 * z");
+/// ```
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum SimplifyMode
+{
+    /// Rewrites that preserve the result of IEEE 754 arithmetic, including
+    /// for `NaN`, infinities and signed zeros:
+    /// - `x * 1`, `1 * x`, `x / 1`, `x - 0`, `x + -0` and `x ^ 1` into `x`
+    /// - `x * -1`, `-1 * x` and `x / -1` into `-x`, `--x` into `x`
+    /// - `x - -y` into `x + y`, `x + -y` into `x - y` and `-x * -y` into `x * y`
+    ///   and `-x / -y` into `x / y`
+    /// - `x ^ 0` into `1`, `x && false` into `false` and `x || true` into
+    ///   `true`, as long as `x` calls no function
+    #[default]
+    IeeeSafe,
+    /// The rewrites of [`SimplifyMode::IeeeSafe`], and those that assume
+    /// finite operands and ignore the sign of zero:
+    /// - `x + 0`, `0 + x` into `x` and `0 - x` into `-x`
+    /// - `x * 0`, `0 * x` and `0 / x` into `0`
+    /// - `x - x` into `0` and `x / x` into `1`, as long as `x` calls no function
+    /// - literals of chained additions and multiplications are gathered, so
+    ///   `(2 * x) * 3` becomes `6 * x` and `(x + 1) + 2` becomes `x + 3`
+    ///
+    /// Rewrites that drop a subexpression drop the function calls it contains.
+    FastMath,
+}
+
+impl Ast<'_>
+{
+    /// Rewrites the tree into a simpler equivalent, see [`SimplifyMode`] for
+    /// the rewrites of each mode.
+    pub fn simplify(&mut self, mode: SimplifyMode)
+    {
+        let ast = mem::replace(self, Ast::Num(0.0));
+        *self = Ast::from(simplify(Node::from(ast), mode));
+    }
+}
+
+/// Rewrites the tokens of `expr`, which is left as is if it isn't well-formed.
+pub(super) fn simplify_expr<T: Token + PartialEq>(expr: &mut Expr<T>, mode: SimplifyMode)
+{
+    let postfix = expr
+        .tokens
+        .iter()
+        .zip(&expr.spans)
+        .map(|(tok, span)| (tok.postfix(), *span));
+
+    if let Ok(node) = Node::from_postfix(postfix) {
+        let mut tokens = Vec::with_capacity(expr.tokens.len());
+        let mut spans = Vec::with_capacity(expr.spans.len());
+        simplify(node, mode).emit(&mut tokens, &mut spans);
+
+        if let Ok(depth) = stack_depth(&tokens) {
+            expr.tokens = tokens;
+            expr.spans = spans;
+            expr.depth = depth;
+        }
+    }
+}
+
+/// Simplifies the tree bottom-up
+fn simplify<L: PartialEq>(node: Node<L>, mode: SimplifyMode) -> Node<L>
+{
+    match node {
+        Node::Num(..) | Node::Var(..) => node,
+        Node::Call(fun, args, span) => Node::Call(
+            fun,
+            args.into_iter().map(|arg| simplify(arg, mode)).collect(),
+            span,
+        ),
+        Node::Unary(op, operand, span) => unary(op, simplify(*operand, mode), span),
+        Node::Binary(op, lhs, rhs, span) => {
+            binary(op, simplify(*lhs, mode), simplify(*rhs, mode), span, mode)
+        }
+        Node::Cond(cond, then_branch, else_branch, spans) => match simplify(*cond, mode) {
+            Node::Num(cond, _) if f64_to_bool(cond) => simplify(*then_branch, mode),
+            Node::Num(..) => simplify(*else_branch, mode),
+            cond => Node::Cond(
+                Box::new(cond),
+                Box::new(simplify(*then_branch, mode)),
+                Box::new(simplify(*else_branch, mode)),
+                spans,
+            ),
+        },
+    }
+}
+
+/// Whether `node` is the literal `num`, telling `0` and `-0` apart
+#[inline]
+fn is_num<L>(node: &Node<L>, num: f64) -> bool
+{
+    matches!(node, Node::Num(x, _) if x.to_bits() == num.to_bits())
+}
+
+#[inline]
+fn is_zero<L>(node: &Node<L>) -> bool
+{
+    matches!(node, Node::Num(x, _) if *x == 0.0)
+}
+
+/// Rewrites a prefix operator whose operand is already simplified
+fn unary<L>(op: Op, operand: Node<L>, span: Span) -> Node<L>
+{
+    match (op, operand) {
+        (op, Node::Num(num, _)) if op.num_operands() == 1 => Node::Num(op.apply(&[num]), span),
+        (Op::Neg, Node::Unary(Op::Neg, operand, _)) => *operand,
+        (op, operand) => Node::Unary(op, Box::new(operand), span),
+    }
+}
+
+/// Operand of a negation
+#[inline]
+fn negated<L>(node: Node<L>) -> Node<L>
+{
+    match node {
+        Node::Unary(Op::Neg, operand, _) => *operand,
+        node => node,
+    }
+}
+
+/// Rewrites a binary operator whose operands are already simplified
+fn binary<L: PartialEq>(
+    op: Op,
+    lhs: Node<L>,
+    rhs: Node<L>,
+    span: Span,
+    mode: SimplifyMode,
+) -> Node<L>
+{
+    let fast = mode == SimplifyMode::FastMath;
+
+    if op.num_operands() != 2 {
+        return Node::Binary(op, Box::new(lhs), Box::new(rhs), span);
+    }
+
+    if let (Node::Num(lhs, _), Node::Num(rhs, _)) = (&lhs, &rhs) {
+        return Node::Num(op.apply(&[*lhs, *rhs]), span);
+    }
+
+    match op {
+        Op::And | Op::Or => {
+            let decides = |node: &Node<L>| matches!(node, Node::Num(num, _) if f64_to_bool(*num) == (op == Op::Or));
+
+            if decides(&lhs) || (decides(&rhs) && (fast || lhs.is_call_free())) {
+                return Node::Num(bool_to_f64(op == Op::Or), span);
+            }
+        }
+        Op::Add => {
+            if is_num(&rhs, -0.0) || (fast && is_zero(&rhs)) {
+                return lhs;
+            }
+            if is_num(&lhs, -0.0) || (fast && is_zero(&lhs)) {
+                return rhs;
+            }
+            if let Node::Unary(Op::Neg, rhs, _) = rhs {
+                return binary(Op::Sub, lhs, *rhs, span, mode);
+            }
+            if fast {
+                return gather(op, lhs, rhs, span, mode);
+            }
+        }
+        Op::Sub => {
+            if is_num(&rhs, 0.0) || (fast && is_zero(&rhs)) {
+                return lhs;
+            }
+            if fast && is_zero(&lhs) {
+                return unary(Op::Neg, rhs, span);
+            }
+            if fast && lhs.is_call_free() && lhs.same(&rhs) {
+                return Node::Num(0.0, span);
+            }
+            if let Node::Unary(Op::Neg, rhs, _) = rhs {
+                return binary(Op::Add, lhs, *rhs, span, mode);
+            }
+        }
+        Op::Mul => {
+            if is_num(&rhs, 1.0) {
+                return lhs;
+            }
+            if is_num(&lhs, 1.0) {
+                return rhs;
+            }
+            if is_num(&rhs, -1.0) {
+                return unary(Op::Neg, lhs, span);
+            }
+            if is_num(&lhs, -1.0) {
+                return unary(Op::Neg, rhs, span);
+            }
+            if fast && (is_zero(&lhs) || is_zero(&rhs)) {
+                return Node::Num(0.0, span);
+            }
+            if let (Node::Unary(Op::Neg, _, _), Node::Unary(Op::Neg, _, _)) = (&lhs, &rhs) {
+                return binary(op, negated(lhs), negated(rhs), span, mode);
+            }
+            if fast {
+                return gather(op, lhs, rhs, span, mode);
+            }
+        }
+        Op::Div => {
+            if is_num(&rhs, 1.0) {
+                return lhs;
+            }
+            if is_num(&rhs, -1.0) {
+                return unary(Op::Neg, lhs, span);
+            }
+            if fast && is_zero(&lhs) {
+                return Node::Num(0.0, span);
+            }
+            if fast && lhs.is_call_free() && lhs.same(&rhs) {
+                return Node::Num(1.0, span);
+            }
+            if let (Node::Unary(Op::Neg, _, _), Node::Unary(Op::Neg, _, _)) = (&lhs, &rhs) {
+                return binary(op, negated(lhs), negated(rhs), span, mode);
+            }
+        }
+        Op::Pow => {
+            if is_num(&rhs, 1.0) {
+                return lhs;
+            }
+            if is_zero(&rhs) && (fast || lhs.is_call_free()) {
+                return Node::Num(1.0, span);
+            }
+        }
+        _ => {}
+    }
+
+    Node::Binary(op, Box::new(lhs), Box::new(rhs), span)
+}
+
+/// Gathers the literals of `lhs op rhs`, each operand being a literal or `op`
+/// applied to a literal, when `op` is associative and commutative.
+fn gather<L: PartialEq>(
+    op: Op,
+    lhs: Node<L>,
+    rhs: Node<L>,
+    span: Span,
+    mode: SimplifyMode,
+) -> Node<L>
+{
+    let (Some(a), Some(b)) = (literal_operand(op, &lhs), literal_operand(op, &rhs)) else {
+        return Node::Binary(op, Box::new(lhs), Box::new(rhs), span);
+    };
+
+    let num = Node::Num(op.apply(&[a, b]), span);
+    let rest = match (non_literal_operand(lhs), non_literal_operand(rhs)) {
+        (Some(lhs), Some(rhs)) => binary(op, lhs, rhs, span, mode),
+        (Some(rest), None) | (None, Some(rest)) => rest,
+        (None, None) => return num,
+    };
+
+    // Coefficients are written first, offsets last
+    match op {
+        Op::Mul => binary(op, num, rest, span, mode),
+        _ => binary(op, rest, num, span, mode),
+    }
+}
+
+/// Literal of `node` if it is one, or if it applies `op` to one
+fn literal_operand<L>(op: Op, node: &Node<L>) -> Option<f64>
+{
+    match node {
+        Node::Num(num, _) => Some(*num),
+        Node::Binary(node_op, lhs, rhs, _) if *node_op == op => {
+            match (lhs.as_ref(), rhs.as_ref()) {
+                (Node::Num(num, _), _) | (_, Node::Num(num, _)) => Some(*num),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Operand of `node` besides the literal found by [`literal_operand`]
+fn non_literal_operand<L>(node: Node<L>) -> Option<Node<L>>
+{
+    match node {
+        Node::Binary(_, lhs, rhs, _) => match (*lhs, *rhs) {
+            (Node::Num(..), rest) | (rest, _) => Some(rest),
+        },
+        _ => None,
+    }
+}
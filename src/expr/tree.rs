@@ -0,0 +1,349 @@
+use std::borrow::Cow;
+
+use crate::{
+    Ast, Error, Span,
+    expr::{Op, Token, ast::AstToken},
+};
+
+/// Tree of a postfix expression whose leaves are its variables and function
+/// calls, left as they were resolved. Each node keeps the span of the token
+/// it was built from, so the tree can be turned back into tokens.
+#[derive(Debug, Clone)]
+pub(super) enum Node<L>
+{
+    Num(f64, Span),
+    Var(L, Span),
+    Call(L, Vec<Node<L>>, Span),
+    Unary(Op, Box<Node<L>>, Span),
+    Binary(Op, Box<Node<L>>, Box<Node<L>>, Span),
+    /// Condition, then and else branches, and the spans of the `?` and the `:`
+    Cond(Box<Node<L>>, Box<Node<L>>, Box<Node<L>>, [Span; 2]),
+}
+
+/// What to do with the value of a node when emitting it
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub(super) enum Temp
+{
+    /// Emits the node as is
+    Keep,
+    /// Emits the node and copies its value into the slot
+    Store(usize),
+    /// Reads the value of the node from the slot instead of emitting it
+    Load(usize),
+}
+
+impl<L: Clone> Node<L>
+{
+    /// Rebuilds the tree of a well-formed postfix expression.
+    ///
+    /// The branches of a ternary operator end where the jump after its then
+    /// branch lands, short-circuit jumps are followed by their operator. The
+    /// subexpressions read from temporary slots are inlined.
+    pub(super) fn from_postfix<'e>(
+        tokens: impl IntoIterator<Item = (AstToken<L>, Span)>,
+    ) -> Result<Node<L>, Error<'e>>
+    {
+        let malformed = || Error::InternalInvariant("malformed postfix expression".to_string());
+
+        let mut stack: Vec<Node<L>> = Vec::new();
+        // Spans of the `?` of the ternaries whose then branch is being built
+        let mut questions: Vec<Span> = Vec::new();
+        let mut cond_ends: Vec<(usize, [Span; 2])> = Vec::new();
+        let mut temps: Vec<Option<Node<L>>> = Vec::new();
+
+        let close_cond = |stack: &mut Vec<Node<L>>, spans: [Span; 2]| {
+            let else_branch = stack.pop().ok_or_else(malformed)?;
+            let then_branch = stack.pop().ok_or_else(malformed)?;
+            let cond = stack.pop().ok_or_else(malformed)?;
+            stack.push(Node::Cond(
+                Box::new(cond),
+                Box::new(then_branch),
+                Box::new(else_branch),
+                spans,
+            ));
+            Ok::<_, Error>(())
+        };
+
+        for (pc, (tok, span)) in tokens.into_iter().enumerate() {
+            while let Some(&(end, spans)) = cond_ends.last()
+                && end == pc
+            {
+                cond_ends.pop();
+                close_cond(&mut stack, spans)?;
+            }
+
+            match tok {
+                AstToken::Num(num) => stack.push(Node::Num(num, span)),
+                AstToken::Var(var) => stack.push(Node::Var(var, span)),
+                AstToken::Fn(fun, argc) => {
+                    let start = stack.len().checked_sub(argc).ok_or_else(malformed)?;
+                    let args = stack.split_off(start);
+                    stack.push(Node::Call(fun, args, span));
+                }
+                AstToken::Op(op) if op.num_operands() == 1 => {
+                    let operand = stack.pop().ok_or_else(malformed)?;
+                    stack.push(Node::Unary(op, Box::new(operand), span));
+                }
+                AstToken::Op(op) => {
+                    let rhs = stack.pop().ok_or_else(malformed)?;
+                    let lhs = stack.pop().ok_or_else(malformed)?;
+                    stack.push(Node::Binary(op, Box::new(lhs), Box::new(rhs), span));
+                }
+                AstToken::JmpIfFalse(_) => questions.push(span),
+                AstToken::Jmp(offset) => {
+                    let question = questions.pop().ok_or_else(malformed)?;
+                    cond_ends.push((pc + 1 + offset, [question, span]));
+                }
+                AstToken::JmpAnd(_) | AstToken::JmpOr(_) => {}
+                AstToken::Store(slot) => {
+                    let node = stack.last().ok_or_else(malformed)?;
+
+                    if temps.len() <= slot {
+                        temps.resize(slot + 1, None);
+                    }
+                    temps[slot] = Some(node.clone());
+                }
+                AstToken::Load(slot) => {
+                    let node = temps.get(slot).and_then(Option::as_ref);
+                    stack.push(node.ok_or_else(malformed)?.clone());
+                }
+            }
+        }
+
+        while let Some((_, spans)) = cond_ends.pop() {
+            close_cond(&mut stack, spans)?;
+        }
+
+        match (stack.pop(), stack.is_empty()) {
+            (Some(node), true) => Ok(node),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+impl<L> Node<L>
+{
+    /// Number of nodes of the tree
+    pub(super) fn size(&self) -> usize
+    {
+        match self {
+            Node::Num(..) | Node::Var(..) => 1,
+            Node::Call(_, args, _) => 1 + args.iter().map(Node::size).sum::<usize>(),
+            Node::Unary(_, operand, _) => 1 + operand.size(),
+            Node::Binary(_, lhs, rhs, _) => 1 + lhs.size() + rhs.size(),
+            Node::Cond(cond, then_branch, else_branch, _) => {
+                1 + cond.size() + then_branch.size() + else_branch.size()
+            }
+        }
+    }
+
+    /// Span of the token the value of the tree is computed by
+    pub(super) fn span(&self) -> Span
+    {
+        match self {
+            Node::Num(_, span)
+            | Node::Var(_, span)
+            | Node::Call(.., span)
+            | Node::Unary(.., span)
+            | Node::Binary(.., span) => *span,
+            Node::Cond(.., [_, colon]) => *colon,
+        }
+    }
+
+    /// Whether the tree calls no function, so that evaluating it once or
+    /// any number of times gives the same result
+    pub(super) fn is_call_free(&self) -> bool
+    {
+        match self {
+            Node::Num(..) | Node::Var(..) => true,
+            Node::Call(..) => false,
+            Node::Unary(_, operand, _) => operand.is_call_free(),
+            Node::Binary(_, lhs, rhs, _) => lhs.is_call_free() && rhs.is_call_free(),
+            Node::Cond(cond, then_branch, else_branch, _) => {
+                cond.is_call_free() && then_branch.is_call_free() && else_branch.is_call_free()
+            }
+        }
+    }
+}
+
+impl<L: PartialEq> Node<L>
+{
+    /// Whether both trees have the same structure and leaves, wherever they
+    /// come from
+    pub(super) fn same(&self, other: &Node<L>) -> bool
+    {
+        match (self, other) {
+            (Node::Num(a, _), Node::Num(b, _)) => a.to_bits() == b.to_bits(),
+            (Node::Var(a, _), Node::Var(b, _)) => a == b,
+            (Node::Call(a, a_args, _), Node::Call(b, b_args, _)) => {
+                a == b
+                    && a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args).all(|(a, b)| a.same(b))
+            }
+            (Node::Unary(a_op, a, _), Node::Unary(b_op, b, _)) => a_op == b_op && a.same(b),
+            (Node::Binary(a_op, a_lhs, a_rhs, _), Node::Binary(b_op, b_lhs, b_rhs, _)) => {
+                a_op == b_op && a_lhs.same(b_lhs) && a_rhs.same(b_rhs)
+            }
+            (Node::Cond(a_cond, a_then, a_else, _), Node::Cond(b_cond, b_then, b_else, _)) => {
+                a_cond.same(b_cond) && a_then.same(b_then) && a_else.same(b_else)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<T: Token> Node<T>
+{
+    /// Appends the tokens of the tree to `tokens` and their spans to `spans`,
+    /// the way the lexer emits them.
+    pub(super) fn emit(self, tokens: &mut Vec<T>, spans: &mut Vec<Span>)
+    {
+        self.emit_with(tokens, spans, &mut |_| Temp::Keep);
+    }
+
+    /// Emits the tree like [`Node::emit`], calling `temp` on each node in
+    /// evaluation order, parents before their children, to decide whether its
+    /// value goes through a temporary slot. The children of a node read from
+    /// a slot are skipped.
+    pub(super) fn emit_with(
+        self,
+        tokens: &mut Vec<T>,
+        spans: &mut Vec<Span>,
+        temp: &mut impl FnMut(&Node<T>) -> Temp,
+    )
+    {
+        let span = self.span();
+
+        let slot = match temp(&self) {
+            Temp::Keep => None,
+            Temp::Store(slot) => Some(slot),
+            Temp::Load(slot) => {
+                tokens.push(T::load(slot));
+                spans.push(span);
+                return;
+            }
+        };
+
+        match self {
+            Node::Num(num, span) => {
+                tokens.push(T::f64(num));
+                spans.push(span);
+            }
+            Node::Var(var, span) => {
+                tokens.push(var);
+                spans.push(span);
+            }
+            Node::Call(fun, args, span) => {
+                for arg in args {
+                    arg.emit_with(tokens, spans, temp);
+                }
+
+                tokens.push(fun);
+                spans.push(span);
+            }
+            Node::Unary(op, operand, span) => {
+                operand.emit_with(tokens, spans, temp);
+                tokens.push(T::op(op));
+                spans.push(span);
+            }
+            Node::Binary(op @ (Op::And | Op::Or), lhs, rhs, span)
+                if !matches!(*lhs, Node::Num(..)) =>
+            {
+                lhs.emit_with(tokens, spans, temp);
+
+                let jmp_idx = tokens.len();
+                tokens.push(T::jmp(0));
+                spans.push(span);
+
+                rhs.emit_with(tokens, spans, temp);
+                tokens.push(T::op(op));
+                spans.push(span);
+
+                let offset = tokens.len() - jmp_idx - 1;
+                tokens[jmp_idx] = match op {
+                    Op::And => T::jmp_and(offset),
+                    _ => T::jmp_or(offset),
+                };
+            }
+            Node::Binary(op, lhs, rhs, span) => {
+                lhs.emit_with(tokens, spans, temp);
+                rhs.emit_with(tokens, spans, temp);
+                tokens.push(T::op(op));
+                spans.push(span);
+            }
+            Node::Cond(cond, then_branch, else_branch, [question, colon]) => {
+                cond.emit_with(tokens, spans, temp);
+
+                let cond_jmp_idx = tokens.len();
+                tokens.push(T::jmp_if_false(0));
+                spans.push(question);
+                then_branch.emit_with(tokens, spans, temp);
+
+                let else_jmp_idx = tokens.len();
+                tokens[cond_jmp_idx] = T::jmp_if_false(else_jmp_idx - cond_jmp_idx);
+                tokens.push(T::jmp(0));
+                spans.push(colon);
+                else_branch.emit_with(tokens, spans, temp);
+
+                tokens[else_jmp_idx] = T::jmp(tokens.len() - else_jmp_idx - 1);
+            }
+        }
+
+        if let Some(slot) = slot {
+            tokens.push(T::store(slot));
+            spans.push(span);
+        }
+    }
+}
+
+impl<'e, N: Into<Cow<'e, str>>> From<Node<N>> for Ast<'e>
+{
+    fn from(node: Node<N>) -> Self
+    {
+        match node {
+            Node::Num(num, _) => Ast::Num(num),
+            Node::Var(name, _) => Ast::Var(name.into()),
+            Node::Call(name, args, _) => {
+                Ast::Call(name.into(), args.into_iter().map(Ast::from).collect())
+            }
+            Node::Unary(op, operand, _) => Ast::Unary(op, Box::new(Ast::from(*operand))),
+            Node::Binary(op, lhs, rhs, _) => {
+                Ast::Binary(op, Box::new(Ast::from(*lhs)), Box::new(Ast::from(*rhs)))
+            }
+            Node::Cond(cond, then_branch, else_branch, _) => Ast::Cond(
+                Box::new(Ast::from(*cond)),
+                Box::new(Ast::from(*then_branch)),
+                Box::new(Ast::from(*else_branch)),
+            ),
+        }
+    }
+}
+
+impl<'e> From<Ast<'e>> for Node<Cow<'e, str>>
+{
+    fn from(ast: Ast<'e>) -> Self
+    {
+        let span = Span::default();
+
+        match ast {
+            Ast::Num(num) => Node::Num(num, span),
+            Ast::Var(name) => Node::Var(name, span),
+            Ast::Call(name, args) => {
+                Node::Call(name, args.into_iter().map(Node::from).collect(), span)
+            }
+            Ast::Unary(op, operand) => Node::Unary(op, Box::new(Node::from(*operand)), span),
+            Ast::Binary(op, lhs, rhs) => Node::Binary(
+                op,
+                Box::new(Node::from(*lhs)),
+                Box::new(Node::from(*rhs)),
+                span,
+            ),
+            Ast::Cond(cond, then_branch, else_branch) => Node::Cond(
+                Box::new(Node::from(*cond)),
+                Box::new(Node::from(*then_branch)),
+                Box::new(Node::from(*else_branch)),
+                [span; 2],
+            ),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+use crate::{
+    Error, EvalError,
+    expr::{Expr, Op, rpn::Rpn},
+};
+
+/// Scratch `f64` local
+const TMP: u32 = 1;
+/// Scratch `i64` local
+const ITMP: u32 = 2;
+/// First local holding a temporary slot
+const SLOTS: u32 = 3;
+
+/// Standalone WebAssembly module evaluating an expression.
+///
+/// The module exports its `memory` and an `eval(vars: i32) -> f64` function
+/// reading the variables as consecutive `f64` values at the address `vars`,
+/// in the order given by [`Wasm::vars`]. Each function of the expression is
+/// imported from the `env` module with its name and one `f64` parameter per
+/// argument, see [`Wasm::fns`]. Expressions using `^` or `%` also import
+/// `fee.pow` and `fee.mod`, which take the two operands and must apply the
+/// operator the same way fee does:
+///
+/// - `fee.mod(x, y)` is the remainder truncated towards zero, `x % y` in Rust
+///   and in JavaScript.
+/// - `fee.pow(x, y)` is `x.powi(y as i32)` if `y` is an integer
+///   (`y as i64 as f64 == y`), and `x.powf(y)` otherwise. `powi` saturates the
+///   exponent to an `i32` and multiplies the squares of `x` selected by its
+///   bits, which can round differently from `pow`. `powf` is the C `pow`,
+///   which unlike `Math.pow` gives `1` for `pow(1, NaN)` and `pow(-1, ±∞)`.
+///
+/// `js/fee.mjs` implements these imports in JavaScript. Apart from the last
+/// bit of `pow` with a non-integer exponent, which depends on the `pow` of the
+/// platform as it does for fee itself, its results are the ones of fee.
+///
+/// It is built from an expression compiled against a default context with
+/// `Wasm::try_from`, so the same formulas evaluated with fee can be evaluated
+/// by any WebAssembly runtime, such as a browser.
+///
+/// # Examples
+/// ```rust
+/// use fee::{DefaultResolver, Wasm, prelude::*};
+///
+/// let ctx = Context::new(DefaultResolver::<_, String, _>::empty(), DefaultResolver::new_fns());
+/// let expr = Expr::compile("x * 2 + f(y, x) ^ 2", &ctx).unwrap();
+///
+/// let wasm = Wasm::try_from(&expr).unwrap();
+/// assert_eq!(wasm.vars(), ["x", "y"]);
+/// assert_eq!(wasm.fns(), [("f", 2)]);
+/// assert!(wasm.bytes().starts_with(b"\0asm"));
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Wasm<'e>
+{
+    bytes: Vec<u8>,
+    vars: Vec<&'e str>,
+    fns: Vec<(&'e str, usize)>,
+}
+
+impl<'e> Wasm<'e>
+{
+    /// Returns the encoded module.
+    pub fn bytes(&self) -> &[u8]
+    {
+        &self.bytes
+    }
+
+    /// Returns the encoded module, consuming it.
+    pub fn into_bytes(self) -> Vec<u8>
+    {
+        self.bytes
+    }
+
+    /// Returns the variables read by `eval`, the `i`-th one being read at
+    /// `vars + 8 * i`.
+    pub fn vars(&self) -> &[&'e str]
+    {
+        &self.vars
+    }
+
+    /// Returns the functions imported from the `env` module along with their
+    /// number of arguments.
+    pub fn fns(&self) -> &[(&'e str, usize)]
+    {
+        &self.fns
+    }
+}
+
+impl<'e> TryFrom<&Expr<Rpn<'e>>> for Wasm<'e>
+{
+    type Error = Error<'static>;
+
+    fn try_from(expr: &Expr<Rpn<'e>>) -> Result<Self, Self::Error>
+    {
+        let mut vars = Vec::new();
+        let mut fns: Vec<(&str, usize)> = Vec::new();
+        let mut slots = 0;
+        let (mut pow, mut rem) = (false, false);
+
+        for tok in &expr.tokens {
+            match *tok {
+                Rpn::Var(name) if !vars.contains(&name) => vars.push(name),
+                Rpn::Fn(name, argc) => match fns.iter().find(|(n, _)| *n == name) {
+                    Some((_, n)) if *n != argc => {
+                        return Err(Error::CodegenError(format!(
+                            "function `{name}` is called with {n} and {argc} arguments"
+                        )));
+                    }
+                    Some(_) => {}
+                    None => fns.push((name, argc)),
+                },
+                Rpn::Op(Op::Pow) => pow = true,
+                Rpn::Op(Op::Mod) => rem = true,
+                Rpn::Store(slot) => slots = slots.max(slot + 1),
+                _ => {}
+            }
+        }
+
+        let mut types = TypeSection::new();
+        let mut imports = ImportSection::new();
+        // Functions sharing a number of arguments share their type
+        let mut arities: Vec<usize> = Vec::new();
+        let mut ty = |argc: usize| match arities.iter().position(|n| *n == argc) {
+            Some(idx) => idx as u32,
+            None => {
+                types
+                    .ty()
+                    .function(vec![ValType::F64; argc], [ValType::F64]);
+                arities.push(argc);
+                arities.len() as u32 - 1
+            }
+        };
+
+        for (name, argc) in &fns {
+            imports.import("env", name, EntityType::Function(ty(*argc)));
+        }
+        let mut imported = fns.len() as u32;
+        let pow = pow.then(|| {
+            imports.import("fee", "pow", EntityType::Function(ty(2)));
+            imported += 1;
+            imported - 1
+        });
+        let rem = rem.then(|| {
+            imports.import("fee", "mod", EntityType::Function(ty(2)));
+            imported += 1;
+            imported - 1
+        });
+        let eval_ty = arities.len() as u32;
+        types.ty().function([ValType::I32], [ValType::F64]);
+
+        let mut func = Function::new([
+            (1, ValType::F64),
+            (1, ValType::I64),
+            (slots as u32, ValType::F64),
+        ]);
+        let translation = Translation {
+            func: &mut func,
+            vars: &vars,
+            fns: &fns,
+            pow,
+            rem,
+        };
+        translation.translate(&expr.tokens)?;
+
+        let mut functions = FunctionSection::new();
+        functions.function(eval_ty);
+
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+
+        let mut exports = ExportSection::new();
+        exports
+            .export("memory", ExportKind::Memory, 0)
+            .export("eval", ExportKind::Func, imported);
+
+        let mut code = CodeSection::new();
+        code.function(&func);
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&imports)
+            .section(&functions)
+            .section(&memories)
+            .section(&exports)
+            .section(&code);
+
+        Ok(Wasm {
+            bytes: module.finish(),
+            vars,
+            fns,
+        })
+    }
+}
+
+/// Block opened by a jump, closed when the jump target is reached
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Block
+{
+    /// Taken branch of a ternary, followed by a `Jmp` to the end of the other
+    Then,
+    /// Right operand of `&&`, evaluating to `0` if it is skipped
+    And,
+    /// Block without anything left to emit before its `end`
+    End,
+}
+
+/// Translates postfix tokens into the body of the `eval` function
+struct Translation<'t, 'e>
+{
+    func: &'t mut Function,
+    vars: &'t [&'e str],
+    fns: &'t [(&'e str, usize)],
+    pow: Option<u32>,
+    rem: Option<u32>,
+}
+
+impl Translation<'_, '_>
+{
+    fn translate(mut self, tokens: &[Rpn<'_>]) -> Result<(), Error<'static>>
+    {
+        let malformed = || Error::EvalError(EvalError::MalformedExpression);
+        // Blocks are nested, so they are closed in the reverse order
+        let mut blocks: Vec<(usize, Block)> = Vec::new();
+
+        for pc in 0..=tokens.len() {
+            while let Some(&(target, block)) = blocks.last() {
+                if target != pc {
+                    break;
+                }
+
+                match block {
+                    Block::Then => return Err(malformed()),
+                    Block::And => {
+                        self.emit(Instruction::Else);
+                        self.emit(Instruction::F64Const(0.0));
+                    }
+                    Block::End => {}
+                }
+
+                self.emit(Instruction::End);
+                blocks.pop();
+            }
+
+            let Some(tok) = tokens.get(pc) else {
+                break;
+            };
+            let target = |offset: usize| pc + 1 + offset;
+
+            match *tok {
+                Rpn::Num(num) => self.emit(Instruction::F64Const(num)),
+                Rpn::Var(name) => {
+                    let idx = self.vars.iter().position(|var| *var == name);
+                    self.emit(Instruction::LocalGet(0));
+                    self.emit(Instruction::F64Load(MemArg {
+                        offset: 8 * idx.ok_or_else(malformed)? as u64,
+                        align: 3,
+                        memory_index: 0,
+                    }));
+                }
+                Rpn::Fn(name, _) => {
+                    let idx = self.fns.iter().position(|(n, _)| *n == name);
+                    self.emit(Instruction::Call(idx.ok_or_else(malformed)? as u32));
+                }
+                Rpn::Op(op) => self.op(op)?,
+                Rpn::Jmp(offset) => {
+                    if blocks.pop() != Some((pc + 1, Block::Then)) {
+                        return Err(malformed());
+                    }
+
+                    self.emit(Instruction::Else);
+                    blocks.push((target(offset), Block::End));
+                }
+                Rpn::JmpIfFalse(offset) => {
+                    self.truthy();
+                    self.emit(Instruction::If(BlockType::Result(ValType::F64)));
+                    blocks.push((target(offset), Block::Then));
+                }
+                Rpn::JmpAnd(offset) => {
+                    self.emit(Instruction::LocalTee(TMP));
+                    self.truthy();
+                    self.emit(Instruction::If(BlockType::Result(ValType::F64)));
+                    self.emit(Instruction::LocalGet(TMP));
+                    blocks.push((target(offset), Block::And));
+                }
+                Rpn::JmpOr(offset) => {
+                    self.emit(Instruction::LocalTee(TMP));
+                    self.truthy();
+                    self.emit(Instruction::If(BlockType::Result(ValType::F64)));
+                    self.emit(Instruction::F64Const(1.0));
+                    self.emit(Instruction::Else);
+                    self.emit(Instruction::LocalGet(TMP));
+                    blocks.push((target(offset), Block::End));
+                }
+                Rpn::Store(slot) => self.emit(Instruction::LocalTee(SLOTS + slot as u32)),
+                Rpn::Load(slot) => self.emit(Instruction::LocalGet(SLOTS + slot as u32)),
+            }
+        }
+
+        if !blocks.is_empty() {
+            return Err(malformed());
+        }
+
+        self.emit(Instruction::End);
+        Ok(())
+    }
+
+    fn op(&mut self, op: Op) -> Result<(), Error<'static>>
+    {
+        let malformed = || Error::EvalError(EvalError::MalformedExpression);
+
+        match op {
+            Op::Add => self.emit(Instruction::F64Add),
+            Op::Sub => self.emit(Instruction::F64Sub),
+            Op::Mul => self.emit(Instruction::F64Mul),
+            Op::Div => self.emit(Instruction::F64Div),
+            Op::Pow => self.emit(Instruction::Call(self.pow.ok_or_else(malformed)?)),
+            Op::Mod => self.emit(Instruction::Call(self.rem.ok_or_else(malformed)?)),
+            Op::Neg => self.emit(Instruction::F64Neg),
+            Op::Not => {
+                self.emit(Instruction::F64Const(0.0));
+                self.emit(Instruction::F64Eq);
+                self.emit(Instruction::F64ConvertI32U);
+            }
+            Op::And | Op::Or => {
+                self.emit(Instruction::LocalSet(TMP));
+                self.truthy();
+                self.emit(Instruction::LocalGet(TMP));
+                self.truthy();
+                self.emit(if op == Op::And {
+                    Instruction::I32And
+                } else {
+                    Instruction::I32Or
+                });
+                self.emit(Instruction::F64ConvertI32U);
+            }
+            Op::Low | Op::Great | Op::LowEq | Op::GreatEq | Op::Eq | Op::NotEq => {
+                self.emit(match op {
+                    Op::Low => Instruction::F64Lt,
+                    Op::Great => Instruction::F64Gt,
+                    Op::LowEq => Instruction::F64Le,
+                    Op::GreatEq => Instruction::F64Ge,
+                    Op::Eq => Instruction::F64Eq,
+                    _ => Instruction::F64Ne,
+                });
+                self.emit(Instruction::F64ConvertI32U);
+            }
+            Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr => {
+                // Operands are truncated the same way `Op::apply` does
+                self.emit(Instruction::I64TruncSatF64S);
+                self.emit(Instruction::LocalSet(ITMP));
+                self.emit(Instruction::I64TruncSatF64S);
+                self.emit(Instruction::LocalGet(ITMP));
+                self.emit(match op {
+                    Op::BitAnd => Instruction::I64And,
+                    Op::BitOr => Instruction::I64Or,
+                    Op::BitXor => Instruction::I64Xor,
+                    Op::Shl => Instruction::I64Shl,
+                    _ => Instruction::I64ShrS,
+                });
+                self.emit(Instruction::F64ConvertI64S);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Turns the value on top of the stack into an `i32` condition
+    fn truthy(&mut self)
+    {
+        self.emit(Instruction::F64Const(0.0));
+        self.emit(Instruction::F64Ne);
+    }
+
+    fn emit(&mut self, instr: Instruction<'_>)
+    {
+        self.func.instruction(&instr);
+    }
+}
@@ -0,0 +1,316 @@
+use std::{fmt, ops::Deref, sync::Arc};
+
+/// A function callable from an expression.
+///
+/// It can be built from a plain function pointer with [`ExprFn::new`], which
+/// keeps the fastest call path, or from a closure capturing its own state
+/// (lookup tables, calibration curves, RNGs...) with [`ExprFn::from_closure`].
+///
+/// # Examples
+///
+/// ```rust
+/// use fee::{prelude::*, DefaultResolver, EmptyResolver};
+///
+/// let table = vec![1.0, 2.0, 4.0, 8.0];
+///
+/// let mut fn_resolver = DefaultResolver::empty();
+/// fn_resolver.insert("lookup", ExprFn::from_closure(move |x| table[x[0] as usize]));
+///
+/// let context = Context::new(EmptyResolver::new(), fn_resolver);
+/// let mut stack = Vec::new();
+///
+/// let expr = Expr::compile("lookup(2) + lookup(3)", &context).unwrap();
+/// assert_eq!(expr.eval(&context, &mut stack), Ok(12.0));
+/// ```
+///
+/// The number of arguments accepted by the function can be declared with
+/// [`ExprFn::with_arity`]. Calls with a wrong number of arguments are then
+/// rejected when compiling the expression, as long as the function can be
+/// resolved at that moment.
+///
+/// A derivative companion can be attached with [`ExprFn::with_derivative`], it
+/// is needed to evaluate the gradient of the expressions calling the function.
+/// A vectorized companion can be attached with [`ExprFn::with_vectorized`], it
+/// is used to call the function over whole blocks of rows in batch evaluations.
+///
+/// Functions are assumed to be pure, always returning the same result for the
+/// same arguments, so that their repeated calls can be merged. Functions with
+/// side effects or hidden state (random numbers, counters...) must be marked
+/// with [`ExprFn::impure`].
+#[derive(Clone)]
+pub struct ExprFn
+{
+    f: FnKind,
+    arity: Arity,
+    derivative: Option<Arc<DynDerivative>>,
+    vectorized: Option<Arc<DynVectorized>>,
+    pure: bool,
+}
+
+type DynFn = dyn Fn(&[f64]) -> f64 + Send + Sync;
+type DynDerivative = dyn Fn(&[f64], &mut [f64]) + Send + Sync;
+type DynVectorized = dyn Fn(&[&[f64]], &mut [f64]) + Send + Sync;
+
+#[derive(Clone)]
+enum FnKind
+{
+    Ptr(fn(&[f64]) -> f64),
+    Closure(Arc<DynFn>),
+}
+
+impl ExprFn
+{
+    pub fn new(f: fn(&[f64]) -> f64) -> Self
+    {
+        ExprFn {
+            f: FnKind::Ptr(f),
+            arity: Arity::Variadic(0),
+            derivative: None,
+            vectorized: None,
+            pure: true,
+        }
+    }
+
+    pub fn from_closure<C>(f: C) -> Self
+    where
+        C: Fn(&[f64]) -> f64 + Send + Sync + 'static,
+    {
+        ExprFn {
+            f: FnKind::Closure(Arc::new(f)),
+            arity: Arity::Variadic(0),
+            derivative: None,
+            vectorized: None,
+            pure: true,
+        }
+    }
+
+    /// Sets the number of arguments the function accepts.
+    ///
+    /// # Panics
+    /// Panics if the minimum of an [`Arity::Range`] is greater than its maximum.
+    pub fn with_arity(mut self, arity: Arity) -> Self
+    {
+        if let Arity::Range(min, max) = arity {
+            assert!(min <= max, "invalid arity range: {min} > {max}");
+        }
+        self.arity = arity;
+        self
+    }
+
+    pub fn arity(&self) -> Arity
+    {
+        self.arity
+    }
+
+    /// Attaches the derivative of the function, used by
+    /// [`ExprEvaluator::eval_with_gradient`](crate::prelude::ExprEvaluator::eval_with_gradient).
+    ///
+    /// The derivative receives the arguments of the call and writes the partial
+    /// derivative with respect to each of them in the second slice, which has
+    /// the same length and starts zeroed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use fee::prelude::*;
+    ///
+    /// let hypot = ExprFn::new(|x| x[0].hypot(x[1])).with_derivative(|x, d| {
+    ///     let h = x[0].hypot(x[1]);
+    ///     d[0] = x[0] / h;
+    ///     d[1] = x[1] / h;
+    /// });
+    /// ```
+    pub fn with_derivative<D>(mut self, derivative: D) -> Self
+    where
+        D: Fn(&[f64], &mut [f64]) + Send + Sync + 'static,
+    {
+        self.derivative = Some(Arc::new(derivative));
+        self
+    }
+
+    pub fn derivative(&self) -> Option<&DynDerivative>
+    {
+        self.derivative.as_deref()
+    }
+
+    /// Attaches a vectorized version of the function, called by
+    /// [`ExprEvaluator::eval_batch`](crate::prelude::ExprEvaluator::eval_batch)
+    /// with a block of rows at once instead of once per row.
+    ///
+    /// It receives one slice per argument, holding the value of that argument
+    /// in each row of the block, and writes the result of each row in the
+    /// second slice, which has the same length as the argument slices.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use fee::prelude::*;
+    ///
+    /// let hypot = ExprFn::new(|x| x[0].hypot(x[1])).with_vectorized(|x, out| {
+    ///     for (row, out) in out.iter_mut().enumerate() {
+    ///         *out = x[0][row].hypot(x[1][row]);
+    ///     }
+    /// });
+    /// ```
+    pub fn with_vectorized<C>(mut self, vectorized: C) -> Self
+    where
+        C: Fn(&[&[f64]], &mut [f64]) + Send + Sync + 'static,
+    {
+        self.vectorized = Some(Arc::new(vectorized));
+        self
+    }
+
+    pub fn vectorized(&self) -> Option<&DynVectorized>
+    {
+        self.vectorized.as_deref()
+    }
+
+    /// Marks the function as impure, so that the `eliminate_common_subexprs`
+    /// methods of the compiled expressions never merge two of its calls, even
+    /// with the same arguments.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// use fee::prelude::*;
+    ///
+    /// let counter = AtomicUsize::new(0);
+    /// let next = ExprFn::from_closure(move |_| counter.fetch_add(1, Ordering::Relaxed) as f64)
+    ///     .impure();
+    /// assert!(!next.is_pure());
+    /// ```
+    pub fn impure(mut self) -> Self
+    {
+        self.pure = false;
+        self
+    }
+
+    pub fn is_pure(&self) -> bool
+    {
+        self.pure
+    }
+
+    #[inline]
+    pub fn call(&self, args: &[f64]) -> f64
+    {
+        match &self.f {
+            FnKind::Ptr(f) => f(args),
+            FnKind::Closure(f) => f(args),
+        }
+    }
+}
+
+impl Deref for ExprFn
+{
+    type Target = DynFn;
+
+    fn deref(&self) -> &Self::Target
+    {
+        match &self.f {
+            FnKind::Ptr(f) => f,
+            FnKind::Closure(f) => f.as_ref(),
+        }
+    }
+}
+
+impl PartialEq for ExprFn
+{
+    fn eq(&self, other: &Self) -> bool
+    {
+        (match (&self.f, &other.f) {
+            (FnKind::Ptr(a), FnKind::Ptr(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (FnKind::Closure(a), FnKind::Closure(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }) && self.arity == other.arity
+            && match (&self.derivative, &other.derivative) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && match (&self.vectorized, &other.vectorized) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+            && self.pure == other.pure
+    }
+}
+
+impl fmt::Debug for ExprFn
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match &self.f {
+            FnKind::Ptr(ptr) => f
+                .debug_struct("ExprFn")
+                .field("f", ptr)
+                .field("arity", &self.arity)
+                .field("derivative", &self.derivative.is_some())
+                .field("vectorized", &self.vectorized.is_some())
+                .field("pure", &self.pure)
+                .finish(),
+            FnKind::Closure(_) => f
+                .debug_struct("ExprFn")
+                .field("f", &"<closure>")
+                .field("arity", &self.arity)
+                .field("derivative", &self.derivative.is_some())
+                .field("vectorized", &self.vectorized.is_some())
+                .field("pure", &self.pure)
+                .finish(),
+        }
+    }
+}
+
+impl Default for ExprFn
+{
+    fn default() -> Self
+    {
+        fn identity(_: &[f64]) -> f64
+        {
+            0.0
+        }
+        ExprFn::new(identity)
+    }
+}
+
+/// Number of arguments accepted by an [`ExprFn`].
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Arity
+{
+    /// Exactly the given number of arguments
+    Exact(usize),
+    /// Between the given minimum and maximum number of arguments, both inclusive
+    Range(usize, usize),
+    /// At least the given number of arguments
+    Variadic(usize),
+}
+
+impl Arity
+{
+    #[inline]
+    pub fn accepts(&self, argc: usize) -> bool
+    {
+        match *self {
+            Arity::Exact(n) => argc == n,
+            Arity::Range(min, max) => (min..=max).contains(&argc),
+            Arity::Variadic(min) => argc >= min,
+        }
+    }
+}
+
+impl fmt::Display for Arity
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        fn plural(n: usize) -> &'static str
+        {
+            if n == 1 { "argument" } else { "arguments" }
+        }
+
+        match *self {
+            Arity::Exact(n) => write!(f, "exactly {n} {}", plural(n)),
+            Arity::Range(min, max) => write!(f, "between {min} and {max} arguments"),
+            Arity::Variadic(min) => write!(f, "at least {min} {}", plural(min)),
+        }
+    }
+}
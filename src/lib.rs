@@ -47,54 +47,43 @@
 //! - [`EmptyResolver`]: Always resolves to `None`; useful for expressions without variables or functions.  
 
 #![forbid(clippy::unwrap_used)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
 
+#[cfg(feature = "capi")]
+pub mod capi;
 mod context;
 mod error;
 mod expr;
+mod function;
 mod parsing;
 mod resolver;
 
 pub mod prelude;
 
-use std::ops::Deref;
-
 pub use crate::context::{LContext, UContext};
 pub use crate::error::*;
-pub use crate::expr::{ifrpn::IFRpn, irpn::IRpn, ivrpn::IVRpn, lrpn::LRpn, rpn::Rpn};
+#[cfg(feature = "jit")]
+pub use crate::expr::jit::Jit;
+#[cfg(feature = "rayon")]
+pub use crate::expr::par::par_eval_batch;
+#[cfg(feature = "wasm")]
+pub use crate::expr::wasm::Wasm;
+pub use crate::expr::{
+    Op,
+    ast::{Ast, parse},
+    codegen,
+    derivative::{Derivatives, derivative},
+    dual::GradVar,
+    ifrpn::IFRpn,
+    irpn::IRpn,
+    ivrpn::IVRpn,
+    lanes::{F64x4, F64x8, Lane},
+    lrpn::LRpn,
+    reg::{Operand, Reg},
+    rpn::Rpn,
+    simplify::SimplifyMode,
+};
+pub use crate::function::{Arity, ExprFn};
 pub use crate::resolver::{
     ConstantResolver, DefaultResolver, EmptyResolver, IndexedResolver, Ptr, SmallResolver,
 };
-
-#[allow(unpredictable_function_pointer_comparisons)]
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct ExprFn(fn(&[f64]) -> f64);
-
-impl ExprFn
-{
-    pub fn new(f: fn(&[f64]) -> f64) -> Self
-    {
-        ExprFn(f)
-    }
-}
-
-impl Deref for ExprFn
-{
-    type Target = fn(&[f64]) -> f64;
-
-    fn deref(&self) -> &Self::Target
-    {
-        &self.0
-    }
-}
-
-impl Default for ExprFn
-{
-    fn default() -> Self
-    {
-        fn identity(_: &[f64]) -> f64
-        {
-            0.0
-        }
-        ExprFn(identity)
-    }
-}
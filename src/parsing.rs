@@ -1,50 +1,190 @@
-use std::{iter::Peekable, str::CharIndices};
+use std::{borrow::Cow, iter::Peekable, str::CharIndices};
 
-pub fn parse_uf64(c: char, chars: &mut Peekable<CharIndices>) -> f64
+use crate::{ParseError, Span};
+
+/// Lexes an unsigned number literal whose first character `c`, at `start`, has
+/// already been consumed, and returns its value.
+///
+/// Decimal literals may have a fraction and an exponent (`6.022e23`), integer
+/// literals may use a `0x`, `0b` or `0o` prefix, and digits may be separated
+/// with `_` (`1_000_000`).
+pub fn parse_number<'e>(
+    input: &'e str,
+    start: usize,
+    c: char,
+    chars: &mut Peekable<CharIndices<'e>>,
+) -> Result<f64, ParseError<'e>>
 {
-    let mut value: f64 = 0.0;
-    let mut frac = 0.1;
-    let mut is_fraction = false;
+    let radix = match (c, chars.peek().map(|&(_, d)| d)) {
+        ('0', Some('x' | 'X')) => 16,
+        ('0', Some('b' | 'B')) => 2,
+        ('0', Some('o' | 'O')) => 8,
+        _ => 10,
+    };
 
-    match c {
-        '0'..='9' => {
-            value = value * 10.0 + (c as u8 - b'0') as f64;
-        }
-        '.' => {
-            is_fraction = true;
-        }
-        _ => unreachable!("can't happend"),
+    if radix != 10 {
+        chars.next();
     }
 
+    let mut prev = c;
+
     while let Some(&(_, d)) = chars.peek() {
         match d {
-            '0'..='9' => {
-                chars.next();
-                if is_fraction {
-                    value += (d as u8 - b'0') as f64 * frac;
-                    frac *= 0.1;
-                } else {
-                    value = value * 10.0 + (d as u8 - b'0') as f64;
-                }
+            '+' | '-' if radix == 10 && matches!(prev, 'e' | 'E') => {}
+            '.' if radix == 10 => {}
+            d if d.is_ascii_alphanumeric() || d == '_' => {}
+            _ => break,
+        }
+
+        prev = d;
+        chars.next();
+    }
+
+    let end = chars.peek().map_or(input.len(), |&(i, _)| i);
+    let text = &input[start..end];
+    let invalid = || ParseError::InvalidNumber(Cow::Borrowed(text), Span::new(start, end));
+
+    match radix {
+        10 => parse_decimal(text).ok_or_else(invalid),
+        _ => parse_radix(&text[2..], radix).ok_or_else(invalid),
+    }
+}
+
+/// Exact powers of ten representable as `f64`
+const POW10: [f64; 23] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16,
+    1e17, 1e18, 1e19, 1e20, 1e21, 1e22,
+];
+
+/// Largest integer below which every integer is exactly representable as `f64`
+const MAX_EXACT_INT: u64 = 1 << 53;
+
+/// Parses a decimal literal with an optional fraction and exponent, correctly
+/// rounded to the nearest `f64`.
+fn parse_decimal(text: &str) -> Option<f64>
+{
+    let (mantissa, exp) = match text.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => (mantissa, Some(exp)),
+        None => (text, None),
+    };
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit() || b == b'_');
+    let starts_with_digit = |s: &str| s.bytes().next().is_some_and(|b| b.is_ascii_digit());
+
+    if !is_digits(int) || !is_digits(frac) || !(starts_with_digit(int) || starts_with_digit(frac)) {
+        return None;
+    }
+    if !frac.is_empty() && !starts_with_digit(frac) {
+        return None;
+    }
+
+    let mut exp10: i32 = match exp {
+        Some(exp) => {
+            let (negative, digits) = match exp.as_bytes().first() {
+                Some(b'-') => (true, &exp[1..]),
+                Some(b'+') => (false, &exp[1..]),
+                _ => (false, exp),
+            };
+
+            if !is_digits(digits) || !starts_with_digit(digits) {
+                return None;
             }
-            '.' if !is_fraction => {
-                chars.next();
-                is_fraction = true;
+
+            let exp = digits
+                .bytes()
+                .filter(u8::is_ascii_digit)
+                .fold(0i32, |acc, d| {
+                    acc.saturating_mul(10).saturating_add((d - b'0') as i32)
+                });
+
+            if negative { -exp } else { exp }
+        }
+        None => 0,
+    };
+
+    // Fast path: the digits fit in the mantissa and the power of ten is exact,
+    // so a single rounding of the product or quotient is correctly rounded
+    let mut digits: u64 = 0;
+    let mut fits = true;
+
+    for d in int.bytes().chain(frac.bytes()).filter(u8::is_ascii_digit) {
+        match digits
+            .checked_mul(10)
+            .and_then(|x| x.checked_add((d - b'0') as u64))
+        {
+            Some(x) if x < MAX_EXACT_INT => digits = x,
+            _ => {
+                fits = false;
+                break;
             }
-            _ => break,
         }
     }
 
-    value
+    if fits {
+        let frac_digits = frac.bytes().filter(u8::is_ascii_digit).count() as i32;
+        exp10 = exp10.saturating_sub(frac_digits);
+
+        match exp10 {
+            0 => return Some(digits as f64),
+            1..=22 => return Some(digits as f64 * POW10[exp10 as usize]),
+            -22..=-1 => return Some(digits as f64 / POW10[-exp10 as usize]),
+            _ => {}
+        }
+    }
+
+    // Slow path, the standard library parser is correctly rounded
+    if text.contains('_') {
+        text.replace('_', "").parse().ok()
+    } else {
+        text.parse().ok()
+    }
 }
 
-pub fn parse_usize(s: &[u8]) -> usize
+/// Parses the digits of a `0x`, `0b` or `0o` integer literal
+fn parse_radix(digits: &str, radix: u32) -> Option<f64>
 {
-    let mut result = 0;
+    let mut value: u64 = 0;
+    let mut has_digits = false;
 
-    for &byte in s {
-        result = result * 10 + (byte - b'0');
+    for d in digits.chars().filter(|&d| d != '_') {
+        let d = d.to_digit(radix)?;
+        value = value.checked_mul(radix as u64)?.checked_add(d as u64)?;
+        has_digits = true;
     }
 
-    result as usize
+    has_digits.then_some(value as f64)
+}
+
+/// Splits a name following the `letter + digits` convention (e.g. `"p12"`)
+/// into the letter identifier, starting at 0 for `'a'`, and the index.
+///
+/// Returns `None` if the name doesn't follow the convention or the index
+/// overflows.
+pub fn parse_indexed_name(name: &str) -> Option<(usize, usize)>
+{
+    let (&letter, digits) = name.as_bytes().split_first()?;
+
+    if !letter.is_ascii_lowercase() || digits.is_empty() {
+        return None;
+    }
+
+    let mut idx: usize = 0;
+
+    for &byte in digits {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+
+        idx = idx.checked_mul(10)?.checked_add((byte - b'0') as usize)?;
+    }
+
+    Some(((letter - b'a') as usize, idx))
+}
+
+/// Builds the name of the item `idx` of the letter identifier `id`, the
+/// inverse of [`parse_indexed_name`].
+pub fn indexed_name(id: usize, idx: usize) -> String
+{
+    format!("{}{}", (id as u8 + b'a') as char, idx)
 }
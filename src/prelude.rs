@@ -1,4 +1,4 @@
-pub use crate::ExprFn;
+pub use crate::{Arity, ExprFn};
 
 pub use crate::context::Context;
 pub use crate::expr::{Expr, ExprCompiler, ExprEvaluator};
@@ -1,10 +1,14 @@
-use std::{borrow::Borrow, collections::HashMap, hash::Hash};
 use ahash::RandomState;
+use std::{
+    borrow::{Borrow, Cow},
+    collections::HashMap,
+    hash::Hash,
+};
 
 use super::Resolver;
 use crate::{
     prelude::*,
-    resolver::{Locked, LockedResolver, ResolverState, Unlocked, UnlockedResolver},
+    resolver::{Locked, LockedResolver, Ptr, ResolverState, Unlocked, UnlockedResolver},
 };
 
 /// General-purpose resolver that stores values indexed by name.
@@ -41,9 +45,17 @@ where
     _state: S,
 }
 
-impl<K, V> LockedResolver<V> for DefaultResolver<Locked, K, V> where
-    K: Borrow<str> + PartialEq<String> + Eq + Hash
+impl<K, V> LockedResolver<V> for DefaultResolver<Locked, K, V>
+where
+    K: Borrow<str> + PartialEq<String> + Eq + Hash,
 {
+    fn name_of(&self, ptr: Ptr<'_, V>) -> Option<Cow<'_, str>>
+    {
+        self.vars
+            .iter()
+            .find(|(_, value)| ptr.points_to(value))
+            .map(|(key, _)| Cow::Borrowed(key.borrow()))
+    }
 }
 impl<K, V> UnlockedResolver<V, DefaultResolver<Locked, K, V>> for DefaultResolver<Unlocked, K, V>
 where
@@ -111,13 +123,29 @@ impl DefaultResolver<Unlocked, String, ExprFn>
     {
         let mut hashmap: HashMap<String, ExprFn, RandomState> = HashMap::default();
 
-        hashmap.insert("abs".to_string(), ExprFn(abs));
-        hashmap.insert("sqrt".to_string(), ExprFn(sqrt));
+        hashmap.insert(
+            "abs".to_string(),
+            ExprFn::new(abs)
+                .with_arity(Arity::Exact(1))
+                .with_derivative(d_abs),
+        );
+        hashmap.insert(
+            "sqrt".to_string(),
+            ExprFn::new(sqrt)
+                .with_arity(Arity::Exact(1))
+                .with_derivative(d_sqrt),
+        );
+        hashmap.insert(
+            "ln".to_string(),
+            ExprFn::new(ln)
+                .with_arity(Arity::Exact(1))
+                .with_derivative(d_ln),
+        );
 
-        return DefaultResolver {
+        DefaultResolver {
             vars: hashmap,
             _state: Unlocked,
-        };
+        }
     }
 }
 
@@ -130,3 +158,23 @@ fn sqrt(x: &[f64]) -> f64
 {
     x[0].sqrt()
 }
+
+fn ln(x: &[f64]) -> f64
+{
+    x[0].ln()
+}
+
+fn d_abs(x: &[f64], d: &mut [f64])
+{
+    d[0] = ((x[0] > 0.0) as i8 - (x[0] < 0.0) as i8) as f64;
+}
+
+fn d_sqrt(x: &[f64], d: &mut [f64])
+{
+    d[0] = 0.5 / x[0].sqrt();
+}
+
+fn d_ln(x: &[f64], d: &mut [f64])
+{
+    d[0] = 1.0 / x[0];
+}
@@ -40,3 +40,11 @@ impl EmptyResolver<Unlocked>
         EmptyResolver { _state: Unlocked }
     }
 }
+
+impl Default for EmptyResolver<Unlocked>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -1,6 +1,8 @@
+use std::borrow::Cow;
+
 use crate::{
     parsing,
-    resolver::{Locked, LockedResolver, ResolverState, Unlocked, UnlockedResolver},
+    resolver::{Locked, LockedResolver, Ptr, ResolverState, Unlocked, UnlockedResolver},
 };
 
 use super::Resolver;
@@ -22,10 +24,16 @@ use super::Resolver;
 /// # Disadvantages
 /// - Limited naming convention.
 ///
+/// Names that don't follow the `"letter + number"` format, use a letter never
+/// registered with [`add_id`](IndexedResolver::add_id) or an index out of its
+/// bounds don't resolve, and compiling an expression that uses them returns
+/// [`Error::UnknownVar`](crate::Error::UnknownVar) or
+/// [`Error::UnknownFn`](crate::Error::UnknownFn).
+///
 /// # Panics
-/// The `resolve()` method will panic if:
-/// - The variable name does not follow the expected `"letter + number"` format.
-/// - The letter or index is out of bounds of the internal storage.
+/// [`add_id`](IndexedResolver::add_id) and [`set`](IndexedResolver::set) panic if
+/// the id is not a lowercase ASCII letter, and `set` also panics if the index is
+/// out of bounds.
 ///
 /// # Examples
 /// ```rust
@@ -61,7 +69,16 @@ pub struct IndexedResolver<S: ResolverState, T>
     _state: S,
 }
 
-impl<T> LockedResolver<T> for IndexedResolver<Locked, T> {}
+impl<T> LockedResolver<T> for IndexedResolver<Locked, T>
+{
+    fn name_of(&self, ptr: Ptr<'_, T>) -> Option<Cow<'_, str>>
+    {
+        self.vars.iter().enumerate().find_map(|(id, values)| {
+            let idx = values.iter().position(|value| ptr.points_to(value))?;
+            Some(Cow::Owned(parsing::indexed_name(id, idx)))
+        })
+    }
+}
 impl<T> UnlockedResolver<T, IndexedResolver<Locked, T>> for IndexedResolver<Unlocked, T>
 {
     fn lock(self) -> IndexedResolver<Locked, T>
@@ -83,11 +100,8 @@ where
     #[inline(always)]
     fn resolve(&self, name: &str) -> Option<&T>
     {
-        let name_bytes = name.as_bytes();
-
-        let letter = name_bytes[0] as usize - ALPHABET_START_USIZE;
-        let idx = parsing::parse_usize(&name_bytes[1..]);
-        Some(&self.vars[letter][idx])
+        let (id, idx) = self.index_of(name)?;
+        self.get(id, idx)
     }
 }
 
@@ -102,7 +116,15 @@ where
 
     pub(crate) fn get(&self, id: usize, index: usize) -> Option<&T>
     {
-        self.vars[id].get(index)
+        self.vars.get(id)?.get(index)
+    }
+
+    /// Returns the id and index `name` resolves to, or `None` if it doesn't follow
+    /// the naming convention or is out of the bounds registered with `add_id`.
+    pub(crate) fn index_of(&self, name: &str) -> Option<(usize, usize)>
+    {
+        let (id, idx) = parsing::parse_indexed_name(name)?;
+        self.get(id, idx).map(|_| (id, idx))
     }
 }
 
@@ -121,3 +143,11 @@ impl<T: Default + Clone> IndexedResolver<Unlocked, T>
         self.vars[id as usize - ALPHABET_START_USIZE] = vec![T::default(); len]
     }
 }
+
+impl<T: Default + Clone> Default for IndexedResolver<Unlocked, T>
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
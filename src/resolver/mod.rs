@@ -1,4 +1,6 @@
-use std::{collections::HashMap, marker::PhantomData};
+use std::{borrow::Cow, collections::HashMap, fmt, marker::PhantomData};
+
+use crate::ExprFn;
 
 mod constant;
 mod default;
@@ -21,6 +23,14 @@ pub trait LockedResolver<T>: Resolver<Locked, T>
             _marker: std::marker::PhantomData,
         })
     }
+
+    /// Returns the name `ptr` was obtained from with
+    /// [`get_ptr`](LockedResolver::get_ptr), or `None` if the resolver can't
+    /// tell. Used to print the expressions compiled against a locked context.
+    fn name_of(&self, _ptr: Ptr<'_, T>) -> Option<Cow<'_, str>>
+    {
+        None
+    }
 }
 
 pub trait UnlockedResolver<T, R: LockedResolver<T>>: Resolver<Unlocked, T>
@@ -44,7 +54,19 @@ impl ResolverState for Unlocked {}
 /// This struct holds a pointer to a value of type `T`. Used by
 /// locked resolvers to safely access and modify a value without
 /// having to resolve the name.
-#[derive(Debug, PartialEq, Copy, Clone)]
+///
+/// # Thread safety
+///
+/// `Ptr` is `Send` and `Sync` when `T` is, so that the expressions compiled
+/// against a locked context, which hold pointers to its values, can be
+/// evaluated from several threads at once. Evaluating an expression only reads
+/// through its pointers.
+///
+/// Writes with [`Ptr::set`] are not synchronized: values must be set before
+/// the context and its expressions are shared between threads, and not while
+/// an evaluation such as `par_eval_batch` runs.
+#[derive(Debug, PartialEq)]
+#[repr(transparent)]
 pub struct Ptr<'a, T>
 {
     ptr: *mut T,
@@ -52,9 +74,40 @@ pub struct Ptr<'a, T>
     _marker: PhantomData<&'a ()>,
 }
 
+impl<T> Clone for Ptr<'_, T>
+{
+    fn clone(&self) -> Self
+    {
+        *self
+    }
+}
+
+impl<T> Copy for Ptr<'_, T> {}
+
+// Shared like a `&'a T`, see the thread safety section above for the writes
+unsafe impl<T: Send + Sync> Send for Ptr<'_, T> {}
+unsafe impl<T: Send + Sync> Sync for Ptr<'_, T> {}
+
+impl<T> fmt::Pointer for Ptr<'_, T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
 impl<'a, T> Ptr<'a, T>
 where
     T: Copy,
+{
+    #[inline]
+    pub fn get(&self) -> T
+    {
+        unsafe { *self.ptr }
+    }
+}
+
+impl<T> Ptr<'_, T>
 {
     #[inline]
     pub fn set(&self, value: T)
@@ -64,10 +117,43 @@ where
         }
     }
 
+    /// Returns whether the pointer points to `value`.
     #[inline]
-    pub fn get(&self) -> T
+    pub(crate) fn points_to(&self, value: &T) -> bool
     {
-        unsafe { *self.ptr }
+        std::ptr::eq(self.ptr, value)
+    }
+
+    /// Returns the address of the pointed value.
+    #[cfg(feature = "jit")]
+    #[inline]
+    pub(crate) fn as_ptr(&self) -> *const T
+    {
+        self.ptr
+    }
+}
+
+impl<'a> Ptr<'a, ExprFn>
+{
+    /// Returns a clone of the pointed function, sharing the state it captures.
+    #[inline]
+    pub fn get(&self) -> ExprFn
+    {
+        self.as_fn().clone()
+    }
+
+    /// Borrows the pointed function.
+    #[inline]
+    pub(crate) fn as_fn(&self) -> &'a ExprFn
+    {
+        unsafe { &*self.ptr }
+    }
+
+    /// Calls the pointed function without cloning it.
+    #[inline]
+    pub(crate) fn call(&self, args: &[f64]) -> f64
+    {
+        unsafe { (*self.ptr).call(args) }
     }
 }
 
@@ -1,6 +1,8 @@
+use std::borrow::Cow;
+
 use crate::{
     prelude::*,
-    resolver::{Locked, LockedResolver, ResolverState, Unlocked, UnlockedResolver},
+    resolver::{Locked, LockedResolver, Ptr, ResolverState, Unlocked, UnlockedResolver},
 };
 
 const CACHE_SIZE: usize = 10; // 30 is the 'limit'
@@ -30,7 +32,18 @@ where
     _state: S,
 }
 
-impl<K, V> LockedResolver<V> for SmallResolver<Locked, K, V> where K: AsRef<str> + Eq {}
+impl<K, V> LockedResolver<V> for SmallResolver<Locked, K, V>
+where
+    K: AsRef<str> + Eq,
+{
+    fn name_of(&self, ptr: Ptr<'_, V>) -> Option<Cow<'_, str>>
+    {
+        self.cache
+            .iter()
+            .find(|(_, value)| ptr.points_to(value))
+            .map(|(key, _)| Cow::Borrowed(key.as_ref()))
+    }
+}
 impl<K, V> UnlockedResolver<V, SmallResolver<Locked, K, V>> for SmallResolver<Unlocked, K, V>
 where
     K: AsRef<str> + Eq,
@@ -84,3 +97,13 @@ where
         self.cache.push((name, value));
     }
 }
+
+impl<K, V> Default for SmallResolver<Unlocked, K, V>
+where
+    K: AsRef<str> + Eq,
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
@@ -0,0 +1,108 @@
+#include <math.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "fee.h"
+
+#define CHECK(cond)                                                      \
+    do {                                                                 \
+        if (!(cond)) {                                                   \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,       \
+                    __LINE__, #cond);                                    \
+            return 1;                                                    \
+        }                                                                \
+    } while (0)
+
+static double sum(const double *args, size_t argc)
+{
+    double res = 0.0;
+    for (size_t i = 0; i < argc; i++) {
+        res += args[i];
+    }
+    return res;
+}
+
+static double twice(const double *args, size_t argc)
+{
+    return argc == 1 ? 2.0 * args[0] : NAN;
+}
+
+static int test_eval(void)
+{
+    FeeContext *ctx = fee_context_new();
+    CHECK(ctx != NULL);
+    CHECK(fee_context_add_var(ctx, "x", 2.0) == FEE_STATUS_OK);
+    CHECK(fee_context_add_var(ctx, "y", -3.0) == FEE_STATUS_OK);
+    CHECK(fee_context_add_fn(ctx, "sum", sum) == FEE_STATUS_OK);
+    CHECK(fee_context_add_fn(ctx, "twice", twice) == FEE_STATUS_OK);
+
+    FeeExpr *expr = NULL;
+    CHECK(fee_compile(ctx, "x + 1", &expr) == FEE_STATUS_CONTEXT_UNLOCKED);
+    CHECK(expr == NULL);
+
+    CHECK(fee_context_lock(ctx) == FEE_STATUS_OK);
+    CHECK(fee_context_add_var(ctx, "z", 1.0) == FEE_STATUS_CONTEXT_LOCKED);
+    CHECK(fee_context_lock(ctx) == FEE_STATUS_CONTEXT_LOCKED);
+
+    CHECK(fee_compile(ctx, "sum(x, y, twice(x)) * abs(y) + (x > y ? 1 : 0)", &expr) == FEE_STATUS_OK);
+
+    double res = 0.0;
+    CHECK(fee_eval(expr, &res) == FEE_STATUS_OK);
+    CHECK(res == 10.0);
+
+    FeeVar *x = NULL;
+    CHECK(fee_context_var(ctx, "x", &x) == FEE_STATUS_OK);
+    CHECK(fee_get_var(x) == 2.0);
+
+    fee_set_var(x, -4.0);
+    CHECK(fee_get_var(x) == -4.0);
+    CHECK(fee_eval(expr, &res) == FEE_STATUS_OK);
+    CHECK(res == -45.0);
+
+    fee_expr_free(expr);
+    fee_context_free(ctx);
+    return 0;
+}
+
+static int test_errors(void)
+{
+    FeeContext *ctx = fee_context_new();
+    CHECK(fee_context_add_var(ctx, "x", 1.0) == FEE_STATUS_OK);
+    CHECK(fee_context_lock(ctx) == FEE_STATUS_OK);
+
+    FeeExpr *expr = NULL;
+    CHECK(fee_compile(ctx, "x + q", &expr) == FEE_STATUS_UNKNOWN_VAR);
+    CHECK(strstr(fee_last_error(), "'q'") != NULL);
+    CHECK(fee_compile(ctx, "f(x)", &expr) == FEE_STATUS_UNKNOWN_FN);
+    CHECK(fee_compile(ctx, "(x + 1", &expr) == FEE_STATUS_UNMATCHED_PARENTHESES);
+    CHECK(fee_compile(ctx, "x + 1.2.3", &expr) == FEE_STATUS_INVALID_NUMBER);
+    CHECK(fee_compile(ctx, "x $ 1", &expr) == FEE_STATUS_UNEXPECTED_CHAR);
+    CHECK(fee_compile(ctx, "sqrt(x, 2)", &expr) == FEE_STATUS_INVALID_ARG_COUNT);
+    CHECK(fee_compile(ctx, "x +", &expr) == FEE_STATUS_UNEXPECTED_END);
+    CHECK(expr == NULL);
+
+    FeeVar *var = NULL;
+    CHECK(fee_context_var(ctx, "q", &var) == FEE_STATUS_UNKNOWN_VAR);
+    CHECK(var == NULL);
+
+    CHECK(fee_compile(NULL, "x", &expr) == FEE_STATUS_NULL_POINTER);
+    CHECK(fee_compile(ctx, NULL, &expr) == FEE_STATUS_NULL_POINTER);
+    CHECK(fee_eval(NULL, NULL) == FEE_STATUS_NULL_POINTER);
+    CHECK(strcmp(fee_last_error(), "null pointer argument") == 0);
+    CHECK(fee_compile(ctx, "x \xff", &expr) == FEE_STATUS_INVALID_UTF8);
+
+    fee_context_free(ctx);
+    return 0;
+}
+
+int main(void)
+{
+    CHECK(fee_last_error() == NULL);
+
+    if (test_eval() != 0 || test_errors() != 0) {
+        return 1;
+    }
+
+    printf("ok\n");
+    return 0;
+}
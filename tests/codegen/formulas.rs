@@ -0,0 +1,67 @@
+// Generated by fee, do not edit.
+
+/// `y0 * 2 + y1 - (p0 - 1)`
+#[allow(clippy::all)]
+pub fn linear(vars: &[f64]) -> f64 {
+    vars[0] * 2.0 + vars[1] - (vars[2] - 1.0)
+}
+
+/// `(2 + 3) * y0 ^ (4 / 2) - 2 ^ 0.5`
+#[allow(clippy::all)]
+pub fn folded(vars: &[f64]) -> f64 {
+    5.0 * vars[0].powi(2) - 1.4142135623730951
+}
+
+/// `y0 ^ y1 + (-y1) ^ 3 - -y0 ^ 2 + 2 ^ p0`
+#[allow(clippy::all)]
+pub fn powers(vars: &[f64]) -> f64 {
+    ({ let (a, b): (f64, f64) = (vars[0], vars[1]); if b == b as i64 as f64 { a.powi(b as i32) } else { a.powf(b) } }) + (-vars[1]).powi(3) - -vars[0].powi(2) + ({ let (a, b): (f64, f64) = (2.0, vars[2]); if b == b as i64 as f64 { a.powi(b as i32) } else { a.powf(b) } })
+}
+
+/// `!(y0 > 1) + (y1 && p0) * 2 + (y0 < 0 || !p0) * 4 + !!y1`
+#[allow(clippy::all)]
+pub fn logic(vars: &[f64]) -> f64 {
+    f64::from(u8::from(!(vars[0] > 1.0))) + f64::from(u8::from(vars[1] != 0.0 && vars[2] != 0.0)) * 2.0 + f64::from(u8::from(vars[0] < 0.0 || vars[2] == 0.0)) * 4.0 + f64::from(u8::from(!(vars[1] == 0.0)))
+}
+
+/// `(y0 <= y1) + (y0 >= p0) * 2 + (y1 == 2) * 4 + (p0 != p0) * 8 + (y0 < y1 < p0)`
+#[allow(clippy::all)]
+pub fn compare(vars: &[f64]) -> f64 {
+    f64::from(u8::from(vars[0] <= vars[1])) + f64::from(u8::from(vars[0] >= vars[2])) * 2.0 + f64::from(u8::from(vars[1] == 2.0)) * 4.0 + f64::from(u8::from(vars[2] != vars[2])) * 8.0 + f64::from(u8::from(f64::from(u8::from(vars[0] < vars[1])) < vars[2]))
+}
+
+/// `(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2) + (-y0 * 8 >> 1)`
+#[allow(clippy::all)]
+pub fn bitwise(vars: &[f64]) -> f64 {
+    (((vars[0] * 4.0) as i64) & 7) as f64 + ((vars[1] as i64) | 8) as f64 - ((vars[0] as i64) ^ 3) as f64 + ((vars[1] as i64) << 2) as f64 + (((-vars[0] * 8.0) as i64) >> 1) as f64
+}
+
+/// `(y0 ^^ y1 < y1) + (y0 << 1 <= p0) * 2 + !(y1 & 3) + (y0 | 1 ? y0 >> 1 < 2 : 3)`
+#[allow(clippy::all)]
+pub fn casts(vars: &[f64]) -> f64 {
+    f64::from(u8::from((((vars[0] as i64) ^ (vars[1] as i64)) as f64) < vars[1])) + f64::from(u8::from((((vars[0] as i64) << 1) as f64) <= vars[2])) * 2.0 + f64::from(u8::from(((vars[1] as i64) & 3) as f64 == 0.0)) + (if ((vars[0] as i64) | 1) as f64 != 0.0 { f64::from(u8::from((((vars[0] as i64) >> 1) as f64) < 2.0)) } else { 3.0 })
+}
+
+/// `y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)`
+#[allow(clippy::all)]
+pub fn ternary(vars: &[f64]) -> f64 {
+    if vars[0] < 0.0 { 1.0 } else if vars[1] > 2.0 { vars[2] } else { vars[0] + (if vars[2] > 0.0 { vars[1] } else { 2.0 }) }
+}
+
+/// `f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0)) + abs(-y0) + sqrt(abs(y1)) + z()`
+#[allow(clippy::all)]
+pub fn calls(vars: &[f64]) -> f64 {
+    super::f0(&[vars[0] * vars[1]]) + super::f1(&[vars[2], 2.0]) * super::f1(&[vars[1] - 1.0, super::f0(&[vars[2]])]) + (-vars[0]).abs() + vars[1].abs().sqrt() + super::z(&[])
+}
+
+/// `-(y0 - (y1 - p0)) / (y0 * (y1 / p0)) % 3`
+#[allow(clippy::all)]
+pub fn nested(vars: &[f64]) -> f64 {
+    -(vars[0] - (vars[1] - vars[2])) / (vars[0] * (vars[1] / vars[2])) % 3.0
+}
+
+/// `(1 + 2) * 3 > 4 ? 0.1 + 0.2 : 7`
+#[allow(clippy::all)]
+pub fn constant(_vars: &[f64]) -> f64 {
+    0.30000000000000004
+}
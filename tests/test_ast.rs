@@ -0,0 +1,364 @@
+use std::borrow::Cow;
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 6] = [
+    "(-y1 + sqrt(y1 ^ 2 - 4 * y0 * p0)) / (2 * y0)",
+    "y0 > 1 ? abs(p0) : -p0 * 2 ^ 3",
+    "y0 && p0 > 1 || !y1",
+    "false && y0 || (true ? y1 : p0)",
+    "max(y0, p0, 1e3 * 0) - min(y1, 2)",
+    "y0 ? y1 ? 1 : 2 : p0 ? 3 : 4",
+];
+
+fn max(args: &[f64]) -> f64
+{
+    args.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+}
+
+fn min(args: &[f64]) -> f64
+{
+    args.iter().copied().fold(f64::INFINITY, f64::min)
+}
+
+#[test]
+fn test_ast_lowering()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), 2.0);
+    vars.insert("y1".to_string(), -3.0);
+    vars.insert("p0".to_string(), 0.5);
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("max".to_string(), ExprFn::new(max));
+    fns.insert("min".to_string(), ExprFn::new(min));
+
+    let ctx = Context::new(vars, fns);
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let ast = parse(src).unwrap();
+        let lowered = Expr::from_ast(&ast, &ctx).unwrap();
+        let compiled = Expr::compile(src, &ctx).unwrap();
+
+        assert_eq!(
+            lowered.eval(&ctx, &mut stack),
+            compiled.eval(&ctx, &mut stack),
+            "{src}"
+        );
+    }
+
+    let ctx = ctx.lock();
+
+    for src in EXPRS {
+        let ast = parse(src).unwrap();
+        let lowered = Expr::from_ast(&ast, &ctx).unwrap();
+        let compiled = Expr::compile(src, &ctx).unwrap();
+
+        assert_eq!(
+            lowered.eval(&ctx, &mut stack),
+            compiled.eval(&ctx, &mut stack),
+            "{src}"
+        );
+    }
+}
+
+#[test]
+fn test_ast_lowering_indexed()
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, 2.0);
+    vars.set('y', 1, -3.0);
+    vars.add_id('p', 1);
+    vars.set('p', 0, 0.5);
+
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.set('f', 0, ExprFn::new(max));
+    fns.set('f', 1, ExprFn::new(min));
+
+    let ctx = Context::new(vars, fns);
+    let mut stack = Vec::new();
+
+    let src = "f0(y0, p0, 1) - f1(y1, 2) * (y0 > 1 ? p0 : y1)";
+    let ast = parse(src).unwrap();
+    let lowered = Expr::from_ast(&ast, &ctx).unwrap();
+    assert_eq!(lowered.eval(&ctx, &mut stack), Ok(3.5));
+
+    let ast = parse("f0(y2)").unwrap();
+    assert_eq!(
+        Expr::from_ast(&ast, &ctx).unwrap_err(),
+        Error::UnknownVar("y2".into(), Span::default())
+    );
+}
+
+#[test]
+fn test_ast_lowering_discarded()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), 2.0);
+    // Locked contexts resolve the names when compiling
+    let ctx = Context::new(vars, DefaultResolver::new_fns()).lock();
+
+    let cases = [
+        ("1 ? y0 : q0", "q0"),
+        ("0 ? g(y0) : y0", "g"),
+        ("true || q0 * 2", "q0"),
+        ("0 && (y0 ? g(1) : 2)", "g"),
+    ];
+
+    for (src, name) in cases {
+        let compiled = Expr::compile(src, &ctx).unwrap_err();
+        let ast = parse(src).unwrap();
+        let lowered = Expr::from_ast(&ast, &ctx).unwrap_err();
+
+        match (&compiled, &lowered) {
+            (Error::UnknownVar(a, _), Error::UnknownVar(b, _))
+            | (Error::UnknownFn(a, _), Error::UnknownFn(b, _)) => {
+                assert_eq!((a.as_ref(), b.as_ref()), (name, name), "{src}");
+            }
+            _ => panic!("{src}: {compiled:?} != {lowered:?}"),
+        }
+
+        // A tree has no source to point at
+        assert_eq!(lowered.span(), None, "{src}");
+        assert!(!lowered.render(src).contains('^'), "{src}");
+    }
+
+    let ast = Ast::Cond(
+        Box::new(Ast::Num(1.0)),
+        Box::new(Ast::Num(2.0)),
+        Box::new(Ast::Call("abs".into(), vec![])),
+    );
+    assert!(matches!(
+        Expr::from_ast(&ast, &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(..)))
+    ));
+    let ast = parse("1 || q0").unwrap();
+    let err = Expr::from_ast(&ast, &ctx).unwrap_err();
+    assert_eq!(err.to_string(), "unknown variable 'q0'");
+}
+
+#[test]
+fn test_ast_by_hand()
+{
+    let ctx = Context::new(
+        DefaultResolver::<_, String, _>::empty(),
+        DefaultResolver::new_fns(),
+    );
+    let mut stack = Vec::new();
+
+    // Names can be owned, e.g. when generated
+    let ast = Ast::Call(
+        Cow::Owned("sqrt".to_string()),
+        vec![Ast::Binary(
+            Op::Add,
+            Box::new(Ast::Num(7.0)),
+            Box::new(Ast::Unary(Op::Neg, Box::new(Ast::Num(-9.0)))),
+        )],
+    );
+    let expr = Expr::from_ast(&ast, &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(4.0));
+
+    let ast = Ast::Call("abs".into(), vec![Ast::Num(1.0), Ast::Num(2.0)]);
+    assert!(matches!(
+        Expr::from_ast(&ast, &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(
+            _,
+            Arity::Exact(1),
+            2,
+            _
+        )))
+    ));
+
+    let ast = Ast::Binary(Op::Neg, Box::new(Ast::Num(1.0)), Box::new(Ast::Num(2.0)));
+    assert_eq!(
+        Expr::from_ast(&ast, &ctx),
+        Err(Error::InvalidOperator(Op::Neg, 2))
+    );
+}
+
+#[test]
+fn test_parse_errors()
+{
+    assert_eq!(
+        parse("(1 + 2"),
+        Err(Error::ParseError(ParseError::UnmatchedParentheses(
+            Span::new(0, 1)
+        )))
+    );
+    assert_eq!(
+        parse("1 +"),
+        Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+            3, 3
+        ))))
+    );
+
+    // Names are not resolved when parsing
+    assert!(parse("unknown(x, y)").is_ok());
+}
+
+#[test]
+fn test_display_compiled()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("x".to_string(), 2.0);
+    let ctx = Context::new(vars, DefaultResolver::new_fns());
+
+    let expr = Expr::compile("(2 * 3 + x) * (1 + 1) ^ -x - abs(-(4 - 5))", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "(6 + x) * 2 ^ -x - abs(1)");
+
+    let expr = Expr::compile("true && x || (false ? 1 : x > 0 ? 2 : 3)", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "1 && x || (x > 0 ? 2 : 3)");
+
+    let ctx = ctx.lock();
+    let expr = Expr::compile("x * 2", &ctx).unwrap();
+    assert!(expr.to_string().starts_with("@0x"));
+    assert!(expr.to_string().ends_with(" * 2"));
+    assert_eq!(expr.display(&ctx).to_string(), "x * 2");
+
+    // Locked expressions are printed with the names of their context
+    let mut vars = SmallResolver::new();
+    vars.insert("x", 2.0);
+    vars.insert("y", 3.0);
+    let ctx = Context::new(vars, DefaultResolver::new_fns()).lock();
+    let expr = Expr::compile("sqrt(y) * (x + 1) ^ -x", &ctx).unwrap();
+    assert_eq!(expr.display(&ctx).to_string(), "sqrt(y) * (x + 1) ^ -x");
+
+    let lowered = Expr::<Reg>::try_from(&expr).unwrap();
+    assert_eq!(
+        lowered.display(&ctx).to_string(),
+        "r0 = y\nr0 = sqrt(r0)\nr1 = x + 1\nr2 = -x\nr1 = r1 ^ r2\nr0 = r0 * r1\nret r0"
+    );
+
+    // Values the resolver can't name keep their address
+    let ctx = Context::new(ConstantResolver::new(1.0), DefaultResolver::new_fns()).lock();
+    let expr: Expr<LRpn> = Expr::compile("abs(x)", &ctx).unwrap();
+    assert!(expr.display(&ctx).to_string().starts_with("abs(@0x"));
+
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.add_id('p', 1);
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    let ctx = Context::new(vars, fns);
+
+    let expr = Expr::compile("f0(y1, 2 * 3) ^ -p0 + (y0 - (y1 - 1))", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "f0(y1, 6) ^ -p0 + (y0 - (y1 - 1))");
+
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 1);
+    let ctx = Context::new(vars, DefaultResolver::new_fns());
+    let expr = Expr::compile("sqrt(y0 * 0.5e1)", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "sqrt(y0 * 5)");
+
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    let ctx = Context::new(DefaultResolver::<_, String, _>::empty(), fns);
+    let expr = Expr::compile("-f0(x)", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "-f0(x)");
+}
+
+/// Random tree with non-negative literals, which are printed as they are parsed
+fn random_ast(seed: &mut u64, depth: usize) -> Ast<'static>
+{
+    const OPS: [Op; 19] = [
+        Op::Add,
+        Op::Sub,
+        Op::Mul,
+        Op::Div,
+        Op::Pow,
+        Op::Mod,
+        Op::Or,
+        Op::And,
+        Op::Low,
+        Op::Great,
+        Op::LowEq,
+        Op::GreatEq,
+        Op::Eq,
+        Op::NotEq,
+        Op::BitAnd,
+        Op::BitOr,
+        Op::BitXor,
+        Op::Shl,
+        Op::Shr,
+    ];
+
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 7;
+    *seed ^= *seed << 17;
+    let choice = *seed as usize;
+
+    if depth == 0 {
+        return match choice % 3 {
+            0 => Ast::Num((choice % 100) as f64 / 4.0),
+            1 => Ast::Var("x".into()),
+            _ => Ast::Call("f".into(), vec![]),
+        };
+    }
+
+    let mut next = || Box::new(random_ast(seed, depth - 1));
+
+    match choice % 6 {
+        0 => Ast::Unary(if choice & 8 == 0 { Op::Neg } else { Op::Not }, next()),
+        1 => Ast::Cond(next(), next(), next()),
+        2 => Ast::Call("g".into(), vec![*next(), *next()]),
+        _ => Ast::Binary(OPS[(choice >> 3) % OPS.len()], next(), next()),
+    }
+}
+
+#[test]
+fn test_display_roundtrip()
+{
+    let mut seed = 0x2545_f491_4f6c_dd1d;
+
+    for _ in 0..2000 {
+        let ast = random_ast(&mut seed, 4);
+        let printed = ast.to_string();
+
+        assert_eq!(parse(&printed).as_ref(), Ok(&ast), "{printed}");
+    }
+}
+
+#[test]
+fn test_display_non_finite()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("x".to_string(), 2.0);
+    let ctx = Context::new(vars, DefaultResolver::new_fns());
+
+    let cases = [
+        ("1 / 0", "(1 / 0)"),
+        ("-1 / 0", "(-1 / 0)"),
+        ("0 / 0", "(0 / 0)"),
+        ("x + 1 / 0", "x + (1 / 0)"),
+        ("(1 / 0) ^ x", "(1 / 0) ^ x"),
+        ("x - -1 / 0", "x - (-1 / 0)"),
+        ("-(0 / 0) * x", "(0 / 0) * x"),
+        ("abs(1 / 0 - 1 / 0) + x", "abs((0 / 0)) + x"),
+        (
+            "x > 0 ? 1e308 * 10 : -1e308 * 10",
+            "x > 0 ? (1 / 0) : (-1 / 0)",
+        ),
+    ];
+
+    // The printed expression compiles back into the same one
+    for (src, expected) in cases {
+        let expr = Expr::compile(src, &ctx).unwrap();
+        let printed = expr.to_string();
+        assert_eq!(printed, expected, "{src}");
+
+        let recompiled = Expr::compile(&printed, &ctx).unwrap();
+        assert_eq!(recompiled.to_string(), printed, "{src}");
+
+        let (a, b) = (
+            expr.eval(&ctx, &mut Vec::new()).unwrap(),
+            recompiled.eval(&ctx, &mut Vec::new()).unwrap(),
+        );
+        assert!(
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+            "{src}"
+        );
+    }
+}
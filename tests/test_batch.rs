@@ -0,0 +1,339 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 12] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2)) / y0",
+    "-y0 ^ 2 + !(y1 > p0) * 3 % 2 + y1 ^ 0.5",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 > 1 || f0(y1)) + (p0 && y1 - 2 && f1(y0, y1))",
+    "f1(y0 > 0 ? y1 : p0, y1 && p0) * z0()",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+    "(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2) + (-y0 * 8 >> 1)",
+    "2 * 3 + 1",
+];
+
+/// More than two blocks of rows, the last one partially filled
+const ROWS: usize = 600;
+
+const VARS: [GradVar; 3] = [
+    GradVar::Name("y0"),
+    GradVar::Indexed('y', 1),
+    GradVar::Name("p0"),
+];
+
+fn columns() -> [Vec<f64>; 3]
+{
+    let y0 = (0..ROWS).map(|i| (i * 7 % 13) as f64 * 0.5 - 3.0).collect();
+    let y1 = (0..ROWS)
+        .map(|i| {
+            if i % 97 == 0 {
+                f64::NAN
+            } else {
+                (i * 5 % 11) as f64 * 0.25 - 1.0
+            }
+        })
+        .collect();
+    let p0 = (0..ROWS).map(|i| (i * 3 % 7) as f64 - 3.0).collect();
+    [y0, y1, p0]
+}
+
+fn fns() -> [(char, usize, ExprFn); 3]
+{
+    [
+        ('f', 0, ExprFn::new(|x| x[0] * x[0] + 1.0)),
+        (
+            'f',
+            1,
+            ExprFn::new(|x| x[0] * 3.0 - x[1]).with_vectorized(|x, out| {
+                for (row, out) in out.iter_mut().enumerate() {
+                    *out = x[0][row] * 3.0 - x[1][row];
+                }
+            }),
+        ),
+        ('z', 0, ExprFn::new(|_| 4.0)),
+    ]
+}
+
+fn indexed_vars() -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.add_id('p', 1);
+    vars
+}
+
+fn indexed_fns() -> IndexedResolver<Unlocked, ExprFn>
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.add_id('z', 1);
+
+    for (id, idx, f) in self::fns() {
+        fns.set(id, idx, f);
+    }
+
+    fns
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+
+    for (id, idx, f) in self::fns() {
+        fns.insert(format!("{id}{idx}"), f);
+    }
+
+    fns
+}
+
+/// Values of the expression evaluated row by row
+fn expected(src: &str, columns: &[Vec<f64>; 3]) -> Vec<f64>
+{
+    let mut stack = Vec::new();
+
+    (0..ROWS)
+        .map(|row| {
+            let values = [columns[0][row], columns[1][row], columns[2][row]];
+            let ctx = Context::new(default_vars(values), default_fns());
+            let expr = Expr::compile(src, &ctx).unwrap();
+            expr.eval(&ctx, &mut stack).unwrap()
+        })
+        .collect()
+}
+
+/// Same values, considering every `NaN` equal
+fn assert_same(src: &str, res: &[f64], expected: &[f64])
+{
+    for (row, (a, b)) in res.iter().zip(expected).enumerate() {
+        assert!(
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+            "{src} row {row}: {a} != {b}"
+        );
+    }
+}
+
+#[test]
+fn test_batch_all_exprs()
+{
+    let columns = columns();
+    let cols: Vec<&[f64]> = columns.iter().map(Vec::as_slice).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in EXPRS {
+        let expected = expected(src, &columns);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns());
+        let expr: Expr<Rpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(indexed_vars(), default_fns());
+        let expr: Expr<IVRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(default_vars([0.0; 3]), indexed_fns());
+        let expr: Expr<IFRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(indexed_vars(), indexed_fns());
+        let expr: Expr<IRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns()).lock();
+        let expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(indexed_vars(), indexed_fns()).lock();
+        let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+    }
+}
+
+#[test]
+fn test_batch_optimized()
+{
+    let columns = columns();
+    let cols: Vec<&[f64]> = columns.iter().map(Vec::as_slice).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in EXPRS {
+        let expected = expected(src, &columns);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns());
+        let mut expr = Expr::compile(src, &ctx).unwrap();
+        expr.simplify(SimplifyMode::IeeeSafe);
+        expr.eliminate_common_subexprs(&ctx);
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns()).lock();
+        let mut expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eliminate_common_subexprs();
+        expr.eval_batch(&ctx, &VARS, &cols, &mut out).unwrap();
+        assert_same(src, &out, &expected);
+    }
+}
+
+#[test]
+fn test_batch_context_vars()
+{
+    let columns = columns();
+    let mut out = vec![0.0; ROWS];
+    let src = "y0 * 2 + y1 - p0";
+
+    // Variables without a column keep the value of the context
+    let ctx = Context::new(default_vars([0.0, 0.5, 1.0]), default_fns());
+    let expr = Expr::compile(src, &ctx).unwrap();
+    expr.eval_batch(&ctx, &[GradVar::Name("y0")], &[&columns[0]], &mut out)
+        .unwrap();
+    assert!((0..ROWS).all(|row| out[row] == columns[0][row] * 2.0 - 0.5));
+
+    // The locked context is left as it was
+    let ctx = Context::new(default_vars([0.0, 0.5, 1.0]), default_fns()).lock();
+    let expr = Expr::<Reg>::try_from(&Expr::<LRpn>::compile(src, &ctx).unwrap()).unwrap();
+    expr.eval_batch(&ctx, &[GradVar::Name("y0")], &[&columns[0]], &mut out)
+        .unwrap();
+    assert!((0..ROWS).all(|row| out[row] == columns[0][row] * 2.0 - 0.5));
+    assert_eq!(ctx.get_var_ptr("y0").unwrap().get(), 0.0);
+
+    // Only the rows of `out` are evaluated
+    let mut out = [0.0; 3];
+    expr.eval_batch(&ctx, &[GradVar::Name("y0")], &[&columns[0]], &mut out)
+        .unwrap();
+    assert_eq!(out, [-6.5, 0.5, -5.5]);
+}
+
+#[test]
+fn test_batch_vectorized_fn()
+{
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let twice = ExprFn::new(|x| 2.0 * x[0]).with_vectorized(move |x, out| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        for (x, out) in x[0].iter().zip(out) {
+            *out = 2.0 * x;
+        }
+    });
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("twice".to_string(), twice);
+    let ctx = Context::new(default_vars([0.0; 3]), fns);
+
+    let columns = columns();
+    let mut out = vec![0.0; ROWS];
+    let expr = Expr::compile("twice(y0 + 1)", &ctx).unwrap();
+    expr.eval_batch(&ctx, &[GradVar::Name("y0")], &[&columns[0]], &mut out)
+        .unwrap();
+
+    assert!((0..ROWS).all(|row| out[row] == 2.0 * (columns[0][row] + 1.0)));
+    // Called once per block of rows
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+#[should_panic(expected = "one column is needed per variable")]
+fn test_batch_missing_column()
+{
+    let ctx = Context::new(default_vars([0.0; 3]), default_fns());
+    let expr = Expr::compile("y0 + y1", &ctx).unwrap();
+    let _ = expr.eval_batch(&ctx, &VARS, &[&[1.0]], &mut [0.0]);
+}
+
+#[test]
+fn test_batch_untaken_calls()
+{
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(x: &[f64]) -> f64
+    {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        x[0]
+    }
+
+    // Indices out of the table panic
+    fn table(x: &[f64]) -> f64
+    {
+        assert!(
+            (0.0..4.0).contains(&x[0]),
+            "index {} out of the table",
+            x[0]
+        );
+        [1.0, 2.0, 3.0, 4.0][x[0] as usize]
+    }
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("count".to_string(), ExprFn::new(count).impure());
+    fns.insert(
+        "vcount".to_string(),
+        ExprFn::new(count)
+            .with_vectorized(|x, out| {
+                CALLS.fetch_add(out.len(), Ordering::Relaxed);
+                out.copy_from_slice(x[0]);
+            })
+            .impure(),
+    );
+    fns.insert("table".to_string(), ExprFn::new(table));
+    fns.insert(
+        "vtable".to_string(),
+        ExprFn::new(table).with_vectorized(|x, out| {
+            for (x, out) in x[0].iter().zip(out) {
+                *out = table(&[*x]);
+            }
+        }),
+    );
+
+    let mut vars = DefaultResolver::empty();
+    vars.insert("i".to_string(), 0.0);
+    let ctx = Context::new(vars, fns).lock();
+
+    let column: Vec<f64> = (0..ROWS).map(|row| (row % 11) as f64 - 5.0).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in [
+        "i >= 0 && i < 4 ? table(i) : i < -1 ? vtable(-i - 2) : -1",
+        "i < 0 || i > 3 || vtable(i) > table(i) - 1",
+        "i > 1 ? count(i) : i > -2 ? count(-i) : count(i) + vcount(i)",
+        "(i && count(i) || vcount(i) ? (i < 2 ? vcount(i) : 1) : count(-i)) + vcount(i)",
+        "i > 0 ? vcount(i > 2 && i < 4 ? vcount(i) : count(i)) : i",
+    ] {
+        let expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+
+        CALLS.store(0, Ordering::Relaxed);
+        let expected: Vec<f64> = column
+            .iter()
+            .map(|i| {
+                ctx.get_var_ptr("i").unwrap().set(*i);
+                expr.eval(&ctx, &mut Vec::new()).unwrap()
+            })
+            .collect();
+        let calls = CALLS.swap(0, Ordering::Relaxed);
+
+        // Functions are only called on the rows taking their branch
+        expr.eval_batch(&ctx, &[GradVar::Name("i")], &[&column], &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+        assert_eq!(CALLS.load(Ordering::Relaxed), calls, "{src}");
+    }
+}
@@ -0,0 +1,56 @@
+#![cfg(feature = "capi")]
+
+use std::env;
+
+const HEADER: &str = include_str!("../include/fee.h");
+
+#[test]
+fn test_capi_header()
+{
+    let mut header = Vec::new();
+    cbindgen::generate(env!("CARGO_MANIFEST_DIR"))
+        .unwrap()
+        .write(&mut header);
+    let header = String::from_utf8(header).unwrap();
+
+    if env::var_os("FEE_BLESS").is_some() {
+        std::fs::write("include/fee.h", &header).unwrap();
+    }
+
+    assert_eq!(
+        header, HEADER,
+        "include/fee.h is outdated, regenerate it with FEE_BLESS=1"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_capi_c_program()
+{
+    use std::process::Command;
+
+    // The libraries of the crate are built next to the test binaries
+    let exe = env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let program = deps.join("test_capi_c");
+
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg("tests/capi/test_capi.c")
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-Iinclude"])
+        // Linked by path, as the libraries of other builds can come first in
+        // the search path of the tests
+        .arg(deps.join("libfee.so"))
+        .args(["-lm", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success(), "failed to compile tests/capi/test_capi.c");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"ok\n");
+}
@@ -0,0 +1,191 @@
+use fee::{
+    codegen::{self, RustOptions, Vars},
+    prelude::*,
+    *,
+};
+
+#[rustfmt::skip]
+#[path = "codegen/formulas.rs"]
+mod formulas;
+
+const FORMULAS: &str = include_str!("codegen/formulas.txt");
+const GENERATED: &str = include_str!("codegen/formulas.rs");
+
+const VARS: [&str; 3] = ["y0", "y1", "p0"];
+
+type Formula = fn(&[f64]) -> f64;
+
+const COMPILED: [(&str, Formula); 11] = [
+    ("linear", formulas::linear),
+    ("folded", formulas::folded),
+    ("powers", formulas::powers),
+    ("logic", formulas::logic),
+    ("compare", formulas::compare),
+    ("bitwise", formulas::bitwise),
+    ("casts", formulas::casts),
+    ("ternary", formulas::ternary),
+    ("calls", formulas::calls),
+    ("nested", formulas::nested),
+    ("constant", formulas::constant),
+];
+
+const VALUES: [[f64; 3]; 6] = [
+    [1.5, 2.0, -0.5],
+    [-2.0, 0.0, 3.0],
+    [0.5, 3.0, 0.0],
+    [2.0, 1.0, 0.25],
+    [f64::NAN, -0.0, f64::INFINITY],
+    [-0.0, f64::NEG_INFINITY, f64::NAN],
+];
+
+fn f0(x: &[f64]) -> f64
+{
+    x[0] * x[0] + 1.0
+}
+
+fn f1(x: &[f64]) -> f64
+{
+    x[0] * 3.0 - x[1]
+}
+
+fn z(_: &[f64]) -> f64
+{
+    4.0
+}
+
+fn options() -> RustOptions<'static>
+{
+    RustOptions::new(Vars::Slice(&VARS))
+        .with_fns_path("super::")
+        .with_builtin_fns()
+}
+
+/// Same value, telling `0` and `-0` apart and considering every `NaN` equal
+fn same(a: f64, b: f64) -> bool
+{
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+#[test]
+fn test_codegen_module()
+{
+    let code = codegen::to_rust_module(FORMULAS, &options()).unwrap();
+
+    if std::env::var_os("FEE_BLESS").is_some() {
+        std::fs::write("tests/codegen/formulas.rs", &code).unwrap();
+    }
+
+    assert_eq!(
+        code, GENERATED,
+        "tests/codegen/formulas.rs is outdated, regenerate it with FEE_BLESS=1"
+    );
+}
+
+#[test]
+fn test_codegen_semantics()
+{
+    let mut stack = Vec::new();
+
+    for line in FORMULAS
+        .lines()
+        .filter(|line| line.contains('=') && !line.starts_with('#'))
+    {
+        let (name, src) = line.split_once(" = ").unwrap();
+        let (_, compiled) = COMPILED.iter().find(|(n, _)| *n == name).unwrap();
+
+        for values in VALUES {
+            let mut vars = DefaultResolver::empty();
+            for (var, val) in VARS.iter().zip(values) {
+                vars.insert(var.to_string(), val);
+            }
+
+            let mut fns = DefaultResolver::new_fns();
+            fns.insert("f0".to_string(), ExprFn::new(f0));
+            fns.insert("f1".to_string(), ExprFn::new(f1));
+            fns.insert("z".to_string(), ExprFn::new(z));
+
+            let ctx = Context::new(vars, fns);
+            let expected = Expr::compile(src, &ctx)
+                .unwrap()
+                .eval(&ctx, &mut stack)
+                .unwrap();
+
+            let res = compiled(&values);
+            assert!(
+                same(res, expected),
+                "{name} {values:?}: {res} != {expected}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_codegen_layouts()
+{
+    let ast = parse("a * (b + 1) > 2 ? !c : -b").unwrap();
+
+    let code = codegen::to_rust("f", &ast, &RustOptions::new(Vars::Struct("State"))).unwrap();
+    assert_eq!(
+        code,
+        "/// `a * (b + 1) > 2 ? !c : -b`\n\
+         #[allow(clippy::all)]\n\
+         pub fn f(vars: &State) -> f64 {\n    \
+             if vars.a * (vars.b + 1.0) > 2.0 { f64::from(u8::from(vars.c == 0.0)) } else { -vars.b }\n\
+         }\n"
+    );
+
+    // Compiled expressions are turned back into a tree
+    let ctx = Context::new(EmptyResolver::new(), DefaultResolver::new_fns());
+    let expr = Expr::compile("2 * 3 + abs(4 - 5)", &ctx).unwrap();
+    let code = codegen::to_rust("c", &expr.to_ast().unwrap(), &options()).unwrap();
+    assert_eq!(
+        code,
+        "/// `6 + abs(-1)`\n#[allow(clippy::all)]\npub fn c(_vars: &[f64]) -> f64 {\n    6.0 + (-1.0_f64).abs()\n}\n"
+    );
+
+    // Without the built-in functions, abs is called like any other function
+    let ast = parse("abs(y0) + sqrt(y1)").unwrap();
+    let options = RustOptions::new(Vars::Slice(&VARS)).with_fns_path("super::");
+    let code = codegen::to_rust("g", &ast, &options).unwrap();
+    assert!(
+        code.contains("super::abs(&[vars[0]]) + super::sqrt(&[vars[1]])"),
+        "{code}"
+    );
+}
+
+#[test]
+fn test_codegen_errors()
+{
+    let options = options();
+
+    let ast = parse("y0 + q").unwrap();
+    assert!(matches!(
+        codegen::to_rust("f", &ast, &options),
+        Err(Error::UnknownVar(name, _)) if name == "q"
+    ));
+
+    let ast = parse("y0").unwrap();
+    assert!(matches!(
+        codegen::to_rust("f-1", &ast, &options),
+        Err(Error::CodegenError(_))
+    ));
+
+    let src = "a = y0\nb y0\n";
+    assert_eq!(
+        codegen::to_rust_module(src, &options),
+        Err(Error::CodegenError(
+            "line 2: expected `name = expression`".to_string()
+        ))
+    );
+
+    let src = "a = y0\na = y1\n";
+    assert!(matches!(
+        codegen::to_rust_module(src, &options),
+        Err(Error::CodegenError(_))
+    ));
+
+    // Parse errors point into the whole file
+    let src = "a = y0\n\nb = (y1 + 1\n";
+    let err = codegen::to_rust_module(src, &options).unwrap_err();
+    assert_eq!(err.span().map(|span| &src[span.start..span.end]), Some("("));
+}
@@ -0,0 +1,165 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 5] = [
+    "2 - (4 + (p0 - 2) * (p0 - 2))",
+    "(-y1 + sqrt(y1 ^ 2 - 4 * y0 * p0)) / (2 * y0) + sqrt(y1 ^ 2 - 4 * y0 * p0)",
+    "y0 * y1 > 1 ? y0 * y1 + (y1 - p0) : (y1 - p0) * (y1 - p0)",
+    "(y0 + 1 && y1 + 1) + (y0 + 1) * (y1 - 1 || y1 - 1)",
+    "abs(y0 - y1) * abs(y0 - y1) - -(y0 - y1)",
+];
+
+const VALUES: [(char, &[f64]); 2] = [('y', &[1.5, 2.0]), ('p', &[0.25])];
+
+fn indexed_vars() -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+
+    for (id, values) in VALUES {
+        vars.add_id(id, values.len());
+
+        for (idx, val) in values.iter().enumerate() {
+            vars.set(id, idx, *val);
+        }
+    }
+
+    vars
+}
+
+fn default_vars() -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+
+    for (id, values) in VALUES {
+        for (idx, val) in values.iter().enumerate() {
+            vars.insert(format!("{id}{idx}"), *val);
+        }
+    }
+
+    vars
+}
+
+#[test]
+fn test_cse_all_exprs()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let ctx = Context::new(default_vars(), DefaultResolver::new_fns());
+        let compiled = Expr::compile(src, &ctx).unwrap();
+        let expected = compiled.eval(&ctx, &mut stack).unwrap();
+
+        let mut expr = Expr::compile(src, &ctx).unwrap();
+        expr.eliminate_common_subexprs(&ctx);
+        assert!(expr.len() < compiled.len(), "{src}");
+        assert_eq!(expr.to_string(), compiled.to_string());
+        assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+
+        let ctx = ctx.lock();
+        let mut expr = Expr::compile(src, &ctx).unwrap();
+        expr.eliminate_common_subexprs();
+        assert!(expr.len() < compiled.len(), "{src}");
+        assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+
+        let ctx = Context::new(indexed_vars(), DefaultResolver::new_fns());
+        let mut expr = Expr::compile(src, &ctx).unwrap();
+        expr.eliminate_common_subexprs(&ctx);
+        assert!(expr.len() < compiled.len(), "{src}");
+        assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+
+        assert!(stack.is_empty());
+    }
+}
+
+fn indexed_fns(calls: &Arc<AtomicUsize>) -> IndexedResolver<Unlocked, ExprFn>
+{
+    let counted = calls.clone();
+
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.set(
+        'f',
+        0,
+        ExprFn::from_closure(move |x| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            x[0] * 2.0
+        }),
+    );
+    fns.set('f', 1, ExprFn::new(|x| x[0] + 1.0).impure());
+    fns
+}
+
+#[test]
+fn test_cse_indexed_fns()
+{
+    let calls = Arc::new(AtomicUsize::new(0));
+    let src = "f0(y0 + y1) + f0(y0 + y1) * f1(p0) - f1(p0)";
+    let mut stack = Vec::new();
+
+    let ctx = Context::new(default_vars(), indexed_fns(&calls));
+    let mut expr = Expr::compile(src, &ctx).unwrap();
+    expr.eliminate_common_subexprs(&ctx);
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(7.0 + 7.0 * 1.25 - 1.25));
+    assert_eq!(calls.swap(0, Ordering::Relaxed), 1);
+    assert_eq!(expr.to_string(), src);
+
+    let ctx = Context::new(indexed_vars(), indexed_fns(&calls));
+    let mut expr = Expr::compile(src, &ctx).unwrap();
+    let len = expr.len();
+    expr.eliminate_common_subexprs(&ctx);
+    assert_eq!(expr.len(), len - 2);
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(7.0 + 7.0 * 1.25 - 1.25));
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_cse_impure()
+{
+    let counter = Arc::new(AtomicUsize::new(0));
+    let next = counter.clone();
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert(
+        "next".to_string(),
+        ExprFn::from_closure(move |_| next.fetch_add(1, Ordering::Relaxed) as f64).impure(),
+    );
+
+    let ctx = Context::new(default_vars(), fns);
+    let mut stack = Vec::new();
+
+    let mut expr = Expr::compile("(next() + 1) * 10 + (next() + 1)", &ctx).unwrap();
+    let len = expr.len();
+    expr.eliminate_common_subexprs(&ctx);
+    assert_eq!(expr.len(), len);
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(12.0));
+    assert_eq!(counter.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_cse_gradient()
+{
+    let ctx = Context::new(default_vars(), DefaultResolver::new_fns());
+    let wrt = [
+        GradVar::Name("y0"),
+        GradVar::Name("y1"),
+        GradVar::Name("p0"),
+    ];
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let compiled = Expr::compile(src, &ctx).unwrap();
+        let expected = compiled.eval_with_gradient(&ctx, &wrt, &mut stack);
+
+        let mut expr = Expr::compile(src, &ctx).unwrap();
+        expr.eliminate_common_subexprs(&ctx);
+        assert_eq!(
+            expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+            expected,
+            "{src}"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use fee::{prelude::*, *};
+
+fn hypot(x: &[f64]) -> f64
+{
+    x[0].hypot(x[1])
+}
+
+fn d_hypot_x(x: &[f64]) -> f64
+{
+    x[0] / x[0].hypot(x[1])
+}
+
+fn d_hypot_y(x: &[f64]) -> f64
+{
+    x[1] / x[0].hypot(x[1])
+}
+
+#[test]
+fn test_derivative_rules()
+{
+    let cases = [
+        ("3 * x + 2", "3"),
+        ("x * y", "y"),
+        ("x ^ 3", "3 * x ^ 2"),
+        ("-x ^ 2", "-(2 * x)"),
+        ("1 / x", "-1 / x ^ 2"),
+        ("x / y", "1 / y"),
+        ("sqrt(x)", "0.5 / sqrt(x)"),
+        ("abs(y * x)", "((y * x > 0) - (y * x < 0)) * y"),
+        ("x > 1 ? x * x : 2 * x", "x > 1 ? x + x : 2"),
+        ("y > 1 ? x : x + 1", "1"),
+        ("x > y && !x", "0"),
+        ("y ^ 2 + f(y)", "0"),
+    ];
+
+    for (src, expected) in cases {
+        let ast = parse(src).unwrap();
+        assert_eq!(
+            derivative(&ast, "x").unwrap().to_string(),
+            expected,
+            "{src}"
+        );
+    }
+
+    let ast = parse("f(x) + 1").unwrap();
+    assert_eq!(
+        derivative(&ast, "x"),
+        Err(Error::UnknownDerivative("f".into()))
+    );
+}
+
+#[test]
+fn test_derivative_lotka_volterra()
+{
+    let equations = ["y0 * (p0 - p1*y1)", "-y1 * (p2 - p3*y0)"];
+
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, 1.5);
+    vars.set('y', 1, 2.0);
+    vars.add_id('p', 4);
+    vars.set('p', 0, 1.0);
+    vars.set('p', 1, 0.5);
+    vars.set('p', 2, 3.0);
+    vars.set('p', 3, 0.25);
+
+    let ctx = Context::new(vars, EmptyResolver::new());
+    let mut stack = Vec::new();
+
+    // d(equation i) / d(y j)
+    let expected = [
+        [1.0 - 0.5 * 2.0, -0.5 * 1.5],
+        [2.0 * 0.25, -(3.0 - 0.25 * 1.5)],
+    ];
+
+    for (i, equation) in equations.iter().enumerate() {
+        let ast = parse(equation).unwrap();
+
+        for (j, var) in ["y0", "y1"].iter().enumerate() {
+            let d_ast = derivative(&ast, var).unwrap();
+            let d_expr = Expr::from_ast(&d_ast, &ctx).unwrap();
+
+            assert_eq!(d_expr.eval(&ctx, &mut stack), Ok(expected[i][j]), "{d_ast}");
+        }
+    }
+}
+
+#[test]
+fn test_derivative_variable_exponent()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("x".to_string(), 1.5);
+    let ctx = Context::new(vars, DefaultResolver::new_fns());
+    let mut stack = Vec::new();
+
+    let x: f64 = 1.5;
+    let cases = [
+        ("2 ^ x", 2f64.powf(x) * 2f64.ln()),
+        ("x ^ x", x.powf(x) * (x.ln() + 1.0)),
+    ];
+
+    for (src, expected) in cases {
+        let d_ast = derivative(&parse(src).unwrap(), "x").unwrap();
+        let d_expr = Expr::from_ast(&d_ast, &ctx).unwrap();
+        let res = d_expr.eval(&ctx, &mut stack).unwrap();
+
+        assert!(
+            (res - expected).abs() < 1e-12,
+            "{src}: {d_ast} = {res} != {expected}"
+        );
+    }
+}
+
+#[test]
+fn test_derivative_numeric()
+{
+    let exprs = [
+        "x ^ 2.5 * sqrt(x + 1) / (x - 3)",
+        "x ^ x + 2 ^ (x * y)",
+        "hypot(x, y * x) - abs(1 - x * x)",
+        "(x * 7) % (y + x)",
+        "x > 1 ? -x / y : (x + y) ^ -2",
+    ];
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("hypot".to_string(), ExprFn::new(hypot));
+    fns.insert("d_hypot_x".to_string(), ExprFn::new(d_hypot_x));
+    fns.insert("d_hypot_y".to_string(), ExprFn::new(d_hypot_y));
+
+    let mut vars = DefaultResolver::empty();
+    vars.insert("x".to_string(), 0.0);
+    vars.insert("y".to_string(), 0.0);
+
+    let mut ctx = Context::new(vars, fns);
+    let mut stack = Vec::new();
+
+    let mut derivatives = Derivatives::new();
+    derivatives.insert("hypot", ["d_hypot_x", "d_hypot_y"]);
+
+    for src in exprs {
+        let ast = parse(src).unwrap();
+        let d_ast = derivatives.derivative(&ast, "x").unwrap();
+
+        for (x, y) in [(0.7, 1.3), (1.9, 0.4), (2.2, 2.5)] {
+            let h = 1e-6;
+            let mut eval_at = |x: f64, ast: &Ast| {
+                ctx.vars_mut().insert("x".to_string(), x);
+                ctx.vars_mut().insert("y".to_string(), y);
+
+                let expr = Expr::from_ast(ast, &ctx).unwrap();
+                expr.eval(&ctx, &mut stack).unwrap()
+            };
+
+            let numeric = (eval_at(x + h, &ast) - eval_at(x - h, &ast)) / (2.0 * h);
+            let symbolic = eval_at(x, &d_ast);
+
+            assert!(
+                (numeric - symbolic).abs() <= 1e-5 * symbolic.abs().max(1.0),
+                "{src}: d/dx = {d_ast} at ({x}, {y}), {symbolic} != {numeric}"
+            );
+        }
+    }
+}
@@ -0,0 +1,343 @@
+use std::borrow::Cow;
+
+use fee::{prelude::*, *};
+
+#[test]
+fn test_error_spans()
+{
+    let ctx = Context::empty();
+
+    assert_eq!(
+        Expr::compile("a = b", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned('='),
+            Span::new(2, 3)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("a ! b", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned('!'),
+            Span::new(2, 3)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("1 + 2 : 3", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned(':'),
+            Span::new(6, 7)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("a ? b", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+            5, 5
+        ))))
+    );
+
+    let mut stack = Vec::new();
+    let expr = Expr::compile("1 + foo * 2", &ctx).unwrap();
+    assert_eq!(
+        expr.eval(&ctx, &mut stack),
+        Err(Error::UnknownVar("foo".into(), Span::new(4, 7)))
+    );
+
+    let expr = Expr::compile("2 * bar(1, 2)", &ctx).unwrap();
+    assert_eq!(
+        expr.eval(&ctx, &mut stack),
+        Err(Error::UnknownFn("bar".into(), Span::new(4, 13)))
+    );
+}
+
+#[test]
+fn test_token_spans()
+{
+    let ctx = Context::empty();
+
+    let expr = Expr::compile("(1 + 2) * x", &ctx).unwrap();
+    assert_eq!(
+        expr.spans(),
+        &[Span::new(1, 6), Span::new(10, 11), Span::new(8, 9)]
+    );
+
+    let expr = Expr::compile("x > 1 ? y : 2", &ctx).unwrap();
+    assert_eq!(
+        expr.spans(),
+        &[
+            Span::new(0, 1),
+            Span::new(4, 5),
+            Span::new(2, 3),
+            Span::new(6, 7),
+            Span::new(8, 9),
+            Span::new(10, 11),
+            Span::new(12, 13),
+        ]
+    );
+}
+
+#[test]
+fn test_error_render()
+{
+    let ctx = Context::empty();
+
+    let src = "a = b";
+    let err = Expr::compile(src, &ctx).unwrap_err();
+    assert_eq!(
+        err.render(src),
+        "error: parse error: unexpected character '=' at 2..3\n \
+         --> line 1, column 3\n  \
+         |\n\
+         1 | a = b\n  \
+         |   ^ did you mean `==`?"
+    );
+
+    let src = "1 +\n\tabs(1, 2)";
+    let ctx_fns = Context::new(EmptyResolver::new(), DefaultResolver::new_fns());
+    let err = Expr::compile(src, &ctx_fns).unwrap_err();
+    assert_eq!(
+        err.render(src),
+        "error: parse error: function 'abs' expects exactly 1 argument, found 2 at 5..14\n \
+         --> line 2, column 2\n  \
+         |\n\
+         2 | \tabs(1, 2)\n  \
+         | \t^^^^^^^^^"
+    );
+
+    let src = "x * 2";
+    let err = Expr::compile(src, &ctx)
+        .unwrap()
+        .eval(&ctx, &mut Vec::new())
+        .unwrap_err();
+    assert_eq!(
+        err.render(src),
+        "error: unknown variable 'x' at 0..1\n \
+         --> line 1, column 1\n  \
+         |\n\
+         1 | x * 2\n  \
+         | ^ variable 'x' is not defined"
+    );
+
+    let err = Error::EvalError(EvalError::MalformedExpression);
+    assert_eq!(err.render(src), "error: eval error: malformed expression");
+}
+
+#[test]
+fn test_malformed_expressions()
+{
+    let ctx = Context::empty();
+
+    let unexpected_end = |start, end| {
+        Err(Error::ParseError(ParseError::UnexpectedEnd(Span::new(
+            start, end,
+        ))))
+    };
+    let unmatched = |start, end| {
+        Err(Error::ParseError(ParseError::UnmatchedParentheses(
+            Span::new(start, end),
+        )))
+    };
+
+    assert_eq!(Expr::compile("", &ctx), unexpected_end(0, 0));
+    assert_eq!(Expr::compile("3 +", &ctx), unexpected_end(3, 3));
+    assert_eq!(Expr::compile("-", &ctx), unexpected_end(1, 1));
+    assert_eq!(Expr::compile(", 4", &ctx), unexpected_end(0, 1));
+    assert_eq!(Expr::compile("(1 +)", &ctx), unexpected_end(4, 5));
+    assert_eq!(Expr::compile("()", &ctx), unexpected_end(1, 2));
+    assert_eq!(Expr::compile("f(1,)", &ctx), unexpected_end(4, 5));
+    assert_eq!(Expr::compile("f(, 1)", &ctx), unexpected_end(2, 3));
+    assert_eq!(Expr::compile("x ? : 1", &ctx), unexpected_end(4, 5));
+    assert_eq!(Expr::compile("(x ? 1)", &ctx), unexpected_end(6, 7));
+    assert_eq!(Expr::compile("f(x ? 1, 2)", &ctx), unexpected_end(7, 8));
+
+    assert_eq!(Expr::compile("(1 + 2", &ctx), unmatched(0, 1));
+    assert_eq!(Expr::compile("f((1)", &ctx), unmatched(1, 2));
+    assert_eq!(Expr::compile("1 + 2)", &ctx), unmatched(5, 6));
+    assert_eq!(Expr::compile("(1))", &ctx), unmatched(3, 4));
+
+    assert_eq!(
+        Expr::compile("(1, 2)", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned(','),
+            Span::new(2, 3)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("1, 2", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned(','),
+            Span::new(1, 2)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("(1)(2)", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned('('),
+            Span::new(3, 4)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("x (2)", &ctx),
+        Err(Error::ParseError(ParseError::UnexpectedChar(
+            Cow::Owned('('),
+            Span::new(2, 3)
+        )))
+    );
+}
+
+#[test]
+fn test_compiled_exprs_are_well_formed()
+{
+    const ALPHABET: &[u8] = b"1x0+-*!&|(),?:f";
+
+    let new_ctx = || {
+        let mut vars = DefaultResolver::empty();
+        vars.insert("x".to_string(), 0.5);
+        let mut fns = DefaultResolver::new_fns();
+        fns.insert("f".to_string(), ExprFn::new(|args| args.iter().sum()));
+        Context::new(vars, fns)
+    };
+    let ctx = new_ctx();
+    let locked_ctx = new_ctx().lock();
+
+    let mut stack = Vec::new();
+    let mut src = Vec::new();
+
+    // Every expression of up to 5 characters either fails to compile or evaluates
+    // without a stack error, and never panics
+    for len in 0..=5u32 {
+        for mut n in 0..ALPHABET.len().pow(len) {
+            src.clear();
+            for _ in 0..len {
+                src.push(ALPHABET[n % ALPHABET.len()]);
+                n /= ALPHABET.len();
+            }
+
+            let src = std::str::from_utf8(&src).unwrap();
+            if let Ok(expr) = Expr::compile(src, &ctx) {
+                stack.clear();
+                let res = expr.eval(&ctx, &mut stack);
+                assert!(!matches!(res, Err(Error::EvalError(_))), "{src}: {res:?}");
+            }
+            if let Ok(expr) = Expr::compile(src, &locked_ctx) {
+                stack.clear();
+                let res = expr.eval(&locked_ctx, &mut stack);
+                assert!(!matches!(res, Err(Error::EvalError(_))), "{src}: {res:?}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_locked_unknown_names()
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("x".to_string(), 2.0);
+    let ctx = Context::new(vars, DefaultResolver::new_fns()).lock();
+
+    assert_eq!(
+        Expr::compile("x + y", &ctx),
+        Err(Error::UnknownVar("y".into(), Span::new(4, 5)))
+    );
+    assert_eq!(
+        Expr::compile("abs(x) * foo(x, 1)", &ctx),
+        Err(Error::UnknownFn("foo".into(), Span::new(9, 18)))
+    );
+    assert_eq!(
+        Expr::compile("false && y", &ctx),
+        Err(Error::UnknownVar("y".into(), Span::new(9, 10)))
+    );
+
+    let expr = Expr::compile("abs(-x) + sqrt(x * 8)", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(6.0));
+}
+
+#[test]
+fn test_indexed_unknown_names()
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('p', 2);
+    vars.set('p', 1, 3.0);
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    fns.set('f', 0, ExprFn::new(|args| args[0] * 2.0));
+    let ctx = Context::new(vars, fns);
+
+    let unknown_var = |name, start, end| {
+        Err(Error::UnknownVar(
+            Cow::Borrowed(name),
+            Span::new(start, end),
+        ))
+    };
+
+    assert_eq!(Expr::compile("1 + P0", &ctx), unknown_var("P0", 4, 6));
+    assert_eq!(Expr::compile("1 + p_1", &ctx), unknown_var("p_1", 4, 7));
+    assert_eq!(Expr::compile("1 + p", &ctx), unknown_var("p", 4, 5));
+    assert_eq!(Expr::compile("1 + p2", &ctx), unknown_var("p2", 4, 6));
+    assert_eq!(Expr::compile("1 + q0", &ctx), unknown_var("q0", 4, 6));
+    assert_eq!(Expr::compile("1 + pp", &ctx), unknown_var("pp", 4, 6));
+    assert_eq!(
+        Expr::compile("p99999999999999999999999", &ctx),
+        unknown_var("p99999999999999999999999", 0, 24)
+    );
+    assert_eq!(
+        Expr::compile("f1(p0)", &ctx),
+        Err(Error::UnknownFn(Cow::Borrowed("f1"), Span::new(0, 6)))
+    );
+    assert_eq!(
+        Expr::compile("Fn0(p0)", &ctx),
+        Err(Error::UnknownFn(Cow::Borrowed("Fn0"), Span::new(0, 7)))
+    );
+
+    let expr = Expr::compile("f0(p1) + p0", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(6.0));
+
+    // Only the indexed resolver checks the names
+    let mut vars = IndexedResolver::new();
+    vars.add_id('x', 1);
+    let ctx = Context::new(vars, DefaultResolver::new_fns());
+    assert_eq!(
+        Expr::compile("abs(x1)", &ctx).unwrap_err(),
+        Error::UnknownVar(Cow::Borrowed("x1"), Span::new(4, 6))
+    );
+    assert!(Expr::compile("abs(x0) + sqrt(x0)", &ctx).is_ok());
+
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    let ctx = Context::new(DefaultResolver::<_, String, _>::empty(), fns);
+    assert!(Expr::compile("f0(Speed_2)", &ctx).is_ok());
+    assert_eq!(
+        Expr::compile("f0(x) + g0(x)", &ctx),
+        Err(Error::UnknownFn(Cow::Borrowed("g0"), Span::new(8, 13)))
+    );
+}
+
+#[test]
+fn test_invalid_numbers()
+{
+    let ctx = Context::empty();
+
+    let invalid = |text, start, end| {
+        Err(Error::ParseError(ParseError::InvalidNumber(
+            Cow::Borrowed(text),
+            Span::new(start, end),
+        )))
+    };
+
+    assert_eq!(Expr::compile("1 + 1e", &ctx), invalid("1e", 4, 6));
+    assert_eq!(Expr::compile("1e+ 2", &ctx), invalid("1e+", 0, 3));
+    assert_eq!(Expr::compile("2e1.5", &ctx), invalid("2e1.5", 0, 5));
+    assert_eq!(Expr::compile("1 + 1.2.3", &ctx), invalid("1.2.3", 4, 9));
+    assert_eq!(Expr::compile("1..2", &ctx), invalid("1..2", 0, 4));
+    assert_eq!(Expr::compile("1e5e5", &ctx), invalid("1e5e5", 0, 5));
+    assert_eq!(Expr::compile("0x", &ctx), invalid("0x", 0, 2));
+    assert_eq!(Expr::compile("0xFG", &ctx), invalid("0xFG", 0, 4));
+    assert_eq!(Expr::compile("0b102", &ctx), invalid("0b102", 0, 5));
+    assert_eq!(Expr::compile("0o8", &ctx), invalid("0o8", 0, 3));
+    assert_eq!(Expr::compile("3x", &ctx), invalid("3x", 0, 2));
+    assert_eq!(Expr::compile("1._5", &ctx), invalid("1._5", 0, 4));
+    assert_eq!(Expr::compile(".", &ctx), invalid(".", 0, 1));
+    assert_eq!(
+        Expr::compile("0x1_0000_0000_0000_0000", &ctx),
+        invalid("0x1_0000_0000_0000_0000", 0, 23)
+    );
+}
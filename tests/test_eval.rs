@@ -207,6 +207,100 @@ fn test_eval_operators()
     let expr = "6 & 3 == 2 && 6 | 3 == 7 && 6 ^^ 3 == 5";
     let expr = Expr::compile(expr, &ctx).unwrap();
     assert_eq!(expr.eval(&ctx, &mut stack).unwrap(), 1.0);
+    let expr = "--2 * -!0 + 2 * --3";
+    let expr = Expr::compile(expr, &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack).unwrap(), 4.0);
+}
+
+#[test]
+fn test_eval_literals()
+{
+    let ctx = Context::empty();
+    let mut stack = Vec::new();
+
+    let cases = [
+        ("1e3", 1000.0),
+        ("2.5E-3 * 1e3", 2.5),
+        ("1e+2 + 1E2", 200.0),
+        ("6.022e23 > 6e23", 1.0),
+        (".5e1", 5.0),
+        ("5.", 5.0),
+        ("1_000_000", 1_000_000.0),
+        ("0.5_0e2", 50.0),
+        ("0xFF", 255.0),
+        ("0x_ff_ff", 65535.0),
+        ("0b1010", 10.0),
+        ("0B1111_0000", 240.0),
+        ("0o17", 15.0),
+        ("0xF0 | 0b1111 == 0xFF", 1.0),
+        ("1 << 0x4", 16.0),
+        ("-0x10 + 2e1", 4.0),
+    ];
+
+    for (expr, expected) in cases {
+        let compiled = Expr::compile(expr, &ctx).unwrap();
+        assert_eq!(compiled.eval(&ctx, &mut stack), Ok(expected), "{expr}");
+    }
+}
+
+#[test]
+fn test_literal_rounding()
+{
+    let ctx = Context::empty();
+    let mut stack = Vec::new();
+
+    let mut literals = vec![
+        "0.1".to_string(),
+        "0.2".to_string(),
+        "0.3".to_string(),
+        "3.141592653589793".to_string(),
+        "2.718281828459045".to_string(),
+        "1.7976931348623157e308".to_string(),
+        "2.2250738585072014e-308".to_string(),
+        "4.9e-324".to_string(),
+        "9007199254740993".to_string(),
+        "123456789012345678901234567890".to_string(),
+        "0.30000000000000004".to_string(),
+        "1e23".to_string(),
+        "8.98846567431158e307".to_string(),
+        "1e400".to_string(),
+        "1e-400".to_string(),
+    ];
+
+    // Pseudo-random literals with up to 20 digits and exponents in -30..30
+    let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    for _ in 0..10_000 {
+        let int_len = next() % 10;
+        let frac_len = next() % 11;
+        let mut lit: String = (0..int_len.max(1))
+            .map(|_| (b'0' + (next() % 10) as u8) as char)
+            .collect();
+        if frac_len > 0 {
+            lit.push('.');
+            lit.extend((0..frac_len).map(|_| (b'0' + (next() % 10) as u8) as char));
+        }
+        if next() % 2 == 0 {
+            lit.push_str(&format!("e{}", (next() % 61) as i64 - 30));
+        }
+        literals.push(lit);
+    }
+
+    for lit in literals {
+        let expected = lit.parse::<f64>().unwrap();
+        let expr = Expr::compile(&lit, &ctx).unwrap();
+        let value = expr.eval(&ctx, &mut stack).unwrap();
+        assert_eq!(value.to_bits(), expected.to_bits(), "{lit}");
+    }
+
+    let expr = Expr::compile("0.1 + 0.2 == 0.30000000000000004", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
 }
 
 #[test]
@@ -262,4 +356,353 @@ fn test_hard_expressions()
         ";
     let expr = Expr::compile(expr, &ctx).unwrap();
     assert_eq!(expr.eval(&ctx, &mut stack).unwrap(), -30.0);
+
+    let expr = "
+        ((x + 3 * y) << 2 ) & 255 | ((10 ^ 2) % 7 )
+        ^^ ((true && (z > 5 || false)) ? 1 : 0 )
+        + max(a, b, c)
+        - min(1, 2, 3)
+        * abs(-42)
+        ";
+    let expr = Expr::compile(expr, &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack).unwrap(), -30.0);
+}
+
+#[test]
+fn test_eval_ternary()
+{
+    fn unreachable(_: &[f64]) -> f64
+    {
+        panic!("untaken branch evaluated")
+    }
+
+    let mut v_resolver = SmallResolver::new();
+    v_resolver.insert("x", 0.0);
+    v_resolver.insert("y", 2.0);
+
+    let mut f_resolver = SmallResolver::new();
+    f_resolver.insert("boom", ExprFn::new(unreachable));
+
+    let ctx = Context::new(v_resolver, f_resolver);
+    let mut stack = Vec::new();
+
+    let expr = Expr::compile("x != 0 ? boom(1 / x) : y + 1", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(3.0));
+
+    let expr = Expr::compile("y > 1 ? y * 10 : boom(y)", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(20.0));
+
+    let expr = Expr::compile("x ? 1 : y ? 2 : 3", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(2.0));
+
+    let expr = Expr::compile("x < 1 ? y < 1 ? 1 : 2 : 3", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(2.0));
+
+    let expr = Expr::compile("2 * (y == 2 ? 1 / x : 0) + 1", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(f64::INFINITY));
+
+    let expr = Expr::compile("true ? y : boom(y)", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(2.0));
+
+    let expr = Expr::compile("false ? boom(y) : 4", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(4.0));
+
+    assert!(Expr::compile("y ? 1", &ctx).is_err());
+    assert!(Expr::compile("y : 1", &ctx).is_err());
+}
+
+#[test]
+fn test_eval_ternary_pipelines()
+{
+    let expr = "p0 > 0 ? f0(p0) : p1 < 0 ? f0(-p1) : 0";
+    let mut stack = Vec::new();
+
+    {
+        let mut var_resolver = IndexedResolver::new();
+        var_resolver.add_id('p', 2);
+        var_resolver.set('p', 0, -1.0);
+        var_resolver.set('p', 1, -9.0);
+
+        let mut fn_resolver = IndexedResolver::new();
+        fn_resolver.add_id('f', 1);
+        fn_resolver.set('f', 0, ExprFn::new(f0));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(3.0));
+    }
+
+    {
+        let mut var_resolver = IndexedResolver::new();
+        var_resolver.add_id('p', 2);
+        var_resolver.set('p', 0, -1.0);
+        var_resolver.set('p', 1, -9.0);
+
+        let mut fn_resolver = DefaultResolver::empty();
+        fn_resolver.insert("f0", ExprFn::new(f0));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(3.0));
+    }
+
+    {
+        let mut var_resolver = DefaultResolver::empty();
+        var_resolver.insert("p0", -1.0);
+        var_resolver.insert("p1", -9.0);
+
+        let mut fn_resolver = IndexedResolver::new();
+        fn_resolver.add_id('f', 1);
+        fn_resolver.set('f', 0, ExprFn::new(f0));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(3.0));
+    }
+
+    {
+        let mut var_resolver = DefaultResolver::empty();
+        var_resolver.insert("p0".to_string(), -1.0);
+        var_resolver.insert("p1".to_string(), -9.0);
+
+        let mut fn_resolver = DefaultResolver::empty();
+        fn_resolver.insert("f0".to_string(), ExprFn::new(f0));
+
+        let context = Context::new(var_resolver, fn_resolver).lock();
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(3.0));
+
+        context.get_var_ptr("p0").unwrap().set(16.0);
+        assert_eq!(expr.eval(&context, &mut stack), Ok(4.0));
+    }
+}
+
+#[test]
+fn test_eval_short_circuit()
+{
+    fn guarded(x: &[f64]) -> f64
+    {
+        assert!(x[0].is_finite(), "guard did not short-circuit");
+        x[0]
+    }
+
+    let mut v_resolver = SmallResolver::new();
+    v_resolver.insert("x", 0.0);
+    v_resolver.insert("y", -0.0);
+
+    let mut f_resolver = SmallResolver::new();
+    f_resolver.insert("f0", ExprFn::new(guarded));
+
+    let ctx = Context::new(v_resolver, f_resolver);
+    let mut stack = Vec::new();
+
+    let expr = Expr::compile("x != 0 && f0(1 / x) > 2", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(0.0));
+
+    let expr = Expr::compile("x == 0 || f0(1 / x) > 2", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
+
+    let expr = Expr::compile("y && f0(1 / x)", &ctx).unwrap();
+    assert_eq!(
+        expr.eval(&ctx, &mut stack).map(f64::to_bits),
+        Ok(0.0f64.to_bits())
+    );
+
+    let expr = Expr::compile("(x + 5) || f0(1 / x)", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
+
+    let expr = Expr::compile("x + 1 && x + 5", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
+
+    let expr = Expr::compile("x || y || x + 1 && 2 * x", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(0.0));
+
+    let expr = Expr::compile("x == 0 ? x || 3 : f0(1 / x)", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
+
+    let expr = Expr::compile("false && f0(1 / x) || x + 1 > 0", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(1.0));
+
+    let ctx = Context::empty().lock();
+    let expr = Expr::compile("1 > 2 && 3 || 4 > 5", &ctx).unwrap();
+    assert_eq!(expr.eval(&ctx, &mut stack), Ok(0.0));
+}
+
+#[test]
+fn test_eval_closures()
+{
+    let table = [0.0, 10.0, 20.0, 30.0];
+    let interp = move |x: &[f64]| {
+        let i = x[0].floor() as usize;
+        table[i] + (table[i + 1] - table[i]) * x[0].fract()
+    };
+    let gain = 3.0;
+
+    let expr = "lerp(p0) * g0(p1)";
+    let mut stack = Vec::new();
+
+    {
+        let mut var_resolver = DefaultResolver::empty();
+        var_resolver.insert("p0", 1.5);
+        var_resolver.insert("p1", 2.0);
+
+        let mut fn_resolver = DefaultResolver::empty();
+        fn_resolver.insert("lerp", ExprFn::from_closure(interp));
+        fn_resolver.insert("g0", ExprFn::from_closure(move |x| x[0] * gain));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(90.0));
+    }
+
+    {
+        let mut var_resolver = IndexedResolver::new();
+        var_resolver.add_id('p', 2);
+        var_resolver.set('p', 0, 1.5);
+        var_resolver.set('p', 1, 2.0);
+
+        let mut fn_resolver = SmallResolver::new();
+        fn_resolver.insert("lerp", ExprFn::from_closure(interp));
+        fn_resolver.insert("g0", ExprFn::from_closure(move |x| x[0] * gain));
+
+        let context = Context::new(var_resolver, fn_resolver);
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(90.0));
+    }
+
+    {
+        let mut fn_resolver = IndexedResolver::new();
+        fn_resolver.add_id('g', 1);
+        fn_resolver.set('g', 0, ExprFn::from_closure(move |x| x[0] * gain));
+
+        let context = Context::new(ConstantResolver::new(2.0), fn_resolver);
+        let expr = Expr::compile("g0(p1)", &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(6.0));
+    }
+
+    {
+        let mut var_resolver = DefaultResolver::empty();
+        var_resolver.insert("p0".to_string(), 1.5);
+        var_resolver.insert("p1".to_string(), 2.0);
+
+        let mut fn_resolver = DefaultResolver::empty();
+        fn_resolver.insert("lerp".to_string(), ExprFn::from_closure(interp));
+        fn_resolver.insert("g0".to_string(), ExprFn::new(f1));
+
+        let context = Context::new(var_resolver, fn_resolver).lock();
+        let expr = Expr::compile(expr, &context).unwrap();
+        assert_eq!(expr.eval(&context, &mut stack), Ok(30.0));
+
+        let g0_ptr = context.get_fn_ptr("g0").unwrap();
+        g0_ptr.set(ExprFn::from_closure(move |x| x[0] * gain));
+        assert_eq!(expr.eval(&context, &mut stack), Ok(90.0));
+    }
+}
+
+#[test]
+fn test_fn_arity()
+{
+    fn sum(x: &[f64]) -> f64
+    {
+        x.iter().sum()
+    }
+
+    let mut var_resolver = DefaultResolver::empty();
+    var_resolver.insert("p0".to_string(), 4.0);
+
+    let mut fn_resolver = DefaultResolver::new_fns();
+    fn_resolver.insert(
+        "clamp".to_string(),
+        ExprFn::new(|x| x[0].clamp(x[1], x[2])).with_arity(Arity::Exact(3)),
+    );
+    fn_resolver.insert(
+        "log".to_string(),
+        ExprFn::new(|x| x[0].log(*x.get(1).unwrap_or(&10.0))).with_arity(Arity::Range(1, 2)),
+    );
+    fn_resolver.insert(
+        "sum".to_string(),
+        ExprFn::new(sum).with_arity(Arity::Variadic(1)),
+    );
+    fn_resolver.insert(
+        "zero".to_string(),
+        ExprFn::new(|_| 0.0).with_arity(Arity::Exact(0)),
+    );
+
+    let ctx = Context::new(var_resolver, fn_resolver);
+    let mut stack = Vec::new();
+
+    let expr = Expr::compile(
+        "clamp(p0, 0, 1) + log(100) + log(8, 2) + sum(1, 2, 3) + zero()",
+        &ctx,
+    );
+    assert_eq!(expr.unwrap().eval(&ctx, &mut stack), Ok(12.0));
+
+    assert_eq!(
+        Expr::compile("2 * abs(4, sqrt(5))", &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(
+            "abs".into(),
+            Arity::Exact(1),
+            2,
+            Span::new(4, 19)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("clamp(p0, 1)", &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(
+            "clamp".into(),
+            Arity::Exact(3),
+            2,
+            Span::new(0, 12)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("log(1, 2, 3)", &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(
+            "log".into(),
+            Arity::Range(1, 2),
+            3,
+            Span::new(0, 12)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("1 + sum( )", &ctx),
+        Err(Error::ParseError(ParseError::InvalidArgCount(
+            "sum".into(),
+            Arity::Variadic(1),
+            0,
+            Span::new(4, 10)
+        )))
+    );
+    assert_eq!(
+        Expr::compile("zero(p0)", &ctx).unwrap_err().to_string(),
+        "parse error: function 'zero' expects exactly 0 arguments, found 1 at 0..8"
+    );
+
+    let ctx = ctx.lock();
+    assert!(Expr::compile("clamp(p0)", &ctx).is_err());
+    assert_eq!(
+        Expr::compile("clamp(p0, 1, 5)", &ctx)
+            .unwrap()
+            .eval(&ctx, &mut stack),
+        Ok(4.0)
+    );
+
+    let mut fn_resolver = IndexedResolver::new();
+    fn_resolver.add_id('f', 1);
+    fn_resolver.set('f', 0, ExprFn::new(f2).with_arity(Arity::Exact(2)));
+    let ctx = Context::new(ConstantResolver::new(1.0), fn_resolver);
+    assert!(Expr::compile("f0(p0)", &ctx).is_err());
+    assert_eq!(
+        Expr::compile("f0(p0, 2)", &ctx)
+            .unwrap()
+            .eval(&ctx, &mut stack),
+        Ok(2.0)
+    );
+}
+
+#[test]
+#[should_panic(expected = "invalid arity range: 2 > 1")]
+fn test_fn_arity_invalid_range()
+{
+    let _ = ExprFn::new(|x| x[0]).with_arity(Arity::Range(2, 1));
 }
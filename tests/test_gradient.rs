@@ -0,0 +1,198 @@
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 6] = [
+    "y0 * (p0 - p1*y1)",
+    "-y1 * (p2 - p3*y0)",
+    "y0 ^ 3 / (y1 + 2) - sqrt(y0 * y1) + abs(p0 - y1)",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 * 5) % (y1 + 1) + y1 ^ y0",
+];
+
+const VALUES: [(char, &[f64]); 2] = [('y', &[1.5, 2.0]), ('p', &[1.0, 0.5, 3.0, 0.25])];
+
+fn ln(x: &[f64]) -> f64
+{
+    x[0].ln()
+}
+
+fn indexed_vars() -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+
+    for (id, values) in VALUES {
+        vars.add_id(id, values.len());
+
+        for (idx, val) in values.iter().enumerate() {
+            vars.set(id, idx, *val);
+        }
+    }
+
+    vars
+}
+
+fn default_vars() -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+
+    for (id, values) in VALUES {
+        for (idx, val) in values.iter().enumerate() {
+            vars.insert(format!("{id}{idx}"), *val);
+        }
+    }
+
+    vars
+}
+
+fn fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("ln".to_string(), ExprFn::new(ln));
+    fns
+}
+
+/// Gradient computed from the symbolic derivatives
+fn symbolic_gradient(src: &str, wrt: &[&str]) -> Vec<f64>
+{
+    let ctx = Context::new(default_vars(), fns());
+    let ast = parse(src).unwrap();
+
+    wrt.iter()
+        .map(|var| {
+            let d_ast = derivative(&ast, var).unwrap();
+            let expr = Expr::from_ast(&d_ast, &ctx).unwrap();
+            expr.eval(&ctx, &mut Vec::new()).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn test_gradient_all_exprs()
+{
+    let wrt = [GradVar::Name("y0"), GradVar::Indexed('y', 1), "p0".into()];
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let expected = symbolic_gradient(src, &["y0", "y1", "p0"]);
+
+        let ctx = Context::new(default_vars(), fns());
+        let value = Expr::compile(src, &ctx)
+            .unwrap()
+            .eval(&ctx, &mut stack)
+            .unwrap();
+        let expr = Expr::compile(src, &ctx).unwrap();
+        assert_eq!(
+            expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+            Ok((value, expected.clone())),
+            "{src}"
+        );
+
+        let ctx = ctx.lock();
+        let expr = Expr::compile(src, &ctx).unwrap();
+        assert_eq!(
+            expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+            Ok((value, expected.clone())),
+            "{src}"
+        );
+
+        let ctx = Context::new(indexed_vars(), fns());
+        let expr = Expr::compile(src, &ctx).unwrap();
+        assert_eq!(
+            expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+            Ok((value, expected.clone())),
+            "{src}"
+        );
+
+        let mut indexed_fns = IndexedResolver::new();
+        indexed_fns.add_id('f', 1);
+        let ctx = Context::new(default_vars(), indexed_fns);
+        if let Ok(expr) = Expr::compile(src, &ctx) {
+            assert_eq!(
+                expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+                Ok((value, expected.clone())),
+                "{src}"
+            );
+        }
+
+        let mut indexed_fns = IndexedResolver::new();
+        indexed_fns.add_id('f', 1);
+        let ctx = Context::new(indexed_vars(), indexed_fns);
+        if let Ok(expr) = Expr::compile(src, &ctx) {
+            assert_eq!(
+                expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+                Ok((value, expected)),
+                "{src}"
+            );
+        }
+
+        assert!(stack.is_empty());
+    }
+}
+
+#[test]
+fn test_gradient_indexed_fns()
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.set(
+        'f',
+        0,
+        ExprFn::new(|x| x[0] * x[1]).with_derivative(|x, d| {
+            d[0] = x[1];
+            d[1] = x[0];
+        }),
+    );
+    fns.set('f', 1, ExprFn::new(|x| x[0].exp()));
+
+    let ctx = Context::new(indexed_vars(), fns);
+    let mut stack = Vec::new();
+    let wrt = [GradVar::Indexed('y', 0), GradVar::Indexed('y', 1)];
+
+    let expr = Expr::compile("f0(y0 * y0, y1 + 1) + f1(p0)", &ctx).unwrap();
+    let (val, grad) = expr.eval_with_gradient(&ctx, &wrt, &mut stack).unwrap();
+    assert_eq!(val, 2.25 * 3.0 + 1f64.exp());
+    assert_eq!(grad, vec![2.0 * 1.5 * 3.0, 2.25]);
+
+    let expr = Expr::compile("f1(y1)", &ctx).unwrap();
+    assert_eq!(
+        expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+        Err(Error::UnknownDerivative("f1".into()))
+    );
+
+    // The gradient isn't needed with respect to p0
+    let expr = Expr::compile("f1(y1)", &ctx).unwrap();
+    assert_eq!(
+        expr.eval_with_gradient(&ctx, &[GradVar::Name("p0")], &mut stack),
+        Ok((2f64.exp(), vec![0.0]))
+    );
+}
+
+#[test]
+fn test_gradient_selection()
+{
+    let ctx = Context::new(default_vars(), fns());
+    let mut stack = Vec::new();
+
+    let expr = Expr::compile("y0 * y1 + 3", &ctx).unwrap();
+
+    // Variables missing from the expression have a zero derivative, repeated ones are repeated
+    let wrt = [
+        GradVar::Name("p3"),
+        GradVar::Name("y1"),
+        GradVar::Indexed('y', 1),
+    ];
+    assert_eq!(
+        expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+        Ok((6.0, vec![0.0, 1.5, 1.5]))
+    );
+    assert_eq!(
+        expr.eval_with_gradient(&ctx, &[], &mut stack),
+        Ok((6.0, vec![]))
+    );
+
+    let expr = Expr::compile("unknown + 1", &ctx).unwrap();
+    assert!(matches!(
+        expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+        Err(Error::UnknownVar(_, _))
+    ));
+}
@@ -0,0 +1,178 @@
+#![cfg(feature = "jit")]
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 12] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2)) / y0",
+    "-y0 ^ 2 + !(y1 > p0) * 3 % 2 + y1 ^ 0.5",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 > 1 || f0(y1)) + (p0 && y1 - 2 && f1(y0, y1))",
+    "f1(y0 > 0 ? y1 : p0, y1 && p0) * z0()",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+    "(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2) + (-y0 * 8 >> 1)",
+    "(y0 <= y1) + (y0 >= p0) * 2 + (y1 == 2) * 4 + (p0 != p0) * 8 + !p0",
+];
+
+const VALUES: [[f64; 3]; 5] = [
+    [1.5, 2.0, -0.5],
+    [-2.0, 0.0, 3.0],
+    [0.5, 3.0, 0.0],
+    [2.0, 1.0, 0.25],
+    [f64::NAN, -0.0, f64::INFINITY],
+];
+
+fn indexed_vars(values: [f64; 3]) -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, values[0]);
+    vars.set('y', 1, values[1]);
+    vars.add_id('p', 1);
+    vars.set('p', 0, values[2]);
+    vars
+}
+
+fn indexed_fns() -> IndexedResolver<Unlocked, ExprFn>
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.set(
+        'f',
+        0,
+        ExprFn::new(|x| x[0] * x[0] + 1.0).with_derivative(|x, d| d[0] = 2.0 * x[0]),
+    );
+    fns.set(
+        'f',
+        1,
+        ExprFn::new(|x| x[0] * 3.0 - x[1]).with_derivative(|_, d| {
+            d[0] = 3.0;
+            d[1] = -1.0;
+        }),
+    );
+    fns.add_id('z', 1);
+    fns.set('z', 0, ExprFn::from_closure(|_| 4.0));
+    fns
+}
+
+/// Same value, considering every `NaN` equal
+fn same(a: f64, b: f64) -> bool
+{
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+#[test]
+fn test_jit_eval()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(indexed_vars(values), indexed_fns()).lock();
+            let rpn = Expr::<LRpn>::try_from((src, &ctx)).unwrap();
+            let expected = rpn.eval(&ctx, &mut stack).unwrap();
+
+            let jit = Jit::try_from(&rpn).unwrap();
+            let res = jit.eval(&ctx, &mut stack).unwrap();
+            assert!(same(res, expected), "{src} {values:?}: {res} != {expected}");
+
+            let reg: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+            let jit = Jit::try_from(&reg).unwrap();
+            let res = jit.eval_stackless::<0>(&ctx).unwrap();
+            assert!(same(res, expected), "{src} {values:?}: {res} != {expected}");
+            assert_eq!(jit.code(), &reg);
+        }
+    }
+}
+
+#[test]
+fn test_jit_optimized()
+{
+    let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let mut rpn = Expr::<LRpn>::try_from((src, &ctx)).unwrap();
+        let expected = rpn.eval(&ctx, &mut stack);
+        rpn.simplify(SimplifyMode::IeeeSafe);
+        rpn.eliminate_common_subexprs();
+
+        let jit = Jit::try_from(&rpn).unwrap();
+        assert_eq!(jit.eval(&ctx, &mut stack), expected, "{src}");
+    }
+}
+
+#[test]
+fn test_jit_reads_vars()
+{
+    let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+    let y0 = ctx.get_var_ptr("y0").unwrap();
+    let p0 = ctx.get_var_ptr("p0").unwrap();
+
+    let expr = Expr::compile("y0 > 0 ? f0(y0) : p0 * 2", &ctx).unwrap();
+    let jit = Jit::try_from(&expr).unwrap();
+    assert_eq!(jit.eval_stackless::<0>(&ctx), Ok(3.25));
+
+    for (y, p) in [(2.0, 1.0), (-1.0, 4.0), (0.0, -3.5)] {
+        y0.set(y);
+        p0.set(p);
+        let expected = if y > 0.0 { y * y + 1.0 } else { p * 2.0 };
+        assert_eq!(jit.eval_stackless::<0>(&ctx), Ok(expected));
+    }
+}
+
+#[test]
+fn test_jit_gradient()
+{
+    let wrt = [
+        GradVar::Name("y0"),
+        GradVar::Indexed('y', 1),
+        GradVar::Name("p0"),
+    ];
+    let mut stack = Vec::new();
+
+    for src in &EXPRS[..10] {
+        let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+        let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+        let expected = expr.eval_with_gradient(&ctx, &wrt, &mut stack);
+
+        let jit = Jit::try_from(&expr).unwrap();
+        assert_eq!(
+            jit.eval_with_gradient(&ctx, &wrt, &mut stack),
+            expected,
+            "{src}"
+        );
+    }
+}
+
+#[test]
+fn test_jit_batch()
+{
+    let vars = [GradVar::Name("y0"), GradVar::Name("p0")];
+    let y0: Vec<f64> = VALUES.iter().map(|values| values[0]).collect();
+    let p0: Vec<f64> = VALUES.iter().map(|values| values[2]).collect();
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+        let rpn = Expr::<LRpn>::try_from((src, &ctx)).unwrap();
+        let mut expected = [0.0; VALUES.len()];
+        rpn.eval_batch(&ctx, &vars, &[&y0, &p0], &mut expected)
+            .unwrap();
+
+        let jit = Jit::try_from(&rpn).unwrap();
+        let mut res = [0.0; VALUES.len()];
+        jit.eval_batch(&ctx, &vars, &[&y0, &p0], &mut res).unwrap();
+        assert!(
+            res.iter().zip(expected).all(|(a, b)| same(*a, b)),
+            "{src}: {res:?} != {expected:?}"
+        );
+
+        // The context is left untouched
+        assert_eq!(jit.eval(&ctx, &mut stack), rpn.eval(&ctx, &mut stack));
+        assert_eq!(ctx.get_var_ptr("y0").unwrap().get(), VALUES[0][0]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 14] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2)) / y0",
+    "-y0 ^ 2 + !(y1 > p0) * 3 % 2 + y1 ^ 0.5",
+    "y0 ^ p0 + (y0 % y1) - y1 ^ -2",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 > 1 || f0(y1)) + (p0 && y1 - 2 && f1(y0, y1))",
+    "f1(y0 > 0 ? y1 : p0, y1 && p0) * z0()",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+    "(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2) + (-y0 * 8 >> 1)",
+    "(y0 <= y1) + (y0 >= p0) * 2 + (y1 == 2) * 4 + (p0 != p0) * 8 + !p0",
+    "2 * 3 + 1",
+];
+
+/// Not a multiple of the width of the lanes
+const ROWS: usize = 1003;
+
+const VARS: [GradVar; 3] = [
+    GradVar::Name("y0"),
+    GradVar::Name("y1"),
+    GradVar::Indexed('p', 0),
+];
+
+/// Samples of a linear congruential generator, with a few special values
+fn columns() -> [Vec<f64>; 3]
+{
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut sample = move || {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        ((seed >> 11) as f64 / (1u64 << 53) as f64) * 8.0 - 4.0
+    };
+
+    let special = [0.0, -0.0, 1.0, 2.0, f64::NAN, f64::INFINITY, -3.0];
+    let mut column = |every: usize| -> Vec<f64> {
+        (0..ROWS)
+            .map(|row| {
+                let x = sample();
+                if row % every == 0 {
+                    special[row / every % special.len()]
+                } else {
+                    x
+                }
+            })
+            .collect()
+    };
+
+    [column(5), column(7), column(11)]
+}
+
+fn fns() -> [(char, usize, ExprFn); 3]
+{
+    [
+        ('f', 0, ExprFn::new(|x| x[0] * x[0] + 1.0)),
+        (
+            'f',
+            1,
+            ExprFn::new(|x| x[0] * 3.0 - x[1]).with_vectorized(|x, out| {
+                for (row, out) in out.iter_mut().enumerate() {
+                    *out = x[0][row] * 3.0 - x[1][row];
+                }
+            }),
+        ),
+        ('z', 0, ExprFn::new(|_| 4.0)),
+    ]
+}
+
+fn indexed_vars() -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.add_id('p', 1);
+    vars
+}
+
+fn indexed_fns() -> IndexedResolver<Unlocked, ExprFn>
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.add_id('z', 1);
+
+    for (id, idx, f) in self::fns() {
+        fns.set(id, idx, f);
+    }
+
+    fns
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+
+    for (id, idx, f) in self::fns() {
+        fns.insert(format!("{id}{idx}"), f);
+    }
+
+    fns
+}
+
+/// Values of the expression given by the scalar evaluator
+fn expected(src: &str, columns: &[Vec<f64>; 3]) -> Vec<f64>
+{
+    let ctx = Context::new(default_vars([0.0; 3]), default_fns()).lock();
+    let expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+    let ptrs = ["y0", "y1", "p0"].map(|name| ctx.get_var_ptr(name).unwrap());
+    let mut stack = Vec::new();
+
+    (0..ROWS)
+        .map(|row| {
+            for (ptr, column) in ptrs.iter().zip(columns) {
+                ptr.set(column[row]);
+            }
+            expr.eval(&ctx, &mut stack).unwrap()
+        })
+        .collect()
+}
+
+/// Same values, considering every `NaN` equal
+fn assert_same(src: &str, res: &[f64], expected: &[f64])
+{
+    for (row, (a, b)) in res.iter().zip(expected).enumerate() {
+        assert!(
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+            "{src} row {row}: {a} != {b}"
+        );
+    }
+}
+
+#[test]
+fn test_lanes_all_exprs()
+{
+    let columns = columns();
+    let cols: Vec<&[f64]> = columns.iter().map(Vec::as_slice).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in EXPRS {
+        let expected = expected(src, &columns);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns());
+        let expr: Expr<Rpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_lanes::<F64x4>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+        expr.eval_lanes::<F64x8>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(indexed_vars(), indexed_fns());
+        let expr: Expr<IRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_lanes::<F64x4>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+        expr.eval_lanes::<F64x8>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(default_vars([0.0; 3]), default_fns()).lock();
+        let expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_lanes::<F64x8>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+
+        let ctx = Context::new(indexed_vars(), indexed_fns()).lock();
+        let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+        expr.eval_lanes::<F64x4>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+    }
+}
+
+#[test]
+fn test_lanes_optimized()
+{
+    let columns = columns();
+    let cols: Vec<&[f64]> = columns.iter().map(Vec::as_slice).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in EXPRS {
+        let expected = expected(src, &columns);
+
+        let ctx = Context::new(indexed_vars(), indexed_fns());
+        let mut expr: Expr<IRpn> = Expr::compile(src, &ctx).unwrap();
+        expr.simplify(SimplifyMode::IeeeSafe);
+        expr.eliminate_common_subexprs(&ctx);
+        expr.eval_lanes::<F64x4>(&ctx, &VARS, &cols, &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+    }
+}
+
+#[test]
+fn test_lanes_short_columns()
+{
+    let ctx = Context::new(default_vars([0.0, 0.0, 2.0]), default_fns());
+    let expr = Expr::compile("y0 > 0 ? y0 ^ p0 : f1(y0, p0)", &ctx).unwrap();
+    let y0 = [3.0, -1.0, 0.5];
+
+    // Fewer rows than a single lane
+    let mut out = [0.0; 3];
+    expr.eval_lanes::<F64x8>(&ctx, &[GradVar::Name("y0")], &[&y0], &mut out)
+        .unwrap();
+    assert_eq!(out, [9.0, -5.0, 0.25]);
+
+    let mut out = [];
+    expr.eval_lanes::<F64x4>(&ctx, &[GradVar::Name("y0")], &[&y0], &mut out)
+        .unwrap();
+}
+
+#[test]
+fn test_lanes_untaken_calls()
+{
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(x: &[f64]) -> f64
+    {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        x[0]
+    }
+
+    // Indices out of the table panic
+    fn table(x: &[f64]) -> f64
+    {
+        assert!(
+            (0.0..4.0).contains(&x[0]),
+            "index {} out of the table",
+            x[0]
+        );
+        [1.0, 2.0, 3.0, 4.0][x[0] as usize]
+    }
+
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("count".to_string(), ExprFn::new(count).impure());
+    fns.insert(
+        "vcount".to_string(),
+        ExprFn::new(count)
+            .with_vectorized(|x, out| {
+                CALLS.fetch_add(out.len(), Ordering::Relaxed);
+                out.copy_from_slice(x[0]);
+            })
+            .impure(),
+    );
+    fns.insert(
+        "vtable".to_string(),
+        ExprFn::new(table).with_vectorized(|x, out| {
+            for (x, out) in x[0].iter().zip(out) {
+                *out = table(&[*x]);
+            }
+        }),
+    );
+
+    let mut vars = DefaultResolver::empty();
+    vars.insert("i".to_string(), 0.0);
+    let ctx = Context::new(vars, fns).lock();
+
+    let column: Vec<f64> = (0..ROWS).map(|row| (row % 11) as f64 - 5.0).collect();
+    let mut out = vec![0.0; ROWS];
+
+    for src in [
+        "i >= 0 && i < 4 ? vtable(i) : i < -1 ? vtable(-i - 2) : -1",
+        "i < 0 || i > 3 || vtable(i) > 2",
+        "i > 1 ? count(i) : i > -2 ? vcount(-i) : count(i) + vcount(i)",
+        "(i && count(i) || vcount(i) ? (i < 2 ? vcount(i) : 1) : count(-i)) + vcount(i)",
+    ] {
+        let expr: Expr<LRpn> = Expr::compile(src, &ctx).unwrap();
+
+        CALLS.store(0, Ordering::Relaxed);
+        let expected: Vec<f64> = column
+            .iter()
+            .map(|i| {
+                ctx.get_var_ptr("i").unwrap().set(*i);
+                expr.eval(&ctx, &mut Vec::new()).unwrap()
+            })
+            .collect();
+        let calls = CALLS.swap(0, Ordering::Relaxed);
+
+        // Functions are only called on the rows taking their branch
+        expr.eval_lanes::<F64x4>(&ctx, &[GradVar::Name("i")], &[&column], &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+        assert_eq!(CALLS.swap(0, Ordering::Relaxed), calls, "{src}");
+
+        expr.eval_lanes::<F64x8>(&ctx, &[GradVar::Name("i")], &[&column], &mut out)
+            .unwrap();
+        assert_same(src, &out, &expected);
+        assert_eq!(CALLS.load(Ordering::Relaxed), calls, "{src}");
+    }
+}
@@ -0,0 +1,169 @@
+#![cfg(feature = "rayon")]
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 8] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2)) / y0",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+    "(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2)",
+    "2 * 3 + 1",
+];
+
+/// Several ranges of rows, the last one partially filled
+const ROWS: usize = 10_000;
+
+const VARS: [GradVar; 2] = [GradVar::Name("y0"), GradVar::Name("y1")];
+
+fn columns() -> [Vec<f64>; 2]
+{
+    let y0 = (0..ROWS).map(|i| (i * 7 % 13) as f64 * 0.5 - 3.0).collect();
+    let y1 = (0..ROWS)
+        .map(|i| {
+            if i % 97 == 0 {
+                f64::NAN
+            } else {
+                (i * 5 % 11) as f64 * 0.25 - 1.0
+            }
+        })
+        .collect();
+    [y0, y1]
+}
+
+fn default_vars() -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), 0.0);
+    vars.insert("y1".to_string(), 0.0);
+    vars.insert("p0".to_string(), 1.5);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("f0".to_string(), ExprFn::new(|x| x[0] * x[0] + 1.0));
+    fns.insert(
+        "f1".to_string(),
+        ExprFn::new(|x| x[0] * 3.0 - x[1]).with_vectorized(|x, out| {
+            for (row, out) in out.iter_mut().enumerate() {
+                *out = x[0][row] * 3.0 - x[1][row];
+            }
+        }),
+    );
+    fns
+}
+
+/// Expects the outputs of the parallel evaluation of `n` expressions to hold
+/// the values of their sequential evaluation, considering every `NaN` equal
+fn assert_same(n: usize, par: impl FnOnce(&mut [&mut [f64]]), seq: impl Fn(usize, &mut [f64]))
+{
+    let mut outs = vec![vec![0.0; ROWS]; n];
+    let mut out_refs: Vec<&mut [f64]> = outs.iter_mut().map(Vec::as_mut_slice).collect();
+    par(&mut out_refs);
+
+    let mut expected = vec![0.0; ROWS];
+    for (i, out) in outs.iter().enumerate() {
+        seq(i, &mut expected);
+
+        for (row, (a, b)) in out.iter().zip(&expected).enumerate() {
+            assert!(
+                a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan()),
+                "expr {i} row {row}: {a} != {b}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_par_eval_batch()
+{
+    let columns = columns();
+    let cols: Vec<&[f64]> = columns.iter().map(Vec::as_slice).collect();
+    // Many more expressions than threads
+    let srcs: Vec<&str> = EXPRS.iter().copied().cycle().take(64).collect();
+
+    let ctx = Context::new(default_vars(), default_fns());
+    let exprs: Vec<Expr<Rpn>> = srcs
+        .iter()
+        .map(|src| Expr::compile(src, &ctx).unwrap())
+        .collect();
+    assert_same(
+        exprs.len(),
+        |outs| par_eval_batch(&exprs, &ctx, &VARS, &cols, outs).unwrap(),
+        |i, out| exprs[i].eval_batch(&ctx, &VARS, &cols, out).unwrap(),
+    );
+
+    let ctx = Context::new(default_vars(), default_fns()).lock();
+    let exprs: Vec<Expr<LRpn>> = srcs
+        .iter()
+        .map(|src| Expr::compile(src, &ctx).unwrap())
+        .collect();
+    assert_same(
+        exprs.len(),
+        |outs| par_eval_batch(&exprs, &ctx, &VARS, &cols, outs).unwrap(),
+        |i, out| exprs[i].eval_batch(&ctx, &VARS, &cols, out).unwrap(),
+    );
+
+    let regs: Vec<Expr<Reg>> = exprs
+        .iter()
+        .map(|expr| Expr::try_from(expr).unwrap())
+        .collect();
+    assert_same(
+        regs.len(),
+        |outs| par_eval_batch(&regs, &ctx, &VARS, &cols, outs).unwrap(),
+        |i, out| regs[i].eval_batch(&ctx, &VARS, &cols, out).unwrap(),
+    );
+}
+
+#[test]
+fn test_par_eval_batch_error()
+{
+    let ctx = Context::new(default_vars(), default_fns());
+    let exprs = ["y0 + 1", "y0 + q0"].map(|src| Expr::compile(src, &ctx).unwrap());
+
+    let columns = columns();
+    let mut outs = [vec![0.0; ROWS], vec![0.0; ROWS]];
+    let mut out_refs: Vec<&mut [f64]> = outs.iter_mut().map(Vec::as_mut_slice).collect();
+    let res = par_eval_batch(
+        &exprs,
+        &ctx,
+        &VARS,
+        &[&columns[0], &columns[1]],
+        &mut out_refs,
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+#[should_panic(expected = "one output is needed per expression")]
+fn test_par_eval_batch_missing_output()
+{
+    let ctx = Context::new(default_vars(), default_fns());
+    let exprs = ["y0 + 1", "y0 * 2"].map(|src| Expr::compile(src, &ctx).unwrap());
+    let _ = par_eval_batch(&exprs, &ctx, &VARS, &[&[1.0], &[2.0]], &mut [&mut [0.0]]);
+}
+
+#[test]
+fn test_send_sync()
+{
+    fn send_sync<T: Send + Sync>() {}
+
+    send_sync::<Ptr<'static, f64>>();
+    send_sync::<
+        UContext<
+            DefaultResolver<Unlocked, String, f64>,
+            DefaultResolver<Unlocked, String, ExprFn>,
+            DefaultResolver<Locked, String, f64>,
+            DefaultResolver<Locked, String, ExprFn>,
+        >,
+    >();
+    send_sync::<LContext<IndexedResolver<Locked, f64>, SmallResolver<Locked, String, ExprFn>>>();
+    send_sync::<Expr<Rpn<'static>>>();
+    send_sync::<Expr<IRpn>>();
+    send_sync::<Expr<LRpn<'static>>>();
+    send_sync::<Expr<Reg<'static>>>();
+}
@@ -24,9 +24,9 @@ fn main()
 
 fn max(x: &[f64]) -> f64 {
     let mut max = x[0];
-    for i in 1..x.len() {
-        if x[i] > max {
-            max = x[i];
+    for &v in &x[1..] {
+        if v > max {
+            max = v;
         }
     }
     max
@@ -0,0 +1,281 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 10] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2))",
+    "-y0 ^ 2 + !(y1 > p0) * 3 % 2",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 > 1 || f0(y1)) + (p0 && y1 - 2 && f1(y0, y1))",
+    "f1(y0 > 0 ? y1 : p0, y1 && p0) * z0()",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+];
+
+const VALUES: [[f64; 3]; 4] = [
+    [1.5, 2.0, -0.5],
+    [-2.0, 0.0, 3.0],
+    [0.5, 3.0, 0.0],
+    [2.0, 1.0, 0.25],
+];
+
+fn indexed_vars(values: [f64; 3]) -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, values[0]);
+    vars.set('y', 1, values[1]);
+    vars.add_id('p', 1);
+    vars.set('p', 0, values[2]);
+    vars
+}
+
+fn fns() -> [(char, usize, ExprFn); 3]
+{
+    [
+        (
+            'f',
+            0,
+            ExprFn::new(|x| x[0] * x[0] + 1.0).with_derivative(|x, d| d[0] = 2.0 * x[0]),
+        ),
+        (
+            'f',
+            1,
+            ExprFn::new(|x| x[0] * 3.0 - x[1]).with_derivative(|_, d| {
+                d[0] = 3.0;
+                d[1] = -1.0;
+            }),
+        ),
+        ('z', 0, ExprFn::new(|_| 4.0)),
+    ]
+}
+
+fn indexed_fns() -> IndexedResolver<Unlocked, ExprFn>
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 2);
+    fns.add_id('z', 1);
+
+    for (id, idx, f) in self::fns() {
+        fns.set(id, idx, f);
+    }
+
+    fns
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+
+    for (id, idx, f) in self::fns() {
+        fns.insert(format!("{id}{idx}"), f);
+    }
+
+    fns
+}
+
+#[test]
+fn test_reg_compile()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(indexed_vars(values), indexed_fns());
+            let expected = Expr::compile(src, &ctx)
+                .unwrap()
+                .eval(&ctx, &mut stack)
+                .unwrap();
+
+            let ctx = ctx.lock();
+            let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}\n{expr}");
+            assert!(stack.is_empty());
+
+            let rpn = Expr::<LRpn>::try_from((src, &ctx)).unwrap();
+            assert_eq!(rpn.eval(&ctx, &mut stack), Ok(expected), "{src}");
+
+            let ast = parse(src).unwrap();
+            let expr: Expr<Reg> = Expr::from_ast(&ast, &ctx).unwrap();
+            assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+        }
+    }
+}
+
+#[test]
+fn test_reg_lowering()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+        let expected = Expr::<LRpn>::try_from((src, &ctx))
+            .unwrap()
+            .eval(&ctx, &mut stack)
+            .unwrap();
+
+        let mut rpn = Expr::<LRpn>::try_from((src, &ctx)).unwrap();
+        rpn.simplify(SimplifyMode::IeeeSafe);
+        rpn.eliminate_common_subexprs();
+
+        let expr = Expr::<Reg>::try_from(&rpn).unwrap();
+        assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}\n{expr}");
+
+        // Any locked expression lowers into register code
+        let ctx = Context::new(default_vars(VALUES[0]), default_fns()).lock();
+        let rpn = Expr::compile(src, &ctx).unwrap();
+        let expr = Expr::<Reg>::try_from(&rpn).unwrap();
+        assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}\n{expr}");
+    }
+}
+
+#[test]
+fn test_reg_gradient()
+{
+    let wrt = [
+        GradVar::Name("y0"),
+        GradVar::Indexed('y', 1),
+        GradVar::Name("p0"),
+        GradVar::Name("y0"),
+    ];
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(indexed_vars(values), indexed_fns()).lock();
+            let expected = Expr::<LRpn>::try_from((src, &ctx))
+                .unwrap()
+                .eval_with_gradient(&ctx, &wrt, &mut stack)
+                .unwrap();
+
+            let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(
+                expr.eval_with_gradient(&ctx, &wrt, &mut stack),
+                Ok(expected),
+                "{src}"
+            );
+            assert!(stack.is_empty());
+        }
+    }
+}
+
+#[test]
+fn test_reg_lanes()
+{
+    let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+    let vars = [GradVar::Name("y0"), GradVar::Name("y1")];
+    let y0 = ctx.get_var_ptr("y0").unwrap();
+    let y1 = ctx.get_var_ptr("y1").unwrap();
+
+    // Not a multiple of the width of the lanes
+    let y0s: Vec<f64> = (0..11).map(|row| row as f64 * 0.5 - 2.0).collect();
+    let y1s: Vec<f64> = (0..11).map(|row| (row % 3) as f64).collect();
+    let mut out = [0.0; 11];
+
+    for src in EXPRS {
+        let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+        let expected: Vec<f64> = y0s
+            .iter()
+            .zip(&y1s)
+            .map(|(a, b)| {
+                y0.set(*a);
+                y1.set(*b);
+                expr.eval(&ctx, &mut Vec::new()).unwrap()
+            })
+            .collect();
+
+        expr.eval_lanes::<F64x4>(&ctx, &vars, &[&y0s, &y1s], &mut out)
+            .unwrap();
+        assert_eq!(out.as_slice(), expected, "{src}");
+        expr.eval_lanes::<F64x8>(&ctx, &vars, &[&y0s, &y1s], &mut out)
+            .unwrap();
+        assert_eq!(out.as_slice(), expected, "{src}");
+    }
+
+    // Functions are only called on the rows taking their branch, a lane at a time
+    static LANES: AtomicUsize = AtomicUsize::new(0);
+
+    fn root(x: &[f64]) -> f64
+    {
+        assert!(x[0] >= 0.0, "f0 called on {}", x[0]);
+        x[0].sqrt()
+    }
+
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    fns.set(
+        'f',
+        0,
+        ExprFn::new(root).with_vectorized(|x, out| {
+            LANES.fetch_add(1, Ordering::Relaxed);
+            for (x, out) in x[0].iter().zip(out) {
+                *out = root(&[*x]);
+            }
+        }),
+    );
+    let ctx = Context::new(indexed_vars(VALUES[0]), fns).lock();
+    let expr: Expr<Reg> = Expr::compile("y0 >= 0 ? f0(y0) : y0 < -1 && f0(-y0)", &ctx).unwrap();
+
+    expr.eval_lanes::<F64x4>(&ctx, &vars, &[&y0s, &y1s], &mut out)
+        .unwrap();
+    // Once per lane: in the else branch of rows 0..4, the then branch of the others
+    assert_eq!(LANES.load(Ordering::Relaxed), 3);
+    assert_eq!(out[..6], [1.0, 1.0, 0.0, 0.0, 0.0, 0.5f64.sqrt()]);
+}
+
+#[test]
+fn test_reg_code()
+{
+    let ctx = Context::new(indexed_vars(VALUES[0]), indexed_fns()).lock();
+    let y0 = ctx.get_var_ptr("y0").unwrap();
+    let y1 = ctx.get_var_ptr("y1").unwrap();
+    let f1 = ctx.get_fn_ptr("f1").unwrap();
+
+    // Constants and variables are read in place
+    let expr: Expr<Reg> = Expr::compile("y0 * 2 + y1", &ctx).unwrap();
+    assert_eq!(
+        expr.to_string(),
+        format!("r0 = @{y0:p} * 2\nr0 = r0 + @{y1:p}\nret r0")
+    );
+    assert_eq!(expr.max_stack_depth(), 1);
+
+    // Arguments and branch values are moved to their register
+    let expr: Expr<Reg> = Expr::compile("y0 > 1 ? f1(y1, 2) : -y0", &ctx).unwrap();
+    assert_eq!(
+        expr.to_string(),
+        format!(
+            "r0 = @{y0:p} > 1\nif !r0 jmp +4\nr0 = @{y1:p}\nr1 = 2\n\
+             r0 = @{f1:p}(r0, r1)\njmp +1\nr0 = -@{y0:p}\nret r0"
+        )
+    );
+    assert_eq!(expr.spans().len(), expr.len());
+    assert_eq!(expr.max_stack_depth(), 2);
+    assert_eq!(
+        expr.display(&ctx).to_string(),
+        "r0 = y0 > 1\nif !r0 jmp +4\nr0 = y1\nr1 = 2\nr0 = f1(r0, r1)\njmp +1\nr0 = -y0\nret r0"
+    );
+
+    let expr: Expr<Reg> = Expr::compile("y1 && 3", &ctx).unwrap();
+    assert_eq!(
+        expr.to_string(),
+        format!("if !@{y1:p} r0 = 0, jmp +1\nr0 = @{y1:p} && 3\nret r0")
+    );
+    assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(1.0));
+
+    let expr: Expr<Reg> = Expr::compile("2 * 3", &ctx).unwrap();
+    assert_eq!(expr.to_string(), "ret 6");
+    assert_eq!(expr.max_stack_depth(), 0);
+    assert_eq!(expr.eval(&ctx, &mut Vec::new()), Ok(6.0));
+}
@@ -0,0 +1,198 @@
+use fee::{prelude::*, *};
+
+const EXPRS: [&str; 8] = [
+    "--y0 * 1 + y1 / -1 - 0",
+    "(y0 * 2) * 3 + (y1 + 1) + 2",
+    "0 * f(y0) + y1 ^ 1 - y0 ^ 0",
+    "-y0 * -y1 - -p0 + y0 - y0",
+    "y0 > 1 ? y1 * -1 : p0 + -0 || y1 && false",
+    "(y0 + 0) / (y0 + 0) + 0 - y1",
+    "f(y0 * 1) ^ 0 + (f(p0) || true)",
+    "y0 ? -(-y1 + -0) : p0 ^ (2 - 1)",
+];
+
+const VALUES: [[f64; 3]; 6] = [
+    [1.5, 2.0, -0.5],
+    [-0.0, 0.0, -0.0],
+    [f64::INFINITY, -1.0, 2.0],
+    [f64::NAN, 3.0, 0.0],
+    [0.0, f64::NEG_INFINITY, f64::NAN],
+    [-2.0, -0.0, f64::INFINITY],
+];
+
+fn f(x: &[f64]) -> f64
+{
+    x[0] * 0.5 + 1.0
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn indexed_vars(values: [f64; 3]) -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, values[0]);
+    vars.set('y', 1, values[1]);
+    vars.add_id('p', 1);
+    vars.set('p', 0, values[2]);
+    vars
+}
+
+fn fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("f".to_string(), ExprFn::new(f));
+    fns
+}
+
+/// Same value, telling `0` and `-0` apart and considering every `NaN` equal
+fn same(a: f64, b: f64) -> bool
+{
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+#[test]
+fn test_simplify_rules()
+{
+    let cases = [
+        ("x * 1 + 1 * y / 1", "x + y", "x + y"),
+        ("x - 0 + -0 + 0", "x + 0", "x"),
+        ("0 + x - -0", "0 + x - -0", "x"),
+        ("--x ^ 1 + -(-y)", "x + y", "x + y"),
+        ("x * -1 + y / -1", "-x - y", "-x - y"),
+        ("-x * -y - -z", "x * y + z", "x * y + z"),
+        ("-x / -y + 0 - x", "x / y + 0 - x", "x / y - x"),
+        ("0 * f(x) + x * 0 + 0 / x", "0 * f(x) + x * 0 + 0 / x", "0"),
+        (
+            "(2 * x) * 3 + 3 * (x * 4)",
+            "2 * x * 3 + 3 * (x * 4)",
+            "6 * x + 12 * x",
+        ),
+        ("(x + 1) + 2 + (3 + y)", "x + 1 + 2 + (3 + y)", "x + y + 6"),
+        ("x ^ 0 + f(x) ^ 0", "1 + f(x) ^ 0", "2"),
+        ("x - x + y / y", "x - x + y / y", "1"),
+        ("f(x) - f(x)", "f(x) - f(x)", "f(x) - f(x)"),
+        ("x && false || (y || true)", "1", "1"),
+        ("f(x) && 0", "f(x) && 0", "0"),
+        ("true ? x * 1 : y", "x", "x"),
+        (
+            "x > 1 ? y * 1 : (2 + 3) * z",
+            "x > 1 ? y : 5 * z",
+            "x > 1 ? y : 5 * z",
+        ),
+    ];
+
+    for (src, safe, fast) in cases {
+        let mut ast = parse(src).unwrap();
+        ast.simplify(SimplifyMode::IeeeSafe);
+        assert_eq!(ast.to_string(), safe, "{src}");
+
+        let mut ast = parse(src).unwrap();
+        ast.simplify(SimplifyMode::FastMath);
+        assert_eq!(ast.to_string(), fast, "{src}");
+    }
+}
+
+#[test]
+fn test_simplify_ieee_safe()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(default_vars(values), fns());
+            let expected = Expr::compile(src, &ctx)
+                .unwrap()
+                .eval(&ctx, &mut stack)
+                .unwrap();
+
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            expr.simplify(SimplifyMode::IeeeSafe);
+            let val = expr.eval(&ctx, &mut stack).unwrap();
+            assert!(
+                same(val, expected),
+                "{src} at {values:?}: {val} != {expected}"
+            );
+
+            let ctx = ctx.lock();
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            expr.simplify(SimplifyMode::IeeeSafe);
+            let val = expr.eval(&ctx, &mut stack).unwrap();
+            assert!(
+                same(val, expected),
+                "{src} at {values:?}: {val} != {expected}"
+            );
+
+            let ctx = Context::new(indexed_vars(values), fns());
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            expr.simplify(SimplifyMode::IeeeSafe);
+            let val = expr.eval(&ctx, &mut stack).unwrap();
+            assert!(
+                same(val, expected),
+                "{src} at {values:?}: {val} != {expected}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_simplify_fast_math()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        // Finite operands, away from the zeros whose sign fast math ignores
+        for values in [[1.5, 2.0, -0.5], [-2.0, 0.25, 3.0]] {
+            let ctx = Context::new(default_vars(values), fns());
+            let expected = Expr::compile(src, &ctx)
+                .unwrap()
+                .eval(&ctx, &mut stack)
+                .unwrap();
+
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            let len = expr.len();
+            expr.simplify(SimplifyMode::FastMath);
+            assert!(expr.len() <= len, "{src}: {expr}");
+            assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+
+            let ctx = Context::new(indexed_vars(values), fns());
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            expr.simplify(SimplifyMode::FastMath);
+            assert_eq!(expr.eval(&ctx, &mut stack), Ok(expected), "{src}");
+        }
+    }
+
+    let ctx = Context::new(default_vars([1.0, 2.0, 3.0]), fns());
+    let mut expr = Expr::compile("0 * f(y0) + (y1 * 2) * 3", &ctx).unwrap();
+    expr.simplify(SimplifyMode::FastMath);
+    assert_eq!(expr.to_string(), "6 * y1");
+    assert_eq!(expr.len(), 3);
+}
+
+#[test]
+fn test_simplify_spans()
+{
+    let ctx = Context::new(default_vars([1.0, 2.0, 3.0]), fns());
+    let mut stack = Vec::new();
+
+    let src = "y0 * 1 + (unknown > 0 ? --y1 : p0 ^ 1)";
+    let mut expr = Expr::compile(src, &ctx).unwrap();
+    expr.simplify(SimplifyMode::IeeeSafe);
+    assert_eq!(expr.to_string(), "y0 + (unknown > 0 ? y1 : p0)");
+
+    // The remaining tokens keep the span they were compiled from
+    let compiled = Expr::compile("y0 + (unknown > 0 ? y1 : p0)", &ctx).unwrap();
+    assert_eq!(expr.len(), compiled.len());
+    assert_eq!(&src[expr.spans()[0].start..expr.spans()[0].end], "y0");
+    assert_eq!(
+        expr.eval(&ctx, &mut stack),
+        Err(Error::UnknownVar("unknown".into(), Span::new(10, 17)))
+    );
+}
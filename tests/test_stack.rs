@@ -0,0 +1,131 @@
+use fee::{prelude::*, *};
+
+const EXPRS: [(&str, usize); 8] = [
+    ("y0 + 1", 2),
+    ("(y0 + 1) * (y1 - 1)", 3),
+    ("f(y0, y1, p0 * 2) + 1", 4),
+    ("y0 * (y1 * (p0 * (y0 * (y1 + 1))))", 6),
+    ("y0 > 1 ? y1 * (p0 + 1) : -y1 ^ 2", 3),
+    ("y0 < 0 && y1 || f(y0, y1 + (p0 + 1), 2)", 5),
+    ("y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)", 3),
+    ("(y0 + p0) * (y0 + p0) - f(y0 + p0, y1, 1)", 4),
+];
+
+const VALUES: [[f64; 3]; 3] = [[1.5, 2.0, -0.5], [-2.0, 0.0, 3.0], [0.5, 3.0, 0.0]];
+
+fn f(x: &[f64]) -> f64
+{
+    x[0] * x[1] - x[2]
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn indexed_vars(values: [f64; 3]) -> IndexedResolver<Unlocked, f64>
+{
+    let mut vars = IndexedResolver::new();
+    vars.add_id('y', 2);
+    vars.set('y', 0, values[0]);
+    vars.set('y', 1, values[1]);
+    vars.add_id('p', 1);
+    vars.set('p', 0, values[2]);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("f".to_string(), ExprFn::new(f));
+    fns
+}
+
+fn indexed_fns() -> IndexedResolver<Unlocked, ExprFn>
+{
+    let mut fns = IndexedResolver::new();
+    fns.add_id('f', 1);
+    fns.set('f', 0, ExprFn::new(f));
+    fns
+}
+
+#[test]
+fn test_max_stack_depth()
+{
+    let ctx = Context::new(default_vars(VALUES[0]), default_fns());
+
+    for (src, depth) in EXPRS {
+        let expr = Expr::compile(src, &ctx).unwrap();
+        assert_eq!(expr.max_stack_depth(), depth, "{src}");
+
+        let ast = parse(src).unwrap();
+        let expr = Expr::from_ast(&ast, &ctx).unwrap();
+        assert_eq!(expr.max_stack_depth(), depth, "{src}");
+    }
+
+    let expr = Expr::compile("2 * (3 + 4)", &ctx).unwrap();
+    assert_eq!(expr.max_stack_depth(), 1);
+}
+
+#[test]
+fn test_max_stack_depth_passes()
+{
+    let ctx = Context::new(default_vars(VALUES[0]), default_fns());
+
+    let mut expr = Expr::compile("y0 * (y1 * (p0 * 1 * (y0 + 0)))", &ctx).unwrap();
+    assert_eq!(expr.max_stack_depth(), 5);
+    expr.simplify(SimplifyMode::FastMath);
+    assert_eq!(expr.to_string(), "y0 * (y1 * (p0 * y0))");
+    assert_eq!(expr.max_stack_depth(), 4);
+
+    // Stored values stay on the stack until they are used
+    let mut expr = Expr::compile("(y0 + p0) * (y0 + p0) - f(y0 + p0, y1, 1)", &ctx).unwrap();
+    expr.eliminate_common_subexprs(&ctx);
+    assert_eq!(expr.max_stack_depth(), 4);
+}
+
+#[test]
+fn test_eval_stackless()
+{
+    let mut stack = Vec::new();
+
+    for (src, _) in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(default_vars(values), default_fns());
+            let expr = Expr::compile(src, &ctx).unwrap();
+            let expected = expr.eval(&ctx, &mut stack).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            // Too deep for the buffer
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+
+            let ctx = Context::new(indexed_vars(values), default_fns());
+            let expr = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+
+            let ctx = Context::new(default_vars(values), indexed_fns());
+            let src = &src.replace("f(", "f0(");
+            let expr = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+
+            let ctx = Context::new(indexed_vars(values), indexed_fns());
+            let expr = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+
+            let ctx = ctx.lock();
+            let expr: Expr<Reg> = Expr::compile(src, &ctx).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+
+            let expr = Expr::<LRpn>::try_from((src.as_str(), &ctx)).unwrap();
+            assert_eq!(expr.eval_stackless::<8>(&ctx), Ok(expected), "{src}");
+            assert_eq!(expr.eval_stackless::<1>(&ctx), Ok(expected), "{src}");
+        }
+    }
+}
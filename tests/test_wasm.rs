@@ -0,0 +1,175 @@
+#![cfg(feature = "wasm")]
+
+use fee::{prelude::*, *};
+use wasmi::{Engine, Linker, Module, Store};
+
+const EXPRS: [&str; 13] = [
+    "y0 * 2 + y1",
+    "2 - (4 + (p0 - 2) * (p0 - 2)) / y0",
+    "-y0 ^ 2 + !(y1 > p0) * 3 % 2 + y1 ^ 0.5",
+    "f0(y0 * y1) + f1(p0, 2) * f1(y1 - 1, f0(p0))",
+    "y0 > 1 ? y0 * y1 : -y1 ^ 2",
+    "y0 < 0 ? 1 : y1 > 2 ? p0 : y0 + (p0 > 0 ? y1 : 2)",
+    "y0 < 0 && y1 || y0 * 0 + 2",
+    "(y0 > 1 || f0(y1)) + (p0 && y1 - 2 && f1(y0, y1))",
+    "f1(y0 > 0 ? y1 : p0, y1 && p0) * z0()",
+    "(y0 + p0) * (y0 + p0) - f0(y0 + p0) * (y1 ? y0 + p0 : 1)",
+    "(y0 * 4 & 7) + (y1 | 8) - (y0 ^^ 3) + (y1 << 2) + (-y0 * 8 >> 1)",
+    "(y0 <= y1) + (y0 >= p0) * 2 + (y1 == 2) * 4 + (p0 != p0) * 8 + !p0",
+    "2 * 3 + 1",
+];
+
+const VALUES: [[f64; 3]; 5] = [
+    [1.5, 2.0, -0.5],
+    [-2.0, 0.0, 3.0],
+    [0.5, 3.0, 0.0],
+    [2.0, 1.0, 0.25],
+    [f64::NAN, -0.0, f64::INFINITY],
+];
+
+fn f0(x: f64) -> f64
+{
+    x * x + 1.0
+}
+
+fn f1(x: f64, y: f64) -> f64
+{
+    x * 3.0 - y
+}
+
+fn default_vars(values: [f64; 3]) -> DefaultResolver<Unlocked, String, f64>
+{
+    let mut vars = DefaultResolver::empty();
+    vars.insert("y0".to_string(), values[0]);
+    vars.insert("y1".to_string(), values[1]);
+    vars.insert("p0".to_string(), values[2]);
+    vars
+}
+
+fn default_fns() -> DefaultResolver<Unlocked, String, ExprFn>
+{
+    let mut fns = DefaultResolver::new_fns();
+    fns.insert("f0".to_string(), ExprFn::new(|x| f0(x[0])));
+    fns.insert("f1".to_string(), ExprFn::new(|x| f1(x[0], x[1])));
+    fns.insert("z0".to_string(), ExprFn::new(|_| 4.0));
+    fns
+}
+
+/// Instantiates the module with the host functions and evaluates it
+fn run(wasm: &Wasm, values: [f64; 3]) -> f64
+{
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm.bytes()).unwrap();
+    let mut store = Store::new(&engine, ());
+
+    let mut linker = Linker::<()>::new(&engine);
+    linker.func_wrap("env", "f0", f0).unwrap();
+    linker.func_wrap("env", "f1", f1).unwrap();
+    linker.func_wrap("env", "z0", || 4.0).unwrap();
+    linker
+        .func_wrap("fee", "pow", |a: f64, b: f64| {
+            if b == b as i64 as f64 {
+                a.powi(b as i32)
+            } else {
+                a.powf(b)
+            }
+        })
+        .unwrap();
+    linker
+        .func_wrap("fee", "mod", |a: f64, b: f64| a % b)
+        .unwrap();
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+
+    let memory = instance.get_memory(&store, "memory").unwrap();
+    for (idx, var) in wasm.vars().iter().enumerate() {
+        let pos = ["y0", "y1", "p0"]
+            .iter()
+            .position(|name| name == var)
+            .unwrap();
+        memory
+            .write(&mut store, 8 + 8 * idx, &values[pos].to_le_bytes())
+            .unwrap();
+    }
+
+    let eval = instance.get_typed_func::<i32, f64>(&store, "eval").unwrap();
+    eval.call(&mut store, 8).unwrap()
+}
+
+/// Same value, considering every `NaN` equal
+fn same(a: f64, b: f64) -> bool
+{
+    a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+}
+
+#[test]
+fn test_wasm_eval()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(default_vars(values), default_fns());
+            let expr = Expr::compile(src, &ctx).unwrap();
+            let expected = expr.eval(&ctx, &mut stack).unwrap();
+
+            let wasm = Wasm::try_from(&expr).unwrap();
+            let res = run(&wasm, values);
+            assert!(same(res, expected), "{src} {values:?}: {res} != {expected}");
+        }
+    }
+}
+
+#[test]
+fn test_wasm_optimized()
+{
+    let mut stack = Vec::new();
+
+    for src in EXPRS {
+        for values in VALUES {
+            let ctx = Context::new(default_vars(values), default_fns());
+            let mut expr = Expr::compile(src, &ctx).unwrap();
+            expr.simplify(SimplifyMode::IeeeSafe);
+            expr.eliminate_common_subexprs(&ctx);
+            let expected = expr.eval(&ctx, &mut stack).unwrap();
+
+            let wasm = Wasm::try_from(&expr).unwrap();
+            let res = run(&wasm, values);
+            assert!(same(res, expected), "{src} {values:?}: {res} != {expected}");
+        }
+    }
+}
+
+#[test]
+fn test_wasm_interface()
+{
+    let ctx = Context::new(default_vars(VALUES[0]), default_fns());
+
+    let expr = Expr::compile("p0 * f1(y0, 2) + f0(p0) ^ y1 % 2", &ctx).unwrap();
+    let wasm = Wasm::try_from(&expr).unwrap();
+    assert_eq!(wasm.vars(), ["p0", "y0", "y1"]);
+    assert_eq!(wasm.fns(), [("f1", 2), ("f0", 1)]);
+
+    // Only the helpers used by the expression are imported
+    let expr = Expr::compile("y0 * 2", &ctx).unwrap();
+    let wasm = Wasm::try_from(&expr).unwrap();
+    let engine = Engine::default();
+    let module = Module::new(&engine, wasm.bytes()).unwrap();
+    assert_eq!(module.imports().len(), 0);
+    let mut exports: Vec<_> = module.exports().map(|export| export.name()).collect();
+    exports.sort();
+    assert_eq!(exports, ["eval", "memory"]);
+}
+
+#[test]
+fn test_wasm_errors()
+{
+    let ctx = Context::new(default_vars(VALUES[0]), default_fns());
+
+    let expr = Expr::compile("f1(y0, 1) + f1(y0)", &ctx).unwrap();
+    assert!(matches!(Wasm::try_from(&expr), Err(Error::CodegenError(_))));
+}